@@ -0,0 +1,274 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::chat::now_millis;
+use crate::config;
+
+/// One banned peer. Either key alone is enough to match: the id is what
+/// the peer calls itself, the fingerprint is its DTLS certificate as
+/// announced in its SDP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub peer_id: Option<String>,
+    pub fingerprint: Option<String>,
+    pub reason: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub since: u64,
+}
+
+impl Ban {
+    fn matches(&self, peer_id: Option<&str>, fingerprint: Option<&str>) -> bool {
+        let id = peer_id.is_some() && self.peer_id.as_deref() == peer_id;
+        let fp = fingerprint.is_some() && self.fingerprint.as_deref() == fingerprint;
+        id || fp
+    }
+}
+
+/// Banned peers, kept as a JSON file that is rewritten on every change.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Ban list that is never written to disk.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let bans = if path.exists() {
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            bans,
+        })
+    }
+
+    pub fn is_banned(&self, peer_id: &str, fingerprint: Option<&str>) -> bool {
+        self.bans.iter().any(|b| b.matches(Some(peer_id), fingerprint))
+    }
+
+    /// Adds a ban, or fills in the missing key of an existing one.
+    pub fn ban(&mut self, peer_id: Option<String>, fingerprint: Option<String>, reason: Option<String>) -> Result<()> {
+        let fingerprint = fingerprint.map(|f| normalize(&f));
+        match self
+            .bans
+            .iter_mut()
+            .find(|b| b.matches(peer_id.as_deref(), fingerprint.as_deref()))
+        {
+            Some(ban) => {
+                ban.peer_id = ban.peer_id.take().or(peer_id);
+                ban.fingerprint = ban.fingerprint.take().or(fingerprint);
+                ban.reason = reason.or(ban.reason.take());
+            }
+            None => self.bans.push(Ban {
+                peer_id,
+                fingerprint,
+                reason,
+                since: now_millis(),
+            }),
+        }
+        self.save()
+    }
+
+    /// Lifts every ban on `key`, a peer id or fingerprint. Returns whether
+    /// there was one.
+    pub fn unban(&mut self, key: &str) -> Result<bool> {
+        let fingerprint = normalize(key);
+        let before = self.bans.len();
+        self.bans.retain(|b| !b.matches(Some(key), Some(&fingerprint)));
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<Ban> {
+        self.bans.clone()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(&self.bans)?)?;
+        Ok(())
+    }
+}
+
+/// Default ban list file.
+pub fn default_path() -> PathBuf {
+    config::data_dir().join("bans.json")
+}
+
+/// Whether `s` looks like a certificate fingerprint rather than a peer id,
+/// e.g. `sha-256 AB:CD:...` or just the colon separated hex.
+pub fn is_fingerprint(s: &str) -> bool {
+    let hex = s.rsplit(' ').next().unwrap_or(s);
+    hex.len() >= 3 * 16 - 1
+        && hex
+            .split(':')
+            .all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Certificate fingerprint from the `a=fingerprint` line of an SDP, as
+/// `<algorithm> <HEX:HEX:...>`.
+pub fn sdp_fingerprint(sdp: &str) -> Option<String> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(normalize)
+}
+
+// Fingerprints compare case-insensitively and may come without the
+// algorithm, which is then taken to be SHA-256.
+pub(crate) fn normalize(fingerprint: &str) -> String {
+    let fingerprint = fingerprint.trim();
+    match fingerprint.split_once(' ') {
+        Some((algo, hex)) => format!("{} {}", algo.to_lowercase(), hex.trim().to_uppercase()),
+        None => format!("sha-256 {}", fingerprint.to_uppercase()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerManager;
+    use crate::transport::loopback;
+    use crate::trust::TrustMode;
+    use std::sync::Arc;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+    const HEX: &str = "3C:4F:A1:0B:92:7E:11:D8:05:6A:BE:CC:29:F0:73:84:5D:E2:19:60:AF:0D:B7:48:92:35:C1:7A:EE:06:5B:F3";
+
+    fn fingerprint() -> String {
+        format!("sha-256 {}", HEX)
+    }
+
+    // An offer from a peer with the certificate above.
+    fn offer() -> String {
+        let sdp = format!(
+            "v=0\r\n\
+             o=- 2927307686215094172 877616351 IN IP4 0.0.0.0\r\n\
+             s=-\r\n\
+             t=0 0\r\n\
+             a=fingerprint:sha-256 {}\r\n\
+             m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             a=setup:actpass\r\n\
+             a=mid:0\r\n\
+             a=ice-ufrag:kZfLbYxWqGcHnRtE\r\n\
+             a=ice-pwd:pQmVrTzXcNbLkJhGfDsAeWuYiOoPlMnB\r\n",
+            HEX.to_lowercase()
+        );
+        serde_json::to_string(&RTCSessionDescription::offer(sdp).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn bans_by_id_or_fingerprint() {
+        let mut bans = BanList::in_memory();
+        bans.ban(Some("mallory".into()), None, Some("spam".into())).unwrap();
+        bans.ban(None, Some(HEX.to_lowercase()), None).unwrap();
+
+        assert!(bans.is_banned("mallory", None));
+        assert!(bans.is_banned("mallory", Some("sha-256 00:11")));
+        assert!(bans.is_banned("eve", Some(&fingerprint())));
+        assert!(!bans.is_banned("bob", None));
+        assert!(!bans.is_banned("bob", Some("sha-256 00:11")));
+        assert_eq!(bans.list()[1].fingerprint, Some(fingerprint()));
+    }
+
+    #[test]
+    fn fills_in_an_existing_ban() {
+        let mut bans = BanList::in_memory();
+        bans.ban(Some("mallory".into()), None, Some("spam".into())).unwrap();
+        bans.ban(Some("mallory".into()), Some(fingerprint()), None).unwrap();
+        bans.ban(None, Some(fingerprint()), Some("cheating".into())).unwrap();
+
+        let list = bans.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].peer_id.as_deref(), Some("mallory"));
+        assert_eq!(list[0].fingerprint, Some(fingerprint()));
+        assert_eq!(list[0].reason.as_deref(), Some("cheating"));
+    }
+
+    #[test]
+    fn unbans_by_either_key() {
+        let mut bans = BanList::in_memory();
+        bans.ban(Some("mallory".into()), Some(fingerprint()), None).unwrap();
+        bans.ban(Some("eve".into()), None, None).unwrap();
+
+        assert!(bans.unban(&HEX.to_lowercase()).unwrap());
+        assert!(!bans.is_banned("mallory", Some(&fingerprint())));
+        assert!(!bans.unban("mallory").unwrap());
+        assert!(bans.unban("eve").unwrap());
+        assert!(bans.list().is_empty());
+    }
+
+    #[test]
+    fn persists_bans() {
+        let dir = std::env::temp_dir().join(format!("lan-racer-ban-test-{}", std::process::id()));
+        let path = dir.join("bans.json");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut bans = BanList::open(&path).unwrap();
+        bans.ban(Some("mallory".into()), None, Some("spam".into())).unwrap();
+        bans.ban(Some("eve".into()), Some(fingerprint()), None).unwrap();
+        bans.unban("eve").unwrap();
+
+        let reopened = BanList::open(&path).unwrap();
+        assert_eq!(reopened.list(), bans.list());
+        assert!(reopened.is_banned("mallory", None));
+        assert!(!reopened.is_banned("eve", None));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn normalizes_fingerprints() {
+        assert_eq!(normalize(&HEX.to_lowercase()), fingerprint());
+        assert_eq!(normalize(&format!(" SHA-256  {} ", HEX.to_lowercase())), fingerprint());
+        assert!(is_fingerprint(HEX));
+        assert!(is_fingerprint(&fingerprint()));
+        assert!(!is_fingerprint("mallory"));
+        assert!(!is_fingerprint("AB:CD"));
+        assert_eq!(sdp_fingerprint("v=0\r\na=fingerprint:sha-256 ab:cd\r\n").as_deref(), Some("sha-256 AB:CD"));
+    }
+
+    #[tokio::test]
+    async fn refuses_banned_peers() {
+        let manager = PeerManager::in_memory("alice", TrustMode::Tofu).await;
+        manager.ban(Some("mallory".into()), None, None).await.unwrap();
+        let err = manager.accept_offer("mallory".into(), &offer()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Peer mallory is banned");
+
+        // The same certificate under another name.
+        manager.ban(None, Some(fingerprint()), None).await.unwrap();
+        assert!(manager.rejects("eve", &offer()));
+        assert!(manager.accept_offer("eve".into(), &offer()).await.is_err());
+        assert!(!manager.has_peer("eve").await);
+    }
+
+    #[tokio::test]
+    async fn drops_a_linked_peer_on_ban() {
+        let manager = PeerManager::in_memory("alice", TrustMode::Tofu).await;
+        let (near, _far) = loopback::pair();
+        manager.attach("mallory".into(), Arc::new(near)).await;
+        assert!(manager.has_peer("mallory").await);
+
+        manager.ban(Some("mallory".into()), None, None).await.unwrap();
+        assert!(!manager.has_peer("mallory").await);
+        assert!(manager.is_banned("mallory"));
+        assert!(manager.unban("mallory").unwrap());
+        assert!(!manager.is_banned("mallory"));
+    }
+}
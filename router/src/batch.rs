@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+// Each packet in a batch frame is prefixed with its length (u16, big endian).
+pub const LEN_PREFIX: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// How long to keep reading from the device after the first packet
+    /// before sending what was collected. Zero disables batching.
+    pub window: Duration,
+    pub max_packets: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_micros(500),
+            max_packets: 32,
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn enabled(&self) -> bool {
+        !self.window.is_zero() && self.max_packets > 1
+    }
+}
+
+pub fn push(batch: &mut Vec<u8>, packet: &[u8]) {
+    batch.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    batch.extend_from_slice(packet);
+}
+
+/// Splits a batch body back into its packets, stopping at the first
+/// truncated entry.
+pub fn unpack(mut body: &[u8]) -> Vec<&[u8]> {
+    let mut packets = Vec::new();
+
+    while body.len() >= LEN_PREFIX {
+        let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let Some(packet) = body.get(LEN_PREFIX..LEN_PREFIX + len) else {
+            break;
+        };
+        packets.push(packet);
+        body = &body[LEN_PREFIX + len..];
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_packets() {
+        let packets: [&[u8]; 3] = [b"first", b"", &[0xab; 1400]];
+        let mut batch = Vec::new();
+        for packet in packets {
+            push(&mut batch, packet);
+        }
+        assert_eq!(batch.len(), packets.iter().map(|p| LEN_PREFIX + p.len()).sum::<usize>());
+        assert_eq!(unpack(&batch), packets);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_packet() {
+        let mut batch = Vec::new();
+        push(&mut batch, b"whole");
+        push(&mut batch, b"cut short");
+        assert_eq!(unpack(&batch[..batch.len() - 1]), vec![&b"whole"[..]]);
+        assert_eq!(unpack(&batch[..LEN_PREFIX + 5 + 1]), vec![&b"whole"[..]]);
+        assert!(unpack(&[0]).is_empty());
+    }
+
+    #[test]
+    fn enabled_needs_a_window_and_room() {
+        assert!(BatchConfig::default().enabled());
+        assert!(!BatchConfig { window: Duration::ZERO, ..Default::default() }.enabled());
+        assert!(!BatchConfig { max_packets: 1, ..Default::default() }.enabled());
+    }
+}
@@ -0,0 +1,187 @@
+use iced::widget::{button, column, container, row, scrollable, text, text_input};
+use iced::{Center, Element, Length};
+use std::collections::HashMap;
+use std::fmt;
+
+use router::chat::{ChatMessage, ChatRecord, ChatTarget};
+
+use crate::Message;
+
+/// Either the broadcast channel or the direct conversation with one peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Conversation {
+    #[default]
+    All,
+    Peer(String),
+}
+
+impl Conversation {
+    /// Conversation `message` belongs to, seen from `me`.
+    fn of(message: &ChatMessage, me: &str) -> Self {
+        match &message.to {
+            ChatTarget::All => Conversation::All,
+            ChatTarget::Peer(to) if message.from == me => Conversation::Peer(to.clone()),
+            ChatTarget::Peer(_) => Conversation::Peer(message.from.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Conversation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conversation::All => f.write_str("Everyone"),
+            Conversation::Peer(peer) => f.write_str(peer),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ChatPane {
+    records: Vec<ChatRecord>,
+    selected: Conversation,
+    unread: HashMap<Conversation, usize>,
+    draft: String,
+}
+
+impl ChatPane {
+    pub fn selected(&self) -> &Conversation {
+        &self.selected
+    }
+
+    pub fn select(&mut self, conversation: Conversation) {
+        self.unread.remove(&conversation);
+        self.selected = conversation;
+    }
+
+    pub fn unread_total(&self) -> usize {
+        self.unread.values().sum()
+    }
+
+    pub fn draft(&self) -> &str {
+        &self.draft
+    }
+
+    pub fn set_draft(&mut self, draft: String) {
+        self.draft = draft;
+    }
+
+    pub fn take_draft(&mut self) -> String {
+        std::mem::take(&mut self.draft)
+    }
+
+    /// Replaces everything with the router's history.
+    pub fn load(&mut self, records: Vec<ChatRecord>) {
+        self.records = records;
+    }
+
+    /// Adds an incoming message, counting it as unread unless its
+    /// conversation is on screen.
+    pub fn receive(&mut self, message: ChatMessage, me: &str, visible: bool) {
+        let known = self
+            .records
+            .iter()
+            .any(|r| r.message.from == message.from && r.message.id == message.id);
+        if known {
+            return;
+        }
+
+        let conversation = Conversation::of(&message, me);
+        if !(visible && conversation == self.selected) {
+            *self.unread.entry(conversation).or_default() += 1;
+        }
+        self.records.push(ChatRecord {
+            message,
+            delivered_to: Vec::new(),
+        });
+    }
+
+    pub fn delivered(&mut self, me: &str, id: &str, peer: &str) {
+        let record = self
+            .records
+            .iter_mut()
+            .rev()
+            .find(|r| r.message.from == me && r.message.id == id);
+        if let Some(record) = record
+            && !record.delivered_to.iter().any(|p| p == peer)
+        {
+            record.delivered_to.push(peer.to_string());
+        }
+    }
+
+    pub fn view<'a>(&'a self, me: &'a str, peers: &[String]) -> Element<'a, Message> {
+        let mut conversations = vec![Conversation::All];
+        let known = peers
+            .iter()
+            .cloned()
+            .map(Conversation::Peer)
+            .chain(self.records.iter().map(|r| Conversation::of(&r.message, me)));
+        for conversation in known {
+            if !conversations.contains(&conversation) {
+                conversations.push(conversation);
+            }
+        }
+
+        let tabs = row(conversations.into_iter().map(|c| {
+            let label = match self.unread.get(&c) {
+                Some(n) if *n > 0 => format!("{} ({})", c, n),
+                _ => c.to_string(),
+            };
+            let style = if c == self.selected { button::primary } else { button::secondary };
+            button(text(label).size(13))
+                .on_press(Message::ChatSelect(c))
+                .style(style)
+                .into()
+        }))
+        .spacing(5)
+        .wrap();
+
+        let messages = self
+            .records
+            .iter()
+            .filter(|r| Conversation::of(&r.message, me) == self.selected)
+            .map(|r| {
+                let m = &r.message;
+                let status = if m.from != me {
+                    ""
+                } else if r.delivered_to.is_empty() {
+                    "  ·"
+                } else {
+                    "  ✓"
+                };
+                row![
+                    text(format!("{}:", m.from)).size(13).style(if m.from == me {
+                        text::secondary
+                    } else {
+                        text::primary
+                    }),
+                    text(format!("{}{}", m.text, status)).size(13),
+                ]
+                .spacing(5)
+                .into()
+            });
+
+        let input = row![
+            text_input(&format!("Message {}", self.selected), &self.draft)
+                .on_input(Message::ChatDraftChanged)
+                .on_submit(Message::ChatSend)
+                .padding(8)
+                .width(Length::Fill),
+            button("Send").on_press(Message::ChatSend).style(button::primary),
+        ]
+        .spacing(10)
+        .align_y(Center);
+
+        column![
+            tabs,
+            container(
+                scrollable(column(messages).spacing(4).width(Length::Fill))
+                    .anchor_bottom()
+                    .height(Length::Fill)
+            )
+            .padding(5),
+            input,
+        ]
+        .spacing(10)
+        .into()
+    }
+}
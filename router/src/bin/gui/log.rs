@@ -0,0 +1,252 @@
+use iced::widget::{column, container, pick_list, row, scrollable, text, text_input};
+use iced::{Element, Length, Theme};
+use std::collections::VecDeque;
+use std::fmt;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use router::chat::now_millis;
+use router::event::LanEvent;
+
+use crate::Message;
+
+// Oldest lines are dropped past this.
+const MAX_LINES: usize = 2000;
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub level: Level,
+    pub target: String,
+    pub text: String,
+}
+
+impl LogLine {
+    pub fn new(level: Level, target: &str, text: String) -> Self {
+        Self {
+            time: now_millis(),
+            level,
+            target: target.to_string(),
+            text,
+        }
+    }
+
+    pub fn event(event: &LanEvent) -> Option<Self> {
+        Some(Self::new(Level::INFO, "event", describe(event)?))
+    }
+}
+
+/// Lowest level the log pane shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Severity {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 4] = [Severity::Debug, Severity::Info, Severity::Warn, Severity::Error];
+
+    fn allows(self, level: Level) -> bool {
+        let rank = |l: Level| match l {
+            Level::TRACE => 0,
+            Level::DEBUG => 1,
+            Level::INFO => 2,
+            Level::WARN => 3,
+            Level::ERROR => 4,
+        };
+        let min = match self {
+            Severity::Debug => Level::DEBUG,
+            Severity::Info => Level::INFO,
+            Severity::Warn => Level::WARN,
+            Severity::Error => Level::ERROR,
+        };
+        rank(level) >= rank(min)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Debug => "Debug",
+            Severity::Info => "Info",
+            Severity::Warn => "Warn",
+            Severity::Error => "Error",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Default)]
+pub struct LogPane {
+    lines: VecDeque<LogLine>,
+    filter: String,
+    severity: Severity,
+}
+
+impl LogPane {
+    pub fn push(&mut self, line: LogLine) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn set_filter(&mut self, filter: String) {
+        self.filter = filter;
+    }
+
+    pub fn set_severity(&mut self, severity: Severity) {
+        self.severity = severity;
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        let filter = self.filter.to_lowercase();
+        let lines = self
+            .lines
+            .iter()
+            .filter(|l| self.severity.allows(l.level))
+            .filter(|l| {
+                filter.is_empty()
+                    || l.text.to_lowercase().contains(&filter)
+                    || l.target.to_lowercase().contains(&filter)
+            })
+            .map(|l| {
+                let line = text(format!("{} {:<5} {}: {}", clock(l.time), l.level, l.target, l.text))
+                    .size(12)
+                    .font(iced::Font::MONOSPACE);
+                let line = match l.level {
+                    Level::ERROR => line.style(text::danger),
+                    Level::WARN => line.style(|theme: &Theme| text::Style {
+                        color: Some(theme.palette().primary),
+                    }),
+                    _ => line,
+                };
+                line.into()
+            });
+
+        let controls = row![
+            text_input("Filter", &self.filter)
+                .on_input(Message::LogFilterChanged)
+                .padding(5)
+                .width(Length::Fill),
+            pick_list(Severity::ALL, Some(self.severity), Message::LogSeverityChanged),
+        ]
+        .spacing(10);
+
+        column![
+            controls,
+            container(
+                scrollable(column(lines).spacing(2).width(Length::Fill))
+                    .anchor_bottom()
+                    .height(Length::Fill)
+            )
+            .padding(5),
+        ]
+        .spacing(10)
+        .into()
+    }
+}
+
+/// Forwards tracing events, including bridged `log` records, to the log
+/// pane, prefixed with the spans they happened in.
+pub struct GuiLayer {
+    tx: broadcast::Sender<LogLine>,
+}
+
+impl GuiLayer {
+    pub fn new(tx: broadcast::Sender<LogLine>) -> Self {
+        Self { tx }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for GuiLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = Fields::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            let text = format!("{}{{{}}}", span.name(), visitor.text.trim_start());
+            span.extensions_mut().insert(SpanText(text));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = Fields::default();
+        event.record(&mut visitor);
+
+        let mut text = String::new();
+        for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            if let Some(SpanText(span)) = span.extensions().get::<SpanText>() {
+                text.push_str(span);
+                text.push_str(": ");
+            }
+        }
+        text.push_str(&visitor.text);
+
+        let meta = event.metadata();
+        let _ = self.tx.send(LogLine::new(*meta.level(), meta.target(), text));
+    }
+}
+
+// A span as shown in front of the lines logged in it, e.g. `peer{id=bob}`.
+struct SpanText(String);
+
+// The message first, then any other fields as `name=value`.
+#[derive(Default)]
+struct Fields {
+    text: String,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use fmt::Write;
+
+        if field.name() == "message" {
+            self.text.insert_str(0, &format!("{:?}", value));
+        } else if !field.name().starts_with("log.") {
+            let _ = write!(self.text, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+fn describe(event: &LanEvent) -> Option<String> {
+    let text = match event {
+        LanEvent::PacketFromPeer(_) | LanEvent::PeerLatency { .. } => return None,
+        LanEvent::NewPeerOffer(peer, _) => format!("Offer received from {}", peer),
+        LanEvent::ChatMessage(m) => format!("Chat message from {}", m.from),
+        LanEvent::ChatDelivered { peer_id, message_id } => {
+            format!("Chat message {} delivered to {}", message_id, peer_id)
+        }
+        LanEvent::PeerConnected(peer) => format!("Peer {} connected", peer),
+        LanEvent::PeerDisconnected(peer) => format!("Peer {} disconnected", peer),
+        LanEvent::PeerHello { peer_id, software, protocol } => {
+            format!("Peer {} runs {} (protocol {})", peer_id, software, protocol)
+        }
+        LanEvent::FileOffered { peer_id, transfer_id, name, size } => {
+            format!("{} offers {} ({} bytes), transfer {}", peer_id, name, size, transfer_id)
+        }
+        LanEvent::FileProgress { peer_id, transfer_id, bytes, total } => {
+            format!("Transfer {} with {}: {}/{} bytes", transfer_id, peer_id, bytes, total)
+        }
+        LanEvent::FileCompleted { peer_id, transfer_id, .. } => {
+            format!("Transfer {} with {} done", transfer_id, peer_id)
+        }
+        LanEvent::FileFailed { peer_id, transfer_id, reason } => {
+            format!("Transfer {} with {} failed: {}", transfer_id, peer_id, reason)
+        }
+    };
+    Some(text)
+}
+
+// UTC time of day, which is all a log pane needs.
+fn clock(millis: u64) -> String {
+    let secs = millis / 1000 % 86_400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
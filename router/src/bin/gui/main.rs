@@ -0,0 +1,847 @@
+use anyhow::Result;
+use clap::Parser;
+use futures::Stream;
+use iced::widget::{
+    button, column, container, horizontal_space, mouse_area, row, scrollable, stack, text,
+    text_input, vertical_space,
+};
+use iced::{
+    Background, Border, Center, Color, Element, Length, Shadow, Subscription, Task, Theme, Vector,
+};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use router::ban::Ban;
+use router::chat::ChatRecord;
+use router::config::RouterConfig;
+use router::event::LanEvent;
+use router::latency::LatencySnapshot;
+use router::logging::{self, LogConfig};
+use router::peer;
+use router::router::{Outcome, Router, RouterCommand};
+
+use chat::{ChatPane, Conversation};
+use log::{GuiLayer, LogLine, LogPane, Severity};
+
+mod chat;
+mod log;
+
+// Status shown for banned peers.
+const BANNED: &str = "banned";
+// How often the peer table is refreshed between events.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+// Log lines not yet taken by the log pane before it starts missing some.
+const LOG_BACKLOG: usize = 1024;
+// Messages loaded into the chat pane.
+const CHAT_HISTORY: usize = 500;
+
+/// Control panel for a router running in this process.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Name of the TUN device to create.
+    #[arg(default_value = "tun1")]
+    device: String,
+    /// Address of this node on the virtual network.
+    #[arg(default_value = "10.10.0.2")]
+    address: Ipv4Addr,
+    /// Id other peers use to reach this node.
+    #[arg(default_value = "peer1")]
+    peer_id: String,
+    #[arg(long, default_value = "255.255.255.0")]
+    netmask: Ipv4Addr,
+    /// Filled into the Start Server dialog.
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    signal_server: String,
+    #[command(flatten)]
+    log: LogConfig,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = RouterConfig {
+        device: args.device,
+        address: args.address,
+        netmask: args.netmask,
+        peer_id: args.peer_id,
+        signal_server: args.signal_server,
+        ..Default::default()
+    };
+
+    let (log_tx, _) = broadcast::channel(LOG_BACKLOG);
+    let _log = logging::init_with(&args.log, "gui", Some(Box::new(GuiLayer::new(log_tx.clone()))))?;
+
+    iced::application("floating", UIState::update, UIState::view)
+        .subscription(UIState::subscription)
+        .theme(|_| Theme::Dracula)
+        .window_size((640.0, 560.0))
+        .run_with(move || (UIState::new(config, log_tx), Task::none()))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct PeerInfo {
+    id: String,
+    ip: String,
+    status: String,
+    copied: bool,
+    latency: Option<LatencySnapshot>,
+}
+
+impl PeerInfo {
+    /// Status with the round trip time and loss once there is one, e.g.
+    /// `connected · 12.3 ms · 0% loss`.
+    fn status_text(&self) -> String {
+        match &self.latency {
+            Some(LatencySnapshot { avg_rtt_ms: Some(rtt), loss, .. }) if self.status == "connected" => {
+                format!("{} · {:.1} ms · {:.0}% loss", self.status, rtt, loss * 100.0)
+            }
+            _ => self.status.clone(),
+        }
+    }
+}
+
+struct UIState {
+    config: RouterConfig,
+    /// Set while a router runs; replaced on every start.
+    router: Option<Arc<Router>>,
+    cmd_tx: Option<mpsc::Sender<RouterCommand>>,
+    token: CancellationToken,
+    /// Fires once the last router started has returned.
+    stopped: Option<oneshot::Receiver<()>>,
+    /// Distinguishes the event streams of successive routers.
+    generation: u64,
+    my_ip: String,
+    my_mask: String,
+    state: String,
+    error: Option<String>,
+    peers: Vec<PeerInfo>,
+    menu: Option<String>,
+    tab: Tab,
+    chat: ChatPane,
+    log: LogPane,
+    log_tx: broadcast::Sender<LogLine>,
+
+    modal:ModalState, 
+    is_editing: bool,
+    input_id: String,
+    input_ip: String,
+    editing_target_id: Option<String>,
+    start_server_ip: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Peers,
+    Chat,
+    Log,
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    OpenAddModal,
+    OpenConnectModal, 
+    OpenEditModal(PeerInfo),
+    OpenStartServerModal,
+    CloseModal,
+    InputIdChanged(String),
+    InputIpChanged(String),
+    InputStartServerIpChanged(String),
+    SubmitForm,
+    DeletePeer(String),
+    BanPeer(String),
+    UnbanPeer(String),
+    CopyIp(String),
+    ToggleMenu(String),
+    RouterStopped(u64, Result<(), String>),
+    Event(LanEvent),
+    Refresh,
+    PeersLoaded(Vec<peer::PeerInfo>),
+    BansLoaded(Vec<Ban>),
+    CommandFailed(String),
+    SelectTab(Tab),
+    ChatSelect(Conversation),
+    ChatDraftChanged(String),
+    ChatSend,
+    ChatLoaded(Vec<ChatRecord>),
+    Log(LogLine),
+    LogFilterChanged(String),
+    LogSeverityChanged(Severity),
+}
+
+#[derive(Debug, Clone)]
+enum ModalState {
+    None,
+    AddPeer,
+    EditPeer,   // peer id
+    ConnectPeer,
+    StartServer,
+}
+
+impl UIState {
+    fn new(config: RouterConfig, log_tx: broadcast::Sender<LogLine>) -> Self {
+        Self {
+            my_ip: config.address.to_string(),
+            my_mask: config.netmask.to_string(),
+            start_server_ip: config.signal_server.clone(),
+            config,
+            router: None,
+            cmd_tx: None,
+            token: CancellationToken::new(),
+            stopped: None,
+            generation: 0,
+            state: "Stopped".to_string(),
+            error: None,
+            peers: Vec::new(),
+            menu: None,
+            tab: Tab::Peers,
+            chat: ChatPane::default(),
+            log: LogPane::default(),
+            log_tx,
+
+            modal: ModalState::None,
+            is_editing: false,
+            input_id: String::new(),
+            input_ip: String::new(),
+            editing_target_id: None,
+        }
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::OpenAddModal => {
+                self.input_id.clear();
+                self.input_ip.clear();
+                self.is_editing = false;
+                self.modal = ModalState::AddPeer;
+            }
+            Message::OpenConnectModal => {
+                self.input_id.clear();
+                self.is_editing = false;
+                self.modal = ModalState::ConnectPeer;
+            }
+
+            Message::OpenEditModal(peer) => {
+                self.input_id = peer.id.clone();
+                self.input_ip = peer.ip;
+                self.editing_target_id = Some(peer.id);
+                self.is_editing = true;
+                self.menu = None;
+                self.modal = ModalState::EditPeer;
+            }
+            Message::CloseModal => {
+                self.modal = ModalState::None;
+            }
+            Message::OpenStartServerModal => {
+                self.modal = ModalState::StartServer;
+            }
+            Message::InputIdChanged(val) => self.input_id = val,
+            Message::InputIpChanged(val) => self.input_ip = val,
+            Message::InputStartServerIpChanged(val) => self.start_server_ip = val,
+
+            Message::SubmitForm => {
+                let modal = std::mem::replace(&mut self.modal, ModalState::None);
+                match modal {
+                    ModalState::StartServer => return self.start(),
+                    ModalState::ConnectPeer => {
+                        let peer_id = self.input_id.trim().to_string();
+                        self.upsert_peer(&peer_id, String::new(), "Connecting");
+                        return self.command(RouterCommand::ConnectToPeer { peer_id });
+                    }
+                    // Peers added by hand are remembered with the address
+                    // they are expected at until the router learns theirs.
+                    ModalState::AddPeer => {
+                        let peer_id = self.input_id.trim().to_string();
+                        self.upsert_peer(&peer_id, self.input_ip.clone(), "Connecting");
+                        return self.command(RouterCommand::ConnectToPeer { peer_id });
+                    }
+                    ModalState::EditPeer => {
+                        if let Some(target) = &self.editing_target_id
+                            && let Some(peer) = self.peers.iter_mut().find(|p| &p.id == target)
+                        {
+                            peer.id = self.input_id.clone();
+                            peer.ip = self.input_ip.clone();
+                        }
+                    }
+                    ModalState::None => {}
+                }
+            }
+
+            Message::DeletePeer(id) => {
+                self.menu = None;
+                self.peers.retain(|p| p.id != id);
+                return self.command(RouterCommand::Disconnect { peer_id: id });
+            }
+            Message::BanPeer(id) => {
+                self.menu = None;
+                self.upsert_peer(&id, String::new(), BANNED);
+                return self.command(RouterCommand::Ban {
+                    peer_id: Some(id),
+                    fingerprint: None,
+                    reason: None,
+                });
+            }
+            Message::UnbanPeer(key) => {
+                self.menu = None;
+                self.peers.retain(|p| p.id != key);
+                return self.command(RouterCommand::Unban { key });
+            }
+            Message::CopyIp(ip) => {
+                for peer in &mut self.peers {
+                    peer.copied = peer.ip == ip;
+                }
+                return iced::clipboard::write::<Message>(ip.clone());
+             }
+
+            Message::ToggleMenu(id) => {
+                self.menu = if self.menu.as_ref() == Some(&id) { None } else { Some(id) };
+            }
+
+            // A router replaced by a newer one.
+            Message::RouterStopped(generation, _) if generation != self.generation => {}
+            Message::RouterStopped(_, result) => {
+                self.router = None;
+                self.cmd_tx = None;
+                for peer in &mut self.peers {
+                    peer.status = "Disconnected".into();
+                }
+                match result {
+                    Ok(()) => self.state = "Stopped".into(),
+                    Err(e) => {
+                        self.state = "Failed".into();
+                        self.log.push(LogLine::new(tracing::Level::ERROR, "gui", e.clone()));
+                        self.error = Some(e);
+                    }
+                }
+            }
+
+            Message::Event(event) => {
+                if let Some(line) = LogLine::event(&event) {
+                    self.log.push(line);
+                }
+                match event {
+                    LanEvent::PeerConnected(id) => {
+                        self.upsert_peer(&id, String::new(), "connected");
+                        return self.command(RouterCommand::ListPeers);
+                    }
+                    LanEvent::PeerDisconnected(id) if !self.is_banned(&id) => {
+                        self.upsert_peer(&id, String::new(), "disconnected");
+                    }
+                    LanEvent::PeerLatency { peer_id, latency } => {
+                        if let Some(peer) = self.peers.iter_mut().find(|p| p.id == peer_id) {
+                            peer.latency = Some(latency);
+                        }
+                    }
+                    LanEvent::ChatMessage(message) => {
+                        let visible = self.tab == Tab::Chat;
+                        self.chat.receive(message, &self.config.peer_id, visible);
+                    }
+                    LanEvent::ChatDelivered { peer_id, message_id } => {
+                        self.chat.delivered(&self.config.peer_id, &message_id, &peer_id);
+                    }
+                    _ => {}
+                }
+            }
+
+            Message::Refresh => {
+                return Task::batch([
+                    self.command(RouterCommand::ListPeers),
+                    self.command(RouterCommand::ListBans),
+                ]);
+            }
+
+            Message::PeersLoaded(list) => {
+                for p in list {
+                    let ip = p
+                        .addresses
+                        .iter()
+                        .find(|a| a.is_ipv4())
+                        .map(|a| a.to_string())
+                        .unwrap_or_default();
+                    self.upsert_peer(&p.id, ip, &p.state.to_string());
+                    if let Some(peer) = self.peers.iter_mut().find(|peer| peer.id == p.id) {
+                        peer.latency = p.latency;
+                    }
+                }
+            }
+            // Bans by fingerprint alone have no id, so the row shows the
+            // fingerprint instead.
+            Message::BansLoaded(bans) => {
+                for ban in bans {
+                    if let Some(key) = ban.peer_id.or(ban.fingerprint) {
+                        self.upsert_peer(&key, String::new(), BANNED);
+                    }
+                }
+            }
+
+            Message::CommandFailed(e) => {
+                self.log.push(LogLine::new(tracing::Level::WARN, "gui", e.clone()));
+                self.error = Some(e);
+            }
+
+            Message::SelectTab(tab) => {
+                self.tab = tab;
+                if tab == Tab::Chat {
+                    let selected = self.chat.selected().clone();
+                    self.chat.select(selected);
+                }
+            }
+            Message::ChatSelect(conversation) => self.chat.select(conversation),
+            Message::ChatDraftChanged(draft) => self.chat.set_draft(draft),
+            Message::ChatSend => {
+                if self.chat.draft().trim().is_empty() {
+                    return Task::none();
+                }
+                let message = self.chat.take_draft();
+                let send = match self.chat.selected().clone() {
+                    Conversation::All => RouterCommand::BroadcastChat { message },
+                    Conversation::Peer(peer_id) => RouterCommand::SendChat { peer_id, message },
+                };
+                // Reload afterwards to show the message as the router sent it.
+                return self.command(send).chain(self.load_chat());
+            }
+            Message::ChatLoaded(records) => self.chat.load(records),
+
+            Message::Log(line) => self.log.push(line),
+            Message::LogFilterChanged(filter) => self.log.set_filter(filter),
+            Message::LogSeverityChanged(severity) => self.log.set_severity(severity),
+        }
+        Task::none()
+    }
+
+    /// Starts the router against the signaling server from the dialog,
+    /// stopping any previous one first.
+    fn start(&mut self) -> Task<Message> {
+        self.token.cancel();
+        self.token = CancellationToken::new();
+        self.generation += 1;
+        self.error = None;
+
+        let config = RouterConfig {
+            signal_server: self.start_server_ip.trim().to_string(),
+            ..self.config.clone()
+        };
+        let router = Arc::new(Router::new(config));
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        self.router = Some(router.clone());
+        self.cmd_tx = Some(cmd_tx);
+        self.state = "Running".into();
+
+        // The old router holds the TUN device until it returns, so the new
+        // one waits for it.
+        let previous = self.stopped.take();
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        self.stopped = Some(stopped_rx);
+
+        let token = self.token.clone();
+        let generation = self.generation;
+        let run = Task::perform(
+            async move {
+                if let Some(previous) = previous {
+                    let _ = previous.await;
+                }
+                let result = router.route(token, cmd_rx).await.map_err(|e| format!("{:#}", e));
+                let _ = stopped_tx.send(());
+                result
+            },
+            move |result| Message::RouterStopped(generation, result),
+        );
+        Task::batch([run, self.load_chat()])
+    }
+
+    fn load_chat(&self) -> Task<Message> {
+        self.command(RouterCommand::ChatHistory {
+            peer_id: None,
+            before: None,
+            limit: CHAT_HISTORY,
+        })
+    }
+
+    /// Runs `cmd` on the router; failures end up in the error line.
+    fn command(&self, cmd: RouterCommand) -> Task<Message> {
+        let Some(cmd_tx) = self.cmd_tx.clone() else {
+            return Task::done(Message::CommandFailed("The router is not running.".into()));
+        };
+        Task::perform(call(cmd_tx, cmd), |result| match result {
+            Ok(Outcome::Peers(peers)) => Message::PeersLoaded(peers),
+            Ok(Outcome::History(records)) => Message::ChatLoaded(records),
+            Ok(Outcome::Bans(bans)) => Message::BansLoaded(bans),
+            Ok(_) => Message::Refresh,
+            Err(e) => Message::CommandFailed(e),
+        })
+    }
+
+    fn is_banned(&self, id: &str) -> bool {
+        self.peers.iter().any(|p| p.id == id && p.status == BANNED)
+    }
+
+    /// Updates the row for `id`, adding one if needed. An empty `ip` keeps
+    /// the one already shown.
+    fn upsert_peer(&mut self, id: &str, ip: String, status: &str) {
+        if id.is_empty() {
+            return;
+        }
+        match self.peers.iter_mut().find(|p| p.id == id) {
+            Some(peer) => {
+                if !ip.is_empty() {
+                    peer.ip = ip;
+                }
+                peer.status = status.to_string();
+            }
+            None => self.peers.push(PeerInfo {
+                id: id.to_string(),
+                ip,
+                status: status.to_string(),
+                copied: false,
+                latency: None,
+            }),
+        }
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        let logs = Subscription::run_with_id("log", log_lines(self.log_tx.subscribe()));
+        let Some(router) = &self.router else {
+            return logs;
+        };
+        Subscription::batch([
+            logs,
+            Subscription::run_with_id(self.generation, events(router.subscribe())),
+            iced::time::every(REFRESH_INTERVAL).map(|_| Message::Refresh),
+        ])
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let info_header = container(
+            row![
+                label_value("IP:", &self.my_ip),
+                label_value("Mask:", &self.my_mask),
+                label_value("State:", &self.state),
+            ]
+            .spacing(20),
+        );
+
+        let controls = row![
+            horizontal_space(),
+            button("Start Server")
+                .on_press(Message::OpenStartServerModal)
+                .style(button::primary),
+            button("Connect Peer")
+                .on_press(Message::OpenConnectModal)
+                .style(button::primary),
+            button("Add Peer")
+                .on_press(Message::OpenAddModal)
+                .style(button::primary),
+            horizontal_space(),
+        ]
+        .spacing(10);
+
+        let table_header = row![
+            text("ID")
+                .width(Length::FillPortion(1))
+                .style(text::primary),
+            text("IP Address")
+                .width(Length::FillPortion(2))
+                .style(text::primary),
+            text("Status")
+                .width(Length::FillPortion(1))
+                .style(text::primary),
+        ]
+        .padding(10);
+
+        let peers_list = column(self.peers.iter().map(|peer| {
+            let row_content = container(
+                row![
+                    text(&peer.id).width(Length::FillPortion(1)),
+                    text(&peer.ip).width(Length::FillPortion(2)),
+                    if peer.copied {
+                            text(format!("{} (copied)", peer.status_text())).width(Length::FillPortion(1)).style(text::success)
+                    } else {
+                            text(peer.status_text()).width(Length::FillPortion(1))
+                    },
+                ]
+                .align_y(Center),
+            )
+            .padding(10)
+            .style(|theme: &Theme| container::Style {
+                background: Some(Background::Color(theme.palette().background)),
+                border: Border {
+                    radius: 4.0.into(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+
+
+            let row_content = mouse_area(row_content).on_right_press(Message::ToggleMenu(peer.id.clone()));
+            if self.menu.as_ref() != Some(&peer.id) {
+                return row_content.into();
+            }
+
+            let menu = container(
+                    column![
+                        button(text("Edit").size(14))
+                            .on_press(Message::OpenEditModal(peer.clone()))
+                            .style(button::text)
+                            .width(Length::Fill),
+                        button(text("Copy IP").size(14))
+                            .on_press(Message::CopyIp(peer.ip.clone()))
+                            .style(button::text)
+                            .width(Length::Fill),
+                        if peer.status == BANNED {
+                            button(text("Unban").size(14))
+                                .on_press(Message::UnbanPeer(peer.id.clone()))
+                                .style(button::text)
+                                .width(Length::Fill)
+                        } else {
+                            button(text("Ban").size(14))
+                                .on_press(Message::BanPeer(peer.id.clone()))
+                                .style(button::text)
+                                .width(Length::Fill)
+                        },
+                        button(text("Delete").size(14))
+                            .on_press(Message::DeletePeer(peer.id.clone()))
+                            .style(button::danger)
+                            .width(Length::Fill),
+
+                        if peer.copied {
+                              container ( text("Copied!").size(12).style(text::success)
+                                .width(Length::Fill)
+                            )
+                        } else {
+                            container(text("") // still not ideal, but better than before
+                                .width(Length::Fill)
+                            )
+                        }    
+                    ]
+                    .padding(5)
+                    .spacing(2)
+                    .width(150),
+                )
+                .style(|theme: &Theme| container::Style {
+                    background: Some(Background::Color(theme.palette().background)),
+                    border: Border {
+                        radius: 10.0.into(),
+                        width: 1.0,
+                        color: theme.palette().primary,
+                    },
+                    shadow: Shadow {
+                        color: Color::BLACK,
+                        offset: Vector::new(0.0, 4.0),
+                        blur_radius: 10.0,
+                    },
+                    ..Default::default()
+                });
+
+            column![row_content, menu].spacing(2).into()
+        }))
+        .spacing(5);
+
+        let error_line = match &self.error {
+            Some(e) => text(e).size(12).style(text::danger),
+            None => text(""),
+        };
+
+        let unread = self.chat.unread_total();
+        let chat_label = if unread > 0 { format!("Chat ({})", unread) } else { "Chat".to_string() };
+        let tabs = row([(Tab::Peers, "Peers".to_string()), (Tab::Chat, chat_label), (Tab::Log, "Log".to_string())]
+            .into_iter()
+            .map(|(tab, label)| {
+                let style = if tab == self.tab { button::primary } else { button::secondary };
+                button(text(label)).on_press(Message::SelectTab(tab)).style(style).into()
+            }))
+        .spacing(10);
+
+        let body: Element<'_, Message> = match self.tab {
+            Tab::Peers => column![
+                controls,
+                vertical_space().height(10),
+                container(column![table_header, scrollable(peers_list)])
+            ]
+            .into(),
+            Tab::Chat => {
+                let peers: Vec<String> = self.peers.iter().map(|p| p.id.clone()).collect();
+                self.chat.view(&self.config.peer_id, &peers)
+            }
+            Tab::Log => self.log.view(),
+        };
+
+        let dashboard = container(
+            column![
+                info_header,
+                error_line,
+                vertical_space().height(10),
+                tabs,
+                vertical_space().height(10),
+                body,
+            ]
+            .padding(20)
+            .max_width(800)
+            .align_x(Center),
+        )
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .center_x(Length::Fill);
+
+        stack![dashboard, self.view_modal_overlay()].into()
+        
+    }
+
+    fn view_modal_overlay(&self) -> Element<'_, Message> {
+    if matches!(self.modal, ModalState::None) {
+        return container(text("")).into();
+    }
+
+    // 🎯 Title based on modal
+    let title = match self.modal {
+        ModalState::AddPeer => "Add New Peer",
+        ModalState::EditPeer => "Edit Peer",
+        ModalState::ConnectPeer => "Connect to Peer",
+        ModalState::StartServer => "Start Server",
+        ModalState::None => "",
+    };
+
+    // 🎯 Dynamic content
+    let content = match self.modal {
+        ModalState::AddPeer | ModalState::EditPeer => column![
+            text("Peer ID").size(12),
+            text_input("e.g. peer-1", &self.input_id)
+                .on_input(Message::InputIdChanged)
+                .padding(10),
+
+            vertical_space().height(10),
+
+            text("IP Address").size(12),
+            text_input("e.g. 10.0.0.5", &self.input_ip)
+                .on_input(Message::InputIpChanged)
+                .padding(10),
+        ],
+
+        ModalState::ConnectPeer => column![
+            text("Peer ID").size(12),
+            text_input("e.g. peer-1", &self.input_id)
+                 .on_input(Message::InputIdChanged)
+                .padding(10),
+                      ],
+
+        ModalState::StartServer => column![
+            text("Signaling Server IP").size(12),
+            text_input("e.g. 192.168.1.1:9000", &self.start_server_ip)
+                .on_input(Message::InputStartServerIpChanged)
+                .padding(10),
+        ],
+
+        ModalState::None => column![],
+    };
+
+    // 🎯 Button text
+    let action_label = match self.modal {
+        ModalState::AddPeer => "Save",
+        ModalState::EditPeer => "Update",
+        ModalState::ConnectPeer => "Connect",
+        ModalState::StartServer => "Start",
+        ModalState::None => "",
+    };
+
+    let modal_card = container(column![
+        text(title).size(18),
+
+        vertical_space().height(15),
+
+        content,
+
+        vertical_space().height(20),
+
+        row![
+            button("Cancel")
+                .on_press(Message::CloseModal)
+                .style(button::secondary),
+
+            horizontal_space(),
+
+            button(action_label)
+                .on_press(Message::SubmitForm)
+                .style(button::primary),
+        ]
+    ])
+    .width(300)
+    .padding(20)
+    .style(|theme: &Theme| container::Style {
+        background: Some(Background::Color(theme.palette().background)),
+        border: Border {
+            radius: 10.0.into(),
+            width: 1.0,
+            color: theme.palette().primary,
+        },
+        shadow: Shadow {
+            color: Color::BLACK,
+            offset: Vector::new(0.0, 4.0),
+            blur_radius: 10.0,
+        },
+        ..Default::default()
+    });
+
+    let overlay = mouse_area(
+        container(modal_card)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x(Length::Fill)
+            .center_y(Length::Fill)
+            .style(|_theme: &Theme| container::Style {
+                background: Some(Background::Color(Color {
+                    a: 0.8,
+                    ..Color::BLACK
+                })),
+                ..Default::default()
+            }),
+    )
+    .on_press(Message::CloseModal);
+
+    overlay.into()
+}
+}
+
+fn label_value<'a>(label: &'a str, value: &'a str) -> Element<'a, Message> {
+    row![
+        text(label).style(text::secondary),
+        text(value).style(text::primary)
+    ]
+    .spacing(5)
+    .into()
+}
+async fn call(cmd_tx: mpsc::Sender<RouterCommand>, command: RouterCommand) -> Result<Outcome, String> {
+    let (reply, rx) = oneshot::channel();
+    let command = Box::new(command);
+    cmd_tx
+        .send(RouterCommand::Call { command, reply })
+        .await
+        .map_err(|_| "The router is not running.".to_string())?;
+    rx.await.map_err(|_| "The router stopped.".to_string())?
+}
+
+fn log_lines(rx: broadcast::Receiver<LogLine>) -> impl Stream<Item = Message> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(line) => return Some((Message::Log(line), rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+fn events(rx: broadcast::Receiver<LanEvent>) -> impl Stream<Item = Message> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((Message::Event(event), rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,135 @@
+use anyhow::{Result, anyhow, bail};
+use clap::Parser;
+use serde_json::{Map, Value, json};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use router::config;
+use router::control::{EVENT, SUBSCRIBE};
+
+/// Talks to a running router over its control socket.
+///
+/// Methods are the router's commands in snake case, e.g. `list_peers`,
+/// `list_routes`, `show_stats`, `status`, `connect_to_peer peer_id=bob`,
+/// `send_chat peer_id=bob message=hi`. `subscribe` prints events as they
+/// happen, one JSON object per line.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Device of the router to talk to.
+    #[arg(long, default_value = "tun1")]
+    device: String,
+    /// Control socket path; overrides `--device`.
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    method: String,
+    /// Parameters as `key=value` pairs, or a single JSON object. Values
+    /// are strings; `key:=value` sends a number, boolean or other JSON
+    /// value instead, e.g. `count:=4`.
+    params: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let socket = args
+        .socket
+        .unwrap_or_else(|| config::control_socket(&args.device));
+    let params = parse_params(&args.params)?;
+
+    let stream = UnixStream::connect(&socket)
+        .await
+        .map_err(|e| anyhow!("cannot reach router at {}: {}", socket.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut request = json!({ "jsonrpc": "2.0", "id": 1, "method": args.method });
+    if let Some(params) = params {
+        request["params"] = params;
+    }
+    writer.write_all(format!("{}\n", request).as_bytes()).await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let msg: Value = serde_json::from_str(&line)?;
+
+        if msg["method"] == EVENT {
+            println!("{}", msg["params"]);
+            continue;
+        }
+        if let Some(error) = msg.get("error") {
+            bail!("{}", error["message"].as_str().unwrap_or("request failed"));
+        }
+        if args.method != SUBSCRIBE {
+            println!("{}", serde_json::to_string_pretty(&msg["result"])?);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_params(args: &[String]) -> Result<Option<Value>> {
+    if let [single] = args
+        && single.trim_start().starts_with('{')
+    {
+        return Ok(Some(serde_json::from_str(single)?));
+    }
+    if args.is_empty() {
+        return Ok(None);
+    }
+
+    let mut params = Map::new();
+    for arg in args {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or(anyhow!("expected key=value or key:=json, got {}", arg))?;
+        let (key, value) = match key.strip_suffix(':') {
+            Some(key) => {
+                let value = serde_json::from_str(value).map_err(|e| anyhow!("bad JSON for {}: {}", key, e))?;
+                (key, value)
+            }
+            None => (key, Value::String(value.to_owned())),
+        };
+        params.insert(key.to_owned(), value);
+    }
+    Ok(Some(Value::Object(params)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Value>> {
+        parse_params(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn values_are_strings() {
+        let params = parse(&["peer_id=123", "message=42", "reason=", "fingerprint=sha-256 AB:CD=="]).unwrap();
+        assert_eq!(
+            params,
+            Some(json!({ "peer_id": "123", "message": "42", "reason": "", "fingerprint": "sha-256 AB:CD==" }))
+        );
+    }
+
+    #[test]
+    fn json_values_need_a_marker() {
+        let params = parse(&["peer_id=bob", "count:=4", "seconds:=2.5", "mode:=\"unreliable\""]).unwrap();
+        assert_eq!(params, Some(json!({ "peer_id": "bob", "count": 4, "seconds": 2.5, "mode": "unreliable" })));
+        assert!(parse(&["count:=four"]).is_err());
+    }
+
+    #[test]
+    fn takes_a_json_object() {
+        let params = parse(&[r#" {"peer_id": "bob", "count": 4}"#]).unwrap();
+        assert_eq!(params, Some(json!({ "peer_id": "bob", "count": 4 })));
+        assert!(parse(&["{not json"]).is_err());
+    }
+
+    #[test]
+    fn rejects_bare_words() {
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert!(parse(&["bob"]).is_err());
+    }
+}
@@ -1,7 +1,25 @@
 use anyhow::Result;
+use clap::Parser;
+use std::net::SocketAddr;
+use router::logging::{self, LogConfig};
 use router::signaling::server;
 
+/// Relays offers and answers between routers.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    listen: String,
+    /// Serve Prometheus metrics at `http://<addr>/metrics`.
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+    #[command(flatten)]
+    log: LogConfig,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    server::run_server("127.0.0.1:9000").await
-}
\ No newline at end of file
+    let args = Args::parse();
+    let _log = logging::init(&args.log, "signal_server")?;
+    server::run_server(&args.listen, args.metrics).await
+}
@@ -0,0 +1,414 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::config;
+
+/// History page size when the caller does not ask for one.
+pub const DEFAULT_PAGE: usize = 20;
+
+// Messages kept per network; the oldest go first. The file is rewritten
+// with only those once it has twice as many lines as that.
+const MAX_RECORDS: usize = 10_000;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatTarget {
+    Peer(String),
+    /// Everyone connected at the time it was sent.
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Unique together with `from`.
+    pub id: String,
+    pub from: String,
+    pub to: ChatTarget,
+    /// Milliseconds since the Unix epoch, on the sender's clock.
+    pub timestamp: u64,
+    pub text: String,
+}
+
+impl ChatMessage {
+    pub fn new(from: String, to: ChatTarget, text: String) -> Self {
+        let timestamp = now_millis();
+        let seq = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            id: format!("{:x}-{}", timestamp, seq),
+            from,
+            to,
+            timestamp,
+            text,
+        }
+    }
+
+    /// Whether the message belongs to the conversation with `peer`: sent to
+    /// or by it directly, or a broadcast by it.
+    pub fn involves(&self, peer: &str) -> bool {
+        self.from == peer || self.to == ChatTarget::Peer(peer.to_owned())
+    }
+}
+
+/// Chat frame payload, sent as JSON on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatWire {
+    Message(ChatMessage),
+    Ack { id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatRecord {
+    pub message: ChatMessage,
+    /// Peers that acknowledged receiving an outgoing message.
+    pub delivered_to: Vec<String>,
+}
+
+// One line of the history file. Acks are appended as they arrive and
+// folded into their message when the file is loaded.
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Message(ChatMessage),
+    Ack { id: String, from: String, peer: String },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Only the conversation with this peer.
+    pub peer: Option<String>,
+    /// Only messages sent before this timestamp.
+    pub before: Option<u64>,
+    pub limit: usize,
+}
+
+/// Chat log of one network, kept as an append-only JSON lines file.
+pub struct ChatHistory {
+    path: Option<PathBuf>,
+    records: Vec<ChatRecord>,
+    seen: HashSet<(String, String)>,
+    limit: usize,
+    /// Lines in the file, and how many it may grow to before it is
+    /// rewritten.
+    lines: usize,
+    compact_at: usize,
+}
+
+impl ChatHistory {
+    /// History that is never written to disk.
+    pub fn in_memory() -> Self {
+        Self::with_limit(MAX_RECORDS)
+    }
+
+    fn with_limit(limit: usize) -> Self {
+        Self {
+            path: None,
+            records: Vec::new(),
+            seen: HashSet::new(),
+            limit,
+            lines: 0,
+            compact_at: 2 * limit,
+        }
+    }
+
+    pub fn open(dir: &Path, network: &str) -> Result<Self> {
+        Self::open_with_limit(dir, network, MAX_RECORDS)
+    }
+
+    fn open_with_limit(dir: &Path, network: &str, limit: usize) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.jsonl", sanitize(network)));
+
+        let mut history = Self::with_limit(limit);
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                history.lines += 1;
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => history.apply(entry),
+                    Err(e) => warn!("Skipping bad chat history line: {}", e),
+                }
+            }
+        }
+        history.path = Some(path);
+        history.trim()?;
+
+        Ok(history)
+    }
+
+    /// Records a message. Returns `false` for one already recorded.
+    pub fn record(&mut self, message: ChatMessage) -> Result<bool> {
+        let key = (message.from.clone(), message.id.clone());
+        if self.seen.contains(&key) {
+            return Ok(false);
+        }
+        self.append(&Entry::Message(message.clone()))?;
+        self.apply(Entry::Message(message));
+        self.trim()?;
+        Ok(true)
+    }
+
+    /// Marks message `id` sent by `from` as delivered to `peer`.
+    pub fn mark_delivered(&mut self, from: &str, id: &str, peer: &str) -> Result<()> {
+        let entry = Entry::Ack {
+            id: id.to_owned(),
+            from: from.to_owned(),
+            peer: peer.to_owned(),
+        };
+        self.append(&entry)?;
+        self.apply(entry);
+        self.trim()?;
+        Ok(())
+    }
+
+    /// Newest `limit` records matching the query, oldest first.
+    pub fn page(&self, query: &HistoryQuery) -> Vec<ChatRecord> {
+        let mut page: Vec<ChatRecord> = self
+            .records
+            .iter()
+            .rev()
+            .filter(|r| query.peer.as_deref().is_none_or(|p| r.message.involves(p)))
+            .filter(|r| query.before.is_none_or(|t| r.message.timestamp < t))
+            .take(query.limit)
+            .cloned()
+            .collect();
+        page.reverse();
+        page
+    }
+
+    /// Writes the whole history to `path`: a JSON array for `.json` files,
+    /// readable text otherwise.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let mut out = File::create(path)?;
+
+        if path.extension().is_some_and(|e| e == "json") {
+            serde_json::to_writer_pretty(&mut out, &self.records)?;
+            return Ok(());
+        }
+
+        for record in &self.records {
+            let m = &record.message;
+            let to = match &m.to {
+                ChatTarget::Peer(peer) => peer.as_str(),
+                ChatTarget::All => "all",
+            };
+            writeln!(out, "[{}] {} -> {}: {}", m.timestamp, m.from, to, m.text)?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let line = serde_json::to_string(entry)?;
+        writeln!(file, "{}", line)?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    // Drops the oldest records past the limit, and rewrites the file with
+    // the rest once it has grown too long.
+    fn trim(&mut self) -> Result<()> {
+        let excess = self.records.len().saturating_sub(self.limit);
+        for record in self.records.drain(..excess) {
+            self.seen.remove(&(record.message.from, record.message.id));
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.lines <= self.compact_at {
+            return Ok(());
+        }
+        let mut part = path.as_os_str().to_owned();
+        part.push(".tmp");
+        let part = PathBuf::from(part);
+
+        let mut out = BufWriter::new(File::create(&part)?);
+        let mut lines = 0;
+        for record in &self.records {
+            let message = &record.message;
+            let mut entries = vec![Entry::Message(message.clone())];
+            entries.extend(record.delivered_to.iter().map(|peer| Entry::Ack {
+                id: message.id.clone(),
+                from: message.from.clone(),
+                peer: peer.clone(),
+            }));
+            for entry in entries {
+                writeln!(out, "{}", serde_json::to_string(&entry)?)?;
+                lines += 1;
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&part, path)?;
+
+        self.lines = lines;
+        // Acks can outnumber messages, so leave room past what was kept.
+        self.compact_at = 2 * lines.max(self.limit);
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Message(message) => {
+                if self.seen.insert((message.from.clone(), message.id.clone())) {
+                    self.records.push(ChatRecord {
+                        message,
+                        delivered_to: Vec::new(),
+                    });
+                }
+            }
+            Entry::Ack { id, from, peer } => {
+                let record = self
+                    .records
+                    .iter_mut()
+                    .rev()
+                    .find(|r| r.message.id == id && r.message.from == from);
+                if let Some(record) = record
+                    && !record.delivered_to.contains(&peer)
+                {
+                    record.delivered_to.push(peer);
+                }
+            }
+        }
+    }
+}
+
+/// Default directory for chat history files.
+pub fn default_dir() -> PathBuf {
+    config::data_dir().join("chat")
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub(crate) fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from: &str, to: ChatTarget, text: &str, timestamp: u64) -> ChatMessage {
+        ChatMessage { timestamp, ..ChatMessage::new(from.into(), to, text.into()) }
+    }
+
+    fn texts(records: &[ChatRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.message.text.as_str()).collect()
+    }
+
+    fn everything() -> HistoryQuery {
+        HistoryQuery { limit: usize::MAX, ..Default::default() }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lan-racer-chat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn keeps_the_newest_records() {
+        let mut history = ChatHistory::with_limit(3);
+        let messages: Vec<ChatMessage> =
+            (0..5).map(|i| message("bob", ChatTarget::All, &i.to_string(), i)).collect();
+        for m in &messages {
+            assert!(history.record(m.clone()).unwrap());
+        }
+
+        assert_eq!(texts(&history.page(&everything())), ["2", "3", "4"]);
+        assert!(!history.record(messages[4].clone()).unwrap());
+        // Acks for a dropped message have nothing to go to.
+        history.mark_delivered("bob", &messages[0].id, "carol").unwrap();
+        assert!(history.page(&everything()).iter().all(|r| r.delivered_to.is_empty()));
+    }
+
+    #[test]
+    fn pages_through_a_conversation() {
+        let mut history = ChatHistory::in_memory();
+        history.record(message("alice", ChatTarget::Peer("bob".into()), "hi bob", 1)).unwrap();
+        history.record(message("carol", ChatTarget::Peer("alice".into()), "hi alice", 2)).unwrap();
+        history.record(message("bob", ChatTarget::All, "hi all", 3)).unwrap();
+        history.record(message("bob", ChatTarget::Peer("alice".into()), "bye", 4)).unwrap();
+
+        let query = |peer: Option<&str>, before, limit| HistoryQuery { peer: peer.map(Into::into), before, limit };
+        assert_eq!(texts(&history.page(&query(Some("bob"), None, 10))), ["hi bob", "hi all", "bye"]);
+        assert_eq!(texts(&history.page(&query(Some("bob"), None, 2))), ["hi all", "bye"]);
+        assert_eq!(texts(&history.page(&query(None, Some(3), 10))), ["hi bob", "hi alice"]);
+        assert!(history.page(&query(Some("dave"), None, 10)).is_empty());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let dir = temp_dir("restart");
+        let sent = message("alice", ChatTarget::All, "hello", 1);
+        let received = message("bob", ChatTarget::Peer("alice".into()), "hi", 2);
+        {
+            let mut history = ChatHistory::open(&dir, "lan party").unwrap();
+            history.record(sent.clone()).unwrap();
+            history.record(received.clone()).unwrap();
+            history.mark_delivered("alice", &sent.id, "bob").unwrap();
+            history.mark_delivered("alice", &sent.id, "bob").unwrap();
+        }
+        let path = dir.join("lan_party.jsonl");
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"not json\n").unwrap();
+
+        let mut history = ChatHistory::open(&dir, "lan party").unwrap();
+        let records = history.page(&everything());
+        assert_eq!(records.iter().map(|r| &r.message).collect::<Vec<_>>(), [&sent, &received]);
+        assert_eq!(records[0].delivered_to, ["bob"]);
+        assert!(!history.record(received).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rewrites_a_long_file() {
+        let dir = temp_dir("rewrite");
+        let mut history = ChatHistory::open_with_limit(&dir, "lan", 2).unwrap();
+        for i in 0..9 {
+            let m = message("alice", ChatTarget::All, &i.to_string(), i);
+            history.record(m.clone()).unwrap();
+            history.mark_delivered("alice", &m.id, "bob").unwrap();
+        }
+
+        let path = dir.join("lan.jsonl");
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 8, "{} lines", lines);
+        let history = ChatHistory::open_with_limit(&dir, "lan", 2).unwrap();
+        let records = history.page(&everything());
+        assert_eq!(texts(&records), ["7", "8"]);
+        assert!(records.iter().all(|r| r.delivered_to == ["bob"]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shortens_an_oversized_file_on_open() {
+        let dir = temp_dir("oversized");
+        {
+            let mut history = ChatHistory::open(&dir, "lan").unwrap();
+            for i in 0..10 {
+                history.record(message("alice", ChatTarget::All, &i.to_string(), i)).unwrap();
+            }
+        }
+        let history = ChatHistory::open_with_limit(&dir, "lan", 3).unwrap();
+        assert_eq!(texts(&history.page(&everything())), ["7", "8", "9"]);
+        assert_eq!(fs::read_to_string(dir.join("lan.jsonl")).unwrap().lines().count(), 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
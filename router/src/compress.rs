@@ -0,0 +1,128 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+
+// Frames smaller than this are mostly headers and rarely shrink.
+const MIN_COMPRESS_LEN: usize = 256;
+// Refuse to inflate anything bigger than a maximal IP packet batch.
+const MAX_DECOMPRESSED_LEN: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses `frame`, returning `None` when it is too small to bother or
+/// the result would not be smaller.
+pub fn compress(algo: Compression, frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() < MIN_COMPRESS_LEN {
+        return None;
+    }
+
+    let out = match algo {
+        Compression::None => return None,
+        Compression::Lz4 => lz4_flex::compress_prepend_size(frame),
+    };
+
+    (out.len() < frame.len()).then_some(out)
+}
+
+pub fn decompress(algo: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match algo {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => {
+            let len = data
+                .get(..4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .ok_or(anyhow!("Truncated LZ4 frame"))?;
+            if len > MAX_DECOMPRESSED_LEN {
+                return Err(anyhow!("LZ4 frame too large: {} bytes", len));
+            }
+            Ok(lz4_flex::decompress_size_prepended(data)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use bytes::Bytes;
+
+    fn packet(n: u8) -> Bytes {
+        let mut packet = vec![0x45, 0, 0, 0];
+        packet.extend(std::iter::repeat_n(n, 400));
+        Bytes::from(packet)
+    }
+
+    #[test]
+    fn roundtrips_lz4() {
+        let frame = packet(7);
+        let compressed = compress(Compression::Lz4, &frame).unwrap();
+        assert!(compressed.len() < frame.len());
+        assert_eq!(decompress(Compression::Lz4, &compressed).unwrap(), frame);
+    }
+
+    #[test]
+    fn skips_what_does_not_shrink() {
+        assert_eq!(compress(Compression::Lz4, &[0; MIN_COMPRESS_LEN - 1]), None);
+        assert_eq!(compress(Compression::None, &packet(7)), None);
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert_eq!(compress(Compression::Lz4, &noise), None);
+    }
+
+    #[test]
+    fn rejects_bad_lz4() {
+        assert!(decompress(Compression::Lz4, &[1, 0]).is_err());
+        let too_big = (MAX_DECOMPRESSED_LEN as u32 + 1).to_le_bytes();
+        assert!(decompress(Compression::Lz4, &too_big).is_err());
+        let mut truncated = compress(Compression::Lz4, &packet(7)).unwrap();
+        truncated.truncate(truncated.len() - 2);
+        assert!(decompress(Compression::Lz4, &truncated).is_err());
+    }
+
+    #[test]
+    fn roundtrips_a_compressed_batch() {
+        let packets = vec![packet(1), packet(2), packet(3)];
+        let batch = Frame::Batch(packets.clone()).encode();
+        let wire = Frame::Compressed {
+            algo: Compression::Lz4.id(),
+            data: Bytes::from(compress(Compression::Lz4, &batch).unwrap()),
+        }
+        .encode();
+        assert!(wire.len() < batch.len());
+
+        let Frame::Compressed { algo, data } = Frame::decode(&wire).unwrap() else {
+            panic!("not a compressed frame");
+        };
+        let algo = Compression::from_id(algo).unwrap();
+        let inner = Bytes::from(decompress(algo, &data).unwrap());
+        assert_eq!(Frame::decode(&inner).unwrap(), Frame::Batch(packets));
+    }
+}
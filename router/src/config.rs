@@ -1 +1,111 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
+use crate::batch::BatchConfig;
+use crate::compress::Compression;
+use crate::forward::{Forward, ReverseForward};
+use crate::mtu::MtuConfig;
+use crate::trust::TrustMode;
+
+/// Settings for how packets are framed onto the data channels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConfig {
+    pub mtu: MtuConfig,
+    pub batch: BatchConfig,
+    /// Offered to peers; only used with peers that offer it too.
+    pub compression: Compression,
+}
+
+/// Where this node sits on the virtual network and how it finds peers.
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    /// Name of the TUN device to create.
+    pub device: String,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub peer_id: String,
+    pub signal_server: String,
+    pub link: LinkConfig,
+    /// Directory for chat history, one file per network. Defaults to the
+    /// user's data directory.
+    pub chat_dir: Option<PathBuf>,
+    /// Ban list file. Defaults to one in the user's data directory.
+    pub ban_file: Option<PathBuf>,
+    /// Key pair and certificate this router is known by. Defaults to one
+    /// for its peer id in the user's data directory.
+    pub identity_file: Option<PathBuf>,
+    /// Trusted peers file. Defaults to one for the peer id in the user's
+    /// data directory.
+    pub trust_file: Option<PathBuf>,
+    /// What to do with peers that connect with an unexpected fingerprint.
+    pub trust: TrustMode,
+    /// Serve Prometheus metrics on this address.
+    pub metrics: Option<SocketAddr>,
+    /// Run the network stack in the router instead of creating a TUN
+    /// device; programs reach the network through the proxies and forwards.
+    pub userspace: bool,
+    /// SOCKS5 proxy address, in userspace mode.
+    pub socks: Option<SocketAddr>,
+    /// HTTP proxy address, in userspace mode.
+    pub http_proxy: Option<SocketAddr>,
+    /// Local ports carried to addresses on the virtual network.
+    pub forwards: Vec<Forward>,
+    /// Ports on this node's address carried to local addresses.
+    pub reverse_forwards: Vec<ReverseForward>,
+    /// Take direct QUIC links from peers on this UDP address.
+    pub direct_listen: Option<SocketAddr>,
+    /// Find peers on the LAN over mDNS and link up with them without the
+    /// signaling server.
+    pub discovery: bool,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            device: "tun1".into(),
+            address: Ipv4Addr::new(10, 10, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            peer_id: "peer1".into(),
+            signal_server: "127.0.0.1:9000".into(),
+            link: LinkConfig::default(),
+            chat_dir: None,
+            ban_file: None,
+            identity_file: None,
+            trust_file: None,
+            trust: TrustMode::default(),
+            metrics: None,
+            userspace: false,
+            socks: None,
+            http_proxy: None,
+            forwards: Vec::new(),
+            reverse_forwards: Vec::new(),
+            direct_listen: None,
+            discovery: true,
+        }
+    }
+}
+
+/// Whether `ip` is another node on the virtual network `address` and
+/// `netmask` describe.
+pub fn on_network(address: Ipv4Addr, netmask: Ipv4Addr, ip: Ipv4Addr) -> bool {
+    let mask = u32::from(netmask);
+    ip != address && u32::from(ip) & mask == u32::from(address) & mask
+}
+
+/// Per-user directory for everything the router keeps between runs.
+pub fn data_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("lan-racer")
+}
+
+/// Default control socket of the router running on `device`, so several
+/// routers on one machine do not collide.
+pub fn control_socket(device: &str) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("lan-racer-{}.sock", device))
+}
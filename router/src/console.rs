@@ -0,0 +1,597 @@
+use anyhow::{Result, anyhow, bail};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::path::PathBuf;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::ban;
+use crate::chat::{self, ChatTarget};
+use crate::config;
+use crate::event::LanEvent;
+use crate::router::{Outcome, RouterCommand};
+use crate::throughput::ThroughputMode;
+
+const PROMPT: &str = "> ";
+
+// Name, arguments, description. Drives both `help` and completion.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("connect", "<peer>", "connect to a peer through the signaling server"),
+    ("direct", "<addr>", "link to a peer listening on addr, without the signaling server"),
+    ("disconnect", "<peer>", "close the connection to a peer"),
+    ("offer", "<peer>", "print an offer to hand to a peer manually"),
+    ("accept", "<peer> <offer>", "accept a peer's offer and print the answer"),
+    ("answer", "<peer> <answer>", "apply a peer's answer to our offer"),
+    ("invite", "<peer>", "print an invite code and QR code to hand to a peer"),
+    ("redeem", "<code>", "accept an invite code, or apply the answer code to ours"),
+    ("chat", "<peer> <message>", "send a chat message"),
+    ("broadcast", "<message>", "send a chat message to every peer"),
+    ("history", "[peer] [-n limit] [-b before]", "show chat history"),
+    ("export-chat", "<path>", "write chat history to a file (.json or text)"),
+    ("send-file", "<peer> <path>", "offer a file to a peer"),
+    ("accept-file", "<peer> <transfer> <path>", "save an offered file as path, or in it if a directory"),
+    ("ban", "<peer|fingerprint> [reason]", "ban a peer and drop its connection"),
+    ("unban", "<peer|fingerprint>", "lift a ban"),
+    ("bans", "", "list banned peers"),
+    ("trust", "<peer> [fingerprint]", "only accept a peer with this fingerprint, or the one it has now"),
+    ("untrust", "<peer>", "forget the fingerprint a peer is trusted with"),
+    ("trusted", "", "list trusted peers and their fingerprints"),
+    ("peers", "", "list peers and their connection state"),
+    ("routes", "", "list virtual addresses and the peer behind each"),
+    ("status", "", "show this node's settings"),
+    ("stats", "", "show traffic counters per peer"),
+    ("ping", "<peer> [-c count]", "measure the round trip to a peer"),
+    ("throughput", "<peer> [-u] [-t seconds] [-b rate]", "measure bandwidth to a peer (-u: unreliable channel)"),
+    ("help", "", "show this list"),
+    ("quit", "", "leave"),
+];
+
+// Commands whose first argument is a peer id.
+const PEER_COMMANDS: &[&str] = &[
+    "connect",
+    "disconnect",
+    "offer",
+    "accept",
+    "answer",
+    "invite",
+    "chat",
+    "history",
+    "send-file",
+    "accept-file",
+    "ban",
+    "unban",
+    "trust",
+    "untrust",
+    "ping",
+    "throughput",
+];
+
+/// One parsed console line.
+#[derive(Debug)]
+pub enum Input {
+    Router(RouterCommand),
+    Help,
+    Quit,
+}
+
+/// Parses a console line. Returns `None` for a blank one.
+pub fn parse(line: &str) -> Result<Option<Input>> {
+    let (name, rest) = next_word(line);
+
+    let input = match name {
+        "" => return Ok(None),
+        "connect" => Input::Router(RouterCommand::ConnectToPeer { peer_id: peer(rest)? }),
+        "direct" => {
+            let addr = rest.parse().map_err(|_| anyhow!("usage: direct <host:port>"))?;
+            Input::Router(RouterCommand::ConnectDirect { addr })
+        }
+        "disconnect" => Input::Router(RouterCommand::Disconnect { peer_id: peer(rest)? }),
+        "offer" => Input::Router(RouterCommand::CreateOffer { peer_id: peer(rest)? }),
+        "accept" => {
+            let (peer_id, sdp) = peer_and_rest(rest, "offer")?;
+            Input::Router(RouterCommand::AcceptOffer { peer_id, sdp })
+        }
+        "answer" => {
+            let (peer_id, sdp) = peer_and_rest(rest, "answer")?;
+            Input::Router(RouterCommand::CreateAnswer { peer_id, sdp })
+        }
+        "invite" => Input::Router(RouterCommand::CreateInvite { peer_id: peer(rest)? }),
+        "redeem" => {
+            if rest.is_empty() {
+                bail!("usage: redeem <code>");
+            }
+            Input::Router(RouterCommand::RedeemCode { code: rest.to_owned() })
+        }
+        "chat" => {
+            let (peer_id, message) = peer_and_rest(rest, "message")?;
+            Input::Router(RouterCommand::SendChat { peer_id, message })
+        }
+        "broadcast" => {
+            if rest.is_empty() {
+                bail!("usage: broadcast <message>");
+            }
+            Input::Router(RouterCommand::BroadcastChat { message: rest.to_owned() })
+        }
+        "history" => parse_history(rest)?,
+        "ping" => parse_ping(rest)?,
+        "throughput" => parse_throughput(rest)?,
+        "export-chat" => {
+            if rest.is_empty() {
+                bail!("usage: export-chat <path>");
+            }
+            Input::Router(RouterCommand::ExportChat { path: PathBuf::from(rest) })
+        }
+        "send-file" => {
+            let (peer_id, path) = peer_and_rest(rest, "path")?;
+            Input::Router(RouterCommand::SendFile { peer_id, path: PathBuf::from(path) })
+        }
+        "accept-file" => {
+            let (peer_id, rest) = peer_and_rest(rest, "transfer")?;
+            let (transfer, path) = next_word(&rest);
+            if path.is_empty() {
+                bail!("usage: accept-file <peer> <transfer> <path>");
+            }
+            let transfer_id = transfer
+                .parse()
+                .map_err(|_| anyhow!("bad transfer id: {}", transfer))?;
+            Input::Router(RouterCommand::AcceptFile {
+                peer_id,
+                transfer_id,
+                path: PathBuf::from(path),
+            })
+        }
+        "ban" => {
+            let (first, rest) = next_word(rest);
+            // A fingerprint may start with its algorithm name.
+            let (key, reason) = if first.to_lowercase().starts_with("sha-") {
+                let (hex, reason) = next_word(rest);
+                (format!("{} {}", first, hex), reason)
+            } else {
+                (first.to_owned(), rest)
+            };
+            if key.is_empty() {
+                bail!("usage: ban <peer|fingerprint> [reason]");
+            }
+            let reason = (!reason.is_empty()).then(|| reason.to_owned());
+            let (peer_id, fingerprint) = if ban::is_fingerprint(&key) {
+                (None, Some(key))
+            } else {
+                (Some(key), None)
+            };
+            Input::Router(RouterCommand::Ban { peer_id, fingerprint, reason })
+        }
+        "unban" => {
+            if rest.is_empty() {
+                bail!("usage: unban <peer|fingerprint>");
+            }
+            Input::Router(RouterCommand::Unban { key: rest.to_owned() })
+        }
+        "bans" => Input::Router(RouterCommand::ListBans),
+        "trust" => {
+            let (peer_id, fingerprint) = next_word(rest);
+            if peer_id.is_empty() {
+                bail!("usage: trust <peer> [fingerprint]");
+            }
+            if !fingerprint.is_empty() && !ban::is_fingerprint(fingerprint) {
+                bail!("bad fingerprint: {}", fingerprint);
+            }
+            Input::Router(RouterCommand::Trust {
+                peer_id: peer_id.to_owned(),
+                fingerprint: (!fingerprint.is_empty()).then(|| fingerprint.to_owned()),
+            })
+        }
+        "untrust" => {
+            if rest.is_empty() {
+                bail!("usage: untrust <peer>");
+            }
+            Input::Router(RouterCommand::Untrust { peer_id: rest.to_owned() })
+        }
+        "trusted" => Input::Router(RouterCommand::ListTrusted),
+        "stats" => Input::Router(RouterCommand::ShowStats),
+        "peers" => Input::Router(RouterCommand::ListPeers),
+        "routes" => Input::Router(RouterCommand::ListRoutes),
+        "status" => Input::Router(RouterCommand::Status),
+        "help" | "?" => Input::Help,
+        "quit" | "exit" => Input::Quit,
+        other => bail!("unknown command: {} (try help)", other),
+    };
+
+    Ok(Some(input))
+}
+
+fn parse_history(args: &str) -> Result<Input> {
+    let mut peer_id = None;
+    let mut before = None;
+    let mut limit = chat::DEFAULT_PAGE;
+
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "-n" => {
+                let value = words.next().ok_or(anyhow!("-n needs a number"))?;
+                limit = value.parse().map_err(|_| anyhow!("bad limit: {}", value))?;
+            }
+            "-b" => {
+                let value = words.next().ok_or(anyhow!("-b needs a timestamp"))?;
+                before = Some(value.parse().map_err(|_| anyhow!("bad timestamp: {}", value))?);
+            }
+            peer if peer_id.is_none() => peer_id = Some(peer.to_owned()),
+            other => bail!("unexpected argument: {}", other),
+        }
+    }
+
+    Ok(Input::Router(RouterCommand::ChatHistory { peer_id, before, limit }))
+}
+
+fn parse_ping(args: &str) -> Result<Input> {
+    let mut peer_id = None;
+    let mut count = 4;
+
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "-c" => {
+                let value = words.next().ok_or(anyhow!("-c needs a number"))?;
+                count = value.parse().map_err(|_| anyhow!("bad count: {}", value))?;
+            }
+            peer if peer_id.is_none() => peer_id = Some(peer.to_owned()),
+            other => bail!("unexpected argument: {}", other),
+        }
+    }
+
+    let peer_id = peer_id.ok_or(anyhow!("usage: ping <peer> [-c count]"))?;
+    Ok(Input::Router(RouterCommand::Ping { peer_id, count }))
+}
+
+fn parse_throughput(args: &str) -> Result<Input> {
+    let mut peer_id = None;
+    let mut mode = ThroughputMode::Reliable;
+    let mut seconds = None;
+    let mut bitrate = None;
+
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "-u" => mode = ThroughputMode::Unreliable,
+            "-t" => {
+                let value = words.next().ok_or(anyhow!("-t needs a number of seconds"))?;
+                match value.parse::<f64>() {
+                    Ok(s) if s.is_finite() && s > 0.0 => seconds = Some(s),
+                    _ => bail!("bad duration: {}", value),
+                }
+            }
+            "-b" => {
+                let value = words.next().ok_or(anyhow!("-b needs a rate"))?;
+                bitrate = Some(parse_bitrate(value)?);
+            }
+            peer if peer_id.is_none() => peer_id = Some(peer.to_owned()),
+            other => bail!("unexpected argument: {}", other),
+        }
+    }
+
+    let peer_id = peer_id.ok_or(anyhow!("usage: throughput <peer> [-u] [-t seconds] [-b rate]"))?;
+    Ok(Input::Router(RouterCommand::ThroughputTest { peer_id, mode, seconds, bitrate }))
+}
+
+// Bits per second, with an optional k, M or G suffix as iperf takes them.
+fn parse_bitrate(value: &str) -> Result<u64> {
+    let (digits, scale) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1e3),
+        Some((i, 'm' | 'M')) => (&value[..i], 1e6),
+        Some((i, 'g' | 'G')) => (&value[..i], 1e9),
+        _ => (value, 1.0),
+    };
+    match digits.parse::<f64>().map(|n| n * scale) {
+        Ok(bits) if bits.is_finite() && bits >= 1.0 => Ok(bits as u64),
+        _ => bail!("bad rate: {}", value),
+    }
+}
+
+fn peer(args: &str) -> Result<String> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [peer] => Ok(peer.to_owned()),
+        _ => bail!("expected exactly one peer id"),
+    }
+}
+
+// A peer id followed by free text, such as a message or an SDP blob.
+fn peer_and_rest(args: &str, what: &str) -> Result<(String, String)> {
+    let (peer, rest) = next_word(args);
+    if peer.is_empty() || rest.is_empty() {
+        bail!("expected a peer id and {}", what);
+    }
+    Ok((peer.to_owned(), rest.to_owned()))
+}
+
+fn next_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (s, ""),
+    }
+}
+
+#[derive(Default)]
+struct ConsoleHelper {
+    /// Known peer ids, refreshed before each prompt.
+    peers: Vec<String>,
+    files: FilenameCompleter,
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let head = &line[..pos];
+        let start = head.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &head[start..];
+        let before: Vec<&str> = head[..start].split_whitespace().collect();
+
+        let candidates: Vec<&str> = match before[..] {
+            [] => COMMANDS.iter().map(|(name, _, _)| *name).collect(),
+            [command] if PEER_COMMANDS.contains(&command) => {
+                self.peers.iter().map(String::as_str).collect()
+            }
+            ["export-chat"] | ["send-file", _] | ["accept-file", _, _] => {
+                return self.files.complete(line, pos, ctx);
+            }
+            _ => Vec::new(),
+        };
+
+        let matches = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| Pair {
+                display: c.to_owned(),
+                replacement: format!("{} ", c),
+            })
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/// Reads commands from the terminal and hands them to the router until
+/// `quit`, end of input, or the router going away. Blocks, so give it a
+/// thread of its own.
+pub fn run(cmd_tx: mpsc::Sender<RouterCommand>) -> Result<()> {
+    let mut editor = Editor::<ConsoleHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ConsoleHelper::default()));
+
+    let history_path = config::data_dir().join("console_history");
+    if let Some(dir) = history_path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = editor.load_history(&history_path);
+
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.peers = match call(&cmd_tx, RouterCommand::ListPeers) {
+                Some(Outcome::Peers(peers)) => peers.into_iter().map(|p| p.id).collect(),
+                _ => Vec::new(),
+            };
+        }
+
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+            let _ = editor.save_history(&history_path);
+        }
+
+        let input = match parse(&line) {
+            Ok(Some(input)) => input,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+
+        match input {
+            Input::Router(cmd) => {
+                if cmd_tx.blocking_send(cmd).is_err() {
+                    break;
+                }
+            }
+            Input::Help => print_help(),
+            Input::Quit => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints what the router hears that the user should see: offers, chat
+/// and file transfers. Runs until the router goes away.
+pub async fn print_events(mut events: broadcast::Receiver<LanEvent>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        match event {
+            LanEvent::NewPeerOffer(pid, sdp) => {
+                println!("\n--- RECEIVED OFFER from {pid} ---");
+                println!("{sdp}");
+            }
+            LanEvent::ChatMessage(message) => {
+                let scope = if message.to == ChatTarget::All { "Chat/all" } else { "Chat" };
+                println!("\n[{}] {}: {}", scope, message.from, message.text);
+            }
+            LanEvent::FileOffered { peer_id, transfer_id, name, size } => {
+                println!("\n[File]: {} offers {} ({} bytes), transfer {}.", peer_id, name, size, transfer_id);
+            }
+            LanEvent::FileProgress { peer_id, transfer_id, bytes, total } => {
+                println!("[File]: transfer {} with {}: {}/{} bytes.", transfer_id, peer_id, bytes, total);
+            }
+            LanEvent::FileCompleted { peer_id, transfer_id, path } => match path {
+                Some(path) => println!("[File]: transfer {} from {} saved to {}.", transfer_id, peer_id, path.display()),
+                None => println!("[File]: transfer {} to {} done.", transfer_id, peer_id),
+            },
+            LanEvent::FileFailed { peer_id, transfer_id, reason } => {
+                println!("[File]: transfer {} with {} failed: {}", transfer_id, peer_id, reason);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn print_help() {
+    for (name, args, description) in COMMANDS {
+        let usage = format!("{} {}", name, args);
+        println!("  {:<42} {}", usage, description);
+    }
+}
+
+// Runs a command for its outcome rather than its printout. `None` once
+// the router has stopped or if the command failed.
+fn call(cmd_tx: &mpsc::Sender<RouterCommand>, command: RouterCommand) -> Option<Outcome> {
+    let (reply, rx) = oneshot::channel();
+    let command = Box::new(command);
+    cmd_tx.blocking_send(RouterCommand::Call { command, reply }).ok()?;
+    rx.blocking_recv().ok()?.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const FINGERPRINT: &str =
+        "3C:4F:A1:0B:92:7E:11:D8:05:6A:BE:CC:29:F0:73:84:5D:E2:19:60:AF:0D:B7:48:92:35:C1:7A:EE:06:5B:F3";
+
+    fn command(line: &str) -> RouterCommand {
+        match parse(line) {
+            Ok(Some(Input::Router(command))) => command,
+            other => panic!("{:?} parsed as {:?}", line, other),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match parse(line) {
+            Err(e) => e.to_string(),
+            other => panic!("{:?} parsed as {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(parse("   ").unwrap().is_none());
+        assert!(matches!(parse("help").unwrap(), Some(Input::Help)));
+        assert!(matches!(parse("exit").unwrap(), Some(Input::Quit)));
+        assert!(matches!(command("connect bob"), RouterCommand::ConnectToPeer { peer_id } if peer_id == "bob"));
+        assert!(matches!(
+            command("direct 192.168.1.20:4433"),
+            RouterCommand::ConnectDirect { addr } if addr.to_string() == "192.168.1.20:4433"
+        ));
+        assert!(matches!(
+            command("chat  bob   hello   there "),
+            RouterCommand::SendChat { peer_id, message } if peer_id == "bob" && message == "hello   there"
+        ));
+        assert!(matches!(
+            command("accept-file bob 42 /tmp/map pack.pak"),
+            RouterCommand::AcceptFile { peer_id, transfer_id: 42, path }
+                if peer_id == "bob" && path == Path::new("/tmp/map pack.pak")
+        ));
+        assert!(matches!(
+            command("history bob -n 5 -b 1700000000000"),
+            RouterCommand::ChatHistory { peer_id: Some(p), before: Some(1700000000000), limit: 5 } if p == "bob"
+        ));
+        assert!(matches!(
+            command("history"),
+            RouterCommand::ChatHistory { peer_id: None, before: None, limit: chat::DEFAULT_PAGE }
+        ));
+        assert!(matches!(command("ping bob -c 10"), RouterCommand::Ping { count: 10, .. }));
+        assert!(matches!(command("trust bob"), RouterCommand::Trust { fingerprint: None, .. }));
+    }
+
+    #[test]
+    fn tells_peers_from_fingerprints_when_banning() {
+        assert!(matches!(
+            command("ban bob spamming chat"),
+            RouterCommand::Ban { peer_id: Some(p), fingerprint: None, reason: Some(r) }
+                if p == "bob" && r == "spamming chat"
+        ));
+        assert!(matches!(
+            command(&format!("ban sha-256 {} cheater", FINGERPRINT)),
+            RouterCommand::Ban { peer_id: None, fingerprint: Some(f), reason: Some(r) }
+                if f == format!("sha-256 {}", FINGERPRINT) && r == "cheater"
+        ));
+        assert!(matches!(
+            command(&format!("ban {}", FINGERPRINT)),
+            RouterCommand::Ban { peer_id: None, fingerprint: Some(_), reason: None }
+        ));
+    }
+
+    #[test]
+    fn parses_throughput_options() {
+        assert!(matches!(
+            command("throughput bob"),
+            RouterCommand::ThroughputTest { mode: ThroughputMode::Reliable, seconds: None, bitrate: None, .. }
+        ));
+        assert!(matches!(
+            command("throughput -u bob -t 2.5 -b 20M"),
+            RouterCommand::ThroughputTest {
+                peer_id,
+                mode: ThroughputMode::Unreliable,
+                seconds: Some(2.5),
+                bitrate: Some(20_000_000),
+            } if peer_id == "bob"
+        ));
+        assert_eq!(parse_bitrate("1.5k").unwrap(), 1500);
+        assert_eq!(parse_bitrate("2G").unwrap(), 2_000_000_000);
+        assert_eq!(parse_bitrate("64000").unwrap(), 64000);
+    }
+
+    #[test]
+    fn rejects_bad_durations_and_rates() {
+        for seconds in ["inf", "-inf", "NaN", "0", "-1", "ten", "1e400"] {
+            assert_eq!(error(&format!("throughput bob -t {}", seconds)), format!("bad duration: {}", seconds));
+        }
+        assert_eq!(error("throughput bob -t"), "-t needs a number of seconds");
+        for rate in ["inf", "NaN", "0", "-5M", "0.1", "M", "fast"] {
+            assert_eq!(error(&format!("throughput bob -b {}", rate)), format!("bad rate: {}", rate));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(error("frobnicate"), "unknown command: frobnicate (try help)");
+        assert_eq!(error("connect"), "expected exactly one peer id");
+        assert_eq!(error("connect bob carol"), "expected exactly one peer id");
+        assert_eq!(error("direct bob"), "usage: direct <host:port>");
+        assert_eq!(error("chat bob"), "expected a peer id and message");
+        assert_eq!(error("accept-file bob 42"), "usage: accept-file <peer> <transfer> <path>");
+        assert_eq!(error("accept-file bob x /tmp/f"), "bad transfer id: x");
+        assert_eq!(error("history -n"), "-n needs a number");
+        assert_eq!(error("history bob carol"), "unexpected argument: carol");
+        assert_eq!(error("ping bob -c many"), "bad count: many");
+        assert_eq!(error("ping"), "usage: ping <peer> [-c count]");
+        assert_eq!(error("throughput bob carol"), "unexpected argument: carol");
+        assert_eq!(error("throughput -u"), "usage: throughput <peer> [-u] [-t seconds] [-b rate]");
+        assert_eq!(error("trust bob not-a-fingerprint"), "bad fingerprint: not-a-fingerprint");
+        assert_eq!(error("ban"), "usage: ban <peer|fingerprint> [reason]");
+        assert_eq!(error("broadcast"), "usage: broadcast <message>");
+    }
+}
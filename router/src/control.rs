@@ -0,0 +1,380 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::warn;
+
+use crate::event::LanEvent;
+use crate::forward;
+use crate::router::RouterCommand;
+
+// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+
+/// Method that turns a connection into an event stream. Everything else is
+/// a `RouterCommand`, named in snake case.
+pub const SUBSCRIBE: &str = "subscribe";
+
+/// Notification method events are delivered with.
+pub const EVENT: &str = "event";
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+/// Serves newline-delimited JSON-RPC 2.0 on a Unix socket at `path`, only
+/// accessible to the current user. Calls are forwarded to the router over
+/// `cmd_tx`; `subscribe` starts `events` notifications on the connection.
+/// Fails if another router already answers on `path`.
+pub async fn serve(
+    path: &Path,
+    cmd_tx: mpsc::Sender<RouterCommand>,
+    events: broadcast::Receiver<LanEvent>,
+) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => bail!("{} is not a socket", path.display()),
+        // A socket that answers belongs to a router still running; one
+        // that does not was left behind by a router that did not exit
+        // cleanly.
+        Ok(_) if UnixStream::connect(path).await.is_ok() => bail!("{} is in use", path.display()),
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = bind_private(path)?;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                forward::accept_failed("Control", e).await;
+                continue;
+            }
+        };
+        let cmd_tx = cmd_tx.clone();
+        let events = events.resubscribe();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, cmd_tx, events).await {
+                warn!("Control client error: {}", e);
+            }
+        });
+    }
+}
+
+// Binds the socket in a directory only we can enter, and only moves it to
+// `path` once it is ours alone, so nobody connects in between.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("control");
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bind = || -> Result<UnixListener> {
+        let tmp = dir.join("sock");
+        let listener = UnixListener::bind(&tmp)?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    let _ = fs::remove_dir_all(&dir);
+    listener
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    cmd_tx: mpsc::Sender<RouterCommand>,
+    events: broadcast::Receiver<LanEvent>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // Responses and event notifications share the socket.
+    let (out_tx, mut out_rx) = mpsc::channel::<Value>(64);
+    let write_task = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            let mut line = msg.to_string();
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut events = Some(events);
+    let mut forwarder = None;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match serde_json::from_str::<Value>(&line) {
+            Ok(value) => value,
+            Err(e) => {
+                let _ = out_tx.send(error(Value::Null, PARSE_ERROR, e.to_string())).await;
+                continue;
+            }
+        };
+        let id = request.get("id").cloned();
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) => request,
+            Err(e) => {
+                let id = id.unwrap_or(Value::Null);
+                let _ = out_tx.send(error(id, INVALID_REQUEST, e.to_string())).await;
+                continue;
+            }
+        };
+
+        let response = if request.method == SUBSCRIBE {
+            if let Some(events) = events.take() {
+                forwarder = Some(tokio::spawn(forward_events(events, out_tx.clone())));
+            }
+            Ok(Value::Bool(true))
+        } else {
+            call(&cmd_tx, request.method, request.params).await
+        };
+
+        // Requests without an id are notifications and get no response.
+        let Some(id) = request.id else {
+            continue;
+        };
+        let msg = match response {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error(id, code, message),
+        };
+        if out_tx.send(msg).await.is_err() {
+            break;
+        }
+    }
+
+    if let Some(forwarder) = forwarder {
+        forwarder.abort();
+    }
+    drop(out_tx);
+    let _ = write_task.await;
+    Ok(())
+}
+
+async fn call(
+    cmd_tx: &mpsc::Sender<RouterCommand>,
+    method: String,
+    params: Option<Value>,
+) -> Result<Value, (i64, String)> {
+    // Commands without arguments take no params, the rest an object, but
+    // clients may leave out an empty object or send one either way.
+    let requests = match params {
+        Some(params) if params.as_object().is_none_or(|p| !p.is_empty()) => {
+            vec![json!({ "method": method, "params": params })]
+        }
+        _ => vec![json!({ "method": method }), json!({ "method": method, "params": {} })],
+    };
+    let mut parsed = Err(None);
+    for request in requests {
+        parsed = serde_json::from_value::<RouterCommand>(request).map_err(Some);
+        if parsed.is_ok() {
+            break;
+        }
+    }
+    let command = parsed.map_err(|e| {
+        let message = e.map(|e| e.to_string()).unwrap_or_default();
+        if message.starts_with("unknown variant") {
+            (METHOD_NOT_FOUND, format!("unknown method: {}", method))
+        } else {
+            (INVALID_PARAMS, message)
+        }
+    })?;
+
+    let (reply, rx) = oneshot::channel();
+    let command = Box::new(command);
+    let stopped = || (COMMAND_FAILED, "router stopped".to_owned());
+    cmd_tx
+        .send(RouterCommand::Call { command, reply })
+        .await
+        .map_err(|_| stopped())?;
+
+    let outcome = rx
+        .await
+        .map_err(|_| stopped())?
+        .map_err(|e| (COMMAND_FAILED, e))?;
+    serde_json::to_value(outcome).map_err(|e| (COMMAND_FAILED, e.to_string()))
+}
+
+async fn forward_events(mut events: broadcast::Receiver<LanEvent>, out_tx: mpsc::Sender<Value>) {
+    loop {
+        let params = match events.recv().await {
+            Ok(event) => serde_json::to_value(event).unwrap_or(Value::Null),
+            // Tell the client it missed some instead of silently skipping.
+            Err(RecvError::Lagged(missed)) => json!({ "event": "lagged", "data": missed }),
+            Err(RecvError::Closed) => break,
+        };
+        let msg = json!({ "jsonrpc": "2.0", "method": EVENT, "params": params });
+        if out_tx.send(msg).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+
+    use crate::router::Outcome;
+
+    struct Client {
+        lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+        writer: WriteHalf<UnixStream>,
+    }
+
+    impl Client {
+        async fn connect(path: &Path) -> Self {
+            let (reader, writer) = tokio::io::split(UnixStream::connect(path).await.unwrap());
+            Self { lines: BufReader::new(reader).lines(), writer }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+        }
+
+        async fn call(&mut self, request: Value) -> Value {
+            self.send(&request.to_string()).await;
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    // Serves a control socket in front of a stand-in router, which passes
+    // on every command it gets.
+    async fn serve_fake(name: &str) -> (std::path::PathBuf, mpsc::Receiver<String>) {
+        let dir = std::env::temp_dir().join(format!("lan-racer-control-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(8);
+        let (seen_tx, seen_rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Some(RouterCommand::Call { command, reply }) = cmd_rx.recv().await {
+                let _ = seen_tx.send(format!("{:?}", command)).await;
+                let outcome = match *command {
+                    RouterCommand::ListPeers => Ok(Outcome::Peers(Vec::new())),
+                    RouterCommand::Disconnect { peer_id } => Err(format!("Not linked with {}", peer_id)),
+                    _ => Ok(Outcome::Done),
+                };
+                let _ = reply.send(outcome);
+            }
+        });
+
+        let (_, events) = broadcast::channel(8);
+        let serve_path = path.clone();
+        tokio::spawn(async move { serve(&serve_path, cmd_tx, events).await });
+        for _ in 0..100 {
+            if UnixStream::connect(&path).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        (path, seen_rx)
+    }
+
+    #[tokio::test]
+    async fn dispatches_commands() {
+        let (path, mut seen) = serve_fake("dispatch").await;
+        let mut client = Client::connect(&path).await;
+
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 1, "method": "list_peers" })).await;
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "id": 1, "result": [] }));
+        assert_eq!(seen.recv().await.unwrap(), "ListPeers");
+
+        // An empty object is as good as no params.
+        let request = json!({ "jsonrpc": "2.0", "id": "two", "method": "list_peers", "params": {} });
+        let response = client.call(request).await;
+        assert_eq!(response["result"], json!([]));
+        assert_eq!(response["id"], "two");
+        seen.recv().await.unwrap();
+
+        let params = json!({ "peer_id": "123", "message": "42" });
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 3, "method": "send_chat", "params": params })).await;
+        assert_eq!(response["result"], Value::Null);
+        assert!(response.get("error").is_none(), "{}", response);
+        assert_eq!(seen.recv().await.unwrap(), r#"SendChat { peer_id: "123", message: "42" }"#);
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_errors() {
+        let (path, _seen) = serve_fake("errors").await;
+        let mut client = Client::connect(&path).await;
+
+        let code = |response: &Value| response["error"]["code"].as_i64().unwrap();
+
+        client.send("{not json").await;
+        let line = client.lines.next_line().await.unwrap().unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!((code(&response), &response["id"]), (PARSE_ERROR, &Value::Null));
+
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 1 })).await;
+        assert_eq!(code(&response), INVALID_REQUEST);
+
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 2, "method": "reboot" })).await;
+        assert_eq!(code(&response), METHOD_NOT_FOUND);
+
+        let params = json!({ "peer_id": 123, "message": "hi" });
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 3, "method": "send_chat", "params": params })).await;
+        assert_eq!(code(&response), INVALID_PARAMS);
+
+        let request = json!({ "jsonrpc": "2.0", "id": 4, "method": "disconnect", "params": { "peer_id": "bob" } });
+        let response = client.call(request).await;
+        assert_eq!(code(&response), COMMAND_FAILED);
+        assert_eq!(response["error"]["message"], "Not linked with bob");
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let (path, mut seen) = serve_fake("notify").await;
+        let mut client = Client::connect(&path).await;
+
+        client.send(&json!({ "jsonrpc": "2.0", "method": "status" }).to_string()).await;
+        assert_eq!(seen.recv().await.unwrap(), "Status");
+        let response = client.call(json!({ "jsonrpc": "2.0", "id": 7, "method": "list_peers" })).await;
+        assert_eq!(response["id"], 7);
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_socket_in_use() {
+        let (path, _seen) = serve_fake("in-use").await;
+        let (cmd_tx, _cmd_rx) = mpsc::channel(1);
+        let (_, events) = broadcast::channel(1);
+        let err = serve(&path, cmd_tx, events).await.unwrap_err();
+        assert!(err.to_string().contains("in use"), "{}", err);
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::{Result, anyhow, bail};
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::Instant;
+use tun_rs::{AsyncDevice, DeviceBuilder};
+
+use crate::config::RouterConfig;
+
+/// Where the router reads the IP packets it tunnels to peers and writes
+/// the ones that come back.
+pub trait PacketDevice: Send + Sync {
+    /// Waits for the next outgoing packet and copies it into `buf`. Must be
+    /// cancel safe: the router gives up waiting when a batch window ends.
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Hands a packet that came from a peer to the local side.
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl PacketDevice for AsyncDevice {
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        AsyncDevice::recv(self, buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        AsyncDevice::send(self, packet)
+    }
+}
+
+/// Creates the TUN device `config` describes. Needs root or
+/// `CAP_NET_ADMIN`.
+pub fn open_tun(config: &RouterConfig) -> Result<AsyncDevice> {
+    Ok(DeviceBuilder::new()
+        .name(&config.device)
+        .mtu(config.link.mtu.device_mtu())
+        .ipv4(config.address, config.netmask, None)
+        .build_async()?)
+}
+
+/// Device backed by channels instead of the kernel, for tests and for
+/// running the router inside another program.
+pub struct ChannelDevice {
+    inbound: Mutex<mpsc::Receiver<Vec<u8>>>,
+    outbound: mpsc::Sender<Vec<u8>>,
+}
+
+/// The local side of a [`ChannelDevice`].
+pub struct DeviceHandle {
+    inbound: mpsc::Sender<Vec<u8>>,
+    outbound: mpsc::Receiver<Vec<u8>>,
+}
+
+/// A channel device and its handle, each direction buffering up to
+/// `capacity` packets.
+pub fn channel(capacity: usize) -> (ChannelDevice, DeviceHandle) {
+    let (inbound_tx, inbound_rx) = mpsc::channel(capacity);
+    let (outbound_tx, outbound_rx) = mpsc::channel(capacity);
+    let device = ChannelDevice {
+        inbound: Mutex::new(inbound_rx),
+        outbound: outbound_tx,
+    };
+    let handle = DeviceHandle {
+        inbound: inbound_tx,
+        outbound: outbound_rx,
+    };
+    (device, handle)
+}
+
+impl PacketDevice for ChannelDevice {
+    // Packets longer than `buf` are cut short, as a TUN device does.
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.outbound
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Ok(packet.len())
+    }
+}
+
+impl DeviceHandle {
+    /// Feeds a packet to the router as if the local side had sent it.
+    pub async fn send(&self, packet: Vec<u8>) -> Result<()> {
+        self.inbound
+            .send(packet)
+            .await
+            .map_err(|_| anyhow!("Device closed"))
+    }
+
+    /// Next packet the router wrote, `None` once the device is gone.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.outbound.recv().await
+    }
+}
+
+/// Replays the IP packets of a pcap capture as if they were sent locally.
+/// Packets for the device are dropped. Once the capture is used up the
+/// device stays silent.
+pub struct PcapDevice {
+    packets: Vec<(Duration, Vec<u8>)>,
+    realtime: bool,
+    replay: Mutex<Replay>,
+}
+
+#[derive(Default)]
+struct Replay {
+    next: usize,
+    started: Option<Instant>,
+}
+
+// Link-layer header types, from tcpdump.org/linktypes.html.
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+impl PcapDevice {
+    /// Reads the capture at `path`. With `realtime` packets come out as far
+    /// apart as they were captured, otherwise as fast as they are read.
+    pub fn open(path: &Path, realtime: bool) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        let packets = parse_pcap(&data).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(Self {
+            packets,
+            realtime,
+            replay: Mutex::new(Replay::default()),
+        })
+    }
+
+    /// IP packets in the capture.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+impl PacketDevice for PcapDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut replay = self.replay.lock().await;
+        let Some((at, packet)) = self.packets.get(replay.next) else {
+            drop(replay);
+            return std::future::pending().await;
+        };
+        if self.realtime {
+            let started = *replay.started.get_or_insert_with(Instant::now);
+            tokio::time::sleep_until(started + *at).await;
+        }
+        replay.next += 1;
+
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        Ok(packet.len())
+    }
+}
+
+// Classic pcap, either byte order, micro- or nanosecond timestamps. Returns
+// each IP packet with its offset from the first one.
+fn parse_pcap(data: &[u8]) -> Result<Vec<(Duration, Vec<u8>)>> {
+    if data.len() < 24 {
+        bail!("not a pcap file");
+    }
+    let magic = u32::from_le_bytes(data[..4].try_into()?);
+    let (swapped, nanos) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        _ => bail!("not a pcap file (pcapng is not supported)"),
+    };
+    let u32_at = |at: usize| -> u32 {
+        let bytes = [data[at], data[at + 1], data[at + 2], data[at + 3]];
+        if swapped { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    };
+    // The upper bits may carry FCS information.
+    let linktype = u32_at(20) & 0x0fff_ffff;
+
+    let mut packets = Vec::new();
+    let mut first = None;
+    let mut at = 24;
+    while at + 16 <= data.len() {
+        let secs = u64::from(u32_at(at));
+        let frac = u64::from(u32_at(at + 4));
+        let captured = u32_at(at + 8) as usize;
+        at += 16;
+        let frame = data.get(at..at + captured).ok_or(anyhow!("truncated packet"))?;
+        at += captured;
+
+        let nanos = if nanos { frac } else { frac * 1000 };
+        let time = Duration::from_secs(secs) + Duration::from_nanos(nanos);
+        let first = *first.get_or_insert(time);
+        if let Some(packet) = ip_payload(linktype, frame) {
+            packets.push((time.saturating_sub(first), packet.to_vec()));
+        }
+    }
+    Ok(packets)
+}
+
+// The IP packet inside a captured frame, if it holds one.
+fn ip_payload(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    let ethertype = |at: usize| Some(u16::from_be_bytes(frame.get(at..at + 2)?.try_into().ok()?));
+    let ip = |ethertype: u16, at: usize| match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(at..),
+        _ => None,
+    };
+
+    let packet = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        // A 4-byte address family in the capturing host's byte order.
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_ETHERNET => match ethertype(12)? {
+            ETHERTYPE_VLAN => ip(ethertype(16)?, 18)?,
+            other => ip(other, 14)?,
+        },
+        LINKTYPE_LINUX_SLL => ip(ethertype(14)?, 16)?,
+        LINKTYPE_LINUX_SLL2 => ip(ethertype(0)?, 20)?,
+        _ => return None,
+    };
+    matches!(packet.first()? >> 4, 4 | 6).then_some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::router::{Router, RouterCommand};
+    use crate::transport::loopback;
+
+    // A UDP packet from `src` to `dst` carrying `payload`.
+    fn udp(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let len = 28 + payload.len();
+        let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(&[0x30, 0x39, 0x30, 0x39, 0, (len - 20) as u8, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn config(dir: &Path, peer_id: &str, address: Ipv4Addr) -> RouterConfig {
+        RouterConfig {
+            device: format!("test-{}", peer_id),
+            address,
+            peer_id: peer_id.into(),
+            // Nothing listens there, so the routers only have each other.
+            signal_server: "127.0.0.1:1".into(),
+            chat_dir: Some(dir.join(peer_id).join("chat")),
+            ban_file: Some(dir.join(peer_id).join("bans.json")),
+            identity_file: Some(dir.join(peer_id).join("identity.pem")),
+            trust_file: Some(dir.join(peer_id).join("trusted.json")),
+            discovery: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_between_channel_devices() {
+        let dir = std::env::temp_dir().join(format!("lan-racer-device-test-{}", std::process::id()));
+        let (alice_ip, bob_ip) = (Ipv4Addr::new(10, 10, 0, 2), Ipv4Addr::new(10, 10, 0, 3));
+        let token = CancellationToken::new();
+
+        let (alice_end, bob_end) = loopback::pair();
+        let mut handles = Vec::new();
+        let mut routers = Vec::new();
+        for (peer_id, address, other, end) in [("alice", alice_ip, "bob", alice_end), ("bob", bob_ip, "alice", bob_end)] {
+            let router = Router::new(config(&dir, peer_id, address));
+            let (device, handle) = channel(256);
+            let (cmd_tx, cmd_rx) = mpsc::channel(8);
+            let token = token.clone();
+            routers.push(tokio::spawn(async move { router.route_with(device, token, cmd_rx).await }));
+            let transport = Arc::new(end);
+            cmd_tx.send(RouterCommand::Attach { peer_id: other.into(), transport }).await.unwrap();
+            handles.push((handle, cmd_tx));
+        }
+        // The routers stop once their command channel closes.
+        let (mut bob, _bob_cmd) = handles.pop().unwrap();
+        let (mut alice, _alice_cmd) = handles.pop().unwrap();
+
+        // Packets sent before the link is up are lost, so keep sending.
+        let packet = udp(alice_ip, bob_ip, b"hello over the loopback");
+        let arrived = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                alice.send(packet.clone()).await.unwrap();
+                // Nothing alice's router writes back, such as ICMP errors,
+                // is of interest.
+                while let Ok(Some(_)) = tokio::time::timeout(Duration::ZERO, alice.recv()).await {}
+                match tokio::time::timeout(Duration::from_millis(100), bob.recv()).await {
+                    Ok(Some(got)) if got == packet => return,
+                    _ => {}
+                }
+            }
+        })
+        .await;
+
+        token.cancel();
+        for router in routers {
+            router.await.unwrap().unwrap();
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(arrived.is_ok(), "packet never came out of bob's device");
+    }
+
+    // A classic pcap file with microsecond timestamps.
+    fn pcap(linktype: u32, frames: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [0xa1b2c3d4u32, 0x0004_0002, 0, 0, 65535, linktype] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for (secs, micros, frame) in frames {
+            for field in [*secs, *micros, frame.len() as u32, frame.len() as u32] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff; 12];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn fixture(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lan-racer-{}-{}.pcap", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn replays_pcap() {
+        let first = udp(Ipv4Addr::new(10, 10, 0, 2), Ipv4Addr::new(10, 10, 0, 3), b"first");
+        let second = udp(Ipv4Addr::new(10, 10, 0, 2), Ipv4Addr::new(10, 10, 0, 4), b"second");
+        let arp = [0u8; 28];
+        let data = pcap(
+            LINKTYPE_ETHERNET,
+            &[
+                (100, 900_000, &ethernet(ETHERTYPE_IPV4, &first)),
+                (100, 950_000, &ethernet(0x0806, &arp)),
+                (101, 100_000, &ethernet(ETHERTYPE_IPV4, &second)),
+            ],
+        );
+        let path = fixture("replay", &data);
+        let device = PcapDevice::open(&path, true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(device.len(), 2);
+
+        let mut buf = [0u8; 1500];
+        let start = Instant::now();
+        let len = device.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &first[..]);
+        let len = device.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &second[..]);
+        // As far apart as they were captured.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Packets for the device go nowhere, and the capture does not loop.
+        assert_eq!(device.send(&first).await.unwrap(), first.len());
+        let more = tokio::time::timeout(Duration::from_millis(100), device.recv(&mut buf)).await;
+        assert!(more.is_err());
+    }
+
+    #[test]
+    fn rejects_bad_pcap() {
+        let packet = udp(Ipv4Addr::new(10, 10, 0, 2), Ipv4Addr::new(10, 10, 0, 3), b"x");
+        let data = pcap(LINKTYPE_RAW, &[(0, 0, &packet)]);
+        assert_eq!(parse_pcap(&data).unwrap().len(), 1);
+        assert!(parse_pcap(&data[..data.len() - 1]).is_err());
+        assert!(parse_pcap(&data[..20]).is_err());
+        assert!(parse_pcap(&[0u8; 24]).is_err());
+    }
+}
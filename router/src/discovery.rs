@@ -0,0 +1,271 @@
+use anyhow::{Context, Result, anyhow, bail};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tracing::{Instrument, debug, info, warn};
+
+use crate::config::{self, RouterConfig};
+use crate::forward;
+use crate::peer::{self, PeerManager};
+use crate::signaling::protocol::SignalMessage;
+use crate::transport::LinkState;
+
+/// DNS-SD service type routers advertise themselves under.
+pub const SERVICE_TYPE: &str = "_lan-racer._tcp.local.";
+
+// How often peers found but not linked are tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Gathering candidates takes a few seconds; a peer that has not answered
+// by then is not going to.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Offers and answers are a few kilobytes of SDP.
+const MAX_MESSAGE: u64 = 64 * 1024;
+
+/// Advertises this router on the local network, browses for the others
+/// and links up with every one found on the same virtual network. Offers
+/// and answers go straight to the peer over TCP, so no signaling server is
+/// needed. Runs until the mDNS daemon stops.
+pub async fn run(manager: &PeerManager, config: &RouterConfig) -> Result<()> {
+    let local_id = config.peer_id.as_str();
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let port = listener.local_addr()?.port();
+
+    let daemon = ServiceDaemon::new().context("mDNS")?;
+    // The virtual network is what we are setting up, not a way to reach it.
+    daemon.disable_interface(config.device.as_str())?;
+    let address = config.address.to_string();
+    let properties = [("id", local_id), ("addr", address.as_str())];
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        local_id,
+        &format!("{}.local.", host_label(local_id)),
+        (),
+        port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    info!("Advertising {} on the LAN, offers on port {}", local_id, port);
+
+    // Peer id by address, and by service name for when it goes away.
+    let found: Mutex<HashMap<String, SocketAddr>> = Mutex::new(HashMap::new());
+    let names: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    // Peers that moved to a new address since last offered to. A router that
+    // comes back on another port has restarted, even if its old link still
+    // looks up for a while.
+    let fresh: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    let connecting: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    let wake = Notify::new();
+
+    let answer_loop = async {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    forward::accept_failed("LAN offer", e).await;
+                    continue;
+                }
+            };
+            let manager = manager.clone();
+            let local_id = local_id.to_owned();
+            tokio::spawn(async move {
+                if let Err(e) = answer(&manager, &local_id, stream).await {
+                    debug!(%remote, "LAN offer failed: {:#}", e);
+                }
+            });
+        }
+    };
+
+    let browse_loop = async {
+        while let Ok(event) = events.recv_async().await {
+            match event {
+                ServiceEvent::ServiceResolved(service) => {
+                    let Some(peer_id) = service.get_property_val_str("id") else {
+                        continue;
+                    };
+                    if peer_id == local_id {
+                        continue;
+                    }
+                    // Routers for other virtual networks share the LAN.
+                    let on_network = service
+                        .get_property_val_str("addr")
+                        .and_then(|a| a.parse().ok())
+                        .is_some_and(|a| config::on_network(config.address, config.netmask, a));
+                    if !on_network {
+                        debug!(parent: &peer::span(peer_id), "Ignoring router on another network");
+                        continue;
+                    }
+                    let ip = service
+                        .get_addresses_v4()
+                        .into_iter()
+                        .filter(|&&ip| !config::on_network(config.address, config.netmask, ip) && ip != config.address)
+                        .min();
+                    let Some(&ip) = ip else {
+                        continue;
+                    };
+                    let addr = SocketAddr::from((ip, service.get_port()));
+                    let known = found.lock().unwrap().insert(peer_id.to_owned(), addr);
+                    names.lock().unwrap().insert(service.get_fullname().to_owned(), peer_id.to_owned());
+                    if known != Some(addr) {
+                        info!(parent: &peer::span(peer_id), "Found on the LAN at {}", addr);
+                        if known.is_some() {
+                            fresh.lock().unwrap().insert(peer_id.to_owned());
+                        }
+                        wake.notify_one();
+                    }
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    if let Some(peer_id) = names.lock().unwrap().remove(&fullname) {
+                        found.lock().unwrap().remove(&peer_id);
+                        debug!(parent: &peer::span(&peer_id), "Gone from the LAN");
+                    }
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let offer_loop = async {
+        loop {
+            let _ = tokio::time::timeout(RETRY_INTERVAL, wake.notified()).await;
+
+            // Only the smaller id offers, so two routers that find each
+            // other at once do not cross offers.
+            let candidates: Vec<(String, SocketAddr)> = found
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(peer_id, _)| local_id < peer_id.as_str())
+                .map(|(peer_id, addr)| (peer_id.clone(), *addr))
+                .collect();
+            for (peer_id, addr) in candidates {
+                if manager.is_banned(&peer_id) || connecting.lock().unwrap().contains(&peer_id) {
+                    continue;
+                }
+                // Anyone on the LAN can advertise a peer's id, so a restart
+                // only brings on a new offer once the old link stops
+                // working; until then the peer stays fresh.
+                let restarted = fresh.lock().unwrap().contains(&peer_id);
+                if restarted && manager.link_state(&peer_id).await == Some(LinkState::Connected) {
+                    continue;
+                }
+                fresh.lock().unwrap().remove(&peer_id);
+                if !restarted && manager.is_linked(&peer_id).await {
+                    continue;
+                }
+                connecting.lock().unwrap().insert(peer_id.clone());
+                let manager = manager.clone();
+                let local_id = local_id.to_owned();
+                let connecting = connecting.clone();
+                let span = peer::span(&peer_id);
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = offer(&manager, &local_id, &peer_id, addr).await {
+                            warn!("LAN offer failed: {:#}", e);
+                        }
+                        connecting.lock().unwrap().remove(&peer_id);
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = answer_loop => {}
+        _ = browse_loop => {}
+        _ = offer_loop => {}
+    }
+    let _ = daemon.shutdown();
+    bail!("mDNS daemon stopped")
+}
+
+// Sends our offer to the peer listening at `addr` and applies its answer.
+async fn offer(manager: &PeerManager, local_id: &str, peer_id: &str, addr: SocketAddr) -> Result<()> {
+    let stream = tokio::time::timeout(EXCHANGE_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .context("Connect timed out")??;
+    let sdp = manager.create_offer(peer_id.to_owned()).await?;
+    let exchange = async {
+        let (reader, mut writer) = stream.into_split();
+        let message = SignalMessage::Offer { from: local_id.to_owned(), to: peer_id.to_owned(), sdp };
+        write_message(&mut writer, &message).await?;
+        info!("Offer sent over the LAN");
+        match read_message(BufReader::new(reader)).await? {
+            SignalMessage::Answer { from, sdp, .. } if from == peer_id => {
+                manager.set_answer_as_offerer(peer_id, &sdp).await
+            }
+            _ => bail!("Expected an answer from {}", peer_id),
+        }
+    };
+    let result = match tokio::time::timeout(EXCHANGE_TIMEOUT, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("No answer")),
+    };
+    if result.is_err() {
+        let _ = manager.remove_peer(peer_id).await;
+    }
+    result
+}
+
+// Takes one offer from a peer that found us and answers it.
+async fn answer(manager: &PeerManager, local_id: &str, stream: TcpStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let message = tokio::time::timeout(EXCHANGE_TIMEOUT, read_message(BufReader::new(reader)))
+        .await
+        .context("No offer")??;
+    let SignalMessage::Offer { from, to, sdp } = message else {
+        bail!("Expected an offer");
+    };
+    if to != local_id {
+        bail!("Offer for {}", to);
+    }
+
+    async {
+        // Whoever advertises a linked peer's id does not get to take over
+        // the link; the peer offers again once it is gone.
+        if manager.link_state(&from).await == Some(LinkState::Connected) {
+            bail!("Offer for a linked peer");
+        }
+        if manager.rejects(&from, &sdp) {
+            info!("Rejected offer from banned peer");
+            return Ok(());
+        }
+        info!("Offer received over the LAN");
+        let sdp = manager.accept_offer(from.clone(), &sdp).await?;
+        let message = SignalMessage::Answer { from: local_id.to_owned(), to: from.clone(), sdp };
+        write_message(&mut writer, &message).await
+    }
+    .instrument(peer::span(&from))
+    .await
+}
+
+async fn read_message<R: AsyncBufRead + Unpin>(reader: R) -> Result<SignalMessage> {
+    let mut line = String::new();
+    reader.take(MAX_MESSAGE).read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &SignalMessage) -> Result<()> {
+    let mut json = serde_json::to_vec(message)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+    Ok(())
+}
+
+// A DNS label for the peer id: letters, digits and hyphens only.
+fn host_label(peer_id: &str) -> String {
+    let label: String = peer_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    format!("lan-racer-{}", label.trim_matches('-'))
+}
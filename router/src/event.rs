@@ -1,9 +1,25 @@
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::chat::ChatMessage;
+use crate::latency::LatencySnapshot;
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
 pub enum LanEvent {
     PacketFromPeer(Vec<u8>),
     NewPeerOffer(String, String),
-    ChatMessage { from: String, message: String },
+    ChatMessage(ChatMessage),
+    ChatDelivered { peer_id: String, message_id: String },
     PeerConnected(String),
     PeerDisconnected(String),
+    PeerHello { peer_id: String, software: String, protocol: u16 },
+    /// Latest latency figures, once per ping round.
+    PeerLatency { peer_id: String, latency: LatencySnapshot },
+    FileOffered { peer_id: String, transfer_id: u64, name: String, size: u64 },
+    FileProgress { peer_id: String, transfer_id: u64, bytes: u64, total: u64 },
+    /// `path` is where the file was saved, or `None` on the sending side.
+    FileCompleted { peer_id: String, transfer_id: u64, path: Option<PathBuf> },
+    FileFailed { peer_id: String, transfer_id: u64, reason: String },
 }
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result, anyhow, bail};
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::config::{self, RouterConfig};
+use crate::netstack::{self, Netstack};
+
+// Pause after a failed accept, such as one out of file descriptors, so a
+// listener that keeps failing does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A local TCP port whose connections are carried to an address on the
+/// virtual network, like `ssh -L`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    pub target: SocketAddrV4,
+}
+
+/// A TCP port on this node's virtual address whose connections are carried
+/// to a local address, like `ssh -R`: how a game server on this machine is
+/// shared with peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReverseForward {
+    pub port: u16,
+    pub target: SocketAddr,
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    /// `[bind:]port:host:port`, binding to localhost unless told otherwise.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (bind, port, host, target_port) = match parts[..] {
+            [port, host, target_port] => (Ipv4Addr::LOCALHOST, port, host, target_port),
+            [bind, port, host, target_port] => (
+                bind.parse().with_context(|| format!("bad bind address {:?}", bind))?,
+                port,
+                host,
+                target_port,
+            ),
+            _ => bail!("expected [bind:]port:host:port, got {:?}", s),
+        };
+        let host = host.parse().with_context(|| format!("bad address {:?}", host))?;
+        Ok(Self {
+            listen: SocketAddr::from((bind, parse_port(port)?)),
+            target: SocketAddrV4::new(host, parse_port(target_port)?),
+        })
+    }
+}
+
+impl FromStr for ReverseForward {
+    type Err = anyhow::Error;
+
+    /// `port[:host:port]`, to the same port on localhost unless told
+    /// otherwise.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (port, target) = match parts[..] {
+            [port] => {
+                let port = parse_port(port)?;
+                (port, SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            }
+            [port, host, target_port] => {
+                let host: Ipv4Addr = host.parse().with_context(|| format!("bad address {:?}", host))?;
+                (parse_port(port)?, SocketAddr::from((host, parse_port(target_port)?)))
+            }
+            _ => bail!("expected port[:host:port], got {:?}", s),
+        };
+        Ok(Self { port, target })
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16> {
+    port.parse().map_err(|_| anyhow!("bad port {:?}", port))
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.listen, self.target)
+    }
+}
+
+impl fmt::Display for ReverseForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "port {} -> {}", self.port, self.target)
+    }
+}
+
+/// How forwards get onto the virtual network.
+#[derive(Clone)]
+pub enum Overlay {
+    /// Through the kernel and the TUN device, which holds `address`.
+    Kernel { address: Ipv4Addr, netmask: Ipv4Addr },
+    Userspace(Netstack),
+}
+
+impl Overlay {
+    /// The TUN device of `config`.
+    pub fn kernel(config: &RouterConfig) -> Self {
+        Self::Kernel {
+            address: config.address,
+            netmask: config.netmask,
+        }
+    }
+}
+
+/// Accepts connections on the forward's local port for as long as it runs.
+pub async fn serve(forward: Forward, overlay: Overlay) -> Result<()> {
+    let listener = TcpListener::bind(forward.listen).await?;
+    info!("Forwarding {} to {}", listener.local_addr()?, forward.target);
+
+    loop {
+        let (mut client, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                accept_failed("Forward", e).await;
+                continue;
+            }
+        };
+        let overlay = overlay.clone();
+        tokio::spawn(async move {
+            let target = forward.target;
+            let result = async {
+                match overlay {
+                    Overlay::Kernel { address, netmask } => {
+                        if !config::on_network(address, netmask, *target.ip()) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::NetworkUnreachable,
+                                format!("{} is not on the virtual network", target.ip()),
+                            ));
+                        }
+                        relay(&mut client, &mut connect(target.into()).await?).await
+                    }
+                    Overlay::Userspace(stack) => relay(&mut client, &mut stack.connect(target).await?).await,
+                }
+            };
+            if let Err(e) = result.await {
+                debug!(%remote, "Forward to {} failed: {}", target, e);
+            }
+        });
+    }
+}
+
+/// Accepts connections from peers on the forward's port for as long as it
+/// runs.
+pub async fn serve_reverse(forward: ReverseForward, overlay: Overlay) -> Result<()> {
+    match overlay {
+        Overlay::Kernel { address, .. } => {
+            // Bound to the virtual address only, so the port is not
+            // exposed on the machine's other networks as well.
+            let listener = TcpListener::bind((address, forward.port)).await?;
+            info!("Forwarding {} from peers to {}", listener.local_addr()?, forward.target);
+            loop {
+                match listener.accept().await {
+                    Ok((conn, remote)) => {
+                        tokio::spawn(expose(conn, remote, forward.target));
+                    }
+                    Err(e) => accept_failed("Forward", e).await,
+                }
+            }
+        }
+        Overlay::Userspace(stack) => {
+            let listener = stack.listen(forward.port)?;
+            info!(
+                "Forwarding {}:{} from peers to {}",
+                stack.address(),
+                listener.port(),
+                forward.target
+            );
+            loop {
+                match listener.accept().await {
+                    Ok((conn, remote)) => {
+                        tokio::spawn(expose(conn, remote, forward.target));
+                    }
+                    Err(e) => accept_failed("Forward", e).await,
+                }
+            }
+        }
+    }
+}
+
+/// Logs a failed accept on the `what` listener and waits a moment before
+/// the next one.
+pub(crate) async fn accept_failed(what: &str, e: io::Error) {
+    warn!("{} accept error: {}", what, e);
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
+
+async fn expose<S>(mut conn: S, remote: SocketAddr, target: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let result = async { relay(&mut conn, &mut connect(target).await?).await };
+    if let Err(e) = result.await {
+        debug!(%remote, "Forward to {} failed: {}", target, e);
+    }
+}
+
+async fn connect(target: SocketAddr) -> io::Result<TcpStream> {
+    tokio::time::timeout(netstack::CONNECT_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+async fn relay<A, B>(a: &mut A, b: &mut B) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::io::copy_bidirectional(a, b).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forward() {
+        let forward: Forward = "8080:10.10.0.3:80".parse().unwrap();
+        assert_eq!(forward.listen, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(forward.target, "10.10.0.3:80".parse().unwrap());
+
+        let forward: Forward = "0.0.0.0:25565:10.10.0.4:25565".parse().unwrap();
+        assert_eq!(forward.listen, "0.0.0.0:25565".parse().unwrap());
+        assert_eq!(forward.target, "10.10.0.4:25565".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_forward() {
+        for spec in [
+            "",
+            "8080",
+            "8080:10.10.0.3",
+            "1:2:3:4:5",
+            "http:10.10.0.3:80",
+            "8080:10.10.0.3:70000",
+            "8080:peer2:80",
+            "localhost:8080:10.10.0.3:80",
+            "8080:10.10.0.3:",
+        ] {
+            assert!(spec.parse::<Forward>().is_err(), "{:?} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn parses_reverse_forward() {
+        let forward: ReverseForward = "25565".parse().unwrap();
+        assert_eq!(forward.port, 25565);
+        assert_eq!(forward.target, "127.0.0.1:25565".parse().unwrap());
+
+        let forward: ReverseForward = "80:192.168.1.5:8080".parse().unwrap();
+        assert_eq!(forward.port, 80);
+        assert_eq!(forward.target, "192.168.1.5:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_reverse_forward() {
+        for spec in ["", "web", "80:192.168.1.5", "80:192.168.1.5:8080:1", "80:host:8080", "65536", "-1"] {
+            assert!(spec.parse::<ReverseForward>().is_err(), "{:?} parsed", spec);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::frame::{FRAGMENT_HEADER, Frame};
+
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_PENDING: usize = 64;
+
+/// Splits an encoded frame into fragment frames no longer than `max_frame`
+/// each. Returns `None` if it would take more than 255 fragments.
+pub fn split(id: u16, frame: &Bytes, max_frame: usize) -> Option<Vec<Frame>> {
+    let chunk = max_frame.checked_sub(FRAGMENT_HEADER).filter(|c| *c > 0)?;
+    let count = u8::try_from(frame.len().div_ceil(chunk)).ok()?;
+
+    let fragments = (0..frame.len())
+        .step_by(chunk)
+        .enumerate()
+        .map(|(index, start)| Frame::Fragment {
+            id,
+            index: index as u8,
+            count,
+            data: frame.slice(start..frame.len().min(start + chunk)),
+        })
+        .collect();
+
+    Some(fragments)
+}
+
+struct Partial {
+    parts: Vec<Option<Bytes>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Collects fragments from one channel back into whole frames. Incomplete
+/// frames are given up after a short timeout, as the packet they carry is
+/// useless by then anyway.
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<u16, Partial>,
+}
+
+impl Reassembler {
+    /// Takes one fragment and returns the original encoded frame once all
+    /// of its fragments have arrived.
+    pub fn push(&mut self, id: u16, index: u8, count: u8, data: Bytes) -> Option<Bytes> {
+        let (index, count) = (usize::from(index), usize::from(count));
+        if count == 0 || index >= count {
+            return None;
+        }
+
+        self.expire();
+
+        if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+
+        let partial = self.pending.entry(id).or_insert_with(|| Partial {
+            parts: vec![None; count],
+            received: 0,
+            started: Instant::now(),
+        });
+        if partial.parts.len() != count {
+            // Id reused with a different shape: start over.
+            self.pending.remove(&id);
+            return None;
+        }
+
+        let slot = &mut partial.parts[index];
+        if slot.is_none() {
+            *slot = Some(data);
+            partial.received += 1;
+        }
+
+        if partial.received < count {
+            return None;
+        }
+
+        let partial = self.pending.remove(&id)?;
+        let whole: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        Some(Bytes::from(whole))
+    }
+
+    fn expire(&mut self) {
+        self.pending
+            .retain(|_, p| p.started.elapsed() < REASSEMBLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mtu::MtuConfig;
+
+    fn frame(len: usize) -> Bytes {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn parts(fragments: Vec<Frame>) -> Vec<(u16, u8, u8, Bytes)> {
+        fragments
+            .into_iter()
+            .map(|f| match f {
+                Frame::Fragment { id, index, count, data } => (id, index, count, data),
+                other => panic!("not a fragment: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_at_the_path_mtu() {
+        let max = MtuConfig::default().max_frame();
+        let chunk = max - FRAGMENT_HEADER;
+
+        assert_eq!(split(1, &frame(chunk), max).unwrap().len(), 1);
+        let fragments = split(1, &frame(chunk + 1), max).unwrap();
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].encode().len(), max);
+        assert_eq!(fragments[1].encode().len(), FRAGMENT_HEADER + 1);
+
+        let fragments = split(1, &frame(3 * chunk), max).unwrap();
+        assert!(fragments.iter().all(|f| f.encode().len() == max));
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_split() {
+        assert!(split(1, &frame(100), FRAGMENT_HEADER).is_none());
+        assert!(split(1, &frame(255 * 10), FRAGMENT_HEADER + 10).is_some());
+        assert!(split(1, &frame(255 * 10 + 1), FRAGMENT_HEADER + 10).is_none());
+    }
+
+    #[test]
+    fn reassembles_in_any_order() {
+        let whole = frame(5000);
+        let mut fragments = parts(split(7, &whole, 1200).unwrap());
+        assert_eq!(fragments.len(), 5);
+        fragments.reverse();
+        fragments.swap(1, 3);
+
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = fragments.split_last().unwrap();
+        for (id, index, count, data) in rest.iter().cloned() {
+            assert_eq!(reassembler.push(id, index, count, data), None);
+        }
+        // A duplicate neither completes the frame nor counts twice.
+        let (id, index, count, data) = rest[0].clone();
+        assert_eq!(reassembler.push(id, index, count, data), None);
+
+        let (id, index, count, data) = last.clone();
+        assert_eq!(reassembler.push(id, index, count, data), Some(whole));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn keeps_frames_apart() {
+        let (a, b) = (frame(3000), Bytes::from(vec![0xab; 3000]));
+        let a_parts = parts(split(1, &a, 1200).unwrap());
+        let b_parts = parts(split(2, &b, 1200).unwrap());
+
+        let mut reassembler = Reassembler::default();
+        let mut done = Vec::new();
+        for (id, index, count, data) in a_parts.into_iter().zip(b_parts).flat_map(|(a, b)| [a, b]) {
+            done.extend(reassembler.push(id, index, count, data));
+        }
+        assert_eq!(done, vec![a, b]);
+    }
+
+    #[test]
+    fn drops_incomplete_frames() {
+        let mut fragments = parts(split(3, &frame(3000), 1200).unwrap());
+        fragments.remove(1);
+
+        let mut reassembler = Reassembler::default();
+        for (id, index, count, data) in fragments {
+            assert_eq!(reassembler.push(id, index, count, data), None);
+        }
+        assert_eq!(reassembler.pending.len(), 1);
+
+        reassembler.pending.get_mut(&3).unwrap().started -= REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(4, 0, 2, frame(10)), None);
+        assert!(!reassembler.pending.contains_key(&3));
+    }
+
+    #[test]
+    fn rejects_bad_fragments() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(1, 0, 0, frame(10)), None);
+        assert_eq!(reassembler.push(1, 2, 2, frame(10)), None);
+        assert!(reassembler.pending.is_empty());
+
+        // An id reused with another count starts over.
+        assert_eq!(reassembler.push(1, 0, 2, frame(10)), None);
+        assert_eq!(reassembler.push(1, 1, 3, frame(10)), None);
+        assert_eq!(reassembler.push(1, 1, 2, frame(10)), None);
+        assert!(reassembler.pending.contains_key(&1));
+    }
+
+    #[test]
+    fn bounds_pending_frames() {
+        let mut reassembler = Reassembler::default();
+        for id in 0..MAX_PENDING as u16 {
+            reassembler.push(id, 0, 2, frame(10));
+        }
+        assert_eq!(reassembler.push(1000, 0, 1, frame(10)), None);
+        assert_eq!(reassembler.push(0, 1, 2, frame(10)).map(|f| f.len()), Some(20));
+    }
+}
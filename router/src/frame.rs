@@ -0,0 +1,532 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::batch;
+use crate::chat::ChatWire;
+use crate::compress::Compression;
+use crate::gossip::GossipMessage;
+use crate::route::RouteAdvert;
+use crate::throughput::ThroughputControl;
+use crate::transfer::FileControl;
+
+/// Version of the data channel framing. Bumped when existing frames change
+/// meaning; new frame types are announced through `Capabilities` instead.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+pub const SOFTWARE_VERSION: &str = concat!("lan-racer/", env!("CARGO_PKG_VERSION"));
+
+const TYPE_PACKET: u8 = 0x01;
+const TYPE_CHAT: u8 = 0x02;
+const TYPE_FRAGMENT: u8 = 0x03;
+const TYPE_HELLO: u8 = 0x04;
+const TYPE_BATCH: u8 = 0x05;
+const TYPE_COMPRESSED: u8 = 0x06;
+const TYPE_FILE: u8 = 0x07;
+const TYPE_FILE_CHUNK: u8 = 0x08;
+const TYPE_CHAT_MESSAGE: u8 = 0x09;
+const TYPE_PING: u8 = 0x0a;
+const TYPE_PONG: u8 = 0x0b;
+const TYPE_THROUGHPUT: u8 = 0x0c;
+const TYPE_THROUGHPUT_DATA: u8 = 0x0d;
+const TYPE_THROUGHPUT_ECHO: u8 = 0x0e;
+const TYPE_GOSSIP: u8 = 0x0f;
+const TYPE_ROUTES: u8 = 0x10;
+
+// Type, message id (u16), index, count.
+pub const FRAGMENT_HEADER: usize = 1 + 2 + 1 + 1;
+
+/// Optional frame types a peer understands. Peers that never sent a
+/// `Hello` are treated as having none of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const FRAGMENT: Capabilities = Capabilities(1 << 0);
+    pub const BATCH: Capabilities = Capabilities(1 << 1);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+    pub const FILE_TRANSFER: Capabilities = Capabilities(1 << 3);
+    /// Chat messages with ids, timestamps, broadcast and acks.
+    pub const CHAT: Capabilities = Capabilities(1 << 4);
+    /// Ping and pong frames for latency and loss measurement.
+    pub const PING: Capabilities = Capabilities(1 << 5);
+    /// Answers throughput tests.
+    pub const THROUGHPUT: Capabilities = Capabilities(1 << 6);
+    /// Shares peer lists and relays offers and answers for other peers.
+    pub const GOSSIP: Capabilities = Capabilities(1 << 7);
+    /// Advertises routes and passes on packets for peers it routes to.
+    pub const ROUTING: Capabilities = Capabilities(1 << 8);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn all() -> Self {
+        Capabilities(
+            Self::FRAGMENT.0
+                | Self::BATCH.0
+                | Self::COMPRESSION.0
+                | Self::FILE_TRANSFER.0
+                | Self::CHAT.0
+                | Self::PING.0
+                | Self::THROUGHPUT.0
+                | Self::GOSSIP.0
+                | Self::ROUTING.0,
+        )
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+}
+
+/// First frame each side sends on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u16,
+    pub software: String,
+    pub capabilities: Capabilities,
+    /// Sender's device MTU.
+    pub mtu: u16,
+    /// Compression algorithm ids the sender accepts.
+    pub compression: Vec<u8>,
+}
+
+impl Hello {
+    /// What a link with the sender of this hello uses: the optional frames
+    /// both sides understand, and `ours` if the sender accepts it.
+    pub fn negotiate(&self, ours: Compression) -> (Capabilities, Compression) {
+        let caps = Capabilities::all().intersection(self.capabilities);
+        let compression = if caps.contains(Capabilities::COMPRESSION)
+            && ours != Compression::None
+            && self.compression.contains(&ours.id())
+        {
+            ours
+        } else {
+            Compression::None
+        };
+        (caps, compression)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Packet(Bytes),
+    /// Plain chat text, as understood by peers without `CHAT`.
+    Chat(String),
+    ChatMessage(ChatWire),
+    Fragment {
+        id: u16,
+        index: u8,
+        count: u8,
+        data: Bytes,
+    },
+    Hello(Hello),
+    Batch(Vec<Bytes>),
+    Compressed {
+        algo: u8,
+        data: Bytes,
+    },
+    File(FileControl),
+    FileChunk {
+        id: u64,
+        offset: u64,
+        data: Bytes,
+    },
+    /// Sent on the unreliable channel and echoed back as a `Pong`.
+    Ping(u32),
+    Pong(u32),
+    Throughput(ThroughputControl),
+    /// Filler sent on the channel under test.
+    ThroughputData {
+        id: u32,
+        seq: u32,
+        data: Bytes,
+    },
+    /// Returned for a sample of data frames, to time the round trip.
+    ThroughputEcho {
+        id: u32,
+        seq: u32,
+    },
+    /// Peer exchange, on the control channel.
+    Gossip(GossipMessage),
+    /// The sender's distance vector, on the control channel.
+    Routes(RouteAdvert),
+    /// A frame type from a newer protocol revision.
+    Unknown(u8),
+}
+
+impl Frame {
+    pub fn encode(&self) -> Bytes {
+        let mut out = Vec::with_capacity(self.encoded_len());
+
+        match self {
+            Frame::Packet(packet) => {
+                out.push(TYPE_PACKET);
+                out.extend_from_slice(packet);
+            }
+            Frame::Chat(text) => {
+                out.push(TYPE_CHAT);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Frame::ChatMessage(msg) => {
+                out.push(TYPE_CHAT_MESSAGE);
+                out.extend_from_slice(&serde_json::to_vec(msg).unwrap_or_default());
+            }
+            Frame::Fragment {
+                id,
+                index,
+                count,
+                data,
+            } => {
+                out.push(TYPE_FRAGMENT);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.push(*index);
+                out.push(*count);
+                out.extend_from_slice(data);
+            }
+            Frame::Hello(hello) => {
+                let software = truncate(&hello.software, u8::MAX as usize);
+                out.push(TYPE_HELLO);
+                out.extend_from_slice(&hello.protocol.to_be_bytes());
+                out.extend_from_slice(&hello.capabilities.bits().to_be_bytes());
+                out.extend_from_slice(&hello.mtu.to_be_bytes());
+                out.push(hello.compression.len() as u8);
+                out.extend_from_slice(&hello.compression);
+                out.push(software.len() as u8);
+                out.extend_from_slice(software.as_bytes());
+            }
+            Frame::Batch(packets) => {
+                out.push(TYPE_BATCH);
+                for packet in packets {
+                    batch::push(&mut out, packet);
+                }
+            }
+            Frame::Compressed { algo, data } => {
+                out.push(TYPE_COMPRESSED);
+                out.push(*algo);
+                out.extend_from_slice(data);
+            }
+            Frame::File(msg) => {
+                out.push(TYPE_FILE);
+                out.extend_from_slice(&serde_json::to_vec(msg).unwrap_or_default());
+            }
+            Frame::FileChunk { id, offset, data } => {
+                out.push(TYPE_FILE_CHUNK);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&offset.to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            Frame::Ping(seq) => {
+                out.push(TYPE_PING);
+                out.extend_from_slice(&seq.to_be_bytes());
+            }
+            Frame::Pong(seq) => {
+                out.push(TYPE_PONG);
+                out.extend_from_slice(&seq.to_be_bytes());
+            }
+            Frame::Throughput(msg) => {
+                out.push(TYPE_THROUGHPUT);
+                out.extend_from_slice(&serde_json::to_vec(msg).unwrap_or_default());
+            }
+            Frame::ThroughputData { id, seq, data } => {
+                out.push(TYPE_THROUGHPUT_DATA);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&seq.to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            Frame::ThroughputEcho { id, seq } => {
+                out.push(TYPE_THROUGHPUT_ECHO);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&seq.to_be_bytes());
+            }
+            Frame::Gossip(msg) => {
+                out.push(TYPE_GOSSIP);
+                out.extend_from_slice(&serde_json::to_vec(msg).unwrap_or_default());
+            }
+            Frame::Routes(advert) => {
+                out.push(TYPE_ROUTES);
+                out.extend_from_slice(&serde_json::to_vec(advert).unwrap_or_default());
+            }
+            Frame::Unknown(frame_type) => out.push(*frame_type),
+        }
+
+        Bytes::from(out)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        1 + match self {
+            Frame::Packet(packet) => packet.len(),
+            Frame::Chat(text) => text.len(),
+            Frame::ChatMessage(_) => 128,
+            Frame::Fragment { data, .. } => FRAGMENT_HEADER - 1 + data.len(),
+            Frame::Hello(hello) => {
+                2 + 4 + 2 + 1 + hello.compression.len() + 1 + hello.software.len().min(255)
+            }
+            Frame::Batch(packets) => packets.iter().map(|p| batch::LEN_PREFIX + p.len()).sum(),
+            Frame::Compressed { data, .. } => 1 + data.len(),
+            Frame::File(_) => 64,
+            Frame::FileChunk { data, .. } => 8 + 8 + data.len(),
+            Frame::Ping(_) | Frame::Pong(_) => 4,
+            Frame::Throughput(_) => 64,
+            Frame::ThroughputData { data, .. } => 4 + 4 + data.len(),
+            Frame::ThroughputEcho { .. } => 4 + 4,
+            Frame::Gossip(_) => 256,
+            Frame::Routes(advert) => 16 + 48 * advert.routes.len(),
+            Frame::Unknown(_) => 0,
+        }
+    }
+
+    pub fn decode(data: &Bytes) -> Result<Frame> {
+        let (&frame_type, _) = data.split_first().ok_or(anyhow!("Empty frame"))?;
+        let body = data.slice(1..);
+
+        let frame = match frame_type {
+            TYPE_PACKET => Frame::Packet(body),
+            TYPE_CHAT => Frame::Chat(String::from_utf8_lossy(&body).to_string()),
+            TYPE_FRAGMENT => {
+                if body.len() < FRAGMENT_HEADER - 1 {
+                    return Err(anyhow!("Truncated fragment"));
+                }
+                Frame::Fragment {
+                    id: u16::from_be_bytes([body[0], body[1]]),
+                    index: body[2],
+                    count: body[3],
+                    data: body.slice(4..),
+                }
+            }
+            TYPE_HELLO => Frame::Hello(decode_hello(&body)?),
+            TYPE_BATCH => Frame::Batch(
+                batch::unpack(&body)
+                    .into_iter()
+                    .map(|p| body.slice_ref(p))
+                    .collect(),
+            ),
+            TYPE_COMPRESSED => {
+                let (&algo, _) = body.split_first().ok_or(anyhow!("Truncated compressed frame"))?;
+                Frame::Compressed {
+                    algo,
+                    data: body.slice(1..),
+                }
+            }
+            TYPE_CHAT_MESSAGE => Frame::ChatMessage(serde_json::from_slice(&body)?),
+            TYPE_FILE => Frame::File(serde_json::from_slice(&body)?),
+            TYPE_FILE_CHUNK => {
+                if body.len() < 16 {
+                    return Err(anyhow!("Truncated file chunk"));
+                }
+                Frame::FileChunk {
+                    id: u64::from_be_bytes(body[..8].try_into()?),
+                    offset: u64::from_be_bytes(body[8..16].try_into()?),
+                    data: body.slice(16..),
+                }
+            }
+            TYPE_PING => Frame::Ping(u32::from_be_bytes(body[..].try_into().map_err(|_| anyhow!("Bad ping"))?)),
+            TYPE_PONG => Frame::Pong(u32::from_be_bytes(body[..].try_into().map_err(|_| anyhow!("Bad pong"))?)),
+            TYPE_THROUGHPUT => Frame::Throughput(serde_json::from_slice(&body)?),
+            TYPE_GOSSIP => Frame::Gossip(serde_json::from_slice(&body)?),
+            TYPE_ROUTES => Frame::Routes(serde_json::from_slice(&body)?),
+            TYPE_THROUGHPUT_DATA | TYPE_THROUGHPUT_ECHO => {
+                if body.len() < 8 {
+                    return Err(anyhow!("Truncated throughput frame"));
+                }
+                let id = u32::from_be_bytes(body[..4].try_into()?);
+                let seq = u32::from_be_bytes(body[4..8].try_into()?);
+                if frame_type == TYPE_THROUGHPUT_DATA {
+                    Frame::ThroughputData { id, seq, data: body.slice(8..) }
+                } else {
+                    Frame::ThroughputEcho { id, seq }
+                }
+            }
+            other => Frame::Unknown(other),
+        };
+
+        Ok(frame)
+    }
+}
+
+// Fields are read in order and anything after the last known one is
+// ignored, so later revisions can append to the hello.
+fn decode_hello(body: &[u8]) -> Result<Hello> {
+    let mut r = Reader(body);
+
+    let protocol = u16::from_be_bytes(r.take()?);
+    let capabilities = Capabilities::from_bits(u32::from_be_bytes(r.take()?));
+    let mtu = u16::from_be_bytes(r.take()?);
+    let [n] = r.take()?;
+    let compression = r.bytes(usize::from(n))?.to_vec();
+    let [n] = r.take()?;
+    let software = String::from_utf8_lossy(r.bytes(usize::from(n))?).to_string();
+
+    Ok(Hello {
+        protocol,
+        software,
+        capabilities,
+        mtu,
+        compression,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(anyhow!("Truncated hello"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into()?)
+    }
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Hello {
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            software: SOFTWARE_VERSION.to_owned(),
+            capabilities: Capabilities::all(),
+            mtu: 1420,
+            compression: vec![Compression::Lz4.id()],
+        }
+    }
+
+    fn roundtrip(frame: Frame) {
+        assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+    }
+
+    #[test]
+    fn roundtrips_every_frame() {
+        let data = Bytes::from_static(b"\x45\x00\x00\x14payload");
+        roundtrip(Frame::Packet(data.clone()));
+        roundtrip(Frame::Chat("hi there ✓".to_owned()));
+        roundtrip(Frame::ChatMessage(ChatWire::Ack { id: "m1".to_owned() }));
+        roundtrip(Frame::Fragment { id: 0xbeef, index: 2, count: 3, data: data.clone() });
+        roundtrip(Frame::Hello(hello()));
+        roundtrip(Frame::Batch(vec![data.clone(), Bytes::new(), Bytes::from_static(b"x")]));
+        roundtrip(Frame::Compressed { algo: Compression::Lz4.id(), data: data.clone() });
+        roundtrip(Frame::File(FileControl::Accept { id: 7, offset: 1 << 40 }));
+        roundtrip(Frame::FileChunk { id: u64::MAX, offset: 4096, data: data.clone() });
+        roundtrip(Frame::Ping(0xdead_beef));
+        roundtrip(Frame::Pong(1));
+        roundtrip(Frame::Throughput(ThroughputControl::Finish { id: 3, frames: 1000 }));
+        roundtrip(Frame::ThroughputData { id: 3, seq: 99, data });
+        roundtrip(Frame::ThroughputEcho { id: 3, seq: 99 });
+        roundtrip(Frame::Gossip(GossipMessage::Peers { peers: vec!["alice".to_owned(), "bob".to_owned()] }));
+        roundtrip(Frame::Routes(RouteAdvert {
+            routes: vec![("10.0.0.2".parse().unwrap(), 0), ("fd00::3".parse().unwrap(), 2)],
+        }));
+        roundtrip(Frame::Unknown(0xee));
+    }
+
+    #[test]
+    fn encoded_len_fits_fixed_frames() {
+        let frames = [
+            Frame::Fragment { id: 1, index: 0, count: 1, data: Bytes::from_static(b"abc") },
+            Frame::Hello(hello()),
+            Frame::Batch(vec![Bytes::from_static(b"ab"), Bytes::from_static(b"cde")]),
+            Frame::FileChunk { id: 1, offset: 2, data: Bytes::from_static(b"abc") },
+            Frame::Ping(1),
+            Frame::ThroughputEcho { id: 1, seq: 2 },
+        ];
+        for frame in frames {
+            assert_eq!(frame.encode().len(), frame.encoded_len(), "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        assert!(Frame::decode(&Bytes::new()).is_err());
+        for frame in [
+            Frame::Fragment { id: 1, index: 0, count: 2, data: Bytes::new() },
+            Frame::Hello(hello()),
+            Frame::FileChunk { id: 1, offset: 0, data: Bytes::new() },
+            Frame::Ping(1),
+            Frame::ThroughputEcho { id: 1, seq: 1 },
+            Frame::Routes(RouteAdvert { routes: vec![("10.0.0.2".parse().unwrap(), 1)] }),
+        ] {
+            let encoded = frame.encode();
+            assert!(Frame::decode(&encoded.slice(..encoded.len() - 1)).is_err(), "{:?}", frame);
+        }
+        assert!(Frame::decode(&Bytes::from_static(&[TYPE_COMPRESSED])).is_err());
+        assert!(Frame::decode(&Bytes::from_static(&[TYPE_PONG, 0, 0, 0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn keeps_unknown_types() {
+        assert_eq!(Frame::decode(&Bytes::from_static(&[0x7f, 1, 2, 3])).unwrap(), Frame::Unknown(0x7f));
+    }
+
+    #[test]
+    fn decodes_hello_from_a_newer_revision() {
+        let mut newer = hello();
+        newer.protocol = PROTOCOL_VERSION + 1;
+        newer.capabilities = Capabilities::from_bits(Capabilities::all().bits() | 1 << 31);
+        let mut encoded = Frame::Hello(newer.clone()).encode().to_vec();
+        encoded.extend_from_slice(b"field from later");
+        assert_eq!(Frame::decode(&Bytes::from(encoded)).unwrap(), Frame::Hello(newer.clone()));
+
+        // Only what both sides know is used.
+        let (caps, compression) = newer.negotiate(Compression::Lz4);
+        assert_eq!(caps, Capabilities::all());
+        assert_eq!(compression, Compression::Lz4);
+    }
+
+    #[test]
+    fn negotiates_with_an_older_peer() {
+        let older = Hello {
+            capabilities: Capabilities::from_bits(Capabilities::FRAGMENT.bits() | Capabilities::BATCH.bits()),
+            ..hello()
+        };
+        let (caps, compression) = older.negotiate(Compression::Lz4);
+        assert!(caps.contains(Capabilities::FRAGMENT) && caps.contains(Capabilities::BATCH));
+        assert!(!caps.contains(Capabilities::CHAT) && !caps.contains(Capabilities::ROUTING));
+        assert_eq!(compression, Compression::None, "the peer did not advertise COMPRESSION");
+    }
+
+    #[test]
+    fn negotiates_compression() {
+        assert_eq!(hello().negotiate(Compression::Lz4).1, Compression::Lz4);
+        assert_eq!(hello().negotiate(Compression::None).1, Compression::None);
+        let plain = Hello { compression: vec![], ..hello() };
+        assert_eq!(plain.negotiate(Compression::Lz4).1, Compression::None);
+        let bare = Hello { capabilities: Capabilities::empty(), ..hello() };
+        assert_eq!(bare.negotiate(Compression::Lz4), (Capabilities::empty(), Compression::None));
+    }
+
+    #[test]
+    fn truncates_long_software_names() {
+        let long = Hello { software: "é".repeat(200), ..hello() };
+        let Frame::Hello(decoded) = Frame::decode(&Frame::Hello(long).encode()).unwrap() else {
+            panic!("not a hello");
+        };
+        assert_eq!(decoded.software, "é".repeat(127));
+    }
+}
@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{Instrument, debug, info, warn};
+
+use crate::frame::{Capabilities, Frame};
+use crate::peer::{self, PeerManager};
+use crate::signaling::protocol::SignalMessage;
+use crate::transport::LinkState;
+
+/// How often every neighbour hears which peers we are linked to, besides
+/// whenever one says hello.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(15);
+
+// Hops a relayed offer or answer may take. One is all it needs while peer
+// lists only name direct links; the rest bounds a loop through stale ones.
+const MAX_TTL: u8 = 3;
+
+// A relayed offer that has not produced a connected link by then is given
+// up, and tried again on the next peer list.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Relayed offers answered and not linked up yet, each holding a peer
+// connection open. Bounds what neighbours can make us set up.
+const MAX_PENDING_ANSWERS: usize = 8;
+
+/// Peer exchange, sent as JSON on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GossipMessage {
+    /// The peers the sender has a link up with.
+    Peers { peers: Vec<String> },
+    /// An offer or answer on its way to the peer it is for, good for at
+    /// most `ttl` more hops.
+    Relay { signal: SignalMessage, ttl: u8 },
+}
+
+/// Lets the mesh form and heal through the links it already has: peers
+/// tell each other who they are linked to, and pass on offers and answers
+/// between peers that are not linked yet, so no signaling server is
+/// needed once a node has any link at all.
+#[derive(Default)]
+pub struct Gossip {
+    /// What each neighbour last said it is linked to.
+    neighbours: Mutex<HashMap<String, Vec<String>>>,
+    /// Peers we have a relayed offer out to.
+    offering: Arc<Mutex<HashSet<String>>>,
+    /// Peers whose relayed offer we answered, until the link is up or
+    /// given up.
+    answering: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Gossip {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tells every neighbour that gossips which peers we are linked to.
+    pub async fn announce(&self, manager: &PeerManager) {
+        let linked = manager.linked_peers().await;
+        let peers: Vec<String> = linked.iter().map(|(id, _)| id.clone()).collect();
+        for (peer_id, caps) in &linked {
+            if !caps.contains(Capabilities::GOSSIP) {
+                continue;
+            }
+            let msg = GossipMessage::Peers { peers: peers.clone() };
+            if let Err(e) = manager.send_control(peer_id, Frame::Gossip(msg)).await {
+                debug!(parent: &peer::span(peer_id), "Peer list not sent: {}", e);
+            }
+        }
+    }
+
+    pub async fn on_message(&self, manager: &PeerManager, peer_id: String, msg: GossipMessage) {
+        match msg {
+            GossipMessage::Peers { peers } => {
+                for other in &peers {
+                    self.offer(manager, &peer_id, other).await;
+                }
+                self.neighbours.lock().unwrap().insert(peer_id, peers);
+            }
+            GossipMessage::Relay { signal, ttl } => self.on_relay(manager, peer_id, signal, ttl).await,
+        }
+    }
+
+    pub fn forget_peer(&self, peer_id: &str) {
+        self.neighbours.lock().unwrap().remove(peer_id);
+    }
+
+    // Offers a link to `target`, which `via` is linked to, unless we are
+    // linked already. Of two peers, only the smaller id offers, so they do
+    // not cross offers on hearing of each other.
+    async fn offer(&self, manager: &PeerManager, via: &str, target: &str) {
+        let local_id = manager.local_id();
+        if target <= local_id || manager.is_banned(target) || manager.is_linked(target).await {
+            return;
+        }
+        if !self.offering.lock().unwrap().insert(target.to_owned()) {
+            return;
+        }
+
+        let manager = manager.clone();
+        let offering = self.offering.clone();
+        let (via, target) = (via.to_owned(), target.to_owned());
+        let span = peer::span(&target);
+        tokio::spawn(
+            async move {
+                match manager.create_offer(target.clone()).await {
+                    Ok(sdp) => {
+                        let signal = SignalMessage::Offer { from: manager.local_id().to_owned(), to: target.clone(), sdp };
+                        let msg = GossipMessage::Relay { signal, ttl: MAX_TTL };
+                        match manager.send_control(&via, Frame::Gossip(msg)).await {
+                            Ok(()) => {
+                                info!("Offer sent through {}", via);
+                                tokio::time::sleep(OFFER_TIMEOUT).await;
+                            }
+                            Err(e) => warn!("Offer through {} not sent: {}", via, e),
+                        }
+                        if manager.link_state(&target).await != Some(LinkState::Connected) {
+                            debug!("No link through {}", via);
+                            let _ = manager.remove_peer(&target).await;
+                        }
+                    }
+                    Err(e) => warn!("Offer error: {}", e),
+                }
+                offering.lock().unwrap().remove(&target);
+            }
+            .instrument(span),
+        );
+    }
+
+    async fn on_relay(&self, manager: &PeerManager, via: String, signal: SignalMessage, ttl: u8) {
+        let (from, to) = match &signal {
+            SignalMessage::Offer { from, to, .. } | SignalMessage::Answer { from, to, .. } => {
+                (from.clone(), to.clone())
+            }
+            _ => return,
+        };
+
+        if to != manager.local_id() {
+            self.forward(manager, &via, &to, signal, ttl).await;
+            return;
+        }
+
+        let span = peer::span(&from);
+        match signal {
+            SignalMessage::Offer { sdp, .. } => {
+                // A neighbour cannot take over a link we have, or one being
+                // set up, by offering in that peer's name.
+                if manager.is_linked(&from).await {
+                    debug!(parent: &span, "Ignoring offer relayed by {} for a peer with a link", via);
+                    return;
+                }
+                if manager.rejects(&from, &sdp) {
+                    info!(parent: &span, "Rejected offer from banned peer");
+                    return;
+                }
+                {
+                    let mut answering = self.answering.lock().unwrap();
+                    if answering.len() >= MAX_PENDING_ANSWERS && !answering.contains(&from) {
+                        warn!(parent: &span, "Ignoring offer relayed by {}: too many pending", via);
+                        return;
+                    }
+                    if !answering.insert(from.clone()) {
+                        debug!(parent: &span, "Ignoring repeated offer relayed by {}", via);
+                        return;
+                    }
+                }
+
+                let manager = manager.clone();
+                let answering = self.answering.clone();
+                tokio::spawn(
+                    async move {
+                        info!("Offer received through {}", via);
+                        match manager.accept_offer(from.clone(), &sdp).await {
+                            Ok(answer) => {
+                                let signal = SignalMessage::Answer {
+                                    from: manager.local_id().to_owned(),
+                                    to: from.clone(),
+                                    sdp: answer,
+                                };
+                                let msg = GossipMessage::Relay { signal, ttl: MAX_TTL };
+                                match manager.send_control(&via, Frame::Gossip(msg)).await {
+                                    Ok(()) => tokio::time::sleep(OFFER_TIMEOUT).await,
+                                    Err(e) => warn!("Answer through {} not sent: {}", via, e),
+                                }
+                                if manager.link_state(&from).await != Some(LinkState::Connected) {
+                                    debug!("No link through {}", via);
+                                    let _ = manager.remove_peer(&from).await;
+                                }
+                            }
+                            Err(e) => warn!("Accept error: {}", e),
+                        }
+                        answering.lock().unwrap().remove(&from);
+                    }
+                    .instrument(span),
+                );
+            }
+            SignalMessage::Answer { sdp, .. } => {
+                if !self.offering.lock().unwrap().contains(&from) {
+                    debug!(parent: &span, "Ignoring answer relayed by {} to no offer", via);
+                    return;
+                }
+                if let Err(e) = manager.set_answer_as_offerer(&from, &sdp).await {
+                    warn!(parent: &span, "Answer error: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Passes `signal` on toward `to`: straight to it when linked, else to
+    // a neighbour other than the one it came from that says it is.
+    async fn forward(&self, manager: &PeerManager, via: &str, to: &str, signal: SignalMessage, ttl: u8) {
+        if ttl == 0 {
+            debug!(parent: &peer::span(to), "Dropping relayed signal from {}: out of hops", via);
+            return;
+        }
+        let next = if manager.is_linked(to).await {
+            Some(to.to_owned())
+        } else {
+            self.neighbours
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(id, peers)| id.as_str() != via && peers.iter().any(|p| p == to))
+                .map(|(id, _)| id.clone())
+        };
+        let Some(next) = next else {
+            debug!(parent: &peer::span(to), "No way to relay from {}", via);
+            return;
+        };
+        let msg = GossipMessage::Relay { signal, ttl: ttl - 1 };
+        if let Err(e) = manager.send_control(&next, Frame::Gossip(msg)).await {
+            debug!(parent: &peer::span(to), "Relay through {} failed: {}", next, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback;
+    use crate::transport::{LinkEvents, PeerTransport};
+    use crate::trust::TrustMode;
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+    async fn manager(local_id: &str) -> PeerManager {
+        PeerManager::in_memory(local_id, TrustMode::Tofu).await
+    }
+
+    // Links `manager` with `peer_id` over a loopback pair and hands back
+    // the far end, which must stay open, and the gossip that arrives there.
+    async fn neighbour(
+        manager: &PeerManager,
+        peer_id: &str,
+    ) -> (loopback::LoopbackTransport, mpsc::UnboundedReceiver<GossipMessage>) {
+        let (near, far) = loopback::pair();
+        let (tx, rx) = mpsc::unbounded_channel();
+        far.start(LinkEvents::new(
+            move |_, data: Bytes| {
+                if let Ok(Frame::Gossip(msg)) = Frame::decode(&data) {
+                    let _ = tx.send(msg);
+                }
+                async {}
+            },
+            |_| async {},
+        ));
+        manager.attach(peer_id.into(), Arc::new(near)).await;
+        (far, rx)
+    }
+
+    fn offer(from: &str, to: &str) -> SignalMessage {
+        let sdp = "v=0\r\n\
+                   o=- 2927307686215094172 877616351 IN IP4 0.0.0.0\r\n\
+                   s=-\r\n\
+                   t=0 0\r\n\
+                   a=fingerprint:sha-256 \
+                   3C:4F:A1:0B:92:7E:11:D8:05:6A:BE:CC:29:F0:73:84:5D:E2:19:60:AF:0D:B7:48:92:35:C1:7A:EE:06:5B:F3\r\n\
+                   a=group:BUNDLE 0\r\n\
+                   m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+                   c=IN IP4 0.0.0.0\r\n\
+                   a=setup:actpass\r\n\
+                   a=mid:0\r\n\
+                   a=sctp-port:5000\r\n\
+                   a=ice-ufrag:kZfLbYxWqGcHnRtE\r\n\
+                   a=ice-pwd:pQmVrTzXcNbLkJhGfDsAeWuYiOoPlMnB\r\n";
+        let sdp = serde_json::to_string(&RTCSessionDescription::offer(sdp.into()).unwrap()).unwrap();
+        SignalMessage::Offer { from: from.into(), to: to.into(), sdp }
+    }
+
+    fn relay(signal: SignalMessage, ttl: u8) -> GossipMessage {
+        GossipMessage::Relay { signal, ttl }
+    }
+
+    #[tokio::test]
+    async fn relays_within_hops() {
+        let manager = manager("alice").await;
+        let (_far, mut bob) = neighbour(&manager, "bob").await;
+        let gossip = Gossip::new();
+
+        gossip.on_message(&manager, "carol".into(), relay(offer("carol", "bob"), 2)).await;
+        let got = tokio::time::timeout(Duration::from_secs(5), bob.recv()).await.unwrap();
+        assert_eq!(got, Some(relay(offer("carol", "bob"), 1)));
+
+        gossip.on_message(&manager, "carol".into(), relay(offer("carol", "bob"), 0)).await;
+        assert!(tokio::time::timeout(Duration::from_millis(200), bob.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn relays_through_a_neighbour_that_knows_the_peer() {
+        let manager = manager("alice").await;
+        let (_far, mut bob) = neighbour(&manager, "bob").await;
+        let gossip = Gossip::new();
+        gossip.neighbours.lock().unwrap().insert("bob".into(), vec!["dave".into()]);
+
+        gossip.on_message(&manager, "carol".into(), relay(offer("carol", "dave"), MAX_TTL)).await;
+        let got = tokio::time::timeout(Duration::from_secs(5), bob.recv()).await.unwrap();
+        assert_eq!(got, Some(relay(offer("carol", "dave"), MAX_TTL - 1)));
+
+        // Not back to where it came from.
+        gossip.on_message(&manager, "bob".into(), relay(offer("carol", "dave"), MAX_TTL)).await;
+        assert!(tokio::time::timeout(Duration::from_millis(200), bob.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_an_offer_once() {
+        let manager = manager("alice").await;
+        let gossip = Gossip::new();
+
+        gossip.on_message(&manager, "bob".into(), relay(offer("carol", "alice"), MAX_TTL)).await;
+        gossip.on_message(&manager, "bob".into(), relay(offer("carol", "alice"), MAX_TTL)).await;
+        assert_eq!(*gossip.answering.lock().unwrap(), HashSet::from(["carol".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn ignores_offers_for_linked_peers() {
+        let manager = manager("alice").await;
+        let _carol = neighbour(&manager, "carol").await;
+        let gossip = Gossip::new();
+
+        gossip.on_message(&manager, "bob".into(), relay(offer("carol", "alice"), MAX_TTL)).await;
+        assert!(gossip.answering.lock().unwrap().is_empty());
+        assert!(manager.is_linked("carol").await);
+    }
+
+    #[tokio::test]
+    async fn limits_pending_answers() {
+        let manager = manager("alice").await;
+        let gossip = Gossip::new();
+
+        for i in 0..MAX_PENDING_ANSWERS + 2 {
+            let from = format!("peer-{}", i);
+            gossip.on_message(&manager, "bob".into(), relay(offer(&from, "alice"), MAX_TTL)).await;
+        }
+        let answering = gossip.answering.lock().unwrap().clone();
+        assert_eq!(answering.len(), MAX_PENDING_ANSWERS);
+        assert!(!answering.contains(&format!("peer-{}", MAX_PENDING_ANSWERS)));
+    }
+}
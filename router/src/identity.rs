@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use rcgen::{CertificateParams, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use webrtc::dtls::crypto::{Certificate, CryptoPrivateKey};
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+use crate::ban;
+use crate::chat;
+use crate::config;
+
+// Name the certificate is made out to. Peers go by its fingerprint, not
+// its name.
+const SUBJECT: &str = "lan-racer";
+
+// The certificate is the identity, so it does not expire in practice.
+const VALID_UNTIL: i32 = 4096;
+
+/// This router's identity: an ECDSA P-256 key pair and a self-signed
+/// certificate for it, which every WebRTC link uses for DTLS and every
+/// direct link for QUIC. Kept on disk, so peers see the same fingerprint
+/// every time.
+#[derive(Clone)]
+pub struct Identity {
+    certificate: RTCCertificate,
+    der: CertificateDer<'static>,
+    key: Vec<u8>,
+    fingerprint: String,
+}
+
+impl Identity {
+    /// Loads the identity kept at `path`, or makes one and keeps it there.
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let pem = fs::read(path)?;
+            let cert = CertificateDer::from_pem_slice(&pem).context("No certificate")?;
+            let key = PrivatePkcs8KeyDer::from_pem_slice(&pem).context("No private key")?;
+            return Self::from_parts(cert, &KeyPair::try_from(&key)?);
+        }
+
+        let key_pair = KeyPair::generate()?;
+        let cert = self_signed(&key_pair)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Whoever reads the key can pass for us.
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+        file.write_all(format!("{}{}", cert.pem(), key_pair.serialize_pem()).as_bytes())?;
+        Self::from_parts(cert.der().clone(), &key_pair)
+    }
+
+    /// An identity that lasts as long as the process.
+    pub fn ephemeral() -> Result<Self> {
+        let key_pair = KeyPair::generate()?;
+        let cert = self_signed(&key_pair)?;
+        Self::from_parts(cert.der().clone(), &key_pair)
+    }
+
+    fn from_parts(cert: CertificateDer<'static>, key_pair: &KeyPair) -> Result<Self> {
+        let fingerprint = fingerprint(&cert);
+        let dtls = Certificate {
+            certificate: vec![cert.clone()],
+            private_key: CryptoPrivateKey::from_key_pair(key_pair)?,
+        };
+        let expires: SystemTime = rcgen::date_time_ymd(VALID_UNTIL, 1, 1).into();
+        Ok(Self {
+            certificate: RTCCertificate::from_existing(dtls, expires),
+            der: cert,
+            key: key_pair.serialize_der(),
+            fingerprint,
+        })
+    }
+
+    pub fn certificate(&self) -> &RTCCertificate {
+        &self.certificate
+    }
+
+    /// The certificate and its key, for TLS.
+    pub fn tls_parts(&self) -> (CertificateDer<'static>, PrivatePkcs8KeyDer<'static>) {
+        (self.der.clone(), PrivatePkcs8KeyDer::from(self.key.clone()))
+    }
+
+    /// SHA-256 fingerprint of the certificate, as peers see it in our SDP.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// SHA-256 fingerprint of a DER certificate, written the way SDP has it.
+pub fn fingerprint(cert: &[u8]) -> String {
+    let hex: Vec<String> = Sha256::digest(cert).iter().map(|b| format!("{:02X}", b)).collect();
+    ban::normalize(&format!("sha-256 {}", hex.join(":")))
+}
+
+/// Default identity file for the router that goes by `peer_id`.
+pub fn default_path(peer_id: &str) -> PathBuf {
+    config::data_dir().join("identity").join(format!("{}.pem", chat::sanitize(peer_id)))
+}
+
+fn self_signed(key_pair: &KeyPair) -> Result<rcgen::Certificate> {
+    let mut params = CertificateParams::new(vec![SUBJECT.to_owned()])?;
+    params.not_after = rcgen::date_time_ymd(VALID_UNTIL, 1, 1);
+    Ok(params.self_signed(key_pair)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_matches_sdp() {
+        let identity = Identity::ephemeral().unwrap();
+        let sdp = &identity.certificate().get_fingerprints()[0];
+        assert_eq!(identity.fingerprint(), ban::normalize(&format!("{} {}", sdp.algorithm, sdp.value)));
+    }
+}
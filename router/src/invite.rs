@@ -0,0 +1,509 @@
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+use crate::chat::now_millis;
+use crate::compress::{self, Compression};
+
+/// How long a code can be redeemed for. Long enough to pass it on by
+/// chat app, short enough that an old one lying around is useless.
+pub const CODE_TTL: Duration = Duration::from_secs(15 * 60);
+
+const INVITE_PREFIX: &str = "lri1.";
+const ANSWER_PREFIX: &str = "lra1.";
+const CHECKSUM_LEN: usize = 4;
+const FLAG_LZ4: u8 = 1;
+
+/// Which half of the exchange a code carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeKind {
+    /// An offer, from the peer that starts the connection.
+    Invite,
+    /// The answer to an invite.
+    Answer,
+}
+
+impl CodeKind {
+    fn prefix(self) -> &'static str {
+        match self {
+            CodeKind::Invite => INVITE_PREFIX,
+            CodeKind::Answer => ANSWER_PREFIX,
+        }
+    }
+}
+
+/// An offer or answer small enough to paste into a chat app or show as a
+/// QR code. Only what the two ends cannot agree on beforehand is kept:
+/// the ICE credentials and candidates, the DTLS fingerprint and role. The
+/// rest of the SDP is the same for every link and is put back on decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub kind: CodeKind,
+    pub from: String,
+    pub to: String,
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+    setup: Setup,
+    fingerprint: [u8; 32],
+    ufrag: String,
+    pwd: String,
+    candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setup {
+    ActPass,
+    Active,
+    Passive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Candidate {
+    typ: CandidateType,
+    priority: u32,
+    ip: IpAddr,
+    port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateType {
+    Host,
+    Srflx,
+    Prflx,
+    Relay,
+}
+
+impl Setup {
+    fn name(self) -> &'static str {
+        match self {
+            Setup::ActPass => "actpass",
+            Setup::Active => "active",
+            Setup::Passive => "passive",
+        }
+    }
+}
+
+impl CandidateType {
+    const ALL: [CandidateType; 4] = [CandidateType::Host, CandidateType::Srflx, CandidateType::Prflx, CandidateType::Relay];
+
+    fn name(self) -> &'static str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::Srflx => "srflx",
+            CandidateType::Prflx => "prflx",
+            CandidateType::Relay => "relay",
+        }
+    }
+}
+
+impl Code {
+    /// Packs the offer or answer `sdp_json` from `from` to `to`, valid
+    /// for [`CODE_TTL`] from now.
+    pub fn new(kind: CodeKind, from: &str, to: &str, sdp_json: &str) -> Result<Self> {
+        let desc = serde_json::from_str::<RTCSessionDescription>(sdp_json)?;
+        let mut code = Code {
+            kind,
+            from: from.to_owned(),
+            to: to.to_owned(),
+            expires_at: now_millis() / 1000 + CODE_TTL.as_secs(),
+            setup: Setup::ActPass,
+            fingerprint: [0; 32],
+            ufrag: String::new(),
+            pwd: String::new(),
+            candidates: Vec::new(),
+        };
+
+        let mut fingerprint = false;
+        let mut media = 0;
+        for line in desc.sdp.lines().map(str::trim) {
+            let Some((key, value)) = line.split_once(':').or(line.split_once('=')) else {
+                continue;
+            };
+            match key {
+                "m" => media += 1,
+                "a=mid" => ensure!(value == "0", "Unsupported SDP: media id {}", value),
+                "a=setup" => {
+                    code.setup = match value {
+                        "actpass" => Setup::ActPass,
+                        "active" => Setup::Active,
+                        "passive" => Setup::Passive,
+                        other => bail!("Unsupported SDP: setup {}", other),
+                    }
+                }
+                "a=fingerprint" => {
+                    code.fingerprint = parse_fingerprint(value)?;
+                    fingerprint = true;
+                }
+                "a=ice-ufrag" => code.ufrag = value.to_owned(),
+                "a=ice-pwd" => code.pwd = value.to_owned(),
+                "a=candidate" => code.candidates.extend(parse_candidate(value)),
+                _ => {}
+            }
+        }
+        ensure!(media == 1, "Unsupported SDP: {} media sections", media);
+        ensure!(fingerprint, "SDP has no fingerprint");
+        ensure!(!code.ufrag.is_empty() && !code.pwd.is_empty(), "SDP has no ICE credentials");
+        // Every string goes in with a one byte length.
+        let strings = [&code.from, &code.to, &code.ufrag, &code.pwd];
+        ensure!(strings.iter().all(|s| s.len() <= u8::MAX as usize), "Peer id too long for a code");
+        Ok(code)
+    }
+
+    /// Reads a code, checking it arrived whole and has not expired.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text: String = text.split_whitespace().collect();
+        let (kind, payload) = if let Some(rest) = text.strip_prefix(INVITE_PREFIX) {
+            (CodeKind::Invite, rest)
+        } else if let Some(rest) = text.strip_prefix(ANSWER_PREFIX) {
+            (CodeKind::Answer, rest)
+        } else {
+            bail!("Not an invite or answer code");
+        };
+
+        let data = URL_SAFE_NO_PAD.decode(payload).context("Code is garbled")?;
+        ensure!(data.len() > 1 + CHECKSUM_LEN, "Code is truncated");
+        let (data, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+        ensure!(checksum == &digest(kind, data)[..], "Code is garbled or incomplete");
+
+        let (flags, body) = (data[0], &data[1..]);
+        let body = if flags & FLAG_LZ4 != 0 {
+            compress::decompress(Compression::Lz4, body)?
+        } else {
+            body.to_vec()
+        };
+        let now = now_millis() / 1000;
+        let code = Self::unpack(kind, &body, now).context("Code is garbled")?;
+        if code.expires_at < now {
+            bail!("Code expired {} minutes ago", (now - code.expires_at).div_ceil(60));
+        }
+        Ok(code)
+    }
+
+    /// The code as text: a prefix naming the kind, then base64url.
+    pub fn encode(&self) -> String {
+        let body = self.pack();
+        let mut data = match compress::compress(Compression::Lz4, &body) {
+            Some(compressed) => [&[FLAG_LZ4][..], &compressed].concat(),
+            None => [&[0][..], &body].concat(),
+        };
+        data.extend_from_slice(&digest(self.kind, &data));
+        format!("{}{}", self.kind.prefix(), URL_SAFE_NO_PAD.encode(data))
+    }
+
+    /// The session description the code stands for, as JSON, the way the
+    /// peer manager takes offers and answers.
+    pub fn sdp_json(&self) -> Result<String> {
+        let fingerprint: Vec<String> = self.fingerprint.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut sdp = format!(
+            "v=0\r\n\
+             o=- 0 0 IN IP4 0.0.0.0\r\n\
+             s=-\r\n\
+             t=0 0\r\n\
+             a=fingerprint:sha-256 {}\r\n\
+             a=group:BUNDLE 0\r\n\
+             m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             a=setup:{}\r\n\
+             a=mid:0\r\n\
+             a=sendrecv\r\n\
+             a=sctp-port:5000\r\n\
+             a=ice-ufrag:{}\r\n\
+             a=ice-pwd:{}\r\n",
+            fingerprint.join(":"),
+            self.setup.name(),
+            self.ufrag,
+            self.pwd,
+        );
+        for (i, c) in self.candidates.iter().enumerate() {
+            let _ = write!(
+                sdp,
+                "a=candidate:{} 1 udp {} {} {} typ {}\r\n",
+                i + 1,
+                c.priority,
+                c.ip,
+                c.port,
+                c.typ.name()
+            );
+        }
+        sdp.push_str("a=end-of-candidates\r\n");
+
+        let desc = match self.kind {
+            CodeKind::Invite => RTCSessionDescription::offer(sdp)?,
+            CodeKind::Answer => RTCSessionDescription::answer(sdp)?,
+        };
+        Ok(serde_json::to_string(&desc)?)
+    }
+
+    // Expiry, ids, DTLS role and fingerprint, ICE credentials, then each
+    // candidate as type, priority, address and port.
+    fn pack(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Only the low 32 bits, which wrap in 2106; see `expiry`.
+        out.extend_from_slice(&(self.expires_at as u32).to_be_bytes());
+        put_str(&mut out, &self.from);
+        put_str(&mut out, &self.to);
+        out.push(self.setup as u8);
+        out.extend_from_slice(&self.fingerprint);
+        put_str(&mut out, &self.ufrag);
+        put_str(&mut out, &self.pwd);
+        out.push(self.candidates.len().min(u8::MAX as usize) as u8);
+        for c in self.candidates.iter().take(u8::MAX as usize) {
+            out.push(c.typ as u8);
+            out.extend_from_slice(&c.priority.to_be_bytes());
+            match c.ip {
+                IpAddr::V4(ip) => {
+                    out.push(4);
+                    out.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    out.push(6);
+                    out.extend_from_slice(&ip.octets());
+                }
+            }
+            out.extend_from_slice(&c.port.to_be_bytes());
+        }
+        out
+    }
+
+    fn unpack(kind: CodeKind, body: &[u8], now: u64) -> Result<Self> {
+        let mut r = Reader(body);
+        let expires_at = expiry(u32::from_be_bytes(r.array()?), now);
+        let from = r.string()?;
+        let to = r.string()?;
+        let setup = match r.u8()? {
+            0 => Setup::ActPass,
+            1 => Setup::Active,
+            2 => Setup::Passive,
+            other => bail!("unknown setup {}", other),
+        };
+        let fingerprint = r.array()?;
+        let ufrag = r.string()?;
+        let pwd = r.string()?;
+
+        let count = r.u8()?;
+        let mut candidates = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let typ = *CandidateType::ALL
+                .get(r.u8()? as usize)
+                .ok_or(anyhow!("unknown candidate type"))?;
+            let priority = u32::from_be_bytes(r.array()?);
+            let ip = match r.u8()? {
+                4 => IpAddr::from(r.array::<4>()?),
+                6 => IpAddr::from(r.array::<16>()?),
+                other => bail!("unknown address family {}", other),
+            };
+            let port = u16::from_be_bytes(r.array()?);
+            candidates.push(Candidate { typ, priority, ip, port });
+        }
+
+        Ok(Code { kind, from, to, expires_at, setup, fingerprint, ufrag, pwd, candidates })
+    }
+}
+
+// The expiry time whose low 32 bits are `low`. Codes last minutes, so of
+// the times those bits could stand for, it is the one nearest `now`; that
+// keeps codes working when the 32-bit Unix time wraps in 2106.
+fn expiry(low: u32, now: u64) -> u64 {
+    const WRAP: u64 = 1 << 32;
+    let time = (now & !(WRAP - 1)) | u64::from(low);
+    [time.saturating_sub(WRAP), time, time + WRAP]
+        .into_iter()
+        .min_by_key(|t| t.abs_diff(now))
+        .unwrap_or(time)
+}
+
+/// `text` as a QR code in block characters, light on dark, for a phone
+/// to scan off the terminal.
+pub fn qr(text: &str) -> Result<String> {
+    let code = QrCode::new(text.as_bytes())?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+// A SHA-256 fingerprint from `sha-256 AB:CD:...`. The only algorithm
+// WebRTC stacks use in practice.
+fn parse_fingerprint(value: &str) -> Result<[u8; 32]> {
+    let (algo, hex) = value.split_once(' ').ok_or(anyhow!("Bad fingerprint: {}", value))?;
+    ensure!(algo.eq_ignore_ascii_case("sha-256"), "Unsupported fingerprint algorithm: {}", algo);
+    let bytes = hex
+        .trim()
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow!("Bad fingerprint: {}", value))?;
+    bytes.try_into().map_err(|_| anyhow!("Bad fingerprint: {}", value))
+}
+
+// The UDP candidates of the first component, the only ones a bundled data
+// channel uses. TCP candidates and mDNS hostnames do not fit the code and
+// are left out.
+fn parse_candidate(value: &str) -> Option<Candidate> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let [_, component, protocol, priority, ip, port, "typ", typ, ..] = fields[..] else {
+        return None;
+    };
+    if component != "1" || !protocol.eq_ignore_ascii_case("udp") {
+        return None;
+    }
+    Some(Candidate {
+        typ: *CandidateType::ALL.iter().find(|t| t.name() == typ)?,
+        priority: priority.parse().ok()?,
+        ip: ip.parse().ok()?,
+        port: port.parse().ok()?,
+    })
+}
+
+fn digest(kind: CodeKind, data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(kind.prefix());
+    hasher.update(data);
+    let hash = hasher.finalize();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.push(s.len() as u8);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        ensure!(self.0.len() >= N, "truncated");
+        let (head, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(head.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u8()? as usize;
+        ensure!(self.0.len() >= len, "truncated");
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(String::from_utf8(head.to_vec())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "sha-256 \
+        3C:4F:A1:0B:92:7E:11:D8:05:6A:BE:CC:29:F0:73:84:5D:E2:19:60:AF:0D:B7:48:92:35:C1:7A:EE:06:5B:F3";
+
+    fn offer() -> String {
+        let sdp = format!(
+            "v=0\r\n\
+             o=- 2927307686215094172 877616351 IN IP4 0.0.0.0\r\n\
+             s=-\r\n\
+             t=0 0\r\n\
+             a=fingerprint:{}\r\n\
+             a=group:BUNDLE 0\r\n\
+             m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             a=setup:actpass\r\n\
+             a=mid:0\r\n\
+             a=sendrecv\r\n\
+             a=sctp-port:5000\r\n\
+             a=ice-ufrag:kZfLbYxWqGcHnRtE\r\n\
+             a=ice-pwd:pQmVrTzXcNbLkJhGfDsAeWuYiOoPlMnB\r\n\
+             a=candidate:167090039 1 udp 2130706431 192.168.1.20 50123 typ host\r\n\
+             a=candidate:167090039 2 udp 2130706431 192.168.1.20 50124 typ host\r\n\
+             a=candidate:3528925834 1 udp 1694498815 203.0.113.7 61002 typ srflx raddr 0.0.0.0 rport 50123\r\n\
+             a=candidate:842163049 1 tcp 1518280447 192.168.1.20 9 typ host tcptype active\r\n\
+             a=candidate:1 1 udp 2130706431 fe80::1 50125 typ host\r\n\
+             a=end-of-candidates\r\n",
+            FINGERPRINT
+        );
+        serde_json::to_string(&RTCSessionDescription::offer(sdp).unwrap()).unwrap()
+    }
+
+    fn invite() -> Code {
+        Code::new(CodeKind::Invite, "alice", "bob", &offer()).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let code = invite();
+        assert_eq!(code.candidates.len(), 3, "TCP and second component candidates are left out");
+
+        let parsed = Code::parse(&code.encode()).unwrap();
+        assert_eq!(parsed, code);
+
+        let sdp = parsed.sdp_json().unwrap();
+        let desc: RTCSessionDescription = serde_json::from_str(&sdp).unwrap();
+        assert_eq!(crate::ban::sdp_fingerprint(&desc.sdp), Some(crate::ban::normalize(FINGERPRINT)));
+        let mut again = Code::new(CodeKind::Invite, "alice", "bob", &sdp).unwrap();
+        again.expires_at = code.expires_at;
+        assert_eq!(again, code);
+    }
+
+    #[test]
+    fn survives_line_breaks() {
+        let code = invite();
+        let text = code.encode();
+        let wrapped: Vec<&str> = text.as_bytes().chunks(40).map(|c| std::str::from_utf8(c).unwrap()).collect();
+        assert_eq!(Code::parse(&wrapped.join("\n  ")).unwrap(), code);
+    }
+
+    #[test]
+    fn rejects_truncated_and_garbled() {
+        let text = invite().encode();
+        assert!(Code::parse(&text[..text.len() - 4]).is_err());
+        assert!(Code::parse(&text[..INVITE_PREFIX.len() + 4]).is_err());
+        assert!(Code::parse(INVITE_PREFIX).is_err());
+
+        let mut garbled = text.clone().into_bytes();
+        let middle = garbled.len() / 2;
+        garbled[middle] = if garbled[middle] == b'A' { b'B' } else { b'A' };
+        assert!(Code::parse(std::str::from_utf8(&garbled).unwrap()).is_err());
+
+        assert!(Code::parse(&format!("{}!!!", INVITE_PREFIX)).is_err());
+        assert!(Code::parse(&text[INVITE_PREFIX.len()..]).is_err());
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let mut code = invite();
+        code.expires_at = now_millis() / 1000 - 120;
+        let err = Code::parse(&code.encode()).unwrap_err();
+        assert!(err.to_string().contains("expired"), "{}", err);
+    }
+
+    #[test]
+    fn invite_is_not_an_answer() {
+        let text = invite().encode();
+        let as_answer = text.replacen(INVITE_PREFIX, ANSWER_PREFIX, 1);
+        assert!(Code::parse(&as_answer).is_err());
+        assert_eq!(Code::parse(&text).unwrap().kind, CodeKind::Invite);
+    }
+
+    #[test]
+    fn expiry_past_2106() {
+        let now = 1_800_000_000;
+        assert_eq!(expiry((now + 900) as u32, now), now + 900);
+        assert_eq!(expiry((now - 60) as u32, now), now - 60);
+
+        // Either side of the 32-bit wrap.
+        let wrap = 1u64 << 32;
+        assert_eq!(expiry(100, wrap - 500), wrap + 100);
+        assert_eq!(expiry(u32::MAX - 100, wrap + 500), wrap - 101);
+    }
+}
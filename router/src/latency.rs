@@ -0,0 +1,240 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// How often every connected peer is pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// A ping unanswered this long counts as lost.
+pub const LOSS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Pings loss is worked out over; a minute and a half at the usual rate.
+const WINDOW: usize = 100;
+
+/// Latency figures for one peer, in milliseconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencySnapshot {
+    /// Latest round trip time.
+    pub rtt_ms: Option<f64>,
+    /// Smoothed round trip time.
+    pub avg_rtt_ms: Option<f64>,
+    pub min_rtt_ms: Option<f64>,
+    /// Mean deviation between consecutive round trips, as RTP computes it.
+    pub jitter_ms: f64,
+    /// Fraction of recent pings that went unanswered.
+    pub loss: f64,
+    pub sent: u64,
+    pub received: u64,
+}
+
+struct Sent {
+    seq: u32,
+    at: Instant,
+    rtt: Option<Duration>,
+}
+
+/// Pings sent to one peer and what came back.
+#[derive(Default)]
+pub struct LatencyTracker {
+    next_seq: u32,
+    window: VecDeque<Sent>,
+    last: Option<Duration>,
+    srtt_ms: Option<f64>,
+    min: Option<Duration>,
+    jitter_ms: f64,
+    sent: u64,
+    received: u64,
+    waiters: HashMap<u32, oneshot::Sender<Duration>>,
+}
+
+impl LatencyTracker {
+    /// Records a ping about to go out and returns its sequence number.
+    pub fn next_ping(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.sent += 1;
+
+        if self.window.len() == WINDOW
+            && let Some(old) = self.window.pop_front()
+        {
+            self.waiters.remove(&old.seq);
+        }
+        self.window.push_back(Sent {
+            seq,
+            at: Instant::now(),
+            rtt: None,
+        });
+        seq
+    }
+
+    /// Resolves with the round trip time once the pong for `seq` arrives.
+    pub fn wait(&mut self, seq: u32) -> oneshot::Receiver<Duration> {
+        let (tx, rx) = oneshot::channel();
+        self.waiters.insert(seq, tx);
+        rx
+    }
+
+    /// Takes a pong into account. Duplicates and pongs for pings that fell
+    /// out of the window are ignored.
+    pub fn on_pong(&mut self, seq: u32) -> Option<Duration> {
+        let sent = self.window.iter_mut().find(|s| s.seq == seq && s.rtt.is_none())?;
+        let rtt = sent.at.elapsed();
+        sent.rtt = Some(rtt);
+        self.received += 1;
+
+        let ms = millis(rtt);
+        if let Some(last) = self.last {
+            self.jitter_ms += ((ms - millis(last)).abs() - self.jitter_ms) / 16.0;
+        }
+        self.srtt_ms = Some(match self.srtt_ms {
+            Some(srtt) => srtt + (ms - srtt) / 8.0,
+            None => ms,
+        });
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.last = Some(rtt);
+
+        if let Some(waiter) = self.waiters.remove(&seq) {
+            let _ = waiter.send(rtt);
+        }
+        Some(rtt)
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        // Pings still within the timeout are neither answered nor lost yet.
+        let settled = self.window.iter().filter(|s| s.rtt.is_some() || s.at.elapsed() >= LOSS_TIMEOUT);
+        let (total, lost) = settled.fold((0, 0), |(total, lost), s| (total + 1, lost + usize::from(s.rtt.is_none())));
+
+        LatencySnapshot {
+            rtt_ms: self.last.map(millis),
+            avg_rtt_ms: self.srtt_ms,
+            min_rtt_ms: self.min.map(millis),
+            jitter_ms: self.jitter_ms,
+            loss: if total == 0 { 0.0 } else { lost as f64 / total as f64 },
+            sent: self.sent,
+            received: self.received,
+        }
+    }
+}
+
+/// Result of an explicit `ping` command.
+#[derive(Debug, Clone, Serialize)]
+pub struct PingReport {
+    pub peer_id: String,
+    /// Round trip time of each ping in milliseconds, `None` if lost.
+    pub replies: Vec<Option<f64>>,
+    /// Continuous figures for the peer, including these pings.
+    pub latency: LatencySnapshot,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers ping `seq` as if it went out `ms` ago.
+    fn answer(tracker: &mut LatencyTracker, seq: u32, ms: u64) -> Option<Duration> {
+        age(tracker, seq, Duration::from_millis(ms));
+        tracker.on_pong(seq)
+    }
+
+    fn age(tracker: &mut LatencyTracker, seq: u32, by: Duration) {
+        let sent = tracker.window.iter_mut().find(|s| s.seq == seq).unwrap();
+        sent.at -= by;
+    }
+
+    fn assert_near(got: f64, want: f64) {
+        assert!((got - want).abs() < 1.0, "{} is not about {}", got, want);
+    }
+
+    #[test]
+    fn averages_round_trips() {
+        let mut tracker = LatencyTracker::default();
+        for ms in [10, 20, 40] {
+            let seq = tracker.next_ping();
+            answer(&mut tracker, seq, ms).unwrap();
+        }
+
+        let snapshot = tracker.snapshot();
+        assert_near(snapshot.rtt_ms.unwrap(), 40.0);
+        assert_near(snapshot.min_rtt_ms.unwrap(), 10.0);
+        // Smoothed an eighth at a time, as TCP does.
+        assert_near(snapshot.avg_rtt_ms.unwrap(), 11.25 + (40.0 - 11.25) / 8.0);
+        assert_near(snapshot.jitter_ms, 0.625 + (20.0 - 0.625) / 16.0);
+        assert_eq!((snapshot.sent, snapshot.received), (3, 3));
+        assert_eq!(snapshot.loss, 0.0);
+    }
+
+    #[test]
+    fn starts_empty() {
+        let snapshot = LatencyTracker::default().snapshot();
+        assert_eq!((snapshot.rtt_ms, snapshot.avg_rtt_ms, snapshot.min_rtt_ms), (None, None, None));
+        assert_eq!(snapshot.loss, 0.0);
+    }
+
+    #[test]
+    fn ignores_duplicate_and_unknown_pongs() {
+        let mut tracker = LatencyTracker::default();
+        let seq = tracker.next_ping();
+        assert!(answer(&mut tracker, seq, 5).is_some());
+        assert!(tracker.on_pong(seq).is_none());
+        assert!(tracker.on_pong(seq + 1).is_none());
+        assert_eq!(tracker.snapshot().received, 1);
+    }
+
+    #[test]
+    fn counts_timed_out_pings_as_lost() {
+        let mut tracker = LatencyTracker::default();
+        let seqs: Vec<u32> = (0..4).map(|_| tracker.next_ping()).collect();
+        answer(&mut tracker, seqs[0], 5);
+        answer(&mut tracker, seqs[1], 5);
+        // The last one is still within the timeout, so counts neither way.
+        age(&mut tracker, seqs[2], LOSS_TIMEOUT);
+
+        let snapshot = tracker.snapshot();
+        assert_near(snapshot.loss, 1.0 / 3.0);
+        assert_eq!((snapshot.sent, snapshot.received), (4, 2));
+
+        // A late answer still counts.
+        tracker.on_pong(seqs[2]).unwrap();
+        assert_eq!(tracker.snapshot().loss, 0.0);
+    }
+
+    #[test]
+    fn works_out_loss_over_recent_pings() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..WINDOW {
+            let seq = tracker.next_ping();
+            age(&mut tracker, seq, LOSS_TIMEOUT);
+        }
+        assert_eq!(tracker.snapshot().loss, 1.0);
+
+        let first = tracker.window.front().unwrap().seq;
+        for _ in 0..WINDOW {
+            let seq = tracker.next_ping();
+            answer(&mut tracker, seq, 5);
+        }
+        assert_eq!(tracker.snapshot().loss, 0.0);
+        assert!(tracker.on_pong(first).is_none());
+    }
+
+    #[test]
+    fn wakes_whoever_waits_for_a_pong() {
+        let mut tracker = LatencyTracker::default();
+        let seq = tracker.next_ping();
+        let mut reply = tracker.wait(seq);
+        assert!(reply.try_recv().is_err());
+        let rtt = answer(&mut tracker, seq, 5).unwrap();
+        assert_eq!(reply.try_recv().unwrap(), rtt);
+
+        // A ping that falls out of the window is never answered.
+        let seq = tracker.next_ping();
+        let mut reply = tracker.wait(seq);
+        for _ in 0..WINDOW {
+            tracker.next_ping();
+        }
+        assert_eq!(reply.try_recv(), Err(oneshot::error::TryRecvError::Closed));
+    }
+}
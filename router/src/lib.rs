@@ -1,4 +1,32 @@
+pub mod ban;
+pub mod batch;
+pub mod chat;
+pub mod compress;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod device;
+pub mod discovery;
+pub mod forward;
+pub mod gossip;
+pub mod identity;
+pub mod invite;
+pub mod fragment;
+pub mod frame;
+pub mod latency;
+pub mod logging;
+pub mod metrics;
+pub mod mtu;
+pub mod netstack;
 pub mod peer;
+pub mod proxy;
+pub mod queue;
+pub mod route;
 pub mod router;
 pub mod event;
-pub mod signaling;
\ No newline at end of file
+pub mod signaling;
+pub mod stats;
+pub mod throughput;
+pub mod transfer;
+pub mod transport;
+pub mod trust;
\ No newline at end of file
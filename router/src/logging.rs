@@ -0,0 +1,117 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+// WebRTC warns about every interface it cannot gather candidates on.
+const DEFAULT_FILTER: &str = "info,webrtc=error";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per event, with the spans it happened in.
+    #[default]
+    Human,
+    /// One JSON object per event.
+    Json,
+}
+
+/// How often the log file starts over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for rolling::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => rolling::Rotation::HOURLY,
+            LogRotation::Daily => rolling::Rotation::DAILY,
+            LogRotation::Never => rolling::Rotation::NEVER,
+        }
+    }
+}
+
+/// Logging options shared by the binaries.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct LogConfig {
+    /// Level or filter, e.g. `debug` or `info,router::peer=trace`.
+    /// Defaults to `RUST_LOG`, then `info`.
+    #[arg(long = "log-level")]
+    pub level: Option<String>,
+    #[arg(long = "log-format", value_enum, default_value_t)]
+    pub format: LogFormat,
+    /// Also write logs to files in this directory.
+    #[arg(long = "log-dir")]
+    pub dir: Option<PathBuf>,
+    #[arg(long = "log-rotation", value_enum, default_value_t)]
+    pub rotation: LogRotation,
+    /// Rotated log files to keep; all of them if unset.
+    #[arg(long = "log-keep")]
+    pub keep: Option<usize>,
+}
+
+/// Sends log output to stderr, and to `<dir>/<name>.<date>.log` if a
+/// directory is set. Keep the returned guard alive until exit, or the last
+/// lines written to the file are lost.
+pub fn init(config: &LogConfig, name: &str) -> Result<Option<WorkerGuard>> {
+    init_with(config, name, None)
+}
+
+/// Like [`init`], also feeding `extra` whatever passes the filter.
+pub fn init_with(
+    config: &LogConfig,
+    name: &str,
+    extra: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) -> Result<Option<WorkerGuard>> {
+    let filter = match &config.level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER)),
+    };
+
+    let (file, guard) = match &config.dir {
+        Some(dir) => {
+            let mut appender = RollingFileAppender::builder()
+                .rotation(config.rotation.into())
+                .filename_prefix(name)
+                .filename_suffix("log");
+            if let Some(keep) = config.keep {
+                appender = appender.max_log_files(keep);
+            }
+            let (writer, guard) = tracing_appender::non_blocking(appender.build(dir)?);
+            (Some(layer(config.format, writer, false)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let ansi = std::io::stderr().is_terminal();
+    tracing_subscriber::registry()
+        .with(extra)
+        .with(filter)
+        .with(layer(config.format, std::io::stderr, ansi))
+        .with(file)
+        .try_init()
+        .map_err(|e| anyhow!("logging already set up: {}", e))?;
+    Ok(guard)
+}
+
+fn layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Human => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(true).boxed(),
+    }
+}
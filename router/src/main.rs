@@ -1,451 +1,203 @@
 use anyhow::Result;
-use iced::widget::{
-    button, column, container, horizontal_space, mouse_area, row, scrollable, stack, text,
-    text_input, vertical_space,
-};
-use iced::{Background, Border, Center, Color, Element, Length, Shadow, Task, Theme, Vector};
-use iced_aw::ContextMenu;
-use std::sync::Arc;
+use clap::Parser;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
-
-pub struct Router;
-impl Router {
-    pub fn new() -> Self {
-        Self
-    }
-    pub async fn route(&self, _token: CancellationToken) {
-        std::future::pending().await
-    }
-}
-
-fn main() -> Result<()> {
-    let token = CancellationToken::new();
-    let router = Arc::new(Router::new());
-
-    iced::application("floating", UIState::update, UIState::view)
-        .theme(|_| Theme::Dracula)
-        .window_size((460.0, 500.0))
-        .run_with(move || {
-            (
-                UIState::new(router.clone()),
-                Task::perform(async move { router.route(token).await }, |_| Message::Exit),
-            )
-        })?;
-    Ok(())
+use tracing::{error, warn};
+
+use router::batch::BatchConfig;
+use router::compress::Compression;
+use router::config::{self, LinkConfig, RouterConfig};
+use router::mtu::MtuConfig;
+use router::device::PcapDevice;
+use router::forward::{Forward, ReverseForward};
+use router::logging::{self, LogConfig};
+use router::{console, control, proxy};
+use router::router::Router;
+use router::trust::TrustMode;
+
+/// Joins the virtual LAN and reads commands from the terminal.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Name of the TUN device to create.
+    #[arg(default_value = "tun1")]
+    device: String,
+    /// Address of this node on the virtual network.
+    #[arg(default_value = "10.10.0.2")]
+    address: Ipv4Addr,
+    /// Id other peers use to reach this node.
+    #[arg(default_value = "peer1")]
+    peer_id: String,
+    #[arg(long, default_value = "255.255.255.0")]
+    netmask: Ipv4Addr,
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    signal_server: String,
+    /// Where to keep chat history.
+    #[arg(long)]
+    chat_dir: Option<PathBuf>,
+    /// Where to keep banned peers.
+    #[arg(long)]
+    ban_file: Option<PathBuf>,
+    /// Where to keep the key pair and certificate this router is known by.
+    #[arg(long)]
+    identity_file: Option<PathBuf>,
+    /// Where to keep trusted peers and their fingerprints.
+    #[arg(long)]
+    trust_file: Option<PathBuf>,
+    /// How to treat a peer whose certificate fingerprint is not the one it
+    /// is trusted with.
+    #[arg(long, value_enum, default_value_t)]
+    trust: TrustMode,
+    /// Unix socket for `lanctl` and other local clients. Defaults to one
+    /// named after the device in the runtime directory.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Serve Prometheus metrics at `http://<addr>/metrics`.
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+    /// Replay the IP packets of a pcap capture, at their original pace,
+    /// instead of creating a TUN device. Packets from peers are dropped.
+    #[arg(long, value_name = "PCAP", conflicts_with = "userspace")]
+    replay: Option<PathBuf>,
+    /// Run the network stack inside the router instead of creating a TUN
+    /// device, so no root is needed. Programs reach the virtual network
+    /// through the SOCKS5 proxy (127.0.0.1:1080 by default), the HTTP proxy
+    /// and forwarded ports.
+    #[arg(long)]
+    userspace: bool,
+    /// SOCKS5 proxy address in userspace mode.
+    #[arg(long, requires = "userspace")]
+    socks: Option<SocketAddr>,
+    /// HTTP proxy address in userspace mode.
+    #[arg(long, requires = "userspace")]
+    http_proxy: Option<SocketAddr>,
+    /// Carry connections to a local port to an address on the virtual
+    /// network, as `[bind:]port:host:port`. Can be repeated.
+    #[arg(long, value_name = "SPEC")]
+    forward: Vec<Forward>,
+    /// Let peers reach a local port through this node's virtual address,
+    /// as `port[:host:port]`. Can be repeated.
+    #[arg(long, value_name = "SPEC")]
+    reverse_forward: Vec<ReverseForward>,
+    /// Take direct links from peers on this UDP address, for peers that
+    /// can reach it without the signaling server, e.g. on the same LAN.
+    #[arg(long, value_name = "ADDR")]
+    direct_listen: Option<SocketAddr>,
+    /// MTU of the physical path to peers. The TUN device gets what is left
+    /// of it after the overlay's headers.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1500,
+        value_parser = clap::value_parser!(u16).range(576..)
+    )]
+    path_mtu: u16,
+    /// Lower the device MTU until every packet fits one path packet,
+    /// instead of splitting larger frames into fragments. Below a path MTU
+    /// of about 1400 that leaves the device too small for IPv6.
+    #[arg(long)]
+    no_fragment: bool,
+    /// Compress frames with peers that offer the same algorithm.
+    #[arg(long, value_enum, default_value_t)]
+    compression: Compression,
+    /// How long to collect packets from the device into one frame, in
+    /// microseconds. 0 sends every packet on its own.
+    #[arg(long, value_name = "MICROS", default_value_t = 500)]
+    batch_window: u64,
+    /// Do not advertise this router on the LAN or look for peers there.
+    #[arg(long)]
+    no_discovery: bool,
+    /// Run without reading commands from the terminal, until interrupted.
+    #[arg(long)]
+    no_console: bool,
+    #[command(flatten)]
+    log: LogConfig,
 }
 
-#[derive(Debug, Clone)]
-struct PeerInfo {
-    id: String,
-    ip: String,
-    status: String,
-    copied: bool,
-    
-}
-
-struct UIState {
-    router: Arc<Router>,
-    my_ip: String,
-    my_mask: String,
-    state: String,
-    peers: Vec<PeerInfo>,
-
-    modal:ModalState, 
-    is_editing: bool,
-    input_id: String,
-    input_ip: String,
-    editing_target_id: Option<String>,
-    start_server_ip: String,
-}
-
-#[derive(Debug, Clone)]
-enum Message {
-    Exit,
-    OpenAddModal,
-    OpenConnectModal, 
-    OpenEditModal(PeerInfo),
-    OpenStartServerModal,
-    CloseModal,
-    InputIdChanged(String),
-    InputIpChanged(String),
-    InputStartServerIpChanged(String),
-    SubmitForm,
-    DeletePeer(String),
-    BanPeer(String),
-    CopyIp(String),
-    Copied(String),
-}
-
-#[derive(Debug, Clone)]
-enum ModalState {
-    None,
-    AddPeer,
-    EditPeer,   // peer id
-    ConnectPeer,
-    StartServer,
-}
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _log = logging::init(&args.log, "router")?;
+    let replay = match &args.replay {
+        Some(path) => Some(PcapDevice::open(path, true)?),
+        None => None,
+    };
+    let control_socket = args
+        .control_socket
+        .unwrap_or_else(|| config::control_socket(&args.device));
+    let router = Router::new(RouterConfig {
+        device: args.device,
+        address: args.address,
+        netmask: args.netmask,
+        peer_id: args.peer_id,
+        signal_server: args.signal_server,
+        chat_dir: args.chat_dir,
+        ban_file: args.ban_file,
+        identity_file: args.identity_file,
+        trust_file: args.trust_file,
+        trust: args.trust,
+        metrics: args.metrics,
+        userspace: args.userspace,
+        socks: args.socks.or(args.userspace.then(proxy::default_socks)),
+        http_proxy: args.http_proxy,
+        forwards: args.forward,
+        reverse_forwards: args.reverse_forward,
+        direct_listen: args.direct_listen,
+        discovery: !args.no_discovery,
+        link: LinkConfig {
+            mtu: MtuConfig {
+                path_mtu: args.path_mtu,
+                fragment: !args.no_fragment,
+            },
+            batch: BatchConfig {
+                window: Duration::from_micros(args.batch_window),
+                ..Default::default()
+            },
+            compression: args.compression,
+        },
+    });
 
-impl UIState {
-    fn new(router: Arc<Router>) -> Self {
-        Self {
-            router,
-            my_ip: "10.0.0.1".to_string(),
-            my_mask: "255.255.255.0".to_string(),
-            state: "Running".to_string(),
-            peers: (0..5)
-                .map(|i| PeerInfo {
-                    id: format!("peer-{}", i),
-                    ip: format!("10.0.0.{}", i + 2),
-                    status: "Idle".into(),
-                    copied: false,
-                })
-                .collect(),
+    let token = CancellationToken::new();
+    let (cmd_tx, cmd_rx) = mpsc::channel(32);
 
-            modal: ModalState::None,
-            is_editing: false,
-            input_id: String::new(),
-            input_ip: String::new(),
-            editing_target_id: None,
-            start_server_ip: String::new(),
+    let control = control::serve(&control_socket, cmd_tx.clone(), router.subscribe());
+    let control = async {
+        if let Err(e) = control.await {
+            warn!("Control socket {} unavailable: {}", control_socket.display(), e);
         }
-    }
-
-    fn update(&mut self, message: Message) -> Task<Message> {
-        match message {
-            Message::Exit => return iced::exit(),
-
-            Message::OpenAddModal => {
-                self.input_id.clear();
-                self.input_ip.clear();
-                self.is_editing = false;
-                self.modal = ModalState::AddPeer;
-            }
-            Message::OpenConnectModal => {
-                self.input_id.clear();
-                self.is_editing = false;
-                self.modal = ModalState::ConnectPeer;
-            }
-
-            Message::OpenEditModal(peer) => {
-                self.input_id = peer.id.clone();
-                self.input_ip = peer.ip;
-                self.editing_target_id = Some(peer.id);
-                self.is_editing = true;
-                self.modal = ModalState::EditPeer;
-            }
-            Message::CloseModal => {
-                self.modal = ModalState::None;
-            }
-            Message::OpenStartServerModal => {
-                self.modal = ModalState::StartServer;
-            }
-            Message::InputIdChanged(val) => self.input_id = val,
-            Message::InputIpChanged(val) => self.input_ip = val,
-            Message::InputStartServerIpChanged(val) => self.start_server_ip = val,
-
-            Message::SubmitForm => {
-                if self.is_editing {
-                    if let Some(target) = &self.editing_target_id {
-                        if let Some(peer) = self.peers.iter_mut().find(|p| &p.id == target) {
-                            peer.id = self.input_id.clone();
-                            peer.ip = self.input_ip.clone();
-                        }
-                    }
-                } else {
-                    self.peers.push(PeerInfo {
-                        id: self.input_id.clone(),
-                        ip: self.input_ip.clone(),
-                        status: "New".into(),
-                        copied: false,
-                    });
-                }
-                self.modal = ModalState::None;
-            }
-
-            Message::DeletePeer(id) => {
-                self.peers.retain(|p| p.id != id);
-            }
-            Message::BanPeer(_id) => {}
-            Message::CopyIp(ip) => {
-                for peer in &mut self.peers {
-                    if peer.ip == ip {
-                        peer.copied = true;
-                        println!("copied: {} = {}", peer.ip, peer.copied);
-                    } else {
-                        peer.copied = false;
-                    }
-                }
-                return iced::clipboard::write::<Message>(ip.clone());
-             }
-
-            Message::Copied(ip) => {
-               for peer in &mut self.peers {
-                println!("before: {} = {}", peer.ip, peer.copied);
-
-                if peer.ip == ip {
-                    peer.copied = true;
-                } else {
-                    peer.copied = false;
-                }
-             }
+        std::future::pending::<()>().await
+    };
 
-            println!("COPIED EVENT DONE");
+    let stop = token.clone();
+    if args.no_console {
+        tokio::spawn(async move {
+            let _ = tokio::signal::ctrl_c().await;
+            stop.cancel();
+        });
+    } else {
+        tokio::spawn(console::print_events(router.subscribe()));
+        // Not a blocking task: the runtime would wait on a pending readline
+        // at shutdown, while a plain thread just goes away with the process.
+        std::thread::spawn(move || {
+            if let Err(e) = console::run(cmd_tx) {
+                error!("Console error: {}", e);
             }
-        }
-        Task::none()
+            stop.cancel();
+        });
     }
 
-    fn view(&self) -> Element<'_, Message> {
-        let info_header = container(
-            row![
-                label_value("IP:", &self.my_ip),
-                label_value("Mask:", &self.my_mask),
-                label_value("State:", &self.state),
-            ]
-            .spacing(20),
-        );
-
-        let controls = row![
-            horizontal_space(),
-            button("Start Server")
-                .on_press(Message::OpenStartServerModal)
-                .style(button::primary),
-            button("Connect Peer")
-                .on_press(Message::OpenConnectModal)
-                .style(button::primary),
-            button("Add Peer")
-                .on_press(Message::OpenAddModal)
-                .style(button::primary),
-            horizontal_space(),
-        ]
-        .spacing(10);
-
-        let table_header = row![
-            text("ID")
-                .width(Length::FillPortion(1))
-                .style(text::primary),
-            text("IP Address")
-                .width(Length::FillPortion(2))
-                .style(text::primary),
-            text("Status")
-                .width(Length::FillPortion(1))
-                .style(text::primary),
-        ]
-        .padding(10);
-
-        let peers_list = column(self.peers.iter().map(|peer| {
-            let row_content = container(
-                row![
-                    text(&peer.id).width(Length::FillPortion(1)),
-                    text(&peer.ip).width(Length::FillPortion(2)),
-                    if peer.copied {
-                            text(format!("{} (copied)", &peer.status)).width(Length::FillPortion(1)).style(text::success)
-                    } else {
-                            text(&peer.status).width(Length::FillPortion(1))
-                    },
-                ]
-                .align_y(Center),
-            )
-            .padding(10)
-            .style(|theme: &Theme| container::Style {
-                background: Some(Background::Color(theme.palette().background)),
-                border: Border {
-                    radius: 4.0.into(),
-                    ..Default::default()
-                },
-                ..Default::default()
-            });
-
-
-            ContextMenu::new(row_content, move || {
-                container(
-                    column![
-                        button(text("Edit").size(14))
-                            .on_press(Message::OpenEditModal(peer.clone()))
-                            .style(button::text)
-                            .width(Length::Fill),
-                        button(text("Copy IP").size(14))
-                            .on_press(Message::CopyIp(peer.ip.clone()))
-                            .style(button::text)
-                            .width(Length::Fill),
-                        button(text("Delete").size(14))
-                            .on_press(Message::DeletePeer(peer.id.clone()))
-                            .style(button::danger)
-                            .width(Length::Fill),
-
-                        if peer.copied {
-                              container ( text("Copied!").size(12).style(text::success)
-                                .width(Length::Fill)
-                            )
-                        } else {
-                            container(text("") // still not ideal, but better than before
-                                .width(Length::Fill)
-                            )
-                        }    
-                    ]
-                    .padding(5)
-                    .spacing(2)
-                    .width(150),
-                )
-                .style(|theme: &Theme| container::Style {
-                    background: Some(Background::Color(theme.palette().background)),
-                    border: Border {
-                        radius: 10.0.into(),
-                        width: 1.0,
-                        color: theme.palette().primary,
-                    },
-                    shadow: Shadow {
-                        color: Color::BLACK,
-                        offset: Vector::new(0.0, 4.0),
-                        blur_radius: 10.0,
-                    },
-                    ..Default::default()
-                })
-                .into()
-            })
-            .into()
-        }))
-        .spacing(5);
-
-        let dashboard = container(
-            column![
-                info_header,
-                vertical_space().height(20),
-                controls,
-                vertical_space().height(10),
-                container(column![table_header, scrollable(peers_list)])
-            ]
-            .padding(20)
-            .max_width(800)
-            .align_x(Center),
-        )
-        .width(Length::Fill)
-        .height(Length::Fill)
-        .center_x(Length::Fill);
-
-        stack![dashboard, self.view_modal_overlay()].into()
-        
-    }
-
-    fn view_modal_overlay(&self) -> Element<'_, Message> {
-    if matches!(self.modal, ModalState::None) {
-        return container(text("")).into();
-    }
-
-    // 🎯 Title based on modal
-    let title = match self.modal {
-        ModalState::AddPeer => "Add New Peer",
-        ModalState::EditPeer => "Edit Peer",
-        ModalState::ConnectPeer => "Connect to Peer",
-        ModalState::StartServer => "Start Server",
-        ModalState::None => "",
-    };
-
-    // 🎯 Dynamic content
-    let content = match self.modal {
-        ModalState::AddPeer | ModalState::EditPeer => column![
-            text("Peer ID").size(12),
-            text_input("e.g. peer-1", &self.input_id)
-                .on_input(Message::InputIdChanged)
-                .padding(10),
-
-            vertical_space().height(10),
-
-            text("IP Address").size(12),
-            text_input("e.g. 10.0.0.5", &self.input_ip)
-                .on_input(Message::InputIpChanged)
-                .padding(10),
-        ],
-
-        ModalState::ConnectPeer => column![
-            text("Peer ID").size(12),
-            text_input("e.g. peer-1", &self.input_id)
-                 .on_input(Message::InputIdChanged)
-                .padding(10),
-                      ],
-
-        ModalState::StartServer => column![
-            text("Signaling Server IP").size(12),
-            text_input("e.g. 192.168.1.1:9000", &self.start_server_ip)
-                .on_input(Message::InputStartServerIpChanged)
-                .padding(10),
-        ],
-
-        ModalState::None => column![],
-    };
-
-    // 🎯 Button text
-    let action_label = match self.modal {
-        ModalState::AddPeer => "Save",
-        ModalState::EditPeer => "Update",
-        ModalState::ConnectPeer => "Connect",
-        ModalState::StartServer => "Start",
-        ModalState::None => "",
+    let run = async {
+        match replay {
+            Some(dev) => router.route_with(dev, token, cmd_rx).await,
+            None => router.route(token, cmd_rx).await,
+        }
     };
 
-    let modal_card = container(column![
-        text(title).size(18),
-
-        vertical_space().height(15),
-
-        content,
-
-        vertical_space().height(20),
-
-        row![
-            button("Cancel")
-                .on_press(Message::CloseModal)
-                .style(button::secondary),
-
-            horizontal_space(),
-
-            button(action_label)
-                .on_press(Message::SubmitForm)
-                .style(button::primary),
-        ]
-    ])
-    .width(300)
-    .padding(20)
-    .style(|theme: &Theme| container::Style {
-        background: Some(Background::Color(theme.palette().background)),
-        border: Border {
-            radius: 10.0.into(),
-            width: 1.0,
-            color: theme.palette().primary,
-        },
-        shadow: Shadow {
-            color: Color::BLACK,
-            offset: Vector::new(0.0, 4.0),
-            blur_radius: 10.0,
-        },
-        ..Default::default()
-    });
-
-    let overlay = mouse_area(
-        container(modal_card)
-            .width(Length::Fill)
-            .height(Length::Fill)
-            .center_x(Length::Fill)
-            .center_y(Length::Fill)
-            .style(|_theme: &Theme| container::Style {
-                background: Some(Background::Color(Color {
-                    a: 0.8,
-                    ..Color::BLACK
-                })),
-                ..Default::default()
-            }),
-    )
-    .on_press(Message::CloseModal);
-
-    overlay.into()
-}
+    tokio::select! {
+        res = run => res,
+        _ = control => Ok(()),
+    }
 }
-
-fn label_value<'a>(label: &'a str, value: &'a str) -> Element<'a, Message> {
-    row![
-        text(label).style(text::secondary),
-        text(value).style(text::primary)
-    ]
-    .spacing(5)
-    .into()
-}
\ No newline at end of file
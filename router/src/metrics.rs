@@ -0,0 +1,287 @@
+use anyhow::Result;
+use std::fmt::{Display, Write};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::forward;
+use crate::queue::QueueStatsSnapshot;
+use crate::stats::PeerMetrics;
+
+// A scrape that is not done by then is dropped, so stuck clients do not
+// pile up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// Prometheus text exposition, written one metric family at a time: a
+/// `describe` followed by that family's samples.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn describe(&mut self, name: &str, kind: Kind, help: &str) {
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", key, escape(value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` on `addr` with whatever `render` returns, each
+/// scrape on a task of its own.
+pub async fn serve<F, Fut>(addr: SocketAddr, render: F) -> Result<()>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics on http://{}/metrics", listener.local_addr()?);
+    let render = Arc::new(render);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                forward::accept_failed("Metrics", e).await;
+                continue;
+            }
+        };
+        let render = render.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &*render)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%remote, "Metrics request failed: {}", e),
+                Err(_) => debug!(%remote, "Metrics request timed out"),
+            }
+        });
+    }
+}
+
+async fn respond<F, Fut>(stream: TcpStream, render: &F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let request = lines.next_line().await?.unwrap_or_default();
+    // The headers say nothing we need, but have to be read past.
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render().await),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Per-peer metrics of a router.
+pub fn write_peers(out: &mut Exposition, peers: &[PeerMetrics]) {
+    out.describe("lanracer_peer_state", Kind::Gauge, "Connection state of each peer, 1 for the current one.");
+    for p in peers {
+        let labels = [("peer", p.peer_id.as_str()), ("state", &p.state.to_string()), ("transport", p.transport)];
+        out.sample("lanracer_peer_state", &labels, 1);
+    }
+
+    out.describe("lanracer_peer_packets_total", Kind::Counter, "IP packets tunneled to or from each peer.");
+    for p in peers {
+        out.sample("lanracer_peer_packets_total", &[("peer", &p.peer_id), ("direction", "out")], p.link.packets_out);
+        out.sample("lanracer_peer_packets_total", &[("peer", &p.peer_id), ("direction", "in")], p.link.packets_in);
+    }
+
+    out.describe(
+        "lanracer_peer_bytes_total",
+        Kind::Counter,
+        "Bytes to or from each peer, as IP packets (raw) or as sent over the data channels (wire).",
+    );
+    for p in peers {
+        let l = &p.link;
+        for (direction, layer, bytes) in [
+            ("out", "raw", l.raw_bytes_out),
+            ("out", "wire", l.wire_bytes_out),
+            ("in", "raw", l.raw_bytes_in),
+            ("in", "wire", l.wire_bytes_in),
+        ] {
+            let labels = [("peer", p.peer_id.as_str()), ("direction", direction), ("layer", layer)];
+            out.sample("lanracer_peer_bytes_total", &labels, bytes);
+        }
+    }
+
+    out.describe("lanracer_peer_frames_total", Kind::Counter, "Data channel frames to or from each peer.");
+    for p in peers {
+        out.sample("lanracer_peer_frames_total", &[("peer", &p.peer_id), ("direction", "out")], p.link.frames_out);
+        out.sample("lanracer_peer_frames_total", &[("peer", &p.peer_id), ("direction", "in")], p.link.frames_in);
+    }
+
+    out.describe("lanracer_dropped_total", Kind::Counter, "Packets or frames dropped for each peer, by reason.");
+    for p in peers {
+        let sum = |f: fn(&QueueStatsSnapshot) -> u64| p.channels.iter().map(|c| f(&c.queue)).sum::<u64>();
+        for (reason, n) in [
+            ("queue_full", sum(|q| q.dropped_full)),
+            ("stale", sum(|q| q.dropped_stale)),
+            ("send_error", sum(|q| q.send_errors)),
+            ("too_big", p.link.too_big_out),
+            ("bad_frame", p.link.bad_frames_in),
+        ] {
+            out.sample("lanracer_dropped_total", &[("peer", &p.peer_id), ("reason", reason)], n);
+        }
+    }
+
+    out.describe("lanracer_channel_buffered_bytes", Kind::Gauge, "Bytes buffered in each data channel, not yet sent.");
+    for p in peers {
+        for c in &p.channels {
+            out.sample("lanracer_channel_buffered_bytes", &[("peer", &p.peer_id), ("channel", &c.label)], c.buffered);
+        }
+    }
+
+    out.describe("lanracer_channel_queued_frames", Kind::Gauge, "Frames waiting in the queue in front of each data channel.");
+    for p in peers {
+        for c in &p.channels {
+            out.sample("lanracer_channel_queued_frames", &[("peer", &p.peer_id), ("channel", &c.label)], c.queued);
+        }
+    }
+
+    out.describe("lanracer_peer_ice_candidate", Kind::Gauge, "Types of the ICE candidate pair in use, 1 for the current one.");
+    for p in peers {
+        if let Some((local, remote)) = &p.candidates {
+            out.sample("lanracer_peer_ice_candidate", &[("peer", &p.peer_id), ("local", local), ("remote", remote)], 1);
+        }
+    }
+
+    out.describe("lanracer_peer_rtt_seconds", Kind::Gauge, "Latest transport round trip time to each peer, from ICE or QUIC.");
+    for p in peers {
+        if let Some(rtt) = p.rtt_seconds {
+            out.sample("lanracer_peer_rtt_seconds", &[("peer", &p.peer_id)], rtt);
+        }
+    }
+
+    let pinged: Vec<_> = peers.iter().filter_map(|p| Some((&p.peer_id, p.latency.as_ref()?))).collect();
+
+    out.describe("lanracer_peer_ping_rtt_seconds", Kind::Gauge, "Smoothed ping round trip time to each peer.");
+    for (peer, l) in &pinged {
+        if let Some(ms) = l.avg_rtt_ms {
+            out.sample("lanracer_peer_ping_rtt_seconds", &[("peer", peer)], ms / 1000.0);
+        }
+    }
+
+    out.describe("lanracer_peer_ping_jitter_seconds", Kind::Gauge, "Ping jitter to each peer.");
+    for (peer, l) in &pinged {
+        out.sample("lanracer_peer_ping_jitter_seconds", &[("peer", peer)], l.jitter_ms / 1000.0);
+    }
+
+    out.describe("lanracer_peer_ping_loss_ratio", Kind::Gauge, "Fraction of recent pings to each peer that went unanswered.");
+    for (peer, l) in &pinged {
+        out.sample("lanracer_peer_ping_loss_ratio", &[("peer", peer)], l.loss);
+    }
+
+    out.describe("lanracer_peer_pings_total", Kind::Counter, "Pings sent to each peer and pongs received.");
+    for (peer, l) in &pinged {
+        out.sample("lanracer_peer_pings_total", &[("peer", peer), ("direction", "out")], l.sent);
+        out.sample("lanracer_peer_pings_total", &[("peer", peer), ("direction", "in")], l.received);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn writes_exposition() {
+        let mut out = Exposition::default();
+        out.describe("lanracer_up", Kind::Gauge, "Whether it runs.");
+        out.sample("lanracer_up", &[], 1);
+        out.sample("lanracer_up", &[("peer", "a\"b\\c\nd"), ("x", "y")], 0.5);
+        assert_eq!(
+            out.finish(),
+            "# HELP lanracer_up Whether it runs.\n\
+             # TYPE lanracer_up gauge\n\
+             lanracer_up 1\n\
+             lanracer_up{peer=\"a\\\"b\\\\c\\nd\",x=\"y\"} 0.5\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_stalled_client_does_not_hold_up_a_scrape() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(addr, || async { "lanracer_up 1\n".to_owned() }));
+        let stalled = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT / 5, get(addr, "GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"))
+            .await
+            .expect("scrape waited on the stalled client");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nlanracer_up 1\n"), "{}", response);
+
+        assert!(get(addr, "GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+        drop(stalled);
+    }
+}
@@ -0,0 +1,254 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Bytes the overlay adds in front of a tunneled packet on the physical
+// path: IPv6 (worst case) + UDP + DTLS 1.2 with AES-GCM + SCTP common
+// header + SCTP DATA chunk header + our frame type byte.
+pub const OVERLAY_OVERHEAD: u16 = 40 + 8 + 37 + 12 + 16 + 1;
+
+// Smallest MTU IPv6 allows on a link.
+pub const IPV6_MIN_MTU: u16 = 1280;
+
+const MIN_PATH_MTU: u16 = 576;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MtuConfig {
+    /// MTU of the physical path between peers.
+    pub path_mtu: u16,
+    /// Split frames that would not fit one path packet into fragments
+    /// instead of lowering the device MTU below what IPv6 needs.
+    pub fragment: bool,
+}
+
+impl Default for MtuConfig {
+    fn default() -> Self {
+        Self {
+            path_mtu: 1500,
+            fragment: true,
+        }
+    }
+}
+
+impl MtuConfig {
+    /// Largest frame that still travels in a single path packet.
+    pub fn max_frame(&self) -> usize {
+        usize::from(self.path_mtu.max(MIN_PATH_MTU) - OVERLAY_OVERHEAD + 1)
+    }
+
+    /// MTU to configure on the TUN device.
+    pub fn device_mtu(&self) -> u16 {
+        let fits = self.path_mtu.max(MIN_PATH_MTU) - OVERLAY_OVERHEAD;
+        if self.fragment { fits.max(IPV6_MIN_MTU) } else { fits }
+    }
+}
+
+/// Builds the ICMP "fragmentation needed" (IPv4 with DF set) or ICMPv6
+/// "packet too big" reply for `packet`, to be written back into the device.
+/// Returns `None` for packets the sender is allowed to have fragmented.
+pub fn too_big_reply(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        4 => icmpv4_frag_needed(packet, mtu),
+        6 => icmpv6_packet_too_big(packet, mtu),
+        _ => None,
+    }
+}
+
+fn icmpv4_frag_needed(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    let ihl = usize::from(packet.first()? & 0x0f) * 4;
+    if ihl < 20 || packet.len() < ihl {
+        return None;
+    }
+
+    let dont_fragment = packet[6] & 0x40 != 0;
+    // Never answer an ICMP error with another one.
+    let is_icmp_error = packet[9] == 1 && packet.get(ihl).is_some_and(|t| ![0, 8].contains(t));
+    if !dont_fragment || is_icmp_error {
+        return None;
+    }
+
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let quoted = &packet[..packet.len().min(ihl + 8)];
+
+    let mut icmp = Vec::with_capacity(8 + quoted.len());
+    icmp.extend_from_slice(&[3, 4, 0, 0, 0, 0]);
+    icmp.extend_from_slice(&mtu.to_be_bytes());
+    icmp.extend_from_slice(quoted);
+    let sum = checksum(&[&icmp]);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let total = (20 + icmp.len()) as u16;
+    let mut reply = Vec::with_capacity(usize::from(total));
+    reply.extend_from_slice(&[0x45, 0]);
+    reply.extend_from_slice(&total.to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0, 64, 1, 0, 0]);
+    // Answer on behalf of the unreachable destination.
+    reply.extend_from_slice(&dst.octets());
+    reply.extend_from_slice(&src.octets());
+    let sum = checksum(&[&reply]);
+    reply[10..12].copy_from_slice(&sum.to_be_bytes());
+    reply.extend_from_slice(&icmp);
+
+    Some(reply)
+}
+
+fn icmpv6_packet_too_big(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    if packet.len() < 40 {
+        return None;
+    }
+
+    let is_icmp_error = packet[6] == 58 && packet.get(40).is_some_and(|t| *t < 128);
+    if is_icmp_error {
+        return None;
+    }
+
+    let src: [u8; 16] = packet[8..24].try_into().ok()?;
+    let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+    let (src, dst) = (Ipv6Addr::from(src), Ipv6Addr::from(dst));
+
+    // The whole reply has to fit the minimum IPv6 MTU.
+    let quoted = &packet[..packet.len().min(usize::from(IPV6_MIN_MTU) - 40 - 8)];
+
+    let mut icmp = Vec::with_capacity(8 + quoted.len());
+    icmp.extend_from_slice(&[2, 0, 0, 0]);
+    icmp.extend_from_slice(&u32::from(mtu).to_be_bytes());
+    icmp.extend_from_slice(quoted);
+
+    let len = (icmp.len() as u32).to_be_bytes();
+    let sum = checksum(&[&dst.octets(), &src.octets(), &len, &[0, 0, 0, 58], &icmp]);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mut reply = Vec::with_capacity(40 + icmp.len());
+    reply.extend_from_slice(&[0x60, 0, 0, 0]);
+    reply.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[58, 64]);
+    reply.extend_from_slice(&dst.octets());
+    reply.extend_from_slice(&src.octets());
+    reply.extend_from_slice(&icmp);
+
+    Some(reply)
+}
+
+/// Internet checksum over the concatenation of `parts`.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd: Option<u8> = None;
+
+    for byte in parts.iter().flat_map(|p| p.iter().copied()) {
+        match odd.take() {
+            Some(hi) => sum += u32::from(u16::from_be_bytes([hi, byte])),
+            None => odd = Some(byte),
+        }
+    }
+    if let Some(hi) = odd {
+        sum += u32::from(u16::from_be_bytes([hi, 0]));
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4(df: bool, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let total = (20 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34, if df { 0x40 } else { 0 }, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        let sum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ipv6(next: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        packet.extend_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn checksums_like_rfc_1071() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&[&data]), !0xddf2);
+        // Parts are summed as one run of bytes, odd lengths included.
+        assert_eq!(checksum(&[&data[..3], &data[3..]]), !0xddf2);
+        assert_eq!(checksum(&[&[0xab]]), !0xab00);
+    }
+
+    #[test]
+    fn answers_ipv4_with_frag_needed() {
+        let packet = ipv4(true, 17, &[0x55; 1400]);
+        let reply = too_big_reply(&packet, 1380).unwrap();
+
+        assert_eq!(reply.len(), 20 + 8 + 28);
+        assert_eq!(u16::from_be_bytes([reply[2], reply[3]]), reply.len() as u16);
+        assert_eq!(checksum(&[&reply[..20]]), 0, "IPv4 header checksum");
+        assert_eq!(reply[9], 1);
+        assert_eq!(&reply[12..16], &packet[16..20]);
+        assert_eq!(&reply[16..20], &packet[12..16]);
+
+        let icmp = &reply[20..];
+        assert_eq!(checksum(&[icmp]), 0, "ICMP checksum");
+        assert_eq!(icmp[..2], [3, 4]);
+        assert_eq!(u16::from_be_bytes([icmp[6], icmp[7]]), 1380);
+        assert_eq!(&icmp[8..], &packet[..28]);
+    }
+
+    #[test]
+    fn leaves_ipv4_alone_when_it_may_fragment() {
+        assert_eq!(too_big_reply(&ipv4(false, 17, &[0; 1400]), 1380), None);
+        // Unreachable and time exceeded are errors; echo requests are not.
+        assert_eq!(too_big_reply(&ipv4(true, 1, &[3, 4, 0, 0]), 1380), None);
+        assert_eq!(too_big_reply(&ipv4(true, 1, &[11, 0, 0, 0]), 1380), None);
+        assert!(too_big_reply(&ipv4(true, 1, &[8, 0, 0, 0]), 1380).is_some());
+        assert_eq!(too_big_reply(&[0x45; 10], 1380), None);
+    }
+
+    #[test]
+    fn answers_ipv6_with_packet_too_big() {
+        let packet = ipv6(17, &[0x55; 1900]);
+        let reply = too_big_reply(&packet, 1380).unwrap();
+
+        assert_eq!(reply.len(), usize::from(IPV6_MIN_MTU));
+        assert_eq!(usize::from(u16::from_be_bytes([reply[4], reply[5]])), reply.len() - 40);
+        assert_eq!(reply[6], 58);
+        assert_eq!(&reply[8..24], &packet[24..40]);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+
+        let icmp = &reply[40..];
+        let len = (icmp.len() as u32).to_be_bytes();
+        let pseudo = [&reply[8..24], &reply[24..40], &len, &[0, 0, 0, 58]];
+        assert_eq!(checksum(&[&pseudo[..], &[icmp]].concat()), 0, "ICMPv6 checksum");
+        assert_eq!(icmp[..2], [2, 0]);
+        assert_eq!(u32::from_be_bytes(icmp[4..8].try_into().unwrap()), 1380);
+        assert_eq!(&icmp[8..], &packet[..icmp.len() - 8]);
+
+        let short = ipv6(17, &[0x55; 100]);
+        assert_eq!(too_big_reply(&short, 1380).unwrap().len(), 40 + 8 + short.len());
+    }
+
+    #[test]
+    fn leaves_icmpv6_errors_alone() {
+        assert_eq!(too_big_reply(&ipv6(58, &[1, 0, 0, 0]), 1380), None);
+        assert!(too_big_reply(&ipv6(58, &[128, 0, 0, 0]), 1380).is_some());
+        assert_eq!(too_big_reply(&[0x60; 39], 1380), None);
+    }
+
+    #[test]
+    fn device_mtu_keeps_ipv6_working() {
+        let small = MtuConfig { path_mtu: 1280, fragment: true };
+        assert_eq!(small.device_mtu(), IPV6_MIN_MTU);
+        assert_eq!(small.max_frame(), usize::from(1280 - OVERLAY_OVERHEAD + 1));
+        assert_eq!(MtuConfig { fragment: false, ..small }.device_mtu(), 1280 - OVERLAY_OVERHEAD);
+        assert_eq!(MtuConfig::default().device_mtu(), 1500 - OVERLAY_OVERHEAD);
+    }
+}
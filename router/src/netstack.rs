@@ -0,0 +1,555 @@
+use smoltcp::iface::{Config, Interface, PollIngressSingleResult, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::AnySocket;
+use smoltcp::socket::tcp;
+use smoltcp::wire::{HardwareAddress, IpCidr};
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::config::{self, RouterConfig};
+use crate::device::PacketDevice;
+
+// Packets waiting in either direction before more are dropped, which TCP
+// takes as congestion.
+const QUEUE_LIMIT: usize = 512;
+const BUFFER_SIZE: usize = 256 * 1024;
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=u16::MAX;
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// Unacknowledged data older than this resets the connection.
+const TCP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long [`Netstack::connect`] waits for the handshake.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// A closed connection the other side never finishes closing is reset after
+// this long.
+const LINGER: Duration = Duration::from_secs(30);
+
+/// A TCP/IP stack in the router's own process, standing in for the kernel
+/// when no TUN device can be created. Its packets go to peers like the ones
+/// read from a TUN device; local programs reach the virtual network through
+/// the proxies and forwards built on [`Netstack::connect`].
+#[derive(Clone)]
+pub struct Netstack {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    stack: Mutex<Stack>,
+    /// Something changed that the stack has to act on.
+    poll: Notify,
+    /// The stack has packets for the router.
+    outbound: Notify,
+    address: Ipv4Addr,
+    netmask: Ipv4Addr,
+}
+
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    queues: Queues,
+    /// Sockets whose stream was dropped, removed once fully closed.
+    closing: Vec<(SocketHandle, Instant)>,
+    listeners: HashMap<u16, Listener>,
+    next_port: u16,
+}
+
+// The sockets of a listening port.
+#[derive(Default)]
+struct Listener {
+    /// Listening, or in the middle of a handshake.
+    pending: Vec<SocketHandle>,
+    /// Connected and waiting to be accepted.
+    ready: VecDeque<SocketHandle>,
+    waker: Option<Waker>,
+}
+
+impl Netstack {
+    /// A stack with the address and netmask of `config`.
+    pub fn new(config: &RouterConfig) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let mut queues = Queues {
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+            mtu: config.link.mtu.device_mtu() as usize,
+        };
+        let mut iface_config = Config::new(HardwareAddress::Ip);
+        iface_config.random_seed = seed;
+        let mut iface = Interface::new(iface_config, &mut queues, now());
+        let prefix = u32::from(config.netmask).count_ones() as u8;
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(config.address.into(), prefix));
+        });
+
+        let ports = EPHEMERAL_PORTS.len() as u64;
+        let stack = Stack {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            queues,
+            closing: Vec::new(),
+            listeners: HashMap::new(),
+            next_port: EPHEMERAL_PORTS.start() + (seed % ports) as u16,
+        };
+        Self {
+            shared: Arc::new(Shared {
+                stack: Mutex::new(stack),
+                poll: Notify::new(),
+                outbound: Notify::new(),
+                address: config.address,
+                netmask: config.netmask,
+            }),
+        }
+    }
+
+    /// The router's side of the stack.
+    pub fn device(&self) -> NetstackDevice {
+        NetstackDevice {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Drives timers and retransmissions. Has to run for as long as the
+    /// stack is used.
+    pub async fn run(&self) {
+        loop {
+            let delay = {
+                let mut stack = self.shared.lock();
+                let Stack {
+                    iface,
+                    sockets,
+                    queues,
+                    closing,
+                    listeners,
+                    ..
+                } = &mut *stack;
+                // One packet at a time, so a listening port is open again
+                // for the next connection before its SYN is taken in.
+                while iface.poll_ingress_single(now(), queues, sockets) != PollIngressSingleResult::None {
+                    for (&port, listener) in listeners.iter_mut() {
+                        listener.update(port, sockets);
+                    }
+                }
+                iface.poll_egress(now(), queues, sockets);
+                for (&port, listener) in listeners.iter_mut() {
+                    listener.update(port, sockets);
+                }
+
+                closing.retain(|&(handle, since)| {
+                    let socket = sockets.get_mut::<tcp::Socket>(handle);
+                    match socket.state() {
+                        tcp::State::Closed | tcp::State::TimeWait => {
+                            sockets.remove(handle);
+                            false
+                        }
+                        _ => {
+                            if since.elapsed() >= LINGER {
+                                socket.abort();
+                            }
+                            true
+                        }
+                    }
+                });
+
+                if !queues.outbound.is_empty() {
+                    self.shared.outbound.notify_one();
+                }
+                if queues.inbound.is_empty() {
+                    iface.poll_delay(now(), sockets).map(Duration::from)
+                } else {
+                    Some(Duration::ZERO)
+                }
+            };
+            match delay {
+                Some(delay) => {
+                    let _ = tokio::time::timeout(delay, self.shared.poll.notified()).await;
+                }
+                None => self.shared.poll.notified().await,
+            }
+        }
+    }
+
+    /// This node's address on the virtual network.
+    pub fn address(&self) -> Ipv4Addr {
+        self.shared.address
+    }
+
+    /// Whether `ip` is another node on the virtual network.
+    pub fn reaches(&self, ip: Ipv4Addr) -> bool {
+        config::on_network(self.shared.address, self.shared.netmask, ip)
+    }
+
+    /// Opens a TCP connection to `remote` across the virtual network.
+    pub async fn connect(&self, remote: SocketAddrV4) -> io::Result<TcpStream> {
+        if !self.reaches(*remote.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::NetworkUnreachable,
+                format!("{} is not on the virtual network", remote.ip()),
+            ));
+        }
+
+        let handle = {
+            let mut stack = self.shared.lock();
+            let port = stack.ephemeral_port()?;
+            let mut socket = new_socket();
+            let Stack { iface, sockets, .. } = &mut *stack;
+            socket
+                .connect(iface.context(), remote, port)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            sockets.add(socket)
+        };
+        self.shared.poll.notify_one();
+
+        // Dropping the stream on failure closes the socket.
+        let stream = TcpStream {
+            shared: self.shared.clone(),
+            handle,
+        };
+        match tokio::time::timeout(CONNECT_TIMEOUT, poll_fn(|cx| stream.poll_established(cx))).await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Accepts TCP connections from peers to `port` on this node's address.
+    pub fn listen(&self, port: u16) -> io::Result<TcpListener> {
+        let mut stack = self.shared.lock();
+        let Stack { sockets, listeners, .. } = &mut *stack;
+        if listeners.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let mut listener = Listener::default();
+        listener.pending.push(listening_socket(port, sockets)?);
+        listeners.insert(port, listener);
+        Ok(TcpListener {
+            shared: self.shared.clone(),
+            port,
+        })
+    }
+}
+
+fn new_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+    );
+    socket.set_nagle_enabled(false);
+    socket.set_ack_delay(None);
+    socket.set_keep_alive(Some(KEEP_ALIVE.into()));
+    socket.set_timeout(Some(TCP_TIMEOUT.into()));
+    socket
+}
+
+fn listening_socket(port: u16, sockets: &mut SocketSet<'static>) -> io::Result<SocketHandle> {
+    let mut socket = new_socket();
+    socket
+        .listen(port)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(sockets.add(socket))
+}
+
+impl Listener {
+    // Moves sockets that finished their handshake to the accept queue, and
+    // opens a new listening socket once the last one has taken a SYN.
+    fn update(&mut self, port: u16, sockets: &mut SocketSet<'static>) {
+        let mut connected = false;
+        self.pending.retain(|&handle| match sockets.get::<tcp::Socket>(handle).state() {
+            tcp::State::Listen | tcp::State::SynReceived => true,
+            tcp::State::Closed => {
+                sockets.remove(handle);
+                false
+            }
+            _ => {
+                self.ready.push_back(handle);
+                connected = true;
+                false
+            }
+        });
+        let listening = self
+            .pending
+            .iter()
+            .any(|&handle| sockets.get::<tcp::Socket>(handle).is_listening());
+        if !listening && let Ok(handle) = listening_socket(port, sockets) {
+            self.pending.push(handle);
+        }
+        if connected && let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Stack {
+    fn ephemeral_port(&mut self) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = if port == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { port + 1 };
+            let in_use = self.sockets.iter().any(|(_, socket)| {
+                tcp::Socket::downcast(socket)
+                    .and_then(|s| s.local_endpoint())
+                    .is_some_and(|e| e.port == port)
+            });
+            if !in_use {
+                return Ok(port);
+            }
+        }
+        Err(io::ErrorKind::AddrInUse.into())
+    }
+}
+
+fn now() -> smoltcp::time::Instant {
+    smoltcp::time::Instant::now()
+}
+
+/// The packet device of a [`Netstack`]: the router reads what the stack
+/// sends and writes what peers send to it.
+pub struct NetstackDevice {
+    shared: Arc<Shared>,
+}
+
+impl PacketDevice for NetstackDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let packet = self.shared.lock().queues.outbound.pop_front();
+            if let Some(packet) = packet {
+                // There is room for whatever the stack held back.
+                self.shared.poll.notify_one();
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                return Ok(len);
+            }
+            self.shared.outbound.notified().await;
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        {
+            let mut stack = self.shared.lock();
+            if stack.queues.inbound.len() < QUEUE_LIMIT {
+                stack.queues.inbound.push_back(packet.to_vec());
+            }
+        }
+        self.shared.poll.notify_one();
+        Ok(packet.len())
+    }
+}
+
+// The stack's view of the router: packets from peers waiting to be taken
+// in, and packets for peers waiting to be picked up.
+struct Queues {
+    inbound: VecDeque<Vec<u8>>,
+    outbound: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl phy::Device for Queues {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: smoltcp::time::Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.inbound.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.outbound)))
+    }
+
+    fn transmit(&mut self, _: smoltcp::time::Instant) -> Option<TxToken<'_>> {
+        (self.outbound.len() < QUEUE_LIMIT).then_some(TxToken(&mut self.outbound))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+/// A port on a [`Netstack`] that peers can connect to. Dropping it stops
+/// listening.
+pub struct TcpListener {
+    shared: Arc<Shared>,
+    port: u16,
+}
+
+impl TcpListener {
+    /// Waits for the next connection and returns it with the peer's address.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let Stack { sockets, listeners, .. } = &mut *stack;
+            let Some(listener) = listeners.get_mut(&self.port) else {
+                return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+            };
+            let Some(handle) = listener.ready.pop_front() else {
+                listener.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+            let remote = sockets
+                .get::<tcp::Socket>(handle)
+                .remote_endpoint()
+                .map_or(SocketAddr::from(([0, 0, 0, 0], 0)), |e| SocketAddr::new(e.addr.into(), e.port));
+            let stream = TcpStream {
+                shared: self.shared.clone(),
+                handle,
+            };
+            Poll::Ready(Ok((stream, remote)))
+        })
+        .await
+    }
+
+    /// The port peers connect to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut stack = self.shared.lock();
+        let Stack {
+            sockets,
+            listeners,
+            closing,
+            ..
+        } = &mut *stack;
+        if let Some(listener) = listeners.remove(&self.port) {
+            for handle in listener.pending.into_iter().chain(listener.ready) {
+                sockets.get_mut::<tcp::Socket>(handle).abort();
+                closing.push((handle, Instant::now()));
+            }
+        }
+        drop(stack);
+        self.shared.poll.notify_one();
+    }
+}
+
+/// A TCP connection through a [`Netstack`]. Dropping it closes the
+/// connection.
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// The local end of the connection.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        let mut stack = self.shared.lock();
+        let port = stack
+            .sockets
+            .get_mut::<tcp::Socket>(self.handle)
+            .local_endpoint()
+            .map_or(0, |e| e.port);
+        SocketAddrV4::new(self.shared.address, port)
+    }
+
+    fn poll_established(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+            tcp::State::Closed => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if socket.can_recv() {
+            let len = socket
+                .recv_slice(buf.initialize_unfilled())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            buf.advance(len);
+            drop(stack);
+            // The window opened up.
+            self.shared.poll.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if !socket.may_recv() {
+            return Poll::Ready(Ok(()));
+        }
+        socket.register_recv_waker(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if !socket.may_send() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !socket.can_send() {
+            socket.register_send_waker(cx.waker());
+            return Poll::Pending;
+        }
+        let len = socket
+            .send_slice(data)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        drop(stack);
+        self.shared.poll.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().sockets.get_mut::<tcp::Socket>(self.handle).close();
+        self.shared.poll.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        {
+            let mut stack = self.shared.lock();
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+            stack.closing.push((self.handle, Instant::now()));
+        }
+        self.shared.poll.notify_one();
+    }
+}
@@ -1,33 +1,182 @@
 #[allow(dead_code)]
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::{RwLock, mpsc};
+use tracing::{Instrument, Span, debug, info, info_span, trace, warn};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::{API, APIBuilder};
-use webrtc::data_channel::RTCDataChannel;
-use webrtc::data_channel::data_channel_message::DataChannelMessage;
-use webrtc::peer_connection::RTCPeerConnection;
-use webrtc::peer_connection::configuration::RTCConfiguration;
-use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+use crate::ban::{self, Ban, BanList};
+use crate::batch;
+use crate::chat::{ChatMessage, ChatTarget, ChatWire};
+use crate::compress::{self, Compression};
+use crate::config::LinkConfig;
 use crate::event::LanEvent;
+use crate::fragment::{self, Reassembler};
+use crate::gossip::Gossip;
+use crate::identity::Identity;
+use crate::latency::{self, LatencySnapshot, LatencyTracker, PingReport};
+use crate::frame::{Capabilities, Frame, Hello, PROTOCOL_VERSION, SOFTWARE_VERSION};
+use crate::mtu;
+use crate::queue::{ChannelSender, DropPolicy};
+use crate::route::{self, Links, Route, RouteAdvert, RouteTable};
+use crate::stats::{ChannelMetrics, LinkStats, LinkStatsSnapshot, PeerMetrics};
+use crate::throughput::{ThroughputMode, ThroughputReport, ThroughputTests};
+use crate::transfer::FileTransfers;
+use crate::transport::quic::{DirectEndpoint, QuicTransport};
+use crate::transport::webrtc::WebRtcTransport;
+use crate::transport::{Channel, LinkEvents, LinkState, PeerTransport};
+use crate::trust::{TrustList, TrustMode, TrustSource, TrustedPeer, Verdict};
 
-const TYPE_PACKET: u8 = 0x01;
-const TYPE_CHAT: u8   = 0x02;
+const PACKET_QUEUE_LEN: usize = 256;
+const CONTROL_QUEUE_LEN: usize = 64;
+const FILE_QUEUE_LEN: usize = 32;
+// A game packet that sat in the queue this long is no longer worth sending.
+const MAX_PACKET_AGE: Duration = Duration::from_millis(200);
+
+/// Span for everything that happens on the link to one peer.
+pub fn span(peer_id: &str) -> Span {
+    info_span!("peer", id = %peer_id)
+}
+
+/// The link to one peer and what came over it.
+#[derive(Clone)]
+struct Peer {
+    transport: Arc<dyn PeerTransport>,
+    /// The same link when it is WebRTC, which still has SDP to exchange.
+    webrtc: Option<Arc<WebRtcTransport>>,
+    /// Tells this link's events apart from those of a link it replaced.
+    link: u64,
+    packet: ChannelSender,
+    control: ChannelSender,
+    file: ChannelSender,
+    /// What the peer said about itself; `None` until its hello arrives.
+    hello: Option<Hello>,
+    /// Capabilities both sides support.
+    caps: Capabilities,
+    compression: Compression,
+    stats: Arc<LinkStats>,
+}
+
+impl Peer {
+    fn new(peer_id: &str, link: u64, transport: Arc<dyn PeerTransport>, webrtc: Option<Arc<WebRtcTransport>>) -> Self {
+        let sender = |channel, capacity, policy| {
+            ChannelSender::spawn(peer_id.to_owned(), transport.clone(), channel, capacity, policy)
+        };
+        let packet_policy = DropPolicy::DropOldest { max_age: MAX_PACKET_AGE };
+        Self {
+            packet: sender(Channel::Packet, PACKET_QUEUE_LEN, packet_policy),
+            control: sender(Channel::Control, CONTROL_QUEUE_LEN, DropPolicy::Block),
+            file: sender(Channel::File, FILE_QUEUE_LEN, DropPolicy::Block),
+            transport,
+            webrtc,
+            link,
+            hello: None,
+            caps: Capabilities::default(),
+            compression: Compression::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    fn senders(&self) -> [&ChannelSender; 3] {
+        [&self.packet, &self.control, &self.file]
+    }
+
+    /// Whether the link may still carry traffic.
+    fn is_up(&self) -> bool {
+        !self.transport.state().is_down()
+    }
+
+    fn mtu(&self) -> Option<u16> {
+        self.hello.as_ref().map(|h| h.mtu)
+    }
+
+    /// Key for sharing encoded frames between peers that negotiated the
+    /// same framing.
+    fn framing(&self, default_mtu: u16) -> Framing {
+        Framing {
+            caps: self.caps,
+            compression: self.compression,
+            mtu: self.mtu().unwrap_or(default_mtu),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Framing {
+    caps: Capabilities,
+    compression: Compression,
+    mtu: u16,
+}
+
+/// Version information a connected peer sent in its hello.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerVersion {
+    pub protocol: u16,
+    pub software: String,
+    pub capabilities: Capabilities,
+}
+
+/// What the manager knows about one peer connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub id: String,
+    pub state: LinkState,
+    /// What carries the link: `webrtc`, `quic` or `loopback`.
+    pub transport: &'static str,
+    /// `None` until the peer's hello arrives.
+    pub version: Option<PeerVersion>,
+    /// Virtual addresses seen behind the peer.
+    pub addresses: Vec<IpAddr>,
+    /// Certificate fingerprint from the peer's SDP.
+    pub fingerprint: Option<String>,
+    /// `None` until the first ping round.
+    pub latency: Option<LatencySnapshot>,
+}
 
 #[derive(Clone)]
 pub struct PeerManager {
+    local_id: String,
     api: Arc<API>,
-    peers: Arc<RwLock<HashMap<String, Arc<RTCPeerConnection>>>>,
-    data_channels: Arc<RwLock<HashMap<String, Arc<RTCDataChannel>>>>,
+    peers: Arc<RwLock<HashMap<String, Peer>>>,
+    /// Numbers each link the manager takes on.
+    next_link: Arc<AtomicU64>,
+    /// For direct links; bound on first use unless listening already.
+    direct: Arc<Mutex<Option<DirectEndpoint>>>,
     event_tx: mpsc::Sender<LanEvent>,
+    link: LinkConfig,
+    fragment_id: Arc<AtomicU16>,
+    transfers: Arc<FileTransfers>,
+    throughput: Arc<ThroughputTests>,
+    gossip: Arc<Gossip>,
+    routes: Arc<RwLock<RouteTable>>,
+    latency: Arc<Mutex<HashMap<String, LatencyTracker>>>,
+    bans: Arc<Mutex<BanList>>,
+    /// Remote certificate fingerprint of each peer, from its SDP.
+    fingerprints: Arc<Mutex<HashMap<String, String>>>,
+    trust: Arc<Mutex<TrustList>>,
+    identity: Arc<Identity>,
 }
 
 impl PeerManager {
-    pub async fn new(event_tx: mpsc::Sender<LanEvent>) -> Result<Self> {
+    pub async fn new(
+        local_id: String,
+        address: IpAddr,
+        event_tx: mpsc::Sender<LanEvent>,
+        link: LinkConfig,
+        bans: BanList,
+        trust: TrustList,
+        identity: Identity,
+    ) -> Result<Self> {
         let mut m = MediaEngine::default();
         m.register_default_codecs()?;
 
@@ -40,174 +189,1237 @@ impl PeerManager {
             .build();
 
         Ok(Self {
+            local_id,
             api: Arc::new(api),
             peers: Arc::new(RwLock::new(HashMap::new())),
-            data_channels: Arc::new(RwLock::new(HashMap::new())),
+            next_link: Arc::new(AtomicU64::new(0)),
+            direct: Arc::new(Mutex::new(None)),
+            transfers: Arc::new(FileTransfers::new(event_tx.clone())),
+            throughput: Arc::new(ThroughputTests::new()),
+            gossip: Arc::new(Gossip::new()),
             event_tx,
+            link,
+            fragment_id: Arc::new(AtomicU16::new(0)),
+            routes: Arc::new(RwLock::new(RouteTable::new(vec![address]))),
+            latency: Arc::new(Mutex::new(HashMap::new())),
+            bans: Arc::new(Mutex::new(bans)),
+            fingerprints: Arc::new(Mutex::new(HashMap::new())),
+            trust: Arc::new(Mutex::new(trust)),
+            identity: Arc::new(identity),
         })
     }
 
+    pub fn local_id(&self) -> &str {
+        &self.local_id
+    }
+
     pub async fn has_peer(&self, peer_id: &str) -> bool {
         self.peers.read().await.contains_key(peer_id)
     }
 
-    async fn new_peer_connection(&self, peer_id: String) -> Result<Arc<RTCPeerConnection>> {
-        let config = RTCConfiguration {
-            ice_servers: vec![webrtc::ice_transport::ice_server::RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
-            ..Default::default()
-        };
+    /// Whether `peer_id` has a link that is up or still coming up.
+    pub async fn is_linked(&self, peer_id: &str) -> bool {
+        self.peers.read().await.get(peer_id).is_some_and(Peer::is_up)
+    }
 
-        let pc = Arc::new(self.api.new_peer_connection(config).await?);
-        let event_tx_clone = self.event_tx.clone();
-        let pid_clone = peer_id.clone();
-
-        pc.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-            let tx = event_tx_clone.clone();
-            let pid = pid_clone.clone();
-            Box::pin(async move {
-                if s == RTCPeerConnectionState::Connected {
-                    let _ = tx.send(LanEvent::PeerConnected(pid)).await;
-                } else if s == RTCPeerConnectionState::Failed || s == RTCPeerConnectionState::Closed
-                {
-                    let _ = tx.send(LanEvent::PeerDisconnected(pid)).await;
-                }
-            })
-        }));
+    pub async fn link_state(&self, peer_id: &str) -> Option<LinkState> {
+        Some(self.peers.read().await.get(peer_id)?.transport.state())
+    }
 
-        let mut peers = self.peers.write().await;
-        peers.insert(peer_id, pc.clone());
+    // Whether a new link for `peer_id` may take the place of the one it
+    // has: only if that one is not up, or the new one comes with the
+    // fingerprint the peer is trusted with.
+    async fn may_replace(&self, peer_id: &str, verdict: &Verdict) -> bool {
+        *verdict == Verdict::Trusted || self.link_state(peer_id).await != Some(LinkState::Connected)
+    }
 
-        Ok(pc)
+    /// Peers with a link up, and what each understands.
+    pub(crate) async fn linked_peers(&self) -> Vec<(String, Capabilities)> {
+        self.peers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, p)| p.is_up())
+            .map(|(id, p)| (id.clone(), p.caps))
+            .collect()
     }
 
-    pub async fn create_offer(&self, peer_id: String) -> Result<String> {
-        let pc = self.new_peer_connection(peer_id.clone()).await?;
+    /// Takes on a link to `peer_id` that is already set up, replacing the
+    /// one the peer had, if any. The hello goes out as soon as the link
+    /// opens. Nothing is checked: the caller vouches for the peer.
+    pub async fn attach(&self, peer_id: String, transport: Arc<dyn PeerTransport>) {
+        self.remember_fingerprint(&peer_id, None);
+        self.attach_link(peer_id, transport, None).await
+    }
 
-        let dc = pc.create_data_channel("chat", None).await?;
-        self.setup_data_channel(&dc, peer_id.clone()).await;
+    async fn attach_link(
+        &self,
+        peer_id: String,
+        transport: Arc<dyn PeerTransport>,
+        webrtc: Option<Arc<WebRtcTransport>>,
+    ) {
+        let link = self.next_link.fetch_add(1, Ordering::Relaxed);
+        let peer = Peer::new(&peer_id, link, transport.clone(), webrtc);
+        peer.control.try_push(Frame::Hello(self.hello()).encode());
 
-        let offer = pc.create_offer(None).await?;
+        let old = self.peers.write().await.insert(peer_id.clone(), peer);
+        if let Some(old) = old {
+            for sender in old.senders() {
+                sender.close();
+            }
+            if let Err(e) = old.transport.close().await {
+                debug!(parent: &span(&peer_id), "Closing replaced link failed: {}", e);
+            }
+        }
 
-        let mut gather_complete = pc.gathering_complete_promise().await;
-        pc.set_local_description(offer).await?;
-        let _ = gather_complete.recv().await;
+        transport.start(self.link_events(peer_id, link));
+    }
 
-        let local_desc = pc.local_description().await.ok_or(anyhow!("No SDP"))?;
-        let json = serde_json::to_string(&local_desc)?;
-        Ok(json)
+    // Feeds what arrives on link number `link` into the manager.
+    fn link_events(&self, peer_id: String, link: u64) -> LinkEvents {
+        let reassemblers = Arc::new(Mutex::new(HashMap::new()));
+        let manager = self.clone();
+        let pid = peer_id.clone();
+        let on_frame = move |channel, data| {
+            let manager = manager.clone();
+            let peer_id = pid.clone();
+            let reassemblers = reassemblers.clone();
+            let span = span(&peer_id);
+            async move {
+                manager.on_frame(peer_id, channel, data, &reassemblers).await;
+            }
+            .instrument(span)
+        };
+
+        let manager = self.clone();
+        let on_state = move |state| {
+            let manager = manager.clone();
+            let peer_id = peer_id.clone();
+            let span = span(&peer_id);
+            async move {
+                manager.on_link_state(peer_id, link, state).await;
+            }
+            .instrument(span)
+        };
+
+        LinkEvents::new(on_frame, on_state)
     }
 
-    pub async fn set_answer_as_offerer(&self, peer_id: &str, answer_json: &str) -> Result<()> {
-        let peers = self.peers.read().await;
-        let pc = peers.get(peer_id).ok_or(anyhow!("Peer not found"))?;
+    async fn on_frame(
+        &self,
+        peer_id: String,
+        channel: Channel,
+        data: Bytes,
+        reassemblers: &Mutex<HashMap<Channel, Reassembler>>,
+    ) {
+        if self.is_banned(&peer_id) {
+            return;
+        }
+
+        let frame = match Frame::decode(&data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Bad frame: {}", e);
+                self.count_bad_frame(&peer_id).await;
+                return;
+            }
+        };
+
+        self.count_in(&peer_id, data.len()).await;
+
+        if let Frame::Fragment { id, index, count, data } = frame {
+            let whole = reassemblers
+                .lock()
+                .unwrap()
+                .entry(channel)
+                .or_default()
+                .push(id, index, count, data);
+            if let Some(whole) = whole {
+                match Frame::decode(&whole) {
+                    Ok(frame) => self.handle_frame(peer_id, frame).await,
+                    Err(e) => {
+                        warn!("Bad frame: {}", e);
+                        self.count_bad_frame(&peer_id).await;
+                    }
+                }
+            }
+        } else {
+            self.handle_frame(peer_id, frame).await;
+        }
+    }
+
+    async fn on_link_state(&self, peer_id: String, link: u64, state: LinkState) {
+        debug!("Connection {}", state);
+
+        // Once a reconnect replaced the link, its end says nothing about
+        // the peer. A removed peer's link still reports its close.
+        let replaced = self
+            .peers
+            .read()
+            .await
+            .get(&peer_id)
+            .is_some_and(|p| p.link != link);
+        if replaced {
+            return;
+        }
 
+        match state {
+            LinkState::Connected => {
+                self.trust_on_first_use(&peer_id);
+                let _ = self.event_tx.send(LanEvent::PeerConnected(peer_id)).await;
+            }
+            LinkState::Failed | LinkState::Closed => {
+                let _ = self.event_tx.send(LanEvent::PeerDisconnected(peer_id)).await;
+            }
+            _ => {}
+        }
+    }
+
+    pub async fn create_offer(&self, peer_id: String) -> Result<String> {
+        // Nobody has answered yet, so nothing says who the new link is to.
+        if self.link_state(&peer_id).await == Some(LinkState::Connected) {
+            bail!("Already linked with {}", peer_id);
+        }
+        let transport = Arc::new(WebRtcTransport::new(&self.api, &peer_id, self.identity.certificate()).await?);
+        self.attach_link(peer_id, transport.clone(), Some(transport.clone())).await;
+        transport.create_offer().await
+    }
+
+    pub async fn set_answer_as_offerer(&self, peer_id: &str, answer_json: &str) -> Result<()> {
         let answer = serde_json::from_str::<RTCSessionDescription>(answer_json)?;
-        pc.set_remote_description(answer).await?;
+        let fingerprint = ban::sdp_fingerprint(&answer.sdp);
+        if self.bans.lock().unwrap().is_banned(peer_id, fingerprint.as_deref()) {
+            let _ = self.remove_peer(peer_id).await;
+            return Err(anyhow!("Peer {} is banned", peer_id));
+        }
+        if let Err(e) = self.verify(peer_id, fingerprint.as_deref()) {
+            let _ = self.remove_peer(peer_id).await;
+            return Err(e);
+        }
+
+        let transport = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .and_then(|p| p.webrtc.clone())
+            .ok_or(anyhow!("Peer not found"))?;
+        transport.set_answer(answer).await?;
+        self.remember_fingerprint(peer_id, fingerprint);
         Ok(())
     }
 
     pub async fn accept_offer(&self, peer_id: String, offer_json: &str) -> Result<String> {
-        let pc = self.new_peer_connection(peer_id.clone()).await?;
+        let offer = serde_json::from_str::<RTCSessionDescription>(offer_json)?;
+        let fingerprint = ban::sdp_fingerprint(&offer.sdp);
+        if self.bans.lock().unwrap().is_banned(&peer_id, fingerprint.as_deref()) {
+            return Err(anyhow!("Peer {} is banned", peer_id));
+        }
+        let verdict = self.verify(&peer_id, fingerprint.as_deref())?;
+        if !self.may_replace(&peer_id, &verdict).await {
+            bail!("Already linked with {}", peer_id);
+        }
 
-        let manager_clone = self.clone();
-        let pid_clone = peer_id.clone();
+        let transport = Arc::new(WebRtcTransport::new(&self.api, &peer_id, self.identity.certificate()).await?);
+        self.attach_link(peer_id.clone(), transport.clone(), Some(transport.clone())).await;
+        self.remember_fingerprint(&peer_id, fingerprint);
+        transport.accept_offer(offer).await
+    }
 
-        pc.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
-            let manager = manager_clone.clone();
-            let pid = pid_clone.clone();
-            Box::pin(async move {
-                manager.setup_data_channel(&dc, pid).await;
-            })
-        }));
+    /// Takes direct links from peers on `addr` for as long as it runs.
+    pub async fn serve_direct(&self, addr: SocketAddr) -> Result<()> {
+        let endpoint = DirectEndpoint::bind(addr, &self.identity)?;
+        *self.direct.lock().unwrap() = Some(endpoint.clone());
+        info!("Direct links on {}", endpoint.local_addr()?);
 
-        let offer = serde_json::from_str::<RTCSessionDescription>(offer_json)?;
-        pc.set_remote_description(offer).await?;
+        while let Some(incoming) = endpoint.accept().await {
+            let manager = self.clone();
+            tokio::spawn(async move {
+                let remote = incoming.remote_address();
+                match DirectEndpoint::handshake(incoming, &manager.local_id).await {
+                    Ok((peer_id, transport)) => manager.accept_direct(peer_id, transport).await,
+                    Err(e) => debug!(%remote, "Direct handshake failed: {}", e),
+                }
+            });
+        }
+        Ok(())
+    }
 
-        let answer = pc.create_answer(None).await?;
-        let mut gather_complete = pc.gathering_complete_promise().await;
-        pc.set_local_description(answer).await?;
-        let _ = gather_complete.recv().await;
+    async fn accept_direct(&self, peer_id: String, transport: QuicTransport) {
+        let span = span(&peer_id);
+        let remote = transport.remote_address();
+        if let Err(e) = self.admit_direct(&peer_id, &transport).await {
+            info!(parent: &span, "Refused direct link from {}: {}", remote, e);
+            let _ = transport.close().await;
+            return;
+        }
+        info!(parent: &span, "Direct link from {}", remote);
+        self.attach_direct(peer_id, transport).await;
+    }
 
-        let local_desc = pc.local_description().await.ok_or(anyhow!("No SDP"))?;
-        let json = serde_json::to_string(&local_desc)?;
-        Ok(json)
+    /// Opens a direct link to the peer listening on `addr`, without the
+    /// signaling server, and returns the peer's id.
+    pub async fn connect_direct(&self, addr: SocketAddr) -> Result<String> {
+        let endpoint = self.direct_endpoint()?;
+        let (peer_id, transport) = endpoint.connect(addr, &self.local_id).await?;
+        if let Err(e) = self.admit_direct(&peer_id, &transport).await {
+            transport.close().await?;
+            return Err(e);
+        }
+        self.attach_direct(peer_id.clone(), transport).await;
+        Ok(peer_id)
     }
 
-    async fn setup_data_channel(&self, dc: &Arc<RTCDataChannel>, peer_id: String) {
-        let value = peer_id.clone();
-        let dc_clone = dc.clone();
-        let tx = self.event_tx.clone();
+    // Checks a direct link the way offers are checked, with the
+    // fingerprint of the certificate the peer proved it holds.
+    async fn admit_direct(&self, peer_id: &str, transport: &QuicTransport) -> Result<()> {
+        let fingerprint = transport.fingerprint();
+        if self.bans.lock().unwrap().is_banned(peer_id, Some(fingerprint)) {
+            bail!("Peer {} is banned", peer_id);
+        }
+        let verdict = self.verify(peer_id, Some(fingerprint))?;
+        if !self.may_replace(peer_id, &verdict).await {
+            bail!("Already linked with {}", peer_id);
+        }
+        Ok(())
+    }
 
-        dc.on_message(Box::new(move |msg| {
-        let tx = tx.clone();
-         let peer_id = value.clone();
+    async fn attach_direct(&self, peer_id: String, transport: QuicTransport) {
+        self.remember_fingerprint(&peer_id, Some(transport.fingerprint().to_owned()));
+        self.attach_link(peer_id, Arc::new(transport), None).await
+    }
 
-        Box::pin(async move {
-            if msg.data.is_empty() {
-                return;
+    fn direct_endpoint(&self) -> Result<DirectEndpoint> {
+        let mut direct = self.direct.lock().unwrap();
+        if let Some(endpoint) = direct.as_ref() {
+            return Ok(endpoint.clone());
+        }
+        // Not listening: any port does to connect out.
+        let endpoint = DirectEndpoint::bind(SocketAddr::from(([0, 0, 0, 0], 0)), &self.identity)?;
+        *direct = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    fn hello(&self) -> Hello {
+        let compression = match self.link.compression {
+            Compression::None => vec![],
+            algo => vec![algo.id()],
+        };
+
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            software: SOFTWARE_VERSION.to_owned(),
+            capabilities: Capabilities::all(),
+            mtu: self.link.mtu.device_mtu(),
+            compression,
         }
+    }
 
-            let msg_type = msg.data[0];
-            let payload = &msg.data[1..];
-        match msg_type {
-            TYPE_PACKET => {
-                let _ = tx.send(LanEvent::PacketFromPeer(payload.to_vec())).await;
+    async fn handle_frame(&self, peer_id: String, frame: Frame) {
+        match frame {
+            Frame::Packet(packet) => {
+                self.deliver(&peer_id, vec![packet]).await;
             }
 
-            TYPE_CHAT => {
-                let text = String::from_utf8_lossy(payload).to_string();
+            Frame::Batch(packets) => {
+                self.deliver(&peer_id, packets).await;
+            }
 
-                let _ = tx.send(LanEvent::ChatMessage {
-                    from: peer_id,
-                    message: text,
+            Frame::Compressed { algo, data } => {
+                let inner = Compression::from_id(algo)
+                    .ok_or(anyhow!("Unknown compression: {}", algo))
+                    .and_then(|algo| compress::decompress(algo, &data))
+                    .and_then(|inner| Frame::decode(&Bytes::from(inner)));
+                match inner {
+                    Ok(frame @ (Frame::Packet(_) | Frame::Batch(_) | Frame::Chat(_) | Frame::ChatMessage(_))) => {
+                        Box::pin(self.handle_frame(peer_id, frame)).await;
+                    }
+                    Ok(other) => warn!("Unexpected compressed frame: {:?}", other),
+                    Err(e) => {
+                        warn!("Bad compressed frame: {}", e);
+                        self.count_bad_frame(&peer_id).await;
+                    }
+                }
+            }
+
+            Frame::Chat(text) => {
+                let to = ChatTarget::Peer(self.local_id.clone());
+                let message = ChatMessage::new(peer_id, to, text);
+                let _ = self.event_tx.send(LanEvent::ChatMessage(message)).await;
+            }
+
+            Frame::ChatMessage(ChatWire::Message(mut message)) => {
+                // The channel, not the payload, says who sent it.
+                message.from = peer_id.clone();
+                let ack = ChatWire::Ack { id: message.id.clone() };
+                if let Err(e) = self.send_control(&peer_id, Frame::ChatMessage(ack)).await {
+                    warn!("chat ack failed: {}", e);
+                }
+                let _ = self.event_tx.send(LanEvent::ChatMessage(message)).await;
+            }
+
+            Frame::ChatMessage(ChatWire::Ack { id }) => {
+                let _ = self.event_tx.send(LanEvent::ChatDelivered {
+                    peer_id,
+                    message_id: id,
                 }).await;
             }
 
-            _ => {
-                eprintln!("Unknown message type: {}", msg_type);
+            Frame::Hello(hello) => {
+                self.on_hello(peer_id, hello).await;
+            }
+
+            Frame::File(msg) => {
+                self.transfers.on_control(self, peer_id, msg).await;
+            }
+
+            Frame::FileChunk { id, offset, data } => {
+                self.transfers.on_chunk(self, peer_id, id, offset, data).await;
+            }
+
+            // Answered on the channel it came in on, so the round trip
+            // sees the same loss as game traffic.
+            Frame::Ping(seq) => {
+                if let Some(sender) = self.packet_sender(&peer_id).await {
+                    sender.try_push(Frame::Pong(seq).encode());
+                }
+            }
+
+            Frame::Pong(seq) => {
+                if let Some(tracker) = self.latency.lock().unwrap().get_mut(&peer_id) {
+                    tracker.on_pong(seq);
+                }
+            }
+
+            Frame::Throughput(msg) => {
+                self.throughput.on_control(self, peer_id, msg).await;
+            }
+
+            Frame::ThroughputData { id, seq, data } => {
+                self.throughput.on_data(self, &peer_id, id, seq, data.len()).await;
+            }
+
+            Frame::ThroughputEcho { id, seq } => {
+                self.throughput.on_echo(&peer_id, id, seq);
+            }
+
+            Frame::Gossip(msg) => {
+                self.gossip.on_message(self, peer_id, msg).await;
+            }
+
+            Frame::Routes(advert) => {
+                let changed = self.routes.write().await.on_advert(&peer_id, advert);
+                if changed {
+                    self.advertise_routes().await;
+                }
+            }
+
+            // Fragments are reassembled before frames get here.
+            Frame::Fragment { .. } => {}
+
+            Frame::Unknown(frame_type) => {
+                // Expected from peers speaking a newer protocol; those only
+                // use new frame types they saw us advertise.
+                let newer = self
+                    .peers
+                    .read()
+                    .await
+                    .get(&peer_id)
+                    .and_then(|c| c.hello.as_ref())
+                    .is_some_and(|h| h.protocol > PROTOCOL_VERSION);
+                if !newer {
+                    warn!("Unknown message type: {}", frame_type);
+                }
             }
         }
-        })
-    }));
+    }
+
+    async fn on_hello(&self, peer_id: String, hello: Hello) {
+        let (caps, compression) = hello.negotiate(self.link.compression);
+
+        let event = LanEvent::PeerHello {
+            peer_id: peer_id.clone(),
+            software: hello.software.clone(),
+            protocol: hello.protocol,
+        };
+
+        if let Some(peer) = self.peers.write().await.get_mut(&peer_id) {
+            peer.caps = caps;
+            peer.compression = compression;
+            peer.hello = Some(hello);
+        }
+
+        let _ = self.event_tx.send(event).await;
+
+        if caps.contains(Capabilities::FILE_TRANSFER) {
+            self.transfers.resume(self, &peer_id).await;
+        }
 
-        let mut channels = self.data_channels.write().await;
-        channels.insert(peer_id, dc_clone);
+        // The new peer learns who else is around, and they learn of it.
+        if caps.contains(Capabilities::GOSSIP) {
+            self.gossip.announce(self).await;
+        }
+        if caps.contains(Capabilities::ROUTING) {
+            self.advertise_routes().await;
+        }
     }
 
-    pub async fn route_and_send(&self, pkt: Vec<u8>) -> Result<()> {
-        let mut framed = Vec::with_capacity(1 + pkt.len());
+    async fn deliver(&self, peer_id: &str, packets: Vec<Bytes>) {
+        if let Some(stats) = self.link_stats(peer_id).await {
+            LinkStats::add(&stats.packets_in, packets.len());
+            LinkStats::add(&stats.raw_bytes_in, packets.iter().map(|p| p.len()).sum());
+        }
+
+        let learned = {
+            let routes = self.routes.read().await;
+            packets.iter().any(|p| routes.is_new(p, peer_id))
+        };
+        if learned {
+            let mut routes = self.routes.write().await;
+            for packet in &packets {
+                routes.learn(packet, peer_id);
+            }
+        }
+
+        let (packets, transit) = self.transit(peer_id, packets).await;
+        if !transit.is_empty() {
+            self.forward(transit).await;
+        }
+
+        for packet in packets {
+            let _ = self.event_tx.send(LanEvent::PacketFromPeer(packet.to_vec())).await;
+        }
+    }
 
-        framed.push(TYPE_PACKET);      // 🧠 header
-        framed.extend_from_slice(&pkt);
+    // Splits off the packets that only pass through here: those from a
+    // peer that routes, for an address another peer is closer to. Each is
+    // paired with that peer and has one hop less to live.
+    async fn transit(&self, peer_id: &str, packets: Vec<Bytes>) -> (Vec<Bytes>, Vec<(Bytes, String)>) {
+        let routing = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .is_some_and(|p| p.caps.contains(Capabilities::ROUTING));
+        if !routing {
+            return (packets, Vec::new());
+        }
 
-        let bytes = bytes::Bytes::from(framed);
+        let links = self.links().await;
+        let routes = self.routes.read().await;
+        let mut local = Vec::with_capacity(packets.len());
+        let mut transit = Vec::new();
+        for packet in packets {
+            let route = route::destination_addr(&packet).and_then(|dst| routes.next_hop(dst, &links));
+            let Some(route) = route else {
+                local.push(packet);
+                continue;
+            };
+            if route.peer_id == peer_id {
+                trace!(parent: &span(peer_id), "Dropping packet for {}: routed back", route.destination);
+            } else if let Some(packet) = route::decrement_ttl(&packet) {
+                transit.push((Bytes::from(packet), route.peer_id));
+            }
+        }
+        (local, transit)
+    }
 
-        for (_, chan) in self.data_channels.read().await.iter() {
-            chan.send(&bytes).await?;
+    // Sends packets on to the peer each is paired with. ICMP errors for
+    // those too big for the next link go back toward their source.
+    async fn forward(&self, transit: Vec<(Bytes, String)>) {
+        let (packets, hops): (Vec<Bytes>, Vec<Option<String>>) =
+            transit.into_iter().map(|(packet, hop)| (packet, Some(hop))).unzip();
+        let replies: Vec<Bytes> = self.send(packets, &hops).await.into_iter().map(Bytes::from).collect();
+        if !replies.is_empty() {
+            let hops = self.next_hops(&replies).await;
+            self.send(replies, &hops).await;
         }
+    }
 
+    async fn count_in(&self, peer_id: &str, wire_len: usize) {
+        if let Some(stats) = self.link_stats(peer_id).await {
+            LinkStats::add(&stats.frames_in, 1);
+            LinkStats::add(&stats.wire_bytes_in, wire_len);
+        }
+    }
+
+    async fn count_bad_frame(&self, peer_id: &str) {
+        if let Some(stats) = self.link_stats(peer_id).await {
+            LinkStats::add(&stats.bad_frames_in, 1);
+        }
+    }
+
+    async fn link_stats(&self, peer_id: &str) -> Option<Arc<LinkStats>> {
+        self.peers
+            .read()
+            .await
+            .get(peer_id)
+            .map(|c| c.stats.clone())
+    }
+
+    pub async fn stats(&self) -> HashMap<String, LinkStatsSnapshot> {
+        self.peers
+            .read()
+            .await
+            .iter()
+            .map(|(id, c)| (id.clone(), c.stats.snapshot()))
+            .collect()
+    }
+
+    /// Link, queue and transport figures for every peer, for the metrics
+    /// endpoint.
+    pub async fn metrics(&self) -> Vec<PeerMetrics> {
+        let peers = self.peers.read().await.clone();
+
+        let mut metrics = Vec::with_capacity(peers.len());
+        for (peer_id, peer) in peers {
+            let transport = peer.transport.stats().await;
+            let mut channels = Vec::new();
+            for sender in peer.senders() {
+                channels.push(ChannelMetrics {
+                    label: sender.channel().label().to_owned(),
+                    buffered: peer.transport.buffered(sender.channel()).await,
+                    queued: sender.len(),
+                    queue: sender.stats().snapshot(),
+                });
+            }
+            metrics.push(PeerMetrics {
+                state: peer.transport.state(),
+                transport: peer.transport.kind(),
+                link: peer.stats.snapshot(),
+                channels,
+                candidates: transport.candidates,
+                rtt_seconds: transport.rtt_seconds,
+                latency: self.latency(&peer_id),
+                peer_id,
+            });
+        }
+        metrics.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        metrics
+    }
+
+    /// Protocol and software versions of peers that sent a hello.
+    pub async fn versions(&self) -> HashMap<String, PeerVersion> {
+        self.peers
+            .read()
+            .await
+            .iter()
+            .filter_map(|(id, c)| {
+                let hello = c.hello.as_ref()?;
+                Some((id.clone(), PeerVersion {
+                    protocol: hello.protocol,
+                    software: hello.software.clone(),
+                    capabilities: c.caps,
+                }))
+            })
+            .collect()
+    }
+
+    /// Every peer connection, connected or not, sorted by id.
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        let mut versions = self.versions().await;
+        let routes = self.routes.read().await;
+        let mut peers: Vec<PeerInfo> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .map(|(id, peer)| PeerInfo {
+                id: id.clone(),
+                state: peer.transport.state(),
+                transport: peer.transport.kind(),
+                version: versions.remove(id),
+                addresses: routes.addresses(id),
+                fingerprint: self.fingerprints.lock().unwrap().get(id).cloned(),
+                latency: self.latency(id),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.id.cmp(&b.id));
+        peers
+    }
+
+    /// Closes the connection to `peer_id` and forgets it.
+    pub async fn remove_peer(&self, peer_id: &str) -> Result<()> {
+        let peer = self
+            .peers
+            .write()
+            .await
+            .remove(peer_id)
+            .ok_or(anyhow!("Peer not found"))?;
+
+        for sender in peer.senders() {
+            sender.close();
+        }
+
+        self.routes.write().await.forget_peer(peer_id);
+        self.fingerprints.lock().unwrap().remove(peer_id);
+        self.latency.lock().unwrap().remove(peer_id);
+        self.throughput.forget_peer(peer_id);
+        self.gossip.forget_peer(peer_id);
+
+        peer.transport.close().await?;
+        self.advertise_routes().await;
         Ok(())
-     }
+    }
+
+    /// Whether `peer_id` is banned, by id or by the fingerprint it
+    /// connected with.
+    pub fn is_banned(&self, peer_id: &str) -> bool {
+        let fingerprint = self.fingerprints.lock().unwrap().get(peer_id).cloned();
+        self.bans.lock().unwrap().is_banned(peer_id, fingerprint.as_deref())
+    }
 
-  pub async fn send_chat(&self, peer_id: &str, message: String) -> Result<()> {
-     let channels = self.data_channels.read().await;
+    /// Whether an offer or answer from `peer_id` carrying `sdp_json` would
+    /// be refused.
+    pub fn rejects(&self, peer_id: &str, sdp_json: &str) -> bool {
+        let fingerprint = serde_json::from_str::<RTCSessionDescription>(sdp_json)
+            .ok()
+            .and_then(|desc| ban::sdp_fingerprint(&desc.sdp));
+        self.bans.lock().unwrap().is_banned(peer_id, fingerprint.as_deref())
+    }
+
+    /// Bans a peer and closes every connection the ban covers. Banning by
+    /// id also records the fingerprint the peer is connected with, so it
+    /// cannot come back under another name.
+    pub async fn ban(&self, peer_id: Option<String>, fingerprint: Option<String>, reason: Option<String>) -> Result<()> {
+        if peer_id.is_none() && fingerprint.is_none() {
+            return Err(anyhow!("Nothing to ban"));
+        }
+        let fingerprint = fingerprint.or_else(|| {
+            let id = peer_id.as_ref()?;
+            self.fingerprints.lock().unwrap().get(id).cloned()
+        });
+        self.bans.lock().unwrap().ban(peer_id, fingerprint, reason)?;
 
-    let chan = channels
-        .get(peer_id)
-        .ok_or(anyhow!("Peer not found"))?;
+        let ids: Vec<String> = self.peers.read().await.keys().cloned().collect();
+        for id in ids {
+            if self.is_banned(&id) {
+                self.remove_peer(&id).await?;
+            }
+        }
+        Ok(())
+    }
 
-    let mut framed = Vec::with_capacity(1 + message.len());
+    /// Lifts the ban on a peer id or fingerprint.
+    pub fn unban(&self, key: &str) -> Result<bool> {
+        self.bans.lock().unwrap().unban(key)
+    }
 
-    framed.push(TYPE_CHAT);                 // 🧠 header
-    framed.extend_from_slice(message.as_bytes());
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().list()
+    }
 
-    let bytes = bytes::Bytes::from(framed);
+    /// Fingerprint of the certificate this router is known by.
+    pub fn fingerprint(&self) -> &str {
+        self.identity.fingerprint()
+    }
 
-    chan.send(&bytes).await?;
+    // Checks the fingerprint `peer_id` links with against the one it is
+    // trusted with. Errors when the link is to be refused.
+    fn verify(&self, peer_id: &str, fingerprint: Option<&str>) -> Result<Verdict> {
+        let trust = self.trust.lock().unwrap();
+        let verdict = trust.check(peer_id, fingerprint);
+        let allowed = trust.allows(&verdict);
+        let span = span(peer_id);
+        match verdict {
+            Verdict::Trusted => Ok(verdict),
+            Verdict::Unknown if allowed => Ok(verdict),
+            Verdict::Unknown => {
+                warn!(parent: &span, "Refused untrusted peer with {}", fingerprint.unwrap_or("no fingerprint"));
+                bail!("Peer {} is not trusted", peer_id)
+            }
+            Verdict::Mismatch { expected } => {
+                let got = fingerprint.unwrap_or("no fingerprint");
+                if allowed {
+                    warn!(parent: &span, "Fingerprint changed to {}, trusted with {}", got, expected);
+                    return Ok(Verdict::Mismatch { expected });
+                }
+                warn!(parent: &span, "Refused: fingerprint {} is not the trusted {}", got, expected);
+                bail!("Peer {} is trusted with another fingerprint", peer_id)
+            }
+        }
+    }
+
+    // Trusts a peer that just connected with the fingerprint DTLS or TLS
+    // proved it holds, unless it is trusted with one already. Not before:
+    // anyone can put a fingerprint in an offer.
+    fn trust_on_first_use(&self, peer_id: &str) {
+        let Some(fingerprint) = self.fingerprints.lock().unwrap().get(peer_id).cloned() else {
+            return;
+        };
+        let mut trust = self.trust.lock().unwrap();
+        if trust.mode() == TrustMode::Explicit || trust.check(peer_id, Some(&fingerprint)) != Verdict::Unknown {
+            return;
+        }
+        match trust.pin(peer_id, &fingerprint, TrustSource::FirstUse) {
+            Ok(()) => info!("Trusting {} from now on", fingerprint),
+            Err(e) => warn!("Trusted peer not saved: {}", e),
+        }
+    }
+
+    /// Trusts `peer_id` only with `fingerprint`, or with the one it is
+    /// connected with. A link under another fingerprint is closed.
+    pub async fn trust(&self, peer_id: &str, fingerprint: Option<String>) -> Result<()> {
+        let connected = self.fingerprints.lock().unwrap().get(peer_id).cloned();
+        let Some(fingerprint) = fingerprint.or_else(|| connected.clone()) else {
+            bail!("No fingerprint known for {}", peer_id);
+        };
+        let fingerprint = ban::normalize(&fingerprint);
+        self.trust.lock().unwrap().pin(peer_id, &fingerprint, TrustSource::Explicit)?;
+        if connected.is_some_and(|fp| fp != fingerprint) {
+            self.remove_peer(peer_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the fingerprint `peer_id` is trusted with.
+    pub fn untrust(&self, peer_id: &str) -> Result<bool> {
+        self.trust.lock().unwrap().unpin(peer_id)
+    }
+
+    pub fn trusted(&self) -> Vec<TrustedPeer> {
+        self.trust.lock().unwrap().list()
+    }
+
+    fn remember_fingerprint(&self, peer_id: &str, fingerprint: Option<String>) {
+        let mut fingerprints = self.fingerprints.lock().unwrap();
+        match fingerprint {
+            Some(fp) => fingerprints.insert(peer_id.to_owned(), fp),
+            None => fingerprints.remove(peer_id),
+        };
+    }
+
+    /// The best route to every address reachable over the links up.
+    pub async fn routes(&self) -> Vec<Route> {
+        let links = self.links().await;
+        self.routes.read().await.routes(&links)
+    }
+
+    /// Tells every neighbour that routes which addresses we reach and in
+    /// how many hops, so peers without a link between them can talk
+    /// through us.
+    pub async fn advertise_routes(&self) {
+        let links = self.links().await;
+        let neighbours = self.linked_peers().await;
+        let adverts: Vec<(String, RouteAdvert)> = {
+            let routes = self.routes.read().await;
+            neighbours
+                .into_iter()
+                .filter(|(_, caps)| caps.contains(Capabilities::ROUTING))
+                .map(|(peer_id, _)| {
+                    let advert = routes.advert_for(&peer_id, &links);
+                    (peer_id, advert)
+                })
+                .collect()
+        };
+        for (peer_id, advert) in adverts {
+            if let Err(e) = self.send_control(&peer_id, Frame::Routes(advert)).await {
+                debug!(parent: &span(&peer_id), "Routes not sent: {}", e);
+            }
+        }
+    }
+
+    // Peers with a link carrying traffic, and their smoothed round trip.
+    async fn links(&self) -> Links {
+        let connected: Vec<String> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, p)| p.transport.state() == LinkState::Connected)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let latency = self.latency.lock().unwrap();
+        connected
+            .into_iter()
+            .map(|id| {
+                let rtt = latency.get(&id).and_then(|t| t.snapshot().avg_rtt_ms);
+                (id, rtt)
+            })
+            .collect()
+    }
+
+    /// Sends `pkt` to the peer its route names, or to every peer when none
+    /// does. Returns an ICMP error to write back into
+    /// the device if the packet is too big for some peer's tunnel MTU.
+    pub async fn route_and_send(&self, pkt: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.route_batch(vec![pkt]).await?.pop())
+    }
+
+    /// Sends packets read from the device together, each to the peer its
+    /// route names or to every peer when none does, batching and
+    /// compressing them as each peer negotiated. Returns ICMP errors to
+    /// write back into the device for packets too big for some peer.
+    pub async fn route_batch(&self, packets: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let packets: Vec<Bytes> = packets.into_iter().map(Bytes::from).collect();
+        let hops = self.next_hops(&packets).await;
+        Ok(self.send(packets, &hops).await)
+    }
+
+    // The peer each packet goes to, or `None` for every peer.
+    async fn next_hops(&self, packets: &[Bytes]) -> Vec<Option<String>> {
+        let links = self.links().await;
+        let routes = self.routes.read().await;
+        packets
+            .iter()
+            .map(|pkt| {
+                let route = route::destination_addr(pkt).and_then(|dst| routes.next_hop(dst, &links));
+                route.map(|r| r.peer_id)
+            })
+            .collect()
+    }
+
+    // Queues each packet for the peer `hops` names, or for every peer. Peers
+    // that negotiated the same framing and get the same packets share the
+    // encoding. Returns ICMP errors for packets too big for some peer.
+    async fn send(&self, packets: Vec<Bytes>, hops: &[Option<String>]) -> Vec<Vec<u8>> {
+        let default_mtu = self.link.mtu.device_mtu();
+        let mut encoded = HashMap::new();
+        // Smallest tunnel MTU each packet exceeded, for its ICMP error.
+        let mut too_big: Vec<Option<u16>> = vec![None; packets.len()];
+
+        // Never waits: each peer's queue applies its own drop policy.
+        for (peer_id, peer) in self.peers.read().await.iter().filter(|(_, p)| p.is_up()) {
+            let stats = &peer.stats;
+            let framing = peer.framing(default_mtu);
+            // Whether any packet is for this peer alone.
+            let mut routed = false;
+            let mut fits = Vec::with_capacity(packets.len());
+            for ((pkt, hop), big) in packets.iter().zip(hops).zip(too_big.iter_mut()) {
+                match hop {
+                    Some(hop) if hop != peer_id => continue,
+                    Some(_) => routed = true,
+                    None => {}
+                }
+                if pkt.len() <= usize::from(framing.mtu) {
+                    fits.push(pkt.clone());
+                } else {
+                    *big = Some(big.map_or(framing.mtu, |m| m.min(framing.mtu)));
+                    LinkStats::add(&stats.too_big_out, 1);
+                }
+            }
+            if fits.is_empty() {
+                continue;
+            }
 
-    Ok(())
+            LinkStats::add(&stats.packets_out, fits.len());
+            LinkStats::add(&stats.raw_bytes_out, fits.iter().map(|p| p.len()).sum());
+
+            let (frames, batched) = encoded
+                .entry((framing, routed.then_some(peer_id)))
+                .or_insert_with(|| self.encode(fits, framing));
+
+            LinkStats::add(&stats.batched_packets_out, *batched);
+            for (frame, compressed) in frames.iter() {
+                LinkStats::add(&stats.frames_out, 1);
+                LinkStats::add(&stats.wire_bytes_out, frame.len());
+                if *compressed {
+                    LinkStats::add(&stats.compressed_frames_out, 1);
+                }
+                peer.packet.try_push(frame.clone());
+            }
+        }
+
+        packets
+            .iter()
+            .zip(too_big)
+            .filter_map(|(pkt, mtu)| mtu::too_big_reply(pkt, mtu?))
+            .collect()
+    }
+
+    /// Turns packets into wire frames using only what the peer supports:
+    /// batch frames, then compression, then fragmentation. Returns each
+    /// frame with whether it was compressed, and how many packets ended up
+    /// sharing a batch frame.
+    fn encode(&self, packets: Vec<Bytes>, framing: Framing) -> (Vec<(Bytes, bool)>, usize) {
+        let max = self.link.mtu.max_frame();
+        let batching = framing.caps.contains(Capabilities::BATCH) && self.link.batch.enabled();
+        let mut frames = Vec::new();
+        let mut batch: Vec<Bytes> = Vec::new();
+        let mut batch_len = 1;
+        let mut batched = 0;
+
+        let mut flush = |frames: &mut Vec<Frame>, batch: &mut Vec<Bytes>| {
+            match batch.len() {
+                0 => {}
+                // A batch of one is just a packet frame.
+                1 => frames.push(Frame::Packet(batch.remove(0))),
+                n => {
+                    frames.push(Frame::Batch(std::mem::take(batch)));
+                    batched += n;
+                }
+            }
+        };
+
+        for pkt in packets {
+            let entry = batch::LEN_PREFIX + pkt.len();
+            if !batching || 1 + entry > max {
+                frames.push(Frame::Packet(pkt));
+                continue;
+            }
+            if batch_len + entry > max {
+                flush(&mut frames, &mut batch);
+                batch_len = 1;
+            }
+            batch.push(pkt);
+            batch_len += entry;
+        }
+        flush(&mut frames, &mut batch);
+
+        let frames = frames
+            .into_iter()
+            .map(|frame| {
+                let encoded = frame.encode();
+                match compress::compress(framing.compression, &encoded) {
+                    Some(data) => {
+                        let compressed = Frame::Compressed {
+                            algo: framing.compression.id(),
+                            data: Bytes::from(data),
+                        };
+                        (compressed.encode(), true)
+                    }
+                    None => (encoded, false),
+                }
+            })
+            .flat_map(|(frame, compressed)| {
+                self.fragment(frame, framing.caps)
+                    .into_iter()
+                    .map(move |f| (f, compressed))
+            })
+            .collect();
+
+        (frames, batched)
+    }
+
+    /// Splits a frame that would not fit a single path packet into
+    /// fragment frames, when fragmentation is enabled and the peer
+    /// understands fragments.
+    fn fragment(&self, frame: Bytes, caps: Capabilities) -> Vec<Bytes> {
+        let max = self.link.mtu.max_frame();
+        if !self.link.mtu.fragment || !caps.contains(Capabilities::FRAGMENT) || frame.len() <= max {
+            return vec![frame];
+        }
+
+        let id = self.fragment_id.fetch_add(1, Ordering::Relaxed);
+        match fragment::split(id, &frame, max) {
+            Some(fragments) => fragments.iter().map(Frame::encode).collect(),
+            None => vec![frame],
+        }
+    }
+
+    /// Sends a chat message to one peer, as plain text if the peer
+    /// predates message ids.
+    pub async fn send_chat(&self, peer_id: &str, message: &ChatMessage) -> Result<()> {
+        let modern = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .is_some_and(|c| c.caps.contains(Capabilities::CHAT));
+
+        let frame = if modern {
+            Frame::ChatMessage(ChatWire::Message(message.clone()))
+        } else {
+            Frame::Chat(message.text.clone())
+        };
+        self.send_control(peer_id, frame).await
+    }
+
+    /// Sends a chat message to every connected peer and returns the ones
+    /// it was queued for.
+    pub async fn broadcast_chat(&self, message: &ChatMessage) -> Vec<String> {
+        let peers: Vec<String> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, p)| p.is_up())
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut reached = Vec::new();
+        for peer_id in peers {
+            match self.send_chat(&peer_id, message).await {
+                Ok(()) => reached.push(peer_id),
+                Err(e) => warn!(parent: &span(&peer_id), "chat send failed: {}", e),
+            }
+        }
+        reached
+    }
+
+    /// Tells every peer that gossips which peers we are linked to, so
+    /// those not linked to each other yet can be.
+    pub async fn gossip(&self) {
+        self.gossip.announce(self).await
+    }
+
+    /// Sends one ping to every peer that answers them and returns each
+    /// one's figures so far.
+    pub async fn ping_all(&self) -> Vec<(String, LatencySnapshot)> {
+        let targets: Vec<(String, ChannelSender)> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, p)| p.is_up() && p.caps.contains(Capabilities::PING))
+            .map(|(id, p)| (id.clone(), p.packet.clone()))
+            .collect();
+
+        let mut latency = self.latency.lock().unwrap();
+        targets
+            .into_iter()
+            .map(|(peer_id, sender)| {
+                let tracker = latency.entry(peer_id.clone()).or_default();
+                sender.try_push(Frame::Ping(tracker.next_ping()).encode());
+                (peer_id, tracker.snapshot())
+            })
+            .collect()
+    }
+
+    /// Pings `peer_id` `count` times, a ping interval apart, and reports
+    /// the round trip of each.
+    pub async fn ping(&self, peer_id: &str, count: u32) -> Result<PingReport> {
+        let caps = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .map(|c| c.caps)
+            .ok_or(anyhow!("Peer not found"))?;
+        if !caps.contains(Capabilities::PING) {
+            return Err(anyhow!("{} does not answer pings", peer_id));
+        }
+
+        let mut replies = Vec::new();
+        for i in 0..count {
+            let started = tokio::time::Instant::now();
+            let sender = self.packet_sender(peer_id).await.ok_or(anyhow!("Peer not found"))?;
+            let reply = {
+                let mut latency = self.latency.lock().unwrap();
+                let tracker = latency.entry(peer_id.to_owned()).or_default();
+                let seq = tracker.next_ping();
+                sender.try_push(Frame::Ping(seq).encode());
+                tracker.wait(seq)
+            };
+            let rtt = tokio::time::timeout(latency::LOSS_TIMEOUT, reply).await;
+            replies.push(rtt.ok().and_then(Result::ok).map(|d| d.as_secs_f64() * 1000.0));
+
+            if i + 1 < count {
+                tokio::time::sleep_until(started + latency::PING_INTERVAL).await;
+            }
+        }
+
+        Ok(PingReport {
+            peer_id: peer_id.to_owned(),
+            replies,
+            latency: self.latency(peer_id).unwrap_or_default(),
+        })
+    }
+
+    /// Runs a throughput test against `peer_id`, which answers it without
+    /// anyone's help.
+    pub async fn throughput_test(
+        &self,
+        peer_id: &str,
+        mode: ThroughputMode,
+        duration: Duration,
+        bitrate: Option<u64>,
+    ) -> Result<ThroughputReport> {
+        let supported = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .ok_or(anyhow!("Peer not found"))?
+            .caps
+            .contains(Capabilities::THROUGHPUT);
+        if !supported {
+            return Err(anyhow!("{} does not answer throughput tests", peer_id));
+        }
+
+        self.throughput.run(self, peer_id, mode, duration, bitrate).await
+    }
+
+    /// Queue a throughput test in `mode` sends on.
+    pub(crate) async fn throughput_sender(&self, peer_id: &str, mode: ThroughputMode) -> Result<ChannelSender> {
+        let peers = self.peers.read().await;
+        let peer = peers.get(peer_id).ok_or(anyhow!("Peer not found"))?;
+        Ok(match mode {
+            ThroughputMode::Reliable => peer.file.clone(),
+            ThroughputMode::Unreliable => peer.packet.clone(),
+        })
+    }
+
+    /// Largest frame that travels in a single path packet.
+    pub(crate) fn max_frame(&self) -> usize {
+        self.link.mtu.max_frame()
+    }
+
+    pub fn latency(&self, peer_id: &str) -> Option<LatencySnapshot> {
+        self.latency.lock().unwrap().get(peer_id).map(LatencyTracker::snapshot)
+    }
+
+    async fn packet_sender(&self, peer_id: &str) -> Option<ChannelSender> {
+        Some(self.peers.read().await.get(peer_id)?.packet.clone())
+    }
+
+    /// Queues a frame on the peer's reliable control channel.
+    pub(crate) async fn send_control(&self, peer_id: &str, frame: Frame) -> Result<()> {
+        let sender = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .map(|p| p.control.clone())
+            .ok_or(anyhow!("Peer not found"))?;
+
+        sender.push(frame.encode()).await
+    }
+
+    pub(crate) async fn send_file_chunk(&self, peer_id: &str, frame: Frame) -> Result<()> {
+        let sender = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .map(|p| p.file.clone())
+            .ok_or(anyhow!("Peer not found"))?;
+
+        sender.push(frame.encode()).await
+    }
+
+    /// Offers a file to a peer and returns the transfer id. Data is sent
+    /// once the peer accepts.
+    pub async fn send_file(&self, peer_id: &str, path: PathBuf) -> Result<u64> {
+        let supported = self
+            .peers
+            .read()
+            .await
+            .get(peer_id)
+            .is_some_and(|c| c.caps.contains(Capabilities::FILE_TRANSFER));
+        if !supported {
+            return Err(anyhow!("{} does not support file transfer", peer_id));
+        }
+
+        self.transfers.offer(self, peer_id, path).await
+    }
+
+    pub async fn accept_file(&self, peer_id: &str, transfer_id: u64, dest: PathBuf) -> Result<()> {
+        self.transfers.accept(self, peer_id, transfer_id, dest).await
+    }
+}
+
+#[cfg(test)]
+impl PeerManager {
+    /// A manager that keeps nothing on disk and drops its events, for
+    /// tests.
+    pub(crate) async fn in_memory(local_id: &str, trust: TrustMode) -> Self {
+        let (event_tx, _) = mpsc::channel(16);
+        let address = IpAddr::V4(std::net::Ipv4Addr::new(10, 10, 0, 2));
+        let trust = TrustList::in_memory(trust);
+        let identity = Identity::ephemeral().unwrap();
+        Self::new(local_id.into(), address, event_tx, LinkConfig::default(), BanList::in_memory(), trust, identity)
+            .await
+            .unwrap()
     }
 }
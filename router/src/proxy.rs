@@ -0,0 +1,394 @@
+use anyhow::{Result, bail};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::forward;
+use crate::netstack::Netstack;
+
+// Longest request head the HTTP proxy reads.
+const MAX_HEAD: usize = 16 * 1024;
+
+// SOCKS5 constants, from RFC 1928.
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Runs a SOCKS5 proxy on `addr` into the virtual network. Only CONNECT,
+/// without authentication, to IPv4 addresses; there is no DNS on the
+/// virtual network, so names have to be addresses.
+pub async fn serve_socks(addr: SocketAddr, stack: Netstack) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("SOCKS5 proxy on {}", listener.local_addr()?);
+
+    loop {
+        let (client, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                forward::accept_failed("SOCKS5 proxy", e).await;
+                continue;
+            }
+        };
+        let stack = stack.clone();
+        tokio::spawn(async move {
+            if let Err(e) = socks(client, &stack).await {
+                debug!(%remote, "SOCKS5 connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn socks(mut client: TcpStream, stack: &Netstack) -> Result<()> {
+    let target = socks_request(&mut client).await?;
+    let unbound = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut upstream = match stack.connect(target).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let code = match e.kind() {
+                io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+                io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+                io::ErrorKind::ConnectionRefused => REP_REFUSED,
+                _ => REP_FAILURE,
+            };
+            client.write_all(&socks_reply(code, unbound)).await?;
+            bail!("{}: {}", target, e);
+        }
+    };
+    client.write_all(&socks_reply(REP_SUCCEEDED, upstream.local_addr())).await?;
+    debug!("SOCKS5 connection to {}", target);
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+// Takes the client through method selection and reads its request, turning
+// down what the proxy does not do. Returns where to connect.
+async fn socks_request<S>(client: &mut S) -> Result<SocketAddrV4>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        bail!("not SOCKS5");
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        client.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        bail!("client insists on authentication");
+    }
+    client.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    let [_, command, _, atyp] = request;
+    let ip = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await?;
+            Some(Ipv4Addr::from(ip))
+        }
+        ATYP_DOMAIN => {
+            let len = client.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            client.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).parse().ok()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip).await?;
+            None
+        }
+        _ => bail!("unknown address type {}", atyp),
+    };
+    let port = client.read_u16().await?;
+
+    let unbound = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    if command != CMD_CONNECT {
+        client.write_all(&socks_reply(REP_COMMAND_NOT_SUPPORTED, unbound)).await?;
+        bail!("unsupported command {}", command);
+    }
+    let Some(ip) = ip else {
+        client.write_all(&socks_reply(REP_ADDRESS_NOT_SUPPORTED, unbound)).await?;
+        bail!("only IPv4 addresses are on the virtual network");
+    };
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+fn socks_reply(code: u8, bound: SocketAddrV4) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VERSION, code, 0, ATYP_IPV4];
+    reply.extend_from_slice(&bound.ip().octets());
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    reply
+}
+
+/// Runs an HTTP proxy on `addr` into the virtual network: CONNECT tunnels
+/// and plain requests with an absolute URI, to IPv4 addresses.
+pub async fn serve_http(addr: SocketAddr, stack: Netstack) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP proxy on {}", listener.local_addr()?);
+
+    loop {
+        let (client, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                forward::accept_failed("HTTP proxy", e).await;
+                continue;
+            }
+        };
+        let stack = stack.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http(client, &stack).await {
+                debug!(%remote, "HTTP proxy connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn http(client: TcpStream, stack: &Netstack) -> Result<()> {
+    // Anything the client sent past the head stays in the reader and is
+    // passed on with the rest of the stream.
+    let mut client = BufReader::new(client);
+    let mut head = Vec::new();
+    loop {
+        let line = read_line(&mut client, &mut head).await?;
+        if line.is_empty() {
+            bail!("connection closed mid-request");
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let request = match HttpRequest::parse(&String::from_utf8_lossy(&head)) {
+        Ok(request) => request,
+        Err((status, body)) => return respond(client.get_mut(), status, body).await,
+    };
+    let target = request.target;
+
+    let mut upstream = match stack.connect(target).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let status = match e.kind() {
+                io::ErrorKind::NetworkUnreachable => "403 Forbidden",
+                io::ErrorKind::TimedOut => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+            respond(client.get_mut(), status, &format!("{}: {}", target, e)).await?;
+            bail!("{}: {}", target, e);
+        }
+    };
+    debug!("HTTP proxy connection to {}", target);
+
+    match request.forward {
+        None => {
+            client
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+        }
+        Some(head) => upstream.write_all(head.as_bytes()).await?,
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// What an HTTP proxy client asked for.
+#[derive(Debug, PartialEq, Eq)]
+struct HttpRequest {
+    target: SocketAddrV4,
+    /// The head to send on for a plain request; `None` for a CONNECT
+    /// tunnel.
+    forward: Option<String>,
+}
+
+impl HttpRequest {
+    // Parses a request head, or returns the status and message to turn
+    // it down with.
+    fn parse(head: &str) -> Result<Self, (&'static str, &'static str)> {
+        let mut lines = head.lines();
+        let request = lines.next().unwrap_or_default();
+        let mut parts = request.split_whitespace();
+        let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(("400 Bad Request", "malformed request"));
+        };
+
+        let connect = method.eq_ignore_ascii_case("CONNECT");
+        let (authority, path) = if connect {
+            (uri, "")
+        } else if let Some(rest) = uri.strip_prefix("http://") {
+            rest.find('/').map_or((rest, "/"), |at| rest.split_at(at))
+        } else {
+            return Err(("400 Bad Request", "absolute http:// URI expected"));
+        };
+        let default_port = if connect { 443 } else { 80 };
+        let Some(target) = parse_authority(authority, default_port) else {
+            return Err(("502 Bad Gateway", "only IPv4 addresses are on the virtual network"));
+        };
+        if connect {
+            return Ok(Self { target, forward: None });
+        }
+
+        // The connection belongs to one origin, so the client must not
+        // reuse it for another.
+        let mut forward = format!("{} {} {}\r\n", method, path, version);
+        for header in lines.take_while(|line| !line.is_empty()) {
+            let name = header.split(':').next().unwrap_or_default().trim();
+            if name.to_ascii_lowercase().starts_with("proxy-") || name.eq_ignore_ascii_case("connection") {
+                continue;
+            }
+            forward.push_str(header);
+            forward.push_str("\r\n");
+        }
+        forward.push_str("Connection: close\r\n\r\n");
+        Ok(Self { target, forward: Some(forward) })
+    }
+}
+
+// Appends the next line to `head` and returns it, failing once the head
+// grows past `MAX_HEAD`.
+async fn read_line(client: &mut BufReader<TcpStream>, head: &mut Vec<u8>) -> Result<String> {
+    let mut line = Vec::new();
+    let mut limited = (&mut *client).take((MAX_HEAD - head.len()) as u64);
+    limited.read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\n") && head.len() + line.len() >= MAX_HEAD {
+        bail!("request head too long");
+    }
+    head.extend_from_slice(&line);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+async fn respond(client: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        body.len() + 1,
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+// `host[:port]` with an IPv4 address for the host.
+fn parse_authority(authority: &str, default_port: u16) -> Option<SocketAddrV4> {
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some(SocketAddrV4::new(host.parse().ok()?, port.parse().ok()?)),
+        None => Some(SocketAddrV4::new(authority.parse().ok()?, default_port)),
+    }
+}
+
+/// Where the SOCKS5 proxy listens in userspace mode unless told otherwise.
+pub fn default_socks() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 1080))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Runs the SOCKS5 handshake against `sent` and returns the outcome and
+    // everything the proxy wrote back.
+    async fn socks(sent: &[u8]) -> (Result<SocketAddrV4>, Vec<u8>) {
+        let mut written = Vec::new();
+        let result = socks_request(&mut tokio::io::join(sent, &mut written)).await;
+        (result, written)
+    }
+
+    #[tokio::test]
+    async fn socks_connect_to_ipv4() {
+        let sent = [5, 2, 0x02, NO_AUTH, 5, CMD_CONNECT, 0, ATYP_IPV4, 10, 10, 0, 3, 0x1f, 0x90];
+        let (target, written) = socks(&sent).await;
+        assert_eq!(target.unwrap(), "10.10.0.3:8080".parse().unwrap());
+        assert_eq!(written, [SOCKS_VERSION, NO_AUTH]);
+    }
+
+    #[tokio::test]
+    async fn socks_connect_to_an_address_as_a_name() {
+        let mut sent = vec![5, 1, NO_AUTH, 5, CMD_CONNECT, 0, ATYP_DOMAIN, 9];
+        sent.extend_from_slice(b"10.10.0.3");
+        sent.extend_from_slice(&22u16.to_be_bytes());
+        assert_eq!(socks(&sent).await.0.unwrap(), "10.10.0.3:22".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn socks_refuses_what_it_cannot_do() {
+        let refusal = |code| [&[SOCKS_VERSION, NO_AUTH][..], &socks_reply(code, "0.0.0.0:0".parse().unwrap())].concat();
+
+        let (result, written) = socks(&[5, 1, 0x02]).await;
+        assert!(result.is_err());
+        assert_eq!(written, [SOCKS_VERSION, NO_ACCEPTABLE_METHOD]);
+
+        // BIND
+        let (result, written) = socks(&[5, 1, NO_AUTH, 5, 0x02, 0, ATYP_IPV4, 10, 10, 0, 3, 0, 80]).await;
+        assert!(result.is_err());
+        assert_eq!(written, refusal(REP_COMMAND_NOT_SUPPORTED));
+
+        let mut sent = vec![5, 1, NO_AUTH, 5, CMD_CONNECT, 0, ATYP_IPV6];
+        sent.extend_from_slice(&[0; 16]);
+        sent.extend_from_slice(&[0, 80]);
+        let (result, written) = socks(&sent).await;
+        assert!(result.is_err());
+        assert_eq!(written, refusal(REP_ADDRESS_NOT_SUPPORTED));
+
+        let mut sent = vec![5, 1, NO_AUTH, 5, CMD_CONNECT, 0, ATYP_DOMAIN, 11];
+        sent.extend_from_slice(b"example.com");
+        sent.extend_from_slice(&[0, 80]);
+        assert_eq!(socks(&sent).await.1, refusal(REP_ADDRESS_NOT_SUPPORTED));
+
+        assert!(socks(&[4, 1, 0, 80, 10, 10, 0, 3, 0]).await.0.is_err());
+        assert!(socks(&[5, 1, NO_AUTH, 5, CMD_CONNECT, 0, 0x09]).await.0.is_err());
+        assert!(socks(&[5, 1, NO_AUTH, 5, CMD_CONNECT, 0, ATYP_IPV4, 10, 10]).await.0.is_err());
+    }
+
+    #[test]
+    fn http_connect() {
+        let request = HttpRequest::parse("CONNECT 10.10.0.3:8443 HTTP/1.1\r\nHost: 10.10.0.3:8443\r\n\r\n").unwrap();
+        assert_eq!(request, HttpRequest { target: "10.10.0.3:8443".parse().unwrap(), forward: None });
+        let request = HttpRequest::parse("connect 10.10.0.3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.target, "10.10.0.3:443".parse().unwrap());
+    }
+
+    #[test]
+    fn http_plain_request() {
+        let head = "GET http://10.10.0.3:8080/index.html?q=1 HTTP/1.1\r\n\
+                    Host: 10.10.0.3:8080\r\n\
+                    Proxy-Authorization: Basic Zm9v\r\n\
+                    Connection: keep-alive\r\n\
+                    Accept: */*\r\n\r\n";
+        let request = HttpRequest::parse(head).unwrap();
+        assert_eq!(request.target, "10.10.0.3:8080".parse().unwrap());
+        assert_eq!(
+            request.forward.unwrap(),
+            "GET /index.html?q=1 HTTP/1.1\r\nHost: 10.10.0.3:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+
+        let request = HttpRequest::parse("HEAD http://10.10.0.3 HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.target, "10.10.0.3:80".parse().unwrap());
+        assert_eq!(request.forward.unwrap(), "HEAD / HTTP/1.0\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn http_refuses_bad_requests() {
+        let status = |head: &str| HttpRequest::parse(head).unwrap_err().0;
+        assert_eq!(status("GET\r\n\r\n"), "400 Bad Request");
+        assert_eq!(status("GET /index.html HTTP/1.1\r\n\r\n"), "400 Bad Request");
+        assert_eq!(status("GET https://10.10.0.3/ HTTP/1.1\r\n\r\n"), "400 Bad Request");
+        assert_eq!(status("CONNECT example.com:443 HTTP/1.1\r\n\r\n"), "502 Bad Gateway");
+        assert_eq!(status("CONNECT [fd00::1]:443 HTTP/1.1\r\n\r\n"), "502 Bad Gateway");
+        assert_eq!(status("CONNECT 10.10.0.3:http HTTP/1.1\r\n\r\n"), "502 Bad Gateway");
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::peer;
+use crate::transport::{Channel, ChannelState, PeerTransport};
+
+// Stop handing data to the transport once this much is buffered below it,
+// and resume once it drains.
+const HIGH_WATERMARK: usize = 1024 * 1024;
+
+// Upper bound on a single wait for the transport to drain, so a missed
+// notification can never wedge the sender task.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+const OPEN_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy)]
+pub enum DropPolicy {
+    /// Real-time traffic: evict the oldest entry when the queue is full and
+    /// discard entries that waited longer than `max_age`.
+    DropOldest { max_age: Duration },
+    /// Reliable traffic: producers wait for room in the queue.
+    Block,
+}
+
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub sent: AtomicU64,
+    pub sent_bytes: AtomicU64,
+    pub dropped_full: AtomicU64,
+    pub dropped_stale: AtomicU64,
+    pub send_errors: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueueStatsSnapshot {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub dropped_full: u64,
+    pub dropped_stale: u64,
+    pub send_errors: u64,
+}
+
+impl QueueStats {
+    pub fn snapshot(&self) -> QueueStatsSnapshot {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        QueueStatsSnapshot {
+            sent: get(&self.sent),
+            sent_bytes: get(&self.sent_bytes),
+            dropped_full: get(&self.dropped_full),
+            dropped_stale: get(&self.dropped_stale),
+            send_errors: get(&self.send_errors),
+        }
+    }
+}
+
+struct Queued {
+    data: Bytes,
+    queued_at: Instant,
+}
+
+struct Shared {
+    peer_id: String,
+    queue: Mutex<VecDeque<Queued>>,
+    capacity: usize,
+    policy: DropPolicy,
+    not_empty: Notify,
+    not_full: Notify,
+    closed: AtomicBool,
+    stats: QueueStats,
+}
+
+/// Bounded outbound queue in front of one channel of a peer's transport,
+/// drained by its own task so a slow or broken peer only ever delays
+/// itself.
+#[derive(Clone)]
+pub struct ChannelSender {
+    shared: Arc<Shared>,
+    channel: Channel,
+}
+
+impl ChannelSender {
+    pub fn spawn(
+        peer_id: String,
+        transport: Arc<dyn PeerTransport>,
+        channel: Channel,
+        capacity: usize,
+        policy: DropPolicy,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            peer_id,
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            not_empty: Notify::new(),
+            not_full: Notify::new(),
+            closed: AtomicBool::new(false),
+            stats: QueueStats::default(),
+        });
+
+        tokio::spawn(run(transport, channel, shared.clone()));
+
+        Self { shared, channel }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.shared.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Enqueues without waiting. With `DropOldest` the oldest entry makes
+    /// room; with `Block` the new entry is dropped and `false` returned.
+    pub fn try_push(&self, data: Bytes) -> bool {
+        if self.shared.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= self.shared.capacity {
+            self.shared.stats.dropped_full.fetch_add(1, Ordering::Relaxed);
+            match self.shared.policy {
+                DropPolicy::DropOldest { .. } => {
+                    queue.pop_front();
+                }
+                DropPolicy::Block => return false,
+            }
+        }
+        queue.push_back(Queued {
+            data,
+            queued_at: Instant::now(),
+        });
+        drop(queue);
+
+        self.shared.not_empty.notify_one();
+        true
+    }
+
+    /// Enqueues, waiting for room when the queue is full.
+    pub async fn push(&self, data: Bytes) -> Result<()> {
+        loop {
+            let notified = self.shared.not_full.notified();
+
+            if self.shared.closed.load(Ordering::Relaxed) {
+                return Err(anyhow!("Channel to {} closed", self.shared.peer_id));
+            }
+
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.len() < self.shared.capacity {
+                    queue.push_back(Queued {
+                        data,
+                        queued_at: Instant::now(),
+                    });
+                    drop(queue);
+                    self.shared.not_empty.notify_one();
+                    return Ok(());
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        self.shared.not_empty.notify_one();
+        self.shared.not_full.notify_waiters();
+    }
+}
+
+async fn next(shared: &Shared) -> Option<Queued> {
+    loop {
+        let notified = shared.not_empty.notified();
+
+        if shared.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let item = shared.queue.lock().unwrap().pop_front();
+        if let Some(item) = item {
+            shared.not_full.notify_waiters();
+            return Some(item);
+        }
+
+        notified.await;
+    }
+}
+
+fn is_stale(shared: &Shared, item: &Queued) -> bool {
+    match shared.policy {
+        DropPolicy::DropOldest { max_age } => item.queued_at.elapsed() > max_age,
+        DropPolicy::Block => false,
+    }
+}
+
+async fn run(transport: Arc<dyn PeerTransport>, channel: Channel, shared: Arc<Shared>) {
+    let mut failing = false;
+
+    while let Some(item) = next(&shared).await {
+        while transport.channel_state(channel) == ChannelState::Connecting {
+            if shared.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            tokio::time::sleep(OPEN_POLL).await;
+        }
+
+        while transport.buffered(channel).await > HIGH_WATERMARK {
+            if shared.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            let _ = tokio::time::timeout(DRAIN_POLL, transport.drained(channel)).await;
+        }
+
+        if is_stale(&shared, &item) {
+            shared.stats.dropped_stale.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        match transport.send(channel, &item.data).await {
+            Ok(n) => {
+                shared.stats.sent.fetch_add(1, Ordering::Relaxed);
+                shared.stats.sent_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                failing = false;
+            }
+            Err(e) => {
+                shared.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                // Report the first error of a run, not every packet after it.
+                if !failing {
+                    warn!(parent: &peer::span(&shared.peer_id), "Send on {} failed: {}", channel.label(), e);
+                    failing = true;
+                }
+                if transport.channel_state(channel) == ChannelState::Closed {
+                    break;
+                }
+            }
+        }
+    }
+
+    shared.closed.store(true, Ordering::Relaxed);
+    shared.not_full.notify_waiters();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::LinkEvents;
+    use crate::transport::loopback::{self, LoopbackTransport};
+    use tokio::sync::mpsc;
+
+    const AGED: DropPolicy = DropPolicy::DropOldest { max_age: Duration::from_secs(60) };
+
+    // A sender on one end of a loopback pair that is not up yet, so what
+    // is pushed stays queued until `open` is called on the pair.
+    fn sender(capacity: usize, policy: DropPolicy) -> (ChannelSender, Pair) {
+        let (near, far) = loopback::pair();
+        let near = Arc::new(near);
+        let sender = ChannelSender::spawn("bob".into(), near.clone(), Channel::Control, capacity, policy);
+        (sender, Pair { near, far })
+    }
+
+    struct Pair {
+        near: Arc<LoopbackTransport>,
+        far: LoopbackTransport,
+    }
+
+    impl Pair {
+        // Brings the link up and hands back what arrives at the far end.
+        fn open(&self) -> mpsc::UnboundedReceiver<Bytes> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.far.start(LinkEvents::new(
+                move |_, data| {
+                    let _ = tx.send(data);
+                    async {}
+                },
+                |_| async {},
+            ));
+            self.near.start(LinkEvents::new(|_, _| async {}, |_| async {}));
+            rx
+        }
+    }
+
+    async fn received(rx: &mut mpsc::UnboundedReceiver<Bytes>, count: usize) -> Vec<Bytes> {
+        let mut got = Vec::new();
+        while got.len() < count {
+            let data = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+            got.push(data.unwrap());
+        }
+        got
+    }
+
+    // Long enough for the sender task to take what it can.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_the_oldest() {
+        let (sender, pair) = sender(2, AGED);
+        for data in ["1", "2", "3", "4"] {
+            assert!(sender.try_push(Bytes::from(data)));
+        }
+        assert_eq!(sender.len(), 2);
+        assert_eq!(sender.stats().snapshot().dropped_full, 2);
+
+        let mut rx = pair.open();
+        assert_eq!(received(&mut rx, 2).await, vec![Bytes::from("3"), Bytes::from("4")]);
+        settle().await;
+        assert_eq!(sender.stats().snapshot().sent, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_discards_stale_entries() {
+        let (sender, pair) = sender(4, DropPolicy::DropOldest { max_age: Duration::from_millis(1) });
+        assert!(sender.try_push(Bytes::from("old")));
+        settle().await;
+
+        let mut rx = pair.open();
+        settle().await;
+        assert!(sender.try_push(Bytes::from("new")));
+        assert_eq!(received(&mut rx, 1).await, vec![Bytes::from("new")]);
+        assert_eq!(sender.stats().snapshot().dropped_stale, 1);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, pair) = sender(1, DropPolicy::Block);
+        assert!(sender.try_push(Bytes::from("1")));
+        // The sender task holds the first entry until the link is up,
+        // leaving room for one more.
+        settle().await;
+        assert!(sender.is_empty());
+        assert!(sender.try_push(Bytes::from("2")));
+        assert!(!sender.try_push(Bytes::from("lost")));
+        assert_eq!(sender.stats().snapshot().dropped_full, 1);
+
+        let waiting = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.push(Bytes::from("3")).await }
+        });
+        settle().await;
+        assert!(!waiting.is_finished());
+
+        let mut rx = pair.open();
+        waiting.await.unwrap().unwrap();
+        let sent = received(&mut rx, 3).await;
+        assert_eq!(sent, vec![Bytes::from("1"), Bytes::from("2"), Bytes::from("3")]);
+    }
+
+    #[tokio::test]
+    async fn closing_wakes_and_refuses_producers() {
+        let (sender, _pair) = sender(1, DropPolicy::Block);
+        assert!(sender.try_push(Bytes::from("1")));
+        settle().await;
+        assert!(sender.try_push(Bytes::from("2")));
+
+        let waiting = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.push(Bytes::from("3")).await }
+        });
+        settle().await;
+        sender.close();
+        let result = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert!(result.is_err());
+        assert!(!sender.try_push(Bytes::from("4")));
+        assert!(sender.push(Bytes::from("5")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_link_closes() {
+        let (sender, pair) = sender(4, DropPolicy::Block);
+        let _rx = pair.open();
+        pair.near.close().await.unwrap();
+        assert!(sender.try_push(Bytes::from("1")));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sender.push(Bytes::from("more")).await.is_ok() {
+                settle().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(sender.stats().snapshot().send_errors >= 1);
+    }
+}
@@ -0,0 +1,386 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// How often every neighbour hears which addresses we route to, besides
+/// whenever one says hello or what we heard changes.
+pub const ADVERT_INTERVAL: Duration = Duration::from_secs(10);
+
+// A neighbour that has not advertised for this long is not routing for us.
+const ADVERT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hop count that means unreachable. Bounds how long a route that went
+/// away can bounce between peers counting up.
+pub const INFINITY: u8 = 16;
+
+/// A virtual address and the peer packets for it go to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Route {
+    pub destination: IpAddr,
+    /// The next hop.
+    pub peer_id: String,
+    /// 1 when the address is behind the next hop itself.
+    pub hops: u8,
+}
+
+/// A distance vector, sent as JSON on the control channel: each address
+/// the sender routes to and how many hops away from it that is, 0 for its
+/// own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteAdvert {
+    pub routes: Vec<(IpAddr, u8)>,
+}
+
+/// Peers with a link up and their smoothed round trip in milliseconds,
+/// which breaks ties between routes of the same length.
+pub type Links = HashMap<String, Option<f64>>;
+
+struct Advert {
+    routes: HashMap<IpAddr, u8>,
+    at: Instant,
+}
+
+/// Where packets for each virtual address go. Peers that route advertise
+/// what they reach; for those that do not, addresses are learned from the
+/// source of packets each one sends, the way a switch learns MAC
+/// addresses.
+pub struct RouteTable {
+    local: Vec<IpAddr>,
+    learned: HashMap<IpAddr, String>,
+    adverts: HashMap<String, Advert>,
+}
+
+impl RouteTable {
+    /// A table for a router that owns `local`.
+    pub fn new(local: Vec<IpAddr>) -> Self {
+        Self { local, learned: HashMap::new(), adverts: HashMap::new() }
+    }
+
+    /// Whether `packet` from `peer_id` would add or move a learned route.
+    pub fn is_new(&self, packet: &[u8], peer_id: &str) -> bool {
+        learnable(packet).is_some_and(|src| self.learned.get(&src).is_none_or(|p| p != peer_id))
+    }
+
+    pub fn learn(&mut self, packet: &[u8], peer_id: &str) {
+        if let Some(src) = learnable(packet) {
+            self.learned.insert(src, peer_id.to_owned());
+        }
+    }
+
+    /// Takes the vector `peer_id` advertised, replacing the last one.
+    /// Returns whether it says anything new.
+    pub fn on_advert(&mut self, peer_id: &str, advert: RouteAdvert) -> bool {
+        let routes: HashMap<IpAddr, u8> = advert
+            .routes
+            .into_iter()
+            .filter(|(addr, hops)| *hops < INFINITY && routable(*addr) && !self.local.contains(addr))
+            .collect();
+        let advert = Advert { routes, at: Instant::now() };
+        match self.adverts.insert(peer_id.to_owned(), advert) {
+            Some(old) => old.routes != self.adverts[peer_id].routes || old.at.elapsed() > ADVERT_TIMEOUT,
+            None => true,
+        }
+    }
+
+    pub fn forget_peer(&mut self, peer_id: &str) {
+        self.learned.retain(|_, p| p != peer_id);
+        self.adverts.remove(peer_id);
+    }
+
+    /// The best way to `destination` over the links up: fewest hops, then
+    /// what a neighbour advertised over what we learned, then the lowest
+    /// round trip. `None` for our own addresses and ones nobody reaches.
+    pub fn next_hop(&self, destination: IpAddr, links: &Links) -> Option<Route> {
+        if self.local.contains(&destination) {
+            return None;
+        }
+
+        let advertised = self.adverts.iter().filter_map(|(peer_id, advert)| {
+            let hops = *advert.routes.get(&destination)?;
+            (advert.at.elapsed() <= ADVERT_TIMEOUT).then_some((peer_id, hops + 1, false))
+        });
+        // A neighbour that advertises the address knows better than the
+        // source of the packets it passes on.
+        let learned = self
+            .learned
+            .get(&destination)
+            .filter(|peer_id| !self.advertises(peer_id, destination))
+            .map(|peer_id| (peer_id, 1, true));
+
+        advertised
+            .chain(learned)
+            .filter(|(peer_id, hops, _)| *hops < INFINITY && links.contains_key(peer_id.as_str()))
+            .min_by(|a, b| {
+                let rtt = |peer_id: &str| links[peer_id].unwrap_or(f64::MAX);
+                (a.1, a.2)
+                    .cmp(&(b.1, b.2))
+                    .then(rtt(a.0).total_cmp(&rtt(b.0)))
+                    .then(a.0.cmp(b.0))
+            })
+            .map(|(peer_id, hops, _)| Route { destination, peer_id: peer_id.clone(), hops })
+    }
+
+    /// Addresses `peer_id` owns: those it advertises as its own, or that
+    /// packets from it came from.
+    pub fn addresses(&self, peer_id: &str) -> Vec<IpAddr> {
+        let own = self
+            .adverts
+            .get(peer_id)
+            .into_iter()
+            .flat_map(|advert| advert.routes.iter())
+            .filter(|(_, hops)| **hops == 0)
+            .map(|(addr, _)| *addr);
+        let learned = self
+            .learned
+            .iter()
+            .filter(|(addr, p)| *p == peer_id && !self.advertises(peer_id, **addr))
+            .map(|(addr, _)| *addr);
+        let addrs: BTreeSet<IpAddr> = own.chain(learned).collect();
+        addrs.into_iter().collect()
+    }
+
+    /// The best route to every address reachable over the links up.
+    pub fn routes(&self, links: &Links) -> Vec<Route> {
+        let destinations: BTreeSet<IpAddr> = self
+            .adverts
+            .values()
+            .flat_map(|advert| advert.routes.keys())
+            .chain(self.learned.keys())
+            .copied()
+            .collect();
+        destinations
+            .into_iter()
+            .filter_map(|destination| self.next_hop(destination, links))
+            .collect()
+    }
+
+    /// What to tell `peer_id`: our own addresses, and everything we route
+    /// to with its hop count. Routes through `peer_id` itself go back as
+    /// unreachable, so it never counts on one that leads through it.
+    pub fn advert_for(&self, peer_id: &str, links: &Links) -> RouteAdvert {
+        let own = self.local.iter().map(|addr| (*addr, 0));
+        let routed = self.routes(links).into_iter().map(|route| {
+            let hops = if route.peer_id == peer_id { INFINITY } else { route.hops };
+            (route.destination, hops)
+        });
+        RouteAdvert { routes: own.chain(routed).collect() }
+    }
+
+    fn advertises(&self, peer_id: &str, destination: IpAddr) -> bool {
+        self.adverts.get(peer_id).is_some_and(|advert| advert.routes.contains_key(&destination))
+    }
+}
+
+fn learnable(packet: &[u8]) -> Option<IpAddr> {
+    source_addr(packet).filter(|a| routable(*a))
+}
+
+fn routable(addr: IpAddr) -> bool {
+    !addr.is_unspecified() && !addr.is_multicast()
+}
+
+/// Source address of an IPv4 or IPv6 packet.
+pub fn source_addr(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let octets: [u8; 4] = packet[12..16].try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 if packet.len() >= 40 => {
+            let octets: [u8; 16] = packet[8..24].try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// Destination address of an IPv4 or IPv6 packet.
+pub fn destination_addr(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let octets: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 if packet.len() >= 40 => {
+            let octets: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// `packet` with its IPv4 TTL or IPv6 hop limit one lower, for passing it
+/// on to the next hop; `None` once it has run out.
+pub fn decrement_ttl(packet: &[u8]) -> Option<Vec<u8>> {
+    let mut packet = packet.to_vec();
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            if packet[8] <= 1 {
+                return None;
+            }
+            // Incremental checksum update (RFC 1624) of the TTL/protocol word.
+            let old = u16::from_be_bytes([packet[8], packet[9]]);
+            packet[8] -= 1;
+            let new = u16::from_be_bytes([packet[8], packet[9]]);
+            let check = u16::from_be_bytes([packet[10], packet[11]]);
+            let mut sum = u32::from(!check) + u32::from(!old) + u32::from(new);
+            sum = (sum & 0xffff) + (sum >> 16);
+            sum = (sum & 0xffff) + (sum >> 16);
+            packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        }
+        6 if packet.len() >= 40 => {
+            if packet[7] <= 1 {
+                return None;
+            }
+            packet[7] -= 1;
+        }
+        _ => return None,
+    }
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mtu::checksum;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn links(peers: &[(&str, Option<f64>)]) -> Links {
+        peers.iter().map(|(p, rtt)| (p.to_string(), *rtt)).collect()
+    }
+
+    fn advert(routes: &[(&str, u8)]) -> RouteAdvert {
+        RouteAdvert { routes: routes.iter().map(|(a, h)| (addr(a), *h)).collect() }
+    }
+
+    fn ipv4(src: [u8; 4], ttl: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 28, 0xab, 0xcd, 0x40, 0, ttl, 17, 0, 0];
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&[10, 0, 0, 9]);
+        let sum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet
+    }
+
+    #[test]
+    fn prefers_fewer_hops_then_lower_rtt() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        table.on_advert("bob", advert(&[("10.0.0.2", 0), ("10.0.0.4", 2)]));
+        table.on_advert("carol", advert(&[("10.0.0.3", 0), ("10.0.0.4", 1)]));
+        table.on_advert("dave", advert(&[("10.0.0.4", 1)]));
+        let up = links(&[("bob", Some(5.0)), ("carol", Some(40.0)), ("dave", Some(20.0))]);
+
+        assert_eq!(table.next_hop(addr("10.0.0.1"), &up), None);
+        let route = table.next_hop(addr("10.0.0.4"), &up).unwrap();
+        assert_eq!((route.peer_id.as_str(), route.hops), ("dave", 2));
+
+        let without_dave = links(&[("bob", Some(5.0)), ("carol", Some(40.0))]);
+        assert_eq!(table.next_hop(addr("10.0.0.4"), &without_dave).unwrap().peer_id, "carol");
+    }
+
+    #[test]
+    fn advertised_beats_learned() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        table.learn(&ipv4([10, 0, 0, 5], 64), "bob");
+        let up = links(&[("bob", None), ("carol", None)]);
+        assert_eq!(table.next_hop(addr("10.0.0.5"), &up).unwrap().peer_id, "bob");
+
+        table.on_advert("carol", advert(&[("10.0.0.5", 0)]));
+        let route = table.next_hop(addr("10.0.0.5"), &up).unwrap();
+        assert_eq!((route.peer_id.as_str(), route.hops), ("carol", 1));
+        assert_eq!(table.addresses("carol"), vec![addr("10.0.0.5")]);
+    }
+
+    #[test]
+    fn infinity_is_unreachable() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        table.on_advert("bob", advert(&[("10.0.0.3", INFINITY - 2), ("10.0.0.4", INFINITY - 1)]));
+        table.on_advert("carol", advert(&[("10.0.0.2", INFINITY)]));
+        let up = links(&[("bob", None), ("carol", None)]);
+
+        assert_eq!(table.next_hop(addr("10.0.0.2"), &up), None);
+        assert_eq!(table.next_hop(addr("10.0.0.3"), &up).unwrap().hops, INFINITY - 1);
+        // One more hop through bob reaches infinity.
+        assert_eq!(table.next_hop(addr("10.0.0.4"), &up), None);
+    }
+
+    #[test]
+    fn ignores_our_own_and_multicast_addresses() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        table.on_advert("bob", advert(&[("10.0.0.1", 0), ("224.0.0.1", 0), ("0.0.0.0", 0)]));
+        assert!(table.routes(&links(&[("bob", None)])).is_empty());
+    }
+
+    #[test]
+    fn poisons_routes_back_to_their_next_hop() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        table.on_advert("bob", advert(&[("10.0.0.2", 0), ("10.0.0.3", 1)]));
+        let up = links(&[("bob", None), ("carol", None)]);
+
+        let to_bob: HashMap<IpAddr, u8> = table.advert_for("bob", &up).routes.into_iter().collect();
+        assert_eq!(to_bob[&addr("10.0.0.1")], 0);
+        assert_eq!(to_bob[&addr("10.0.0.2")], INFINITY);
+        assert_eq!(to_bob[&addr("10.0.0.3")], INFINITY);
+
+        let to_carol: HashMap<IpAddr, u8> = table.advert_for("carol", &up).routes.into_iter().collect();
+        assert_eq!(to_carol[&addr("10.0.0.2")], 1);
+        assert_eq!(to_carol[&addr("10.0.0.3")], 2);
+
+        // Bob taking the poisoned routes back leaves nothing to loop on.
+        let mut bob = RouteTable::new(vec![addr("10.0.0.2")]);
+        bob.on_advert("alice", table.advert_for("bob", &up));
+        assert_eq!(bob.next_hop(addr("10.0.0.3"), &links(&[("alice", None)])), None);
+    }
+
+    #[test]
+    fn forgets_stale_adverts() {
+        let mut table = RouteTable::new(vec![addr("10.0.0.1")]);
+        assert!(table.on_advert("bob", advert(&[("10.0.0.2", 0)])));
+        assert!(!table.on_advert("bob", advert(&[("10.0.0.2", 0)])));
+        let up = links(&[("bob", None)]);
+        assert!(table.next_hop(addr("10.0.0.2"), &up).is_some());
+
+        table.adverts.get_mut("bob").unwrap().at -= ADVERT_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(table.next_hop(addr("10.0.0.2"), &up), None);
+        // The same vector again brings the route back, which is news.
+        assert!(table.on_advert("bob", advert(&[("10.0.0.2", 0)])));
+        assert!(table.next_hop(addr("10.0.0.2"), &up).is_some());
+
+        table.forget_peer("bob");
+        assert_eq!(table.next_hop(addr("10.0.0.2"), &up), None);
+    }
+
+    #[test]
+    fn decrements_ipv4_ttl_with_a_valid_checksum() {
+        for ttl in [2, 64, 128, 255] {
+            for src in [[10, 0, 0, 5], [192, 168, 255, 255], [0xff, 0xff, 0xff, 0xfe]] {
+                let packet = ipv4(src, ttl);
+                let out = decrement_ttl(&packet).unwrap();
+                assert_eq!(out[8], ttl - 1);
+
+                let mut expected = out.clone();
+                expected[10..12].fill(0);
+                let sum = checksum(&[&expected[..20]]);
+                assert_eq!(out[10..12], sum.to_be_bytes(), "ttl {} from {:?}", ttl, src);
+                assert_eq!(checksum(&[&out[..20]]), 0);
+            }
+        }
+        assert_eq!(decrement_ttl(&ipv4([10, 0, 0, 5], 1)), None);
+        assert_eq!(decrement_ttl(&ipv4([10, 0, 0, 5], 0)), None);
+    }
+
+    #[test]
+    fn decrements_ipv6_hop_limit() {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        packet.extend_from_slice(&[0; 32]);
+        assert_eq!(decrement_ttl(&packet).unwrap()[7], 63);
+        packet[7] = 1;
+        assert_eq!(decrement_ttl(&packet), None);
+        assert_eq!(decrement_ttl(&packet[..39]), None);
+    }
+}
@@ -1,222 +1,975 @@
 #[allow(dead_code)]
-use anyhow::Result;
-use std::net::Ipv4Addr;
-use tokio::sync::mpsc;
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
-use tun_rs::DeviceBuilder;
+use tracing::{Instrument, error, info, info_span, warn};
 
+use crate::ban::{self, Ban, BanList};
+use crate::chat::{self, ChatHistory, ChatMessage, ChatRecord, ChatTarget, HistoryQuery};
+use crate::config::RouterConfig;
+use crate::device::{self, PacketDevice};
+use crate::discovery;
 use crate::event::LanEvent;
-use crate::peer::PeerManager;
+use crate::forward::{self, Overlay};
+use crate::gossip;
+use crate::identity::{self, Identity};
+use crate::invite::{self, Code, CodeKind};
+use crate::latency::{self, PingReport};
+use crate::metrics::{self, Exposition};
+use crate::netstack::Netstack;
+use crate::peer::{self, PeerInfo, PeerManager};
+use crate::proxy;
+use crate::route::{self, Route};
+use crate::stats::LinkStatsSnapshot;
+use crate::throughput::{self, ThroughputMode, ThroughputReport};
+use crate::transport::LinkState;
+use crate::trust::{self, TrustList, TrustSource, TrustedPeer};
 
-#[derive(Debug)]
+/// Commands the router takes from the console or the control socket. The
+/// serde form is the control API's method name and parameters.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
 pub enum RouterCommand{ 
     CreateOffer { peer_id: String },
     AcceptOffer { peer_id: String, sdp: String },
     CreateAnswer { peer_id: String, sdp: String },
+    /// Like `CreateOffer`, as a compact code to pass on by hand.
+    CreateInvite { peer_id: String },
+    /// Accepts an invite code, producing the answer code, or applies an
+    /// answer code to our invite.
+    RedeemCode { code: String },
     ConnectToPeer { peer_id: String }, 
+    /// Opens a direct QUIC link to a peer listening on `addr`, without the
+    /// signaling server.
+    ConnectDirect { addr: SocketAddr },
+    Disconnect { peer_id: String },
     SendChat { peer_id: String, message: String },
+    BroadcastChat { message: String },
+    ChatHistory {
+        peer_id: Option<String>,
+        before: Option<u64>,
+        #[serde(default = "default_page")]
+        limit: usize,
+    },
+    ExportChat { path: PathBuf },
+    ShowStats,
+    SendFile { peer_id: String, path: PathBuf },
+    AcceptFile { peer_id: String, transfer_id: u64, path: PathBuf },
+    ListPeers,
+    ListRoutes,
+    Status,
+    /// Bans by peer id, certificate fingerprint, or both.
+    Ban {
+        peer_id: Option<String>,
+        fingerprint: Option<String>,
+        reason: Option<String>,
+    },
+    /// Lifts the ban on a peer id or fingerprint.
+    Unban { key: String },
+    ListBans,
+    /// Trusts a peer only with `fingerprint`, or with the one it is
+    /// connected with.
+    Trust {
+        peer_id: String,
+        fingerprint: Option<String>,
+    },
+    /// Forgets the fingerprint a peer is trusted with.
+    Untrust { peer_id: String },
+    ListTrusted,
+    /// Measures the round trip to a peer, like ping(8).
+    Ping {
+        peer_id: String,
+        #[serde(default = "default_ping_count")]
+        count: u32,
+    },
+    /// Sends to a peer for `seconds` and reports what got through, like
+    /// iperf. `bitrate` caps the rate in bits per second; unreliable tests
+    /// default to a modest one.
+    ThroughputTest {
+        peer_id: String,
+        #[serde(default)]
+        mode: ThroughputMode,
+        seconds: Option<f64>,
+        bitrate: Option<u64>,
+    },
+    /// Takes on a link to `peer_id` set up by a test, such as one end of
+    /// a loopback pair. Nothing is checked.
+    #[cfg(test)]
+    #[serde(skip)]
+    Attach {
+        peer_id: String,
+        transport: std::sync::Arc<dyn crate::transport::PeerTransport>,
+    },
+    /// Runs `command` and sends back what it produced instead of printing it.
+    #[serde(skip)]
+    Call {
+        command: Box<RouterCommand>,
+        reply: oneshot::Sender<Result<Outcome, String>>,
+    },
+}
+
+fn default_page() -> usize {
+    chat::DEFAULT_PAGE
+}
+
+fn default_ping_count() -> u32 {
+    4
+}
+
+/// What a command produced.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Outcome {
+    Done,
+    Offer { peer_id: String, sdp: String },
+    Connected { peer_id: String },
+    Answer { peer_id: String, sdp: String },
+    Code { peer_id: String, kind: CodeKind, code: String, expires_at: u64 },
+    FileOffered { peer_id: String, transfer_id: u64 },
+    History(Vec<ChatRecord>),
+    Exported { path: PathBuf },
+    Stats(BTreeMap<String, LinkStatsSnapshot>),
+    Peers(Vec<PeerInfo>),
+    Routes(Vec<Route>),
+    Status(RouterStatus),
+    Bans(Vec<Ban>),
+    Trusted(Vec<TrustedPeer>),
+    Ping(PingReport),
+    Throughput(ThroughputReport),
+}
+
+/// Answer to `RouterCommand::Status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouterStatus {
+    pub peer_id: String,
+    /// Certificate fingerprint peers know this router by.
+    pub fingerprint: String,
+    pub device: String,
+    pub address: String,
+    pub mtu: u16,
+    pub signal_server: String,
+    pub peers: usize,
+    pub connected: usize,
+}
+
+// Events not yet taken by a slow subscriber before it starts missing some.
+const EVENT_BACKLOG: usize = 256;
+
+pub struct Router {
+    config: RouterConfig,
+    events: broadcast::Sender<LanEvent>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(RouterConfig::default())
+    }
 }
-pub struct Router; 
 
 impl Router {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(config: RouterConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BACKLOG);
+        Self { config, events }
     }
 
-    pub async fn route(&self, token: CancellationToken,mut cmd_rx: mpsc::Receiver<RouterCommand>) -> Result<()> {
-        let (tx, mut rx) = mpsc::channel(32);
-        let manager = PeerManager::new(tx.clone()).await?;
+    /// Every event the router handles except tunneled packets.
+    pub fn subscribe(&self) -> broadcast::Receiver<LanEvent> {
+        self.events.subscribe()
+    }
 
-        use crate::signaling::client::SignalClient;
-        use crate::signaling::protocol::SignalMessage;
+    fn open_ban_list(&self) -> BanList {
+        let path = self.config.ban_file.clone().unwrap_or_else(ban::default_path);
+        BanList::open(&path).unwrap_or_else(|e| {
+            warn!("Ban list {} unreadable ({}), starting with an empty one", path.display(), e);
+            BanList::in_memory()
+        })
+    }
 
-        
-        let args: Vec<_> = std::env::args().collect();
+    fn open_trust_list(&self) -> TrustList {
+        let config = &self.config;
+        let path = config.trust_file.clone().unwrap_or_else(|| trust::default_path(&config.peer_id));
+        TrustList::open(&path, config.trust).unwrap_or_else(|e| {
+            warn!("Trust list {} unreadable ({}), starting with an empty one", path.display(), e);
+            TrustList::in_memory(config.trust)
+        })
+    }
 
+    fn open_identity(&self) -> Result<Identity> {
+        let config = &self.config;
+        let path = config.identity_file.clone().unwrap_or_else(|| identity::default_path(&config.peer_id));
+        match Identity::open(&path) {
+            Ok(identity) => Ok(identity),
+            Err(e) => {
+                warn!("Identity {} unreadable ({}), using a new one for this run", path.display(), e);
+                Identity::ephemeral()
+            }
+        }
+    }
 
-        let dev_name = args
-        .get(1)
-        .map(|s| s.as_str())
-        .unwrap_or("tun1");
+    fn open_chat_history(&self) -> ChatHistory {
+        let dir = self.config.chat_dir.clone().unwrap_or_else(chat::default_dir);
+        ChatHistory::open(&dir, &self.config.signal_server).unwrap_or_else(|e| {
+            warn!("Chat history unavailable ({}), keeping it in memory only", e);
+            ChatHistory::in_memory()
+        })
+    }
 
-        let local_ip = args
-        .get(2)
-        .map(|s| s.as_str())
-        .unwrap_or("10.10.0.2");
+    /// Runs the router on a new TUN device, or on its own network stack in
+    /// userspace mode, until `token` is cancelled.
+    pub async fn route(&self, token: CancellationToken, cmd_rx: mpsc::Receiver<RouterCommand>) -> Result<()> {
+        if !self.config.userspace {
+            let dev = device::open_tun(&self.config)?;
+            let services = self.services(Overlay::kernel(&self.config));
+            return tokio::select! {
+                res = self.route_with(dev, token, cmd_rx) => res,
+                _ = services => Ok(()),
+            };
+        }
 
-        let my_id = args
-        .get(3)
-        .cloned()
-        .unwrap_or("peer1".into());
-        
-        let mask = Ipv4Addr::new(255, 255, 255, 0);
-        let dev = DeviceBuilder::new()
-            .name(dev_name)
-            .mtu(1500)
-            .ipv4(local_ip, mask, None)
-            .build_async()?;
+        let stack = Netstack::new(&self.config);
+        let services = self.services(Overlay::Userspace(stack.clone()));
+        tokio::select! {
+            res = self.route_with(stack.device(), token, cmd_rx) => res,
+            _ = stack.run() => Ok(()),
+            _ = services => Ok(()),
+        }
+    }
 
-        let (signal_client, mut signal_rx) =
-            SignalClient::connect(
-            "127.0.0.1:9000",
+    // The forwards, and in userspace mode the proxies, each left alone if
+    // it cannot start. Never finishes.
+    async fn services(&self, overlay: Overlay) {
+        let mut services: Vec<BoxFuture<'_, ()>> = Vec::new();
+        if let Overlay::Userspace(stack) = &overlay {
+            if let Some(addr) = self.config.socks {
+                let stack = stack.clone();
+                services.push(Box::pin(async move {
+                    if let Err(e) = proxy::serve_socks(addr, stack).await {
+                        warn!("SOCKS5 proxy on {} unavailable: {}", addr, e);
+                    }
+                }));
+            }
+            if let Some(addr) = self.config.http_proxy {
+                let stack = stack.clone();
+                services.push(Box::pin(async move {
+                    if let Err(e) = proxy::serve_http(addr, stack).await {
+                        warn!("HTTP proxy on {} unavailable: {}", addr, e);
+                    }
+                }));
+            }
+        }
+        for &forward in &self.config.forwards {
+            let overlay = overlay.clone();
+            services.push(Box::pin(async move {
+                if let Err(e) = forward::serve(forward, overlay).await {
+                    warn!("Forward {} unavailable: {}", forward, e);
+                }
+            }));
+        }
+        for &forward in &self.config.reverse_forwards {
+            let overlay = overlay.clone();
+            services.push(Box::pin(async move {
+                if let Err(e) = forward::serve_reverse(forward, overlay).await {
+                    warn!("Reverse forward {} unavailable: {}", forward, e);
+                }
+            }));
+        }
+        futures::future::join_all(services).await;
+        std::future::pending().await
+    }
+
+    /// Like [`Router::route`], over any packet device.
+    pub async fn route_with<D: PacketDevice>(
+        &self,
+        dev: D,
+        token: CancellationToken,
+        mut cmd_rx: mpsc::Receiver<RouterCommand>,
+    ) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(32);
+
+        use crate::signaling::client::SignalClient;
+        use crate::signaling::protocol::SignalMessage;
+
+
+        let config = &self.config;
+        let my_id = config.peer_id.clone();
+
+        let address = IpAddr::V4(config.address);
+        let identity = self.open_identity()?;
+        info!("Known to peers by {}", identity.fingerprint());
+        let manager = PeerManager::new(
             my_id.clone(),
-         )
+            address,
+            tx.clone(),
+            config.link,
+            self.open_ban_list(),
+            self.open_trust_list(),
+            identity,
+        )
         .await?;
+        let history = Mutex::new(self.open_chat_history());
+
+        // Peers on the LAN and direct links do without the signaling
+        // server, so not reaching it is no reason to stop.
+        let (signal_client, mut signal_rx) =
+            match SignalClient::connect(&config.signal_server, my_id.clone()).await {
+                Ok((client, rx)) => (Some(client), Some(rx)),
+                Err(e) => {
+                    warn!("Signaling server {} unavailable: {}", config.signal_server, e);
+                    (None, None)
+                }
+            };
 
         let recvloop = async {
-            let mut buf = vec![0u8; 1500];
+            // Sized for the largest IP packet, not the device MTU, so
+            // oversized packets can still be answered with an ICMP error.
+            let mut buf = vec![0u8; u16::MAX as usize];
+            let batch = config.link.batch;
             loop {
-                let len = dev.recv(&mut buf).await.unwrap();
-                if len > 0 {
-                    let packet = buf[..len].to_vec();
-                    if let Err(e) = manager.route_and_send(packet).await {
-                        eprintln!("Error routing packet: {}", e);
+                let len = match dev.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error reading from device: {}", e);
+                        break;
+                    }
+                };
+                if len == 0 {
+                    continue;
+                }
+                let mut packets = vec![buf[..len].to_vec()];
+
+                // Whatever else arrives within the window goes out with it.
+                if batch.enabled() {
+                    let deadline = tokio::time::Instant::now() + batch.window;
+                    while packets.len() < batch.max_packets {
+                        match tokio::time::timeout_at(deadline, dev.recv(&mut buf)).await {
+                            Ok(Ok(len)) if len > 0 => packets.push(buf[..len].to_vec()),
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => {
+                                error!("Error reading from device: {}", e);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
                     }
                 }
+
+                match manager.route_batch(packets).await {
+                    Ok(replies) => {
+                        for reply in replies {
+                            if let Err(e) = dev.send(&reply).await {
+                                error!("Error writing to device: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Error routing packet: {}", e),
+                }
             }
         };
 
         let mainloop = async {
-            loop {
-                match rx.recv().await {
-                    Some(LanEvent::PacketFromPeer(packet)) => {
-                        if let Err(e) = dev.send(&packet).await {
-                            eprintln!("Error writing to TUN: {}", e);
-                        }
+            while let Some(event) = rx.recv().await {
+                if let LanEvent::PacketFromPeer(packet) = &event {
+                    if let Err(e) = dev.send(packet).await {
+                        error!("Error writing to device: {}", e);
+                    }
+                    continue;
+                }
+
+                let _ = self.events.send(event.clone());
+
+                match event {
+                    LanEvent::PeerConnected(pid) => {
+                        info!(parent: &peer::span(&pid), "Peer connected");
                     }
-                    Some(LanEvent::PeerConnected(pid)) => {
-                        println!("[System]: Peer {} connected.", pid);
+                    LanEvent::PeerDisconnected(pid) => {
+                        info!(parent: &peer::span(&pid), "Peer disconnected");
                     }
-                    Some(LanEvent::PeerDisconnected(pid)) => {
-                        println!("[System]: Peer {} disconnected.", pid);
+                    LanEvent::PeerHello { peer_id, software, protocol } => {
+                        info!(parent: &peer::span(&peer_id), %software, protocol, "Peer introduced itself");
                     }
-                    Some(LanEvent::NewPeerOffer(pid, sdp)) => {
-                        println!("\n--- RECEIVED OFFER from {pid} ---");
-                        println!("{sdp}");
+                    LanEvent::ChatMessage(message) => {
+                        if let Err(e) = history.lock().unwrap().record(message) {
+                            warn!("chat history error: {e}");
+                        }
                     }
-                    Some(LanEvent::ChatMessage { from, message }) => {
-                        println!("\n[Chat] {}: {}", from, message);
+                    LanEvent::ChatDelivered { peer_id, message_id } => {
+                        if let Err(e) = history.lock().unwrap().mark_delivered(&my_id, &message_id, &peer_id) {
+                            warn!("chat history error: {e}");
+                        }
                     }
-                    None => break,
+                    _ => {}
                 }
             }
         };
 
-    let command_loop = async {
-      while let Some(cmd) = cmd_rx.recv().await {
-        match cmd {
-            RouterCommand::CreateOffer { peer_id } => {
-                match manager.create_offer(peer_id.clone()).await {
-                    Ok(sdp) => {
-                        println!("\n=== OFFER for {} ===", peer_id);
-                        println!("{sdp}");
-                    }
-                    Err(e) => eprintln!("Error: {e}"),
+        let execute = async |cmd: RouterCommand| -> Result<Outcome> {
+            let outcome = match cmd {
+                RouterCommand::CreateOffer { peer_id } => {
+                    let sdp = manager.create_offer(peer_id.clone()).await?;
+                    Outcome::Offer { peer_id, sdp }
                 }
-            }
 
-            RouterCommand::AcceptOffer { peer_id, sdp } => {
-                match manager.accept_offer(peer_id.clone(), &sdp).await {
-                    Ok(answer) => {
-                        println!("\n=== ANSWER for {} ===", peer_id);
-                        println!("{answer}");
-                    }
-                    Err(e) => eprintln!("Error: {e}"),
+                RouterCommand::AcceptOffer { peer_id, sdp } => {
+                    let sdp = manager.accept_offer(peer_id.clone(), &sdp).await?;
+                    Outcome::Answer { peer_id, sdp }
                 }
-            }
 
-            RouterCommand::CreateAnswer { peer_id, sdp } => {
-                if let Err(e) = manager
-                    .set_answer_as_offerer(&peer_id, &sdp)
-                    .await
-                {
-                    eprintln!("Error: {e}");
+                RouterCommand::CreateAnswer { peer_id, sdp } => {
+                    manager.set_answer_as_offerer(&peer_id, &sdp).await?;
+                    Outcome::Done
                 }
-            }
 
-            RouterCommand::ConnectToPeer { peer_id } => {
-                match manager.create_offer(peer_id.clone()).await {
-                    Ok(offer) => {
-                    if let Err(e) = signal_client.send(
-                        SignalMessage::Offer {
+                RouterCommand::CreateInvite { peer_id } => {
+                    let sdp = manager.create_offer(peer_id.clone()).await?;
+                    let code = match Code::new(CodeKind::Invite, &my_id, &peer_id, &sdp) {
+                        Ok(code) => code,
+                        Err(e) => {
+                            let _ = manager.remove_peer(&peer_id).await;
+                            return Err(e);
+                        }
+                    };
+                    Outcome::Code { peer_id, kind: code.kind, code: code.encode(), expires_at: code.expires_at }
+                }
+
+                RouterCommand::RedeemCode { code } => {
+                    let code = Code::parse(&code)?;
+                    if code.to != my_id {
+                        bail!("Code is for {}, not {}", code.to, my_id);
+                    }
+                    let sdp = code.sdp_json()?;
+                    match code.kind {
+                        CodeKind::Invite => {
+                            let answer = manager.accept_offer(code.from.clone(), &sdp).await?;
+                            let answer = Code::new(CodeKind::Answer, &my_id, &code.from, &answer)?;
+                            Outcome::Code {
+                                peer_id: code.from,
+                                kind: answer.kind,
+                                code: answer.encode(),
+                                expires_at: answer.expires_at,
+                            }
+                        }
+                        CodeKind::Answer => {
+                            manager.set_answer_as_offerer(&code.from, &sdp).await?;
+                            Outcome::Done
+                        }
+                    }
+                }
+
+                RouterCommand::ConnectToPeer { peer_id } => {
+                    let signal_client = signal_client.as_ref().ok_or(anyhow!("No signaling server"))?;
+                    let offer = manager.create_offer(peer_id.clone()).await.context("Offer error")?;
+                    signal_client
+                        .send(SignalMessage::Offer {
                             from: my_id.clone(),
-                            to: peer_id,
+                            to: peer_id.clone(),
                             sdp: offer,
-                        }
-                    ).await {
-                        eprintln!("Signal error: {}", e);
+                        })
+                        .await
+                        .context("Signal error")?;
+                    info!(parent: &peer::span(&peer_id), "Offer sent");
+                    Outcome::Done
+                }
+
+                RouterCommand::ConnectDirect { addr } => {
+                    let peer_id = manager.connect_direct(addr).await.context("Direct link error")?;
+                    Outcome::Connected { peer_id }
+                }
+
+                #[cfg(test)]
+                RouterCommand::Attach { peer_id, transport } => {
+                    manager.attach(peer_id, transport).await;
+                    Outcome::Done
+                }
+
+                RouterCommand::Disconnect { peer_id } => {
+                    manager.remove_peer(&peer_id).await.context("Disconnect error")?;
+                    Outcome::Done
+                }
+
+                RouterCommand::SendChat { peer_id, message } => {
+                    let message = ChatMessage::new(my_id.clone(), ChatTarget::Peer(peer_id.clone()), message);
+                    manager.send_chat(&peer_id, &message).await.context("chat send error")?;
+                    history.lock().unwrap().record(message).context("chat history error")?;
+                    Outcome::Done
+                }
+
+                RouterCommand::BroadcastChat { message } => {
+                    let message = ChatMessage::new(my_id.clone(), ChatTarget::All, message);
+                    let sent = manager.broadcast_chat(&message).await;
+                    history.lock().unwrap().record(message).context("chat history error")?;
+                    if sent.is_empty() {
+                        bail!("chat send error: no connected peers");
                     }
+                    Outcome::Done
+                }
+
+                RouterCommand::ChatHistory { peer_id, before, limit } => {
+                    let query = HistoryQuery { peer: peer_id, before, limit };
+                    Outcome::History(history.lock().unwrap().page(&query))
+                }
+
+                RouterCommand::ExportChat { path } => {
+                    history.lock().unwrap().export(&path).context("chat export error")?;
+                    Outcome::Exported { path }
+                }
+
+                RouterCommand::ShowStats => Outcome::Stats(manager.stats().await.into_iter().collect()),
+
+                RouterCommand::ListPeers => Outcome::Peers(manager.peers().await),
+
+                RouterCommand::ListRoutes => Outcome::Routes(manager.routes().await),
+
+                RouterCommand::Status => {
+                    let peers = manager.peers().await;
+                    let connected = peers
+                        .iter()
+                        .filter(|p| p.state == LinkState::Connected)
+                        .count();
+                    Outcome::Status(RouterStatus {
+                        peer_id: my_id.clone(),
+                        fingerprint: manager.fingerprint().to_owned(),
+                        device: config.device.clone(),
+                        address: format!("{}/{}", config.address, config.netmask),
+                        mtu: config.link.mtu.device_mtu(),
+                        signal_server: config.signal_server.clone(),
+                        peers: peers.len(),
+                        connected,
+                    })
+                }
+
+                RouterCommand::Ban { peer_id, fingerprint, reason } => {
+                    manager.ban(peer_id, fingerprint, reason).await.context("Ban error")?;
+                    Outcome::Done
+                }
+
+                RouterCommand::Unban { key } => {
+                    if !manager.unban(&key).context("Ban error")? {
+                        bail!("{} is not banned", key);
                     }
+                    Outcome::Done
+                }
 
-                    Err(e) => {
-                        eprintln!("Offer error: {}", e);
+                RouterCommand::ListBans => Outcome::Bans(manager.bans()),
+
+                RouterCommand::Trust { peer_id, fingerprint } => {
+                    manager.trust(&peer_id, fingerprint).await.context("Trust error")?;
+                    Outcome::Done
+                }
+
+                RouterCommand::Untrust { peer_id } => {
+                    if !manager.untrust(&peer_id).context("Trust error")? {
+                        bail!("{} is not trusted", peer_id);
                     }
+                    Outcome::Done
                 }
-            }
 
-            RouterCommand::SendChat { peer_id, message } => {
-               if let Err(e) = manager.send_chat(&peer_id, message.clone()).await {
-                eprintln!("chat send error: {e}");
+                RouterCommand::ListTrusted => Outcome::Trusted(manager.trusted()),
+
+                RouterCommand::Ping { .. }
+                | RouterCommand::ThroughputTest { .. }
+                | RouterCommand::SendFile { .. }
+                | RouterCommand::AcceptFile { .. } => return Err(anyhow!("not run inline")),
+
+                RouterCommand::Call { .. } => return Err(anyhow!("nested call")),
+            };
+            Ok(outcome)
+        };
+
+        let command_loop = async {
+            while let Some(cmd) = cmd_rx.recv().await {
+                let (command, reply) = match cmd {
+                    RouterCommand::Call { command, reply } => (*command, Some(reply)),
+                    cmd => (cmd, None),
+                };
+                match in_background(&manager, command) {
+                    Ok(task) => {
+                        tokio::spawn(async move { report(task.await, reply) });
+                    }
+                    Err(command) => report(execute(command).await, reply),
                 }
             }
-        }
-    }
-};
-
-let signaling_loop = async {
-    while let Some(msg) = signal_rx.recv().await {
-        match msg {
-            SignalMessage::Offer { from, sdp, .. } => {
-                match manager.accept_offer(from.clone(), &sdp).await {
-                    Ok(answer) => {
-                        if let Err(e) = signal_client.send(
-                            SignalMessage::Answer {
-                                from: my_id.clone(),
-                                to: from,
-                                sdp: answer,
+        };
+
+        let signaling_loop = async {
+            let (Some(signal_client), Some(signal_rx)) = (&signal_client, &mut signal_rx) else {
+                return std::future::pending().await;
+            };
+            while let Some(msg) = signal_rx.recv().await {
+                match msg {
+                    SignalMessage::Offer { from, sdp, .. } => {
+                        let span = peer::span(&from);
+                        async {
+                            if manager.rejects(&from, &sdp) {
+                                info!("Rejected offer from banned peer");
+                                return;
+                            }
+                            info!("Offer received");
+                            match manager.accept_offer(from.clone(), &sdp).await {
+                                Ok(answer) => {
+                                    let answer = SignalMessage::Answer { from: my_id.clone(), to: from, sdp: answer };
+                                    if let Err(e) = signal_client.send(answer).await {
+                                        warn!("Signal error: {}", e);
+                                    }
+                                }
+
+                                Err(e) => {
+                                    warn!("Accept error: {}", e);
+                                }
                             }
-                        ).await {
-                            eprintln!("Signal error: {}", e);
                         }
+                        .instrument(span)
+                        .await
                     }
 
-                    Err(e) => {
-                        eprintln!("Accept error: {}", e);
+                    SignalMessage::Answer { from, sdp, .. } => {
+                        if let Err(e) = manager.set_answer_as_offerer(&from, &sdp).await {
+                            warn!(parent: &peer::span(&from), "Answer error: {}", e);
+                        }
                     }
+
+                    _ => {}
                 }
             }
+        }
+        .instrument(info_span!("signaling", server = %config.signal_server));
 
-            SignalMessage::Answer { from, sdp, .. } => {
-                if let Err(e) =
-                    manager.set_answer_as_offerer(&from, &sdp).await
-                {
-                    eprintln!("Answer error: {}", e);
+        // Keeps the latency figures of every peer current.
+        let ping_loop = async {
+            let mut ticker = tokio::time::interval(latency::PING_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for (peer_id, latency) in manager.ping_all().await {
+                    let _ = tx.send(LanEvent::PeerLatency { peer_id, latency }).await;
                 }
             }
+        };
 
-            _ => {}
-        }
-    }
-};
+        // Keeps peer lists going round, so the mesh fills in and heals
+        // without the signaling server.
+        let gossip_loop = async {
+            let mut ticker = tokio::time::interval(gossip::GOSSIP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                manager.gossip().await;
+            }
+        };
+
+        let route_loop = async {
+            let mut ticker = tokio::time::interval(route::ADVERT_INTERVAL);
+            loop {
+                ticker.tick().await;
+                manager.advertise_routes().await;
+            }
+        };
+
+        let direct_server = async {
+            if let Some(addr) = config.direct_listen
+                && let Err(e) = manager.serve_direct(addr).await
+            {
+                warn!("Direct links on {} unavailable: {}", addr, e);
+            }
+            std::future::pending::<()>().await
+        };
+
+        let discovery = async {
+            if config.discovery
+                && let Err(e) = discovery::run(&manager, config).instrument(info_span!("discovery")).await
+            {
+                warn!("LAN discovery unavailable: {:#}", e);
+            }
+            std::future::pending::<()>().await
+        };
+
+        let metrics_server = async {
+            if let Some(addr) = config.metrics {
+                let manager = manager.clone();
+                let render = move || {
+                    let manager = manager.clone();
+                    async move {
+                        let mut out = Exposition::default();
+                        metrics::write_peers(&mut out, &manager.metrics().await);
+                        out.finish()
+                    }
+                };
+                if let Err(e) = metrics::serve(addr, render).await {
+                    warn!("Metrics endpoint {} unavailable: {}", addr, e);
+                }
+            }
+            std::future::pending::<()>().await
+        };
 
         tokio::select! {
+            _ = metrics_server => {}
+            _ = direct_server => {}
+            _ = discovery => {}
+            _ = ping_loop => {}
+            _ = gossip_loop => {}
+            _ = route_loop => {}
             _ = mainloop => {
-                println!("the mainloop exited to early");
+                warn!("the mainloop exited too early");
             },
             _ = recvloop => {
-                println!("the recvloop exited to early");
+                warn!("the recvloop exited too early");
             },
-            _ = command_loop => { 
-                println!("command loop exited"); 
+            _ = command_loop => {
+                info!("command loop exited");
             }
             _ = signaling_loop => {
-                println!("signaling_loop exited");
+                warn!("signaling_loop exited");
             }
             _ = token.cancelled() => {
-                println!("Bye!!");
+                info!("Bye!!");
             }
         };
 
         Ok(())
     }
 }
+
+// The commands that take seconds or more, as tasks of their own, so other
+// commands do not wait on them: latency and throughput tests, and file
+// transfers, which hash the whole file first. Gives back any other command.
+fn in_background(manager: &PeerManager, cmd: RouterCommand) -> Result<BoxFuture<'static, Result<Outcome>>, RouterCommand> {
+    let manager = manager.clone();
+    let task: BoxFuture<'static, Result<Outcome>> = match cmd {
+        RouterCommand::Ping { peer_id, count } => Box::pin(async move {
+            Ok(Outcome::Ping(manager.ping(&peer_id, count.max(1)).await.context("Ping error")?))
+        }),
+
+        RouterCommand::ThroughputTest { peer_id, mode, seconds, bitrate } => Box::pin(async move {
+            let duration = throughput::duration(seconds).context("Throughput error")?;
+            let bitrate = match mode {
+                ThroughputMode::Unreliable => bitrate.or(Some(throughput::DEFAULT_UNRELIABLE_BITRATE)),
+                ThroughputMode::Reliable => bitrate,
+            };
+            let report = manager.throughput_test(&peer_id, mode, duration, bitrate).await.context("Throughput error")?;
+            Ok(Outcome::Throughput(report))
+        }),
+
+        RouterCommand::SendFile { peer_id, path } => Box::pin(async move {
+            let transfer_id = manager.send_file(&peer_id, path).await.context("file send error")?;
+            Ok(Outcome::FileOffered { peer_id, transfer_id })
+        }),
+
+        RouterCommand::AcceptFile { peer_id, transfer_id, path } => Box::pin(async move {
+            manager.accept_file(&peer_id, transfer_id, path).await.context("file accept error")?;
+            Ok(Outcome::Done)
+        }),
+
+        cmd => return Err(cmd),
+    };
+    Ok(task)
+}
+
+// Sends what a command came to back to the caller, or prints it when the
+// command was not a `Call`.
+fn report(result: Result<Outcome>, reply: Option<oneshot::Sender<Result<Outcome, String>>>) {
+    match (reply, result) {
+        (Some(reply), result) => {
+            let _ = reply.send(result.map_err(|e| format!("{:#}", e)));
+        }
+        (None, Ok(outcome)) => print_outcome(outcome),
+        (None, Err(e)) => eprintln!("{:#}", e),
+    }
+}
+
+fn print_outcome(outcome: Outcome) {
+    match outcome {
+        Outcome::Done => {}
+        Outcome::Offer { peer_id, sdp } => {
+            println!("\n=== OFFER for {} ===", peer_id);
+            println!("{sdp}");
+        }
+        Outcome::Connected { peer_id } => println!("Linked directly to {}.", peer_id),
+        Outcome::Answer { peer_id, sdp } => {
+            println!("\n=== ANSWER for {} ===", peer_id);
+            println!("{sdp}");
+        }
+        Outcome::Code { peer_id, kind, code, .. } => {
+            let (what, then) = match kind {
+                CodeKind::Invite => ("INVITE", "redeem it and send back the answer code"),
+                CodeKind::Answer => ("ANSWER CODE", "redeem it to connect"),
+            };
+            println!("\n=== {} for {} ===", what, peer_id);
+            match invite::qr(&code) {
+                Ok(qr) => println!("{qr}"),
+                Err(e) => warn!("No QR code: {}", e),
+            }
+            println!("{code}");
+            println!("Valid for {} minutes; {} should {}.", invite::CODE_TTL.as_secs() / 60, peer_id, then);
+        }
+        Outcome::FileOffered { peer_id, transfer_id } => {
+            println!("[File]: offered transfer {} to {}.", transfer_id, peer_id);
+        }
+        Outcome::History(records) => {
+            for record in records {
+                let m = &record.message;
+                let to = match &m.to {
+                    ChatTarget::Peer(peer) => peer.as_str(),
+                    ChatTarget::All => "all",
+                };
+                let delivered = if record.delivered_to.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (delivered to {})", record.delivered_to.join(", "))
+                };
+                println!("[{}] {} -> {}: {}{}", m.timestamp, m.from, to, m.text, delivered);
+            }
+        }
+        Outcome::Exported { path } => println!("[Chat]: history exported to {}.", path.display()),
+        Outcome::Stats(stats) => {
+            for (peer_id, s) in stats {
+                println!(
+                    "[Stats] {}: out {} pkts / {} frames, {} -> {} bytes ({:.1}% saved, {} batched, {} compressed); in {} pkts / {} frames, {} -> {} bytes",
+                    peer_id,
+                    s.packets_out,
+                    s.frames_out,
+                    s.raw_bytes_out,
+                    s.wire_bytes_out,
+                    s.saved_out() * 100.0,
+                    s.batched_packets_out,
+                    s.compressed_frames_out,
+                    s.packets_in,
+                    s.frames_in,
+                    s.wire_bytes_in,
+                    s.raw_bytes_in,
+                );
+            }
+        }
+        Outcome::Peers(peers) => {
+            if peers.is_empty() {
+                println!("No peers.");
+            }
+            for p in peers {
+                let addresses: Vec<String> = p.addresses.iter().map(|a| a.to_string()).collect();
+                let version = p
+                    .version
+                    .map(|v| format!("{} (protocol {})", v.software, v.protocol))
+                    .unwrap_or_default();
+                let rtt = p
+                    .latency
+                    .and_then(|l| l.avg_rtt_ms)
+                    .map(|ms| format!("{:.1} ms", ms))
+                    .unwrap_or_default();
+                println!(
+                    "{:<16} {:<12} {:<8} {:<16} {:<10} {}",
+                    p.id,
+                    p.state,
+                    p.transport,
+                    addresses.join(","),
+                    rtt,
+                    version
+                );
+            }
+        }
+        Outcome::Routes(routes) => {
+            if routes.is_empty() {
+                println!("No routes.");
+            }
+            for r in routes {
+                match r.hops {
+                    1 => println!("{:<40} via {}", r.destination, r.peer_id),
+                    hops => println!("{:<40} via {} ({} hops)", r.destination, r.peer_id, hops),
+                }
+            }
+        }
+        Outcome::Trusted(peers) => {
+            if peers.is_empty() {
+                println!("No trusted peers.");
+            }
+            for p in peers {
+                let source = match p.source {
+                    TrustSource::FirstUse => "first use",
+                    TrustSource::Explicit => "explicit",
+                };
+                println!("{:<16} {:<10} {}", p.peer_id, source, p.fingerprint);
+            }
+        }
+        Outcome::Bans(bans) => {
+            if bans.is_empty() {
+                println!("No bans.");
+            }
+            for b in bans {
+                println!(
+                    "{:<16} {:<20} {}",
+                    b.peer_id.as_deref().unwrap_or("-"),
+                    b.reason.as_deref().unwrap_or(""),
+                    b.fingerprint.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        Outcome::Ping(report) => {
+            for (seq, reply) in report.replies.iter().enumerate() {
+                match reply {
+                    Some(ms) => println!("{}: seq={} time={:.2} ms", report.peer_id, seq, ms),
+                    None => println!("{}: seq={} lost", report.peer_id, seq),
+                }
+            }
+            let times: Vec<f64> = report.replies.iter().flatten().copied().collect();
+            let sent = report.replies.len();
+            let lost = sent - times.len();
+            println!(
+                "--- {}: {} sent, {} received, {:.0}% loss",
+                report.peer_id,
+                sent,
+                times.len(),
+                lost as f64 * 100.0 / sent as f64
+            );
+            if !times.is_empty() {
+                let min = times.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = times.iter().copied().fold(0.0, f64::max);
+                let avg = times.iter().sum::<f64>() / times.len() as f64;
+                println!("rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", min, avg, max);
+            }
+            let l = report.latency;
+            println!(
+                "continuous: avg {:.2} ms, jitter {:.2} ms, loss {:.1}% over {} pings",
+                l.avg_rtt_ms.unwrap_or_default(),
+                l.jitter_ms,
+                l.loss * 100.0,
+                l.sent
+            );
+        }
+        Outcome::Throughput(r) => {
+            println!(
+                "[Throughput] {}, {}, {:.1} s: sent {} ({} frames), received {} ({} frames), {:.1}% loss, {} reordered",
+                r.peer_id,
+                r.mode,
+                r.seconds,
+                format_rate(r.sent_bps),
+                r.sent_frames,
+                format_rate(r.received_bps),
+                r.received_frames,
+                r.loss * 100.0,
+                r.reordered,
+            );
+            if let Some(rtt) = r.rtt {
+                println!(
+                    "rtt under load min/p50/p90/p99/max = {:.2}/{:.2}/{:.2}/{:.2}/{:.2} ms ({} samples)",
+                    rtt.min_ms, rtt.p50_ms, rtt.p90_ms, rtt.p99_ms, rtt.max_ms, rtt.samples
+                );
+            }
+        }
+        Outcome::Status(s) => {
+            println!("Peer id:        {}", s.peer_id);
+            println!("Fingerprint:    {}", s.fingerprint);
+            println!("Device:         {} {} (mtu {})", s.device, s.address, s.mtu);
+            println!("Signal server:  {}", s.signal_server);
+            println!("Peers:          {} connected, {} known", s.connected, s.peers);
+        }
+    }
+}
+
+fn format_rate(bps: f64) -> String {
+    match bps {
+        b if b >= 1e9 => format!("{:.2} Gbit/s", b / 1e9),
+        b if b >= 1e6 => format!("{:.2} Mbit/s", b / 1e6),
+        b => format!("{:.1} kbit/s", b / 1e3),
+    }
+}
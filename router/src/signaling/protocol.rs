@@ -1,6 +1,6 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug,Clone, Serialize, Deserialize)]
+#[derive(Debug,Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SignalMessage {
     Register {
         peer_id: String,
@@ -4,35 +4,85 @@ use tokio::{
     net::{TcpListener, TcpStream},
     sync::{mpsc, RwLock},
 };
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use std::{collections::HashMap, sync::Arc};
-
+use crate::metrics::{self, Exposition, Kind};
 use crate::signaling::protocol::SignalMessage;
 
 type PeerMap = Arc<RwLock<HashMap<String, mpsc::Sender<SignalMessage>>>>;
 
-pub async fn run_server(addr: &str) -> Result<()> {
+#[derive(Debug, Default)]
+struct ServerStats {
+    sessions: AtomicU64,
+    sessions_total: AtomicU64,
+    offers_relayed: AtomicU64,
+    answers_relayed: AtomicU64,
+    undeliverable: AtomicU64,
+}
+
+/// Relays offers and answers between registered peers, optionally serving
+/// Prometheus metrics on `metrics_addr`.
+pub async fn run_server(addr: &str, metrics_addr: Option<SocketAddr>) -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
     let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+    let stats = Arc::new(ServerStats::default());
+
+    info!("Signaling server listening on {}", addr);
+
+    let metrics_server = async {
+        if let Some(metrics_addr) = metrics_addr {
+            let (stats, peers) = (stats.clone(), peers.clone());
+            let render = move || {
+                let (stats, peers) = (stats.clone(), peers.clone());
+                async move { render(&stats, peers.read().await.len()) }
+            };
+            if let Err(e) = metrics::serve(metrics_addr, render).await {
+                warn!("Metrics endpoint {} unavailable: {}", metrics_addr, e);
+            }
+        }
+        std::future::pending::<()>().await
+    };
 
-    println!("Signaling server listening on {}", addr);
+    tokio::select! {
+        res = accept(listener, peers.clone(), stats.clone()) => res,
+        _ = metrics_server => Ok(()),
+    }
+}
 
+async fn accept(listener: TcpListener, peers: PeerMap, stats: Arc<ServerStats>) -> Result<()> {
     loop {
-        let (socket, _) = listener.accept().await?;
+        let (socket, remote) = listener.accept().await?;
 
         let peers = peers.clone();
+        let stats = stats.clone();
 
+        // The peer id is filled in once the client registers.
+        let span = info_span!("session", %remote, peer = field::Empty);
         tokio::spawn(async move {
-            if let Err(e) = handle_peer(socket, peers).await {
-                eprintln!("Peer error: {}", e);
+            stats.sessions.fetch_add(1, Ordering::Relaxed);
+            stats.sessions_total.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = handle_peer(socket, peers, &stats).await {
+                warn!("Peer error: {}", e);
             }
-        });
+            stats.sessions.fetch_sub(1, Ordering::Relaxed);
+            info!("Session closed");
+        }.instrument(span));
     }
 }
 
 async fn handle_peer(
     socket: TcpStream,
     peers: PeerMap,
+    stats: &ServerStats,
 ) -> Result<()> {
     let (reader, mut writer) = socket.into_split();
 
@@ -59,13 +109,23 @@ async fn handle_peer(
 
                 my_peer_id = Some(peer_id.clone());
 
-                println!("Registered {}", peer_id);
+                Span::current().record("peer", field::display(peer_id));
+                info!("Registered {}", peer_id);
             }
 
             SignalMessage::Offer { to, .. }
             | SignalMessage::Answer { to, .. } => {
                 if let Some(target) = peers.read().await.get(to) {
+                    debug!("Relaying to {}", to);
+                    let relayed = match msg {
+                        SignalMessage::Offer { .. } => &stats.offers_relayed,
+                        _ => &stats.answers_relayed,
+                    };
+                    relayed.fetch_add(1, Ordering::Relaxed);
                     target.send(msg).await?;
+                } else {
+                    debug!("Nobody registered as {}", to);
+                    stats.undeliverable.fetch_add(1, Ordering::Relaxed);
                 }
             }
 
@@ -78,4 +138,26 @@ async fn handle_peer(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+fn render(stats: &ServerStats, registered: usize) -> String {
+    let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    let mut out = Exposition::default();
+
+    out.describe("lanracer_signal_sessions", Kind::Gauge, "Open client connections.");
+    out.sample("lanracer_signal_sessions", &[], get(&stats.sessions));
+
+    out.describe("lanracer_signal_sessions_total", Kind::Counter, "Client connections accepted.");
+    out.sample("lanracer_signal_sessions_total", &[], get(&stats.sessions_total));
+
+    out.describe("lanracer_signal_registered_peers", Kind::Gauge, "Peers registered under an id.");
+    out.sample("lanracer_signal_registered_peers", &[], registered);
+
+    out.describe("lanracer_signal_relayed_total", Kind::Counter, "Messages relayed to their recipient, by kind.");
+    out.sample("lanracer_signal_relayed_total", &[("kind", "offer")], get(&stats.offers_relayed));
+    out.sample("lanracer_signal_relayed_total", &[("kind", "answer")], get(&stats.answers_relayed));
+
+    out.describe("lanracer_signal_undeliverable_total", Kind::Counter, "Messages for ids nobody registered.");
+    out.sample("lanracer_signal_undeliverable_total", &[], get(&stats.undeliverable));
+
+    out.finish()
+}
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::latency::LatencySnapshot;
+use crate::queue::QueueStatsSnapshot;
+use crate::transport::LinkState;
+
+/// Per-peer counters for the framing layer. "Raw" bytes are IP packets as
+/// read from or written to the device, "wire" bytes are what went over the
+/// data channel after batching, compression and fragmentation.
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    pub packets_out: AtomicU64,
+    pub raw_bytes_out: AtomicU64,
+    pub frames_out: AtomicU64,
+    pub wire_bytes_out: AtomicU64,
+    pub batched_packets_out: AtomicU64,
+    pub compressed_frames_out: AtomicU64,
+    /// Packets larger than the peer's tunnel MTU, answered with an ICMP
+    /// error instead.
+    pub too_big_out: AtomicU64,
+    pub packets_in: AtomicU64,
+    pub raw_bytes_in: AtomicU64,
+    pub frames_in: AtomicU64,
+    pub wire_bytes_in: AtomicU64,
+    /// Frames that failed to decode.
+    pub bad_frames_in: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkStatsSnapshot {
+    pub packets_out: u64,
+    pub raw_bytes_out: u64,
+    pub frames_out: u64,
+    pub wire_bytes_out: u64,
+    pub batched_packets_out: u64,
+    pub compressed_frames_out: u64,
+    pub too_big_out: u64,
+    pub packets_in: u64,
+    pub raw_bytes_in: u64,
+    pub frames_in: u64,
+    pub wire_bytes_in: u64,
+    pub bad_frames_in: u64,
+}
+
+impl LinkStats {
+    pub fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LinkStatsSnapshot {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        LinkStatsSnapshot {
+            packets_out: get(&self.packets_out),
+            raw_bytes_out: get(&self.raw_bytes_out),
+            frames_out: get(&self.frames_out),
+            wire_bytes_out: get(&self.wire_bytes_out),
+            batched_packets_out: get(&self.batched_packets_out),
+            compressed_frames_out: get(&self.compressed_frames_out),
+            too_big_out: get(&self.too_big_out),
+            packets_in: get(&self.packets_in),
+            raw_bytes_in: get(&self.raw_bytes_in),
+            frames_in: get(&self.frames_in),
+            wire_bytes_in: get(&self.wire_bytes_in),
+            bad_frames_in: get(&self.bad_frames_in),
+        }
+    }
+}
+
+impl LinkStatsSnapshot {
+    /// Fraction of outbound raw bytes saved on the wire; negative when
+    /// framing overhead outweighs batching and compression.
+    pub fn saved_out(&self) -> f64 {
+        if self.raw_bytes_out == 0 {
+            return 0.0;
+        }
+        1.0 - self.wire_bytes_out as f64 / self.raw_bytes_out as f64
+    }
+}
+
+/// Everything the metrics endpoint reports about one peer.
+#[derive(Debug, Clone)]
+pub struct PeerMetrics {
+    pub peer_id: String,
+    pub state: LinkState,
+    /// What carries the link: `webrtc`, `quic` or `loopback`.
+    pub transport: &'static str,
+    pub link: LinkStatsSnapshot,
+    pub channels: Vec<ChannelMetrics>,
+    /// Local and remote type of the ICE candidate pair in use, e.g.
+    /// `host` and `srflx`.
+    pub candidates: Option<(String, String)>,
+    /// The transport's own round trip estimate: the latest STUN round trip
+    /// on that pair, or QUIC's.
+    pub rtt_seconds: Option<f64>,
+    /// From our own pings over the packet channel.
+    pub latency: Option<LatencySnapshot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelMetrics {
+    pub label: String,
+    /// Bytes handed to the transport and not yet sent.
+    pub buffered: usize,
+    /// Frames waiting in our own queue in front of it.
+    pub queued: usize,
+    pub queue: QueueStatsSnapshot,
+}
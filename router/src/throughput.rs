@@ -0,0 +1,459 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::frame::Frame;
+use crate::peer::{self, PeerManager};
+use crate::transfer::CHUNK_SIZE;
+
+/// How long a test sends for unless asked otherwise.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// Sending rate of an unreliable test unless asked otherwise, in bits per
+/// second. Unpaced, it would only measure how fast the local queue drops.
+pub const DEFAULT_UNRELIABLE_BITRATE: u64 = 10_000_000;
+/// Longest test that can be asked for.
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// How long a test asked to run for `seconds` sends for: the default when
+/// not asked, and never longer than `MAX_DURATION`.
+pub fn duration(seconds: Option<f64>) -> Result<Duration> {
+    match seconds {
+        None => Ok(DEFAULT_DURATION),
+        // Infinite or too large for a `Duration` before clamping.
+        Some(s) if s > 0.0 => {
+            Ok(Duration::try_from_secs_f64(s.min(MAX_DURATION.as_secs_f64())).unwrap_or(MAX_DURATION))
+        }
+        Some(_) => Err(anyhow!("duration must be a positive number")),
+    }
+}
+
+// Type, test id, sequence number.
+pub const DATA_HEADER: usize = 1 + 4 + 4;
+
+// Every this many data frames is echoed back as a round trip sample.
+const ECHO_EVERY: u32 = 16;
+// The remote has this long to get ready for a test.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+// Once the sender is done, the receiver reports when everything sent has
+// arrived, when nothing has for a while, or at the latest after the limit.
+const SETTLE: Duration = Duration::from_millis(500);
+const DRAIN_LIMIT: Duration = Duration::from_secs(10);
+const SETTLE_POLL: Duration = Duration::from_millis(50);
+// Granularity of the sending rate.
+const PACE: Duration = Duration::from_millis(5);
+
+/// Which data channel a test runs over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThroughputMode {
+    /// The ordered, reliable channel file transfers use.
+    #[default]
+    Reliable,
+    /// The unordered, no-retransmit channel tunneled packets use.
+    Unreliable,
+}
+
+impl fmt::Display for ThroughputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ThroughputMode::Reliable => "reliable",
+            ThroughputMode::Unreliable => "unreliable",
+        })
+    }
+}
+
+/// Test negotiation, sent as JSON on the control channel. Data frames and
+/// their echoes travel on the channel under test.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThroughputControl {
+    Start { id: u32, mode: ThroughputMode },
+    Ready { id: u32 },
+    /// The sender is done; `frames` is how many it sent.
+    Finish { id: u32, frames: u64 },
+    /// What the receiver got, `micros` after it was ready.
+    Result {
+        id: u32,
+        frames: u64,
+        bytes: u64,
+        reordered: u64,
+        micros: u64,
+    },
+}
+
+/// Outcome of a throughput test, seen from the sending side.
+#[derive(Debug, Clone, Serialize)]
+pub struct ThroughputReport {
+    pub peer_id: String,
+    pub mode: ThroughputMode,
+    /// How long data was sent for, in seconds.
+    pub seconds: f64,
+    pub sent_frames: u64,
+    pub sent_bytes: u64,
+    pub received_frames: u64,
+    pub received_bytes: u64,
+    /// Rate data was handed to the channel, in bits per second.
+    pub sent_bps: f64,
+    /// Rate data arrived at the peer, in bits per second.
+    pub received_bps: f64,
+    /// Fraction of frames that never arrived.
+    pub loss: f64,
+    /// Frames that arrived after a later one.
+    pub reordered: u64,
+    /// Round trips of sampled data frames, so under load.
+    pub rtt: Option<RttDistribution>,
+}
+
+/// Round trip times in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct RttDistribution {
+    pub samples: usize,
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl RttDistribution {
+    fn new(mut samples: Vec<Duration>) -> Option<Self> {
+        samples.sort();
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        // Nearest rank.
+        let percentile = |p: usize| ms(&samples[(samples.len() * p).div_ceil(100).max(1) - 1]);
+        Some(Self {
+            samples: samples.len(),
+            min_ms: ms(samples.first()?),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: ms(samples.last()?),
+        })
+    }
+}
+
+struct Received {
+    frames: u64,
+    bytes: u64,
+    reordered: u64,
+    micros: u64,
+}
+
+struct Sending {
+    peer_id: String,
+    ready: Option<oneshot::Sender<()>>,
+    result: Option<oneshot::Sender<Received>>,
+    /// Send times of frames that will be echoed.
+    echoed: HashMap<u32, Instant>,
+    rtts: Vec<Duration>,
+}
+
+struct Receiving {
+    mode: ThroughputMode,
+    ready_at: Instant,
+    last_at: Option<Instant>,
+    frames: u64,
+    bytes: u64,
+    next_seq: u32,
+    reordered: u64,
+    /// Frames sent, once the sender said it is done.
+    expected: Option<u64>,
+}
+
+type ReceivingMap = Arc<Mutex<HashMap<(String, u32), Receiving>>>;
+
+/// Throughput tests with connected peers. Either side can start one; the
+/// other answers on its own, so nobody has to run anything at the far end.
+pub struct ThroughputTests {
+    next_id: AtomicU32,
+    sending: Mutex<HashMap<u32, Sending>>,
+    receiving: ReceivingMap,
+}
+
+impl Default for ThroughputTests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThroughputTests {
+    pub fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+
+        Self {
+            next_id: AtomicU32::new(seed),
+            sending: Mutex::new(HashMap::new()),
+            receiving: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Sends to `peer_id` for `duration`, at most at `bitrate` bits per
+    /// second if given, and reports what arrived.
+    pub async fn run(
+        &self,
+        manager: &PeerManager,
+        peer_id: &str,
+        mode: ThroughputMode,
+        duration: Duration,
+        bitrate: Option<u64>,
+    ) -> Result<ThroughputReport> {
+        let sender = manager.throughput_sender(peer_id, mode).await?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (result_tx, result_rx) = oneshot::channel();
+        self.sending.lock().unwrap().insert(id, Sending {
+            peer_id: peer_id.to_owned(),
+            ready: Some(ready_tx),
+            result: Some(result_tx),
+            echoed: HashMap::new(),
+            rtts: Vec::new(),
+        });
+
+        let result = async {
+            let start = ThroughputControl::Start { id, mode };
+            manager.send_control(peer_id, Frame::Throughput(start)).await?;
+            tokio::time::timeout(READY_TIMEOUT, ready_rx)
+                .await
+                .map_err(|_| anyhow!("{} did not answer the test", peer_id))??;
+
+            let payload = Bytes::from(vec![0u8; frame_len(manager, mode) - DATA_HEADER]);
+            let started = Instant::now();
+            let deadline = started + duration;
+            let (mut frames, mut bytes) = (0u64, 0u64);
+            while Instant::now() < deadline {
+                if let Some(bitrate) = bitrate {
+                    let allowed = (bitrate as f64 / 8.0 * started.elapsed().as_secs_f64()) as u64;
+                    if bytes >= allowed {
+                        tokio::time::sleep(PACE).await;
+                        continue;
+                    }
+                }
+
+                let seq = frames as u32;
+                let frame = Frame::ThroughputData { id, seq, data: payload.clone() }.encode();
+                let len = frame.len() as u64;
+                if seq.is_multiple_of(ECHO_EVERY)
+                    && let Some(test) = self.sending.lock().unwrap().get_mut(&id)
+                {
+                    test.echoed.insert(seq, Instant::now());
+                }
+                match mode {
+                    ThroughputMode::Reliable => match tokio::time::timeout_at(deadline, sender.push(frame)).await {
+                        Ok(result) => result?,
+                        Err(_) => break,
+                    },
+                    ThroughputMode::Unreliable => {
+                        sender.try_push(frame);
+                    }
+                }
+                frames += 1;
+                bytes += len;
+            }
+            let elapsed = started.elapsed();
+
+            let finish = ThroughputControl::Finish { id, frames };
+            manager.send_control(peer_id, Frame::Throughput(finish)).await?;
+            let received = tokio::time::timeout(DRAIN_LIMIT + READY_TIMEOUT, result_rx)
+                .await
+                .map_err(|_| anyhow!("{} did not report the test", peer_id))??;
+            Ok::<_, anyhow::Error>((frames, bytes, elapsed, received))
+        }
+        .await;
+
+        let rtts = self
+            .sending
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|test| test.rtts)
+            .unwrap_or_default();
+        let (frames, bytes, elapsed, received) = result?;
+
+        let seconds = elapsed.as_secs_f64();
+        let received_seconds = received.micros as f64 / 1e6;
+        Ok(ThroughputReport {
+            peer_id: peer_id.to_owned(),
+            mode,
+            seconds,
+            sent_frames: frames,
+            sent_bytes: bytes,
+            received_frames: received.frames,
+            received_bytes: received.bytes,
+            sent_bps: rate(bytes, seconds),
+            received_bps: rate(received.bytes, received_seconds),
+            loss: if frames == 0 {
+                0.0
+            } else {
+                frames.saturating_sub(received.frames) as f64 / frames as f64
+            },
+            reordered: received.reordered,
+            rtt: RttDistribution::new(rtts),
+        })
+    }
+
+    pub async fn on_control(&self, manager: &PeerManager, peer_id: String, msg: ThroughputControl) {
+        match msg {
+            ThroughputControl::Start { id, mode } => {
+                self.receiving.lock().unwrap().insert((peer_id.clone(), id), Receiving {
+                    mode,
+                    ready_at: Instant::now(),
+                    last_at: None,
+                    frames: 0,
+                    bytes: 0,
+                    next_seq: 0,
+                    reordered: 0,
+                    expected: None,
+                });
+
+                debug!(%mode, "Throughput test started by peer");
+                let ready = ThroughputControl::Ready { id };
+                if let Err(e) = manager.send_control(&peer_id, Frame::Throughput(ready)).await {
+                    warn!("Throughput test not started: {}", e);
+                }
+            }
+
+            ThroughputControl::Ready { id } => {
+                if let Some(test) = self.sending.lock().unwrap().get_mut(&id).filter(|t| t.peer_id == peer_id)
+                    && let Some(ready) = test.ready.take()
+                {
+                    let _ = ready.send(());
+                }
+            }
+
+            ThroughputControl::Finish { id, frames } => {
+                let key = (peer_id, id);
+                match self.receiving.lock().unwrap().get_mut(&key) {
+                    Some(test) => test.expected = Some(frames),
+                    None => return,
+                }
+                tokio::spawn(report(manager.clone(), self.receiving.clone(), key));
+            }
+
+            ThroughputControl::Result { id, frames, bytes, reordered, micros } => {
+                if let Some(test) = self.sending.lock().unwrap().get_mut(&id).filter(|t| t.peer_id == peer_id)
+                    && let Some(result) = test.result.take()
+                {
+                    let _ = result.send(Received { frames, bytes, reordered, micros });
+                }
+            }
+        }
+    }
+
+    /// Counts a data frame and echoes the sampled ones back on the channel
+    /// they came in on.
+    pub async fn on_data(&self, manager: &PeerManager, peer_id: &str, id: u32, seq: u32, len: usize) {
+        let mode = {
+            let mut receiving = self.receiving.lock().unwrap();
+            let Some(test) = receiving.get_mut(&(peer_id.to_owned(), id)) else {
+                return;
+            };
+            test.frames += 1;
+            test.bytes += (DATA_HEADER + len) as u64;
+            test.last_at = Some(Instant::now());
+            if seq < test.next_seq {
+                test.reordered += 1;
+            } else {
+                test.next_seq = seq.wrapping_add(1);
+            }
+            test.mode
+        };
+
+        if seq.is_multiple_of(ECHO_EVERY)
+            && let Ok(sender) = manager.throughput_sender(peer_id, mode).await
+        {
+            sender.try_push(Frame::ThroughputEcho { id, seq }.encode());
+        }
+    }
+
+    pub fn on_echo(&self, peer_id: &str, id: u32, seq: u32) {
+        if let Some(test) = self.sending.lock().unwrap().get_mut(&id).filter(|t| t.peer_id == peer_id)
+            && let Some(sent) = test.echoed.remove(&seq)
+        {
+            test.rtts.push(sent.elapsed());
+        }
+    }
+
+    /// Drops the tests a disconnected peer was sending us.
+    pub fn forget_peer(&self, peer_id: &str) {
+        self.receiving.lock().unwrap().retain(|(pid, _), _| pid != peer_id);
+    }
+}
+
+// Waits for the rest of the data to come in, then tells the sender what
+// arrived.
+async fn report(manager: PeerManager, receiving: ReceivingMap, key: (String, u32)) {
+    let finished = Instant::now();
+    let received = loop {
+        tokio::time::sleep(SETTLE_POLL).await;
+        let mut tests = receiving.lock().unwrap();
+        let Some(test) = tests.get(&key) else {
+            return;
+        };
+        let complete = test.expected.is_some_and(|n| test.frames >= n);
+        let quiet = test.last_at.unwrap_or(finished).elapsed() >= SETTLE;
+        if complete || quiet || finished.elapsed() >= DRAIN_LIMIT {
+            break tests.remove(&key);
+        }
+    };
+    let Some(test) = received else {
+        return;
+    };
+
+    let (peer_id, id) = key;
+    let micros = test.last_at.map_or(0, |last| (last - test.ready_at).as_micros() as u64);
+    let result = ThroughputControl::Result {
+        id,
+        frames: test.frames,
+        bytes: test.bytes,
+        reordered: test.reordered,
+        micros,
+    };
+    if let Err(e) = manager.send_control(&peer_id, Frame::Throughput(result)).await {
+        warn!(parent: &peer::span(&peer_id), "Throughput result not sent: {}", e);
+    }
+}
+
+// Reliable tests send file-sized chunks; unreliable ones stay within one
+// path packet, like tunneled packets do.
+fn frame_len(manager: &PeerManager, mode: ThroughputMode) -> usize {
+    match mode {
+        ThroughputMode::Reliable => DATA_HEADER + CHUNK_SIZE,
+        ThroughputMode::Unreliable => manager.max_frame(),
+    }
+}
+
+fn rate(bytes: u64, seconds: f64) -> f64 {
+    if seconds > 0.0 { bytes as f64 * 8.0 / seconds } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_duration() {
+        assert_eq!(duration(None).unwrap(), DEFAULT_DURATION);
+        assert_eq!(duration(Some(2.5)).unwrap(), Duration::from_millis(2500));
+        assert_eq!(duration(Some(61.0)).unwrap(), MAX_DURATION);
+        assert_eq!(duration(Some(1e300)).unwrap(), MAX_DURATION);
+        assert_eq!(duration(Some(f64::INFINITY)).unwrap(), MAX_DURATION);
+        assert_eq!(duration(Some(f64::MIN_POSITIVE)).unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_bad_duration() {
+        for s in [0.0, -1.0, f64::NEG_INFINITY, f64::NAN] {
+            assert!(duration(Some(s)).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::event::LanEvent;
+use crate::frame::Frame;
+use crate::peer::{self, PeerManager};
+
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+// Progress events go out at most once per this many bytes.
+const PROGRESS_STEP: u64 = 256 * 1024;
+
+/// Transfer negotiation, sent as JSON on the control channel. Chunks
+/// travel separately on the file channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileControl {
+    Offer {
+        id: u64,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Start, or resume after a reconnect, sending from `offset`.
+    Accept { id: u64, offset: u64 },
+    Cancel { id: u64, reason: String },
+    /// Receiver verified (or failed to verify) the whole file.
+    Complete { id: u64, ok: bool },
+}
+
+struct Outgoing {
+    peer_id: String,
+    path: PathBuf,
+    size: u64,
+    stream: Option<JoinHandle<()>>,
+}
+
+struct Incoming {
+    name: String,
+    size: u64,
+    sha256: String,
+    /// Set once the user accepted the offer.
+    dest: Option<PathBuf>,
+    file: Option<File>,
+    received: u64,
+    reported: u64,
+}
+
+impl Incoming {
+    fn part_path(dest: &Path) -> PathBuf {
+        let mut name = dest.as_os_str().to_owned();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+}
+
+/// File transfers with connected peers. Offers have to be accepted before
+/// any data flows; partially received files are kept next to their
+/// destination so a transfer cut off by a reconnect picks up where it
+/// stopped.
+pub struct FileTransfers {
+    event_tx: mpsc::Sender<LanEvent>,
+    next_id: AtomicU64,
+    outgoing: Mutex<HashMap<u64, Outgoing>>,
+    incoming: Mutex<HashMap<(String, u64), Incoming>>,
+}
+
+impl FileTransfers {
+    pub fn new(event_tx: mpsc::Sender<LanEvent>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self {
+            event_tx,
+            next_id: AtomicU64::new(seed),
+            outgoing: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn offer(&self, manager: &PeerManager, peer_id: &str, path: PathBuf) -> Result<u64> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or(anyhow!("Not a file: {}", path.display()))?
+            .to_owned();
+        let size = fs::metadata(&path).await?.len();
+        let sha256 = sha256_file(&path).await?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.outgoing.lock().await.insert(id, Outgoing {
+            peer_id: peer_id.to_owned(),
+            path,
+            size,
+            stream: None,
+        });
+
+        let offer = FileControl::Offer { id, name, size, sha256 };
+        if let Err(e) = manager.send_control(peer_id, Frame::File(offer)).await {
+            self.outgoing.lock().await.remove(&id);
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    /// Takes up offer `id` from `peer_id`, saving to `dest`, or under the
+    /// offered name when `dest` is a directory.
+    pub async fn accept(&self, manager: &PeerManager, peer_id: &str, id: u64, dest: PathBuf) -> Result<()> {
+        let offset = {
+            let mut incoming = self.incoming.lock().await;
+            let transfer = incoming
+                .get_mut(&(peer_id.to_owned(), id))
+                .ok_or(anyhow!("No file offer {} from {}", id, peer_id))?;
+            let dest = if fs::metadata(&dest).await.is_ok_and(|m| m.is_dir()) {
+                dest.join(&transfer.name)
+            } else {
+                dest
+            };
+
+            // Keep whatever an earlier attempt already wrote.
+            let part = Incoming::part_path(&dest);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&part)
+                .await?;
+            let mut offset = file.metadata().await?.len();
+            if offset > transfer.size {
+                file.set_len(0).await?;
+                offset = 0;
+            }
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+            transfer.dest = Some(dest);
+            transfer.file = Some(file);
+            transfer.received = offset;
+            transfer.reported = offset;
+            offset
+        };
+
+        manager
+            .send_control(peer_id, Frame::File(FileControl::Accept { id, offset }))
+            .await?;
+
+        if offset > 0 {
+            self.finish_if_done(manager, peer_id, id).await;
+        }
+        Ok(())
+    }
+
+    pub async fn on_control(&self, manager: &PeerManager, peer_id: String, msg: FileControl) {
+        match msg {
+            FileControl::Offer { id, name, size, sha256 } => {
+                let name = safe_name(&name);
+                let key = (peer_id.clone(), id);
+                let mut incoming = self.incoming.lock().await;
+                // A re-sent offer for a transfer already under way is
+                // answered by `resume` instead.
+                if incoming.contains_key(&key) {
+                    return;
+                }
+                incoming.insert(key, Incoming {
+                    name: name.clone(),
+                    size,
+                    sha256,
+                    dest: None,
+                    file: None,
+                    received: 0,
+                    reported: 0,
+                });
+                drop(incoming);
+
+                let _ = self.event_tx.send(LanEvent::FileOffered {
+                    peer_id,
+                    transfer_id: id,
+                    name,
+                    size,
+                }).await;
+            }
+
+            FileControl::Accept { id, offset } => {
+                let mut outgoing = self.outgoing.lock().await;
+                let Some(transfer) = outgoing.get_mut(&id).filter(|t| t.peer_id == peer_id) else {
+                    drop(outgoing);
+                    let cancel = FileControl::Cancel { id, reason: "unknown transfer".into() };
+                    let _ = manager.send_control(&peer_id, Frame::File(cancel)).await;
+                    return;
+                };
+
+                if let Some(stream) = transfer.stream.take() {
+                    stream.abort();
+                }
+                transfer.stream = Some(tokio::spawn(stream_file(
+                    manager.clone(),
+                    self.event_tx.clone(),
+                    peer_id,
+                    id,
+                    transfer.path.clone(),
+                    transfer.size,
+                    offset,
+                )));
+            }
+
+            FileControl::Cancel { id, reason } => {
+                if let Some(transfer) = self.outgoing.lock().await.remove(&id)
+                    && let Some(stream) = transfer.stream
+                {
+                    stream.abort();
+                }
+                if let Some(transfer) = self.incoming.lock().await.remove(&(peer_id.clone(), id))
+                    && let Some(dest) = transfer.dest
+                {
+                    let _ = fs::remove_file(Incoming::part_path(&dest)).await;
+                }
+                self.failed(peer_id, id, reason).await;
+            }
+
+            FileControl::Complete { id, ok } => {
+                self.outgoing.lock().await.remove(&id);
+                if ok {
+                    let _ = self.event_tx.send(LanEvent::FileCompleted {
+                        peer_id,
+                        transfer_id: id,
+                        path: None,
+                    }).await;
+                } else {
+                    self.failed(peer_id, id, "checksum mismatch".into()).await;
+                }
+            }
+        }
+    }
+
+    pub async fn on_chunk(&self, manager: &PeerManager, peer_id: String, id: u64, offset: u64, data: Bytes) {
+        let progress = {
+            let mut incoming = self.incoming.lock().await;
+            let Some(transfer) = incoming.get_mut(&(peer_id.clone(), id)) else {
+                return;
+            };
+            let Some(file) = transfer.file.as_mut() else {
+                return;
+            };
+            // Left over from a stream that was restarted at another offset.
+            if offset != transfer.received {
+                return;
+            }
+            if let Err(e) = file.write_all(&data).await {
+                drop(incoming);
+                self.abort(manager, &peer_id, id, format!("write failed: {e}")).await;
+                return;
+            }
+            transfer.received += data.len() as u64;
+
+            let done = transfer.received >= transfer.size;
+            if done || transfer.received - transfer.reported >= PROGRESS_STEP {
+                transfer.reported = transfer.received;
+                Some((transfer.received, transfer.size, done))
+            } else {
+                None
+            }
+        };
+
+        let Some((bytes, total, done)) = progress else {
+            return;
+        };
+        let _ = self.event_tx.send(LanEvent::FileProgress {
+            peer_id: peer_id.clone(),
+            transfer_id: id,
+            bytes,
+            total,
+        }).await;
+
+        if done {
+            self.finish_if_done(manager, &peer_id, id).await;
+        }
+    }
+
+    /// Called when a peer's control channel is (re)established: asks it to
+    /// continue every accepted but unfinished transfer from that peer.
+    pub async fn resume(&self, manager: &PeerManager, peer_id: &str) {
+        let pending: Vec<(u64, u64)> = self
+            .incoming
+            .lock()
+            .await
+            .iter()
+            .filter(|((pid, _), t)| pid == peer_id && t.dest.is_some() && t.received < t.size)
+            .map(|((_, id), t)| (*id, t.received))
+            .collect();
+
+        for (id, offset) in pending {
+            let accept = FileControl::Accept { id, offset };
+            if let Err(e) = manager.send_control(peer_id, Frame::File(accept)).await {
+                warn!(parent: &peer::span(peer_id), "Resuming transfer {} failed: {}", id, e);
+            }
+        }
+    }
+
+    async fn finish_if_done(&self, manager: &PeerManager, peer_id: &str, id: u64) {
+        let key = (peer_id.to_owned(), id);
+        let transfer = {
+            let mut incoming = self.incoming.lock().await;
+            match incoming.get(&key) {
+                Some(t) if t.dest.is_some() && t.received >= t.size => incoming.remove(&key),
+                _ => None,
+            }
+        };
+        let Some(mut transfer) = transfer else {
+            return;
+        };
+        let Some(dest) = transfer.dest.take() else {
+            return;
+        };
+
+        let part = Incoming::part_path(&dest);
+        if let Some(mut file) = transfer.file.take() {
+            let _ = file.flush().await;
+        }
+
+        let verified = match sha256_file(&part).await {
+            Ok(sum) if sum == transfer.sha256 => fs::rename(&part, &dest).await.is_ok(),
+            _ => false,
+        };
+
+        let complete = FileControl::Complete { id, ok: verified };
+        let _ = manager.send_control(peer_id, Frame::File(complete)).await;
+
+        if verified {
+            let _ = self.event_tx.send(LanEvent::FileCompleted {
+                peer_id: peer_id.to_owned(),
+                transfer_id: id,
+                path: Some(dest),
+            }).await;
+        } else {
+            let _ = fs::remove_file(&part).await;
+            self.failed(peer_id.to_owned(), id, format!("{}: checksum mismatch", transfer.name)).await;
+        }
+    }
+
+    async fn abort(&self, manager: &PeerManager, peer_id: &str, id: u64, reason: String) {
+        self.incoming.lock().await.remove(&(peer_id.to_owned(), id));
+        let cancel = FileControl::Cancel { id, reason: reason.clone() };
+        let _ = manager.send_control(peer_id, Frame::File(cancel)).await;
+        self.failed(peer_id.to_owned(), id, reason).await;
+    }
+
+    async fn failed(&self, peer_id: String, id: u64, reason: String) {
+        let _ = self.event_tx.send(LanEvent::FileFailed {
+            peer_id,
+            transfer_id: id,
+            reason,
+        }).await;
+    }
+}
+
+async fn stream_file(
+    manager: PeerManager,
+    event_tx: mpsc::Sender<LanEvent>,
+    peer_id: String,
+    id: u64,
+    path: PathBuf,
+    size: u64,
+    mut offset: u64,
+) {
+    let result = async {
+        let mut file = File::open(&path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut reported = offset;
+        while offset < size {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow!("{} shrank while sending", path.display()));
+            }
+
+            let chunk = Frame::FileChunk {
+                id,
+                offset,
+                data: Bytes::copy_from_slice(&buf[..n]),
+            };
+            manager.send_file_chunk(&peer_id, chunk).await?;
+            offset += n as u64;
+
+            if offset - reported >= PROGRESS_STEP || offset >= size {
+                reported = offset;
+                let _ = event_tx.send(LanEvent::FileProgress {
+                    peer_id: peer_id.clone(),
+                    transfer_id: id,
+                    bytes: offset,
+                    total: size,
+                }).await;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    // A dropped connection is not fatal: the receiver asks to resume once
+    // it is back.
+    if let Err(e) = result {
+        warn!(parent: &peer::span(&peer_id), "Sending {} stopped: {}", path.display(), e);
+    }
+}
+
+// The offered name is the sender's to pick, so it is cut down to a bare
+// file name with nothing a terminal would act on before it is shown or
+// saved under.
+fn safe_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let base: String = base.chars().filter(|c| !c.is_control()).collect();
+    match base.trim() {
+        "" | "." | ".." => "file".to_owned(),
+        base => base.to_owned(),
+    }
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback::{self, LoopbackTransport};
+    use crate::transport::{LinkEvents, PeerTransport};
+    use crate::trust::TrustMode;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const PEER: &str = "bob";
+
+    struct Receiver {
+        dir: PathBuf,
+        manager: PeerManager,
+        transfers: FileTransfers,
+        events: mpsc::Receiver<LanEvent>,
+        sent: mpsc::UnboundedReceiver<FileControl>,
+        _far: LoopbackTransport,
+    }
+
+    // Transfers linked with `PEER` over a loopback pair, with what they
+    // send it collected on the far end.
+    async fn receiver(name: &str) -> Receiver {
+        let dir = std::env::temp_dir().join(format!("lan-racer-transfer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let manager = PeerManager::in_memory("alice", TrustMode::Tofu).await;
+        let (near, far) = loopback::pair();
+        let (tx, sent) = mpsc::unbounded_channel();
+        far.start(LinkEvents::new(
+            move |_, data| {
+                if let Ok(Frame::File(msg)) = Frame::decode(&data) {
+                    let _ = tx.send(msg);
+                }
+                async {}
+            },
+            |_| async {},
+        ));
+        manager.attach(PEER.into(), Arc::new(near)).await;
+
+        let (event_tx, events) = mpsc::channel(64);
+        Receiver { dir, manager, transfers: FileTransfers::new(event_tx), events, sent, _far: far }
+    }
+
+    impl Receiver {
+        async fn offer(&self, id: u64, name: &str, size: u64, sha256: String) {
+            let offer = FileControl::Offer { id, name: name.into(), size, sha256 };
+            self.transfers.on_control(&self.manager, PEER.into(), offer).await;
+        }
+
+        async fn accept(&self, id: u64, dest: PathBuf) {
+            self.transfers.accept(&self.manager, PEER, id, dest).await.unwrap();
+        }
+
+        async fn send(&self, id: u64, data: &[u8]) {
+            for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+                let offset = (i * CHUNK_SIZE) as u64;
+                let chunk = Bytes::copy_from_slice(chunk);
+                self.transfers.on_chunk(&self.manager, PEER.into(), id, offset, chunk).await;
+            }
+        }
+
+        // The next event other than progress.
+        async fn outcome(&mut self) -> LanEvent {
+            loop {
+                let event = tokio::time::timeout(Duration::from_secs(5), self.events.recv()).await.unwrap().unwrap();
+                if !matches!(event, LanEvent::FileOffered { .. } | LanEvent::FileProgress { .. }) {
+                    return event;
+                }
+            }
+        }
+
+        async fn sent(&mut self) -> FileControl {
+            tokio::time::timeout(Duration::from_secs(5), self.sent.recv()).await.unwrap().unwrap()
+        }
+    }
+
+    impl Drop for Receiver {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Not a multiple of the chunk size, so the last chunk is short.
+    fn content() -> Vec<u8> {
+        (0..CHUNK_SIZE * 2 + 1000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    async fn sha256(dir: &Path, data: &[u8]) -> String {
+        let path = dir.join("source");
+        std::fs::write(&path, data).unwrap();
+        let sum = sha256_file(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        sum
+    }
+
+    #[tokio::test]
+    async fn reassembles_chunks() {
+        let mut rx = receiver("reassemble").await;
+        let data = content();
+        let sum = sha256(&rx.dir, &data).await;
+        let dest = rx.dir.join("map.pak");
+
+        rx.offer(1, "map.pak", data.len() as u64, sum).await;
+        rx.accept(1, dest.clone()).await;
+        assert_eq!(rx.sent().await, FileControl::Accept { id: 1, offset: 0 });
+        // A chunk from anywhere but where the file stands is left over
+        // from an earlier stream.
+        rx.transfers.on_chunk(&rx.manager, PEER.into(), 1, 5, Bytes::from_static(b"stray")).await;
+        rx.send(1, &data).await;
+
+        match rx.outcome().await {
+            LanEvent::FileCompleted { transfer_id: 1, path: Some(path), .. } => assert_eq!(path, dest),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(rx.sent().await, FileControl::Complete { id: 1, ok: true });
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert!(!Incoming::part_path(&dest).exists());
+    }
+
+    #[tokio::test]
+    async fn resumes_from_what_was_kept() {
+        let mut rx = receiver("resume").await;
+        let data = content();
+        let sum = sha256(&rx.dir, &data).await;
+        let dest = rx.dir.join("map.pak");
+        std::fs::write(Incoming::part_path(&dest), &data[..CHUNK_SIZE]).unwrap();
+
+        rx.offer(2, "map.pak", data.len() as u64, sum).await;
+        rx.accept(2, dest.clone()).await;
+        let offset = CHUNK_SIZE as u64;
+        assert_eq!(rx.sent().await, FileControl::Accept { id: 2, offset });
+        rx.transfers.on_chunk(&rx.manager, PEER.into(), 2, offset, Bytes::copy_from_slice(&data[CHUNK_SIZE..])).await;
+
+        assert!(matches!(rx.outcome().await, LanEvent::FileCompleted { path: Some(_), .. }));
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_checksum() {
+        let mut rx = receiver("checksum").await;
+        let data = content();
+        let sum = sha256(&rx.dir, b"something else").await;
+        let dest = rx.dir.join("map.pak");
+
+        rx.offer(3, "map.pak", data.len() as u64, sum).await;
+        rx.accept(3, dest.clone()).await;
+        rx.sent().await;
+        rx.send(3, &data).await;
+
+        match rx.outcome().await {
+            LanEvent::FileFailed { transfer_id: 3, reason, .. } => assert!(reason.contains("checksum mismatch")),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(rx.sent().await, FileControl::Complete { id: 3, ok: false });
+        assert!(!dest.exists());
+        assert!(!Incoming::part_path(&dest).exists());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_size() {
+        let mut rx = receiver("size").await;
+        let data = content();
+        let sum = sha256(&rx.dir, &data).await;
+        let dest = rx.dir.join("map.pak");
+
+        // The checksum is right, but the file ends up longer than offered.
+        rx.offer(4, "map.pak", CHUNK_SIZE as u64, sum).await;
+        rx.accept(4, dest.clone()).await;
+        rx.sent().await;
+        rx.send(4, &data).await;
+
+        assert!(matches!(rx.outcome().await, LanEvent::FileFailed { transfer_id: 4, .. }));
+        assert_eq!(rx.sent().await, FileControl::Complete { id: 4, ok: false });
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn saves_under_a_safe_name() {
+        let mut rx = receiver("names").await;
+        let data = b"not a password file".to_vec();
+        let sum = sha256(&rx.dir, &data).await;
+        let downloads = rx.dir.join("downloads");
+        std::fs::create_dir(&downloads).unwrap();
+
+        rx.offer(5, "../../etc/\x1b[2Jpasswd", data.len() as u64, sum).await;
+        match rx.events.recv().await.unwrap() {
+            LanEvent::FileOffered { name, .. } => assert_eq!(name, "[2Jpasswd"),
+            event => panic!("unexpected {:?}", event),
+        }
+        rx.accept(5, downloads.clone()).await;
+        rx.send(5, &data).await;
+
+        let saved = downloads.join("[2Jpasswd");
+        match rx.outcome().await {
+            LanEvent::FileCompleted { path: Some(path), .. } => assert_eq!(path, saved),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(std::fs::read(&saved).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&rx.dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn cuts_names_down_to_a_file_name() {
+        assert_eq!(safe_name("map.pak"), "map.pak");
+        assert_eq!(safe_name("/etc/passwd"), "passwd");
+        assert_eq!(safe_name("..\\..\\boot.ini"), "boot.ini");
+        assert_eq!(safe_name("mods/"), "file");
+        assert_eq!(safe_name(".."), "file");
+        assert_eq!(safe_name("a\nb\x07c"), "abc");
+    }
+
+    #[tokio::test]
+    async fn ignores_chunks_for_unaccepted_offers() {
+        let mut rx = receiver("unaccepted").await;
+        rx.offer(6, "map.pak", 5, String::new()).await;
+        rx.transfers.on_chunk(&rx.manager, PEER.into(), 6, 0, Bytes::from_static(b"hello")).await;
+        rx.transfers.on_chunk(&rx.manager, PEER.into(), 7, 0, Bytes::from_static(b"hello")).await;
+
+        assert!(matches!(rx.events.recv().await, Some(LanEvent::FileOffered { transfer_id: 6, .. })));
+        assert!(rx.events.try_recv().is_err());
+        assert!(rx.transfers.accept(&rx.manager, PEER, 7, rx.dir.join("x")).await.is_err());
+    }
+}
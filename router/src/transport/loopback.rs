@@ -0,0 +1,148 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::{Channel, ChannelState, LinkEvents, LinkState, PeerTransport};
+
+// Frames in flight each way before senders wait.
+const CAPACITY: usize = 256;
+
+/// One end of an in-process link, for tests and for running two peer
+/// managers in one program. Every channel is reliable and ordered, and
+/// frames sent before the other end starts wait for it.
+pub struct LoopbackTransport {
+    outbound: mpsc::Sender<(Channel, Bytes)>,
+    inbound: Mutex<Option<mpsc::Receiver<(Channel, Bytes)>>>,
+    started: AtomicBool,
+    closed: CancellationToken,
+}
+
+/// Two connected ends; close either and both go down.
+pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+    let (a_tx, a_rx) = mpsc::channel(CAPACITY);
+    let (b_tx, b_rx) = mpsc::channel(CAPACITY);
+    let closed = CancellationToken::new();
+    let end = |outbound, inbound| LoopbackTransport {
+        outbound,
+        inbound: Mutex::new(Some(inbound)),
+        started: AtomicBool::new(false),
+        closed: closed.clone(),
+    };
+    (end(a_tx, b_rx), end(b_tx, a_rx))
+}
+
+impl PeerTransport for LoopbackTransport {
+    fn kind(&self) -> &'static str {
+        "loopback"
+    }
+
+    fn start(&self, events: LinkEvents) {
+        let Some(mut inbound) = self.inbound.lock().unwrap().take() else {
+            return;
+        };
+        self.started.store(true, Ordering::Relaxed);
+        let closed = self.closed.clone();
+        tokio::spawn(async move {
+            events.state(LinkState::Connected).await;
+            loop {
+                tokio::select! {
+                    frame = inbound.recv() => match frame {
+                        Some((channel, data)) => events.frame(channel, data).await,
+                        None => break,
+                    },
+                    _ = closed.cancelled() => break,
+                }
+            }
+            events.state(LinkState::Closed).await;
+        });
+    }
+
+    fn send<'a>(&'a self, channel: Channel, frame: &'a Bytes) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            if self.closed.is_cancelled() {
+                return Err(anyhow!("Loopback link closed"));
+            }
+            self.outbound
+                .send((channel, frame.clone()))
+                .await
+                .map_err(|_| anyhow!("Loopback link closed"))?;
+            Ok(frame.len())
+        })
+    }
+
+    fn channel_state(&self, _channel: Channel) -> ChannelState {
+        match self.state() {
+            LinkState::Connected => ChannelState::Open,
+            LinkState::Closed => ChannelState::Closed,
+            _ => ChannelState::Connecting,
+        }
+    }
+
+    fn state(&self) -> LinkState {
+        if self.closed.is_cancelled() {
+            LinkState::Closed
+        } else if self.started.load(Ordering::Relaxed) {
+            LinkState::Connected
+        } else {
+            LinkState::New
+        }
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        self.closed.cancel();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn next<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn carries_every_channel_both_ways() {
+        let (alice, bob) = pair();
+        // Sent before the far end starts, so it waits for it.
+        alice.send(Channel::Control, &Bytes::from_static(b"early")).await.unwrap();
+
+        let (events, mut at_alice, mut alice_states) = LinkEvents::recorder();
+        alice.start(events);
+        let (events, mut at_bob, _) = LinkEvents::recorder();
+        bob.start(events);
+        assert_eq!(next(&mut alice_states).await, LinkState::Connected);
+        assert_eq!(next(&mut at_bob).await, (Channel::Control, Bytes::from_static(b"early")));
+
+        for channel in Channel::ALL {
+            let data = Bytes::from(channel.label());
+            alice.send(channel, &data).await.unwrap();
+            assert_eq!(next(&mut at_bob).await, (channel, data.clone()));
+            bob.send(channel, &data).await.unwrap();
+            assert_eq!(next(&mut at_alice).await, (channel, data));
+            assert_eq!(alice.channel_state(channel), ChannelState::Open);
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_one_end_closes_both() {
+        let (alice, bob) = pair();
+        assert_eq!(alice.state(), LinkState::New);
+        assert_eq!(alice.channel_state(Channel::Control), ChannelState::Connecting);
+        let (events, _, mut bob_states) = LinkEvents::recorder();
+        bob.start(events);
+        assert_eq!(next(&mut bob_states).await, LinkState::Connected);
+
+        alice.close().await.unwrap();
+        assert_eq!(next(&mut bob_states).await, LinkState::Closed);
+        assert_eq!(bob.state(), LinkState::Closed);
+        assert_eq!(bob.channel_state(Channel::File), ChannelState::Closed);
+        assert!(bob.send(Channel::Control, &Bytes::from_static(b"late")).await.is_err());
+    }
+}
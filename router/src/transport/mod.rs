@@ -0,0 +1,195 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+pub mod loopback;
+pub mod quic;
+pub mod webrtc;
+
+/// The three kinds of traffic a link carries, each with its own delivery
+/// guarantees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Tunneled IP packets, pings and unreliable throughput tests:
+    /// unordered and never retransmitted, so a lost packet never holds
+    /// back the ones behind it.
+    Packet,
+    /// Hello, chat and other control frames: reliable and ordered.
+    Control,
+    /// File transfer chunks: reliable and ordered, kept apart from control
+    /// so a big transfer never delays chat.
+    File,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Packet, Channel::Control, Channel::File];
+
+    /// Name in logs and metrics; also the WebRTC data channel label.
+    pub fn label(self) -> &'static str {
+        match self {
+            Channel::Packet => "packets",
+            Channel::Control => "control",
+            Channel::File => "files",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// Not open yet; frames wait in the queue.
+    Connecting,
+    Open,
+    Closed,
+}
+
+/// State of the link to a peer as a whole, named as WebRTC names its
+/// peer connection states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+impl LinkState {
+    /// Whether the link is gone for good; a reconnect makes a new one.
+    pub fn is_down(self) -> bool {
+        matches!(self, LinkState::Failed | LinkState::Closed)
+    }
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LinkState::New => "new",
+            LinkState::Connecting => "connecting",
+            LinkState::Connected => "connected",
+            LinkState::Disconnected => "disconnected",
+            LinkState::Failed => "failed",
+            LinkState::Closed => "closed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a transport can tell about the path it runs over.
+#[derive(Debug, Clone, Default)]
+pub struct TransportStats {
+    /// Local and remote type of the ICE candidate pair in use, e.g.
+    /// `host` and `srflx`. WebRTC only.
+    pub candidates: Option<(String, String)>,
+    /// The transport's own round trip estimate.
+    pub rtt_seconds: Option<f64>,
+}
+
+type FrameHandler = dyn Fn(Channel, Bytes) -> BoxFuture<'static, ()> + Send + Sync;
+type StateHandler = dyn Fn(LinkState) -> BoxFuture<'static, ()> + Send + Sync;
+
+/// Where a transport hands what it receives: every whole frame, with the
+/// channel it came in on, and every change of link state.
+#[derive(Clone)]
+pub struct LinkEvents {
+    on_frame: Arc<FrameHandler>,
+    on_state: Arc<StateHandler>,
+}
+
+impl LinkEvents {
+    pub fn new<F, FF, S, SF>(on_frame: F, on_state: S) -> Self
+    where
+        F: Fn(Channel, Bytes) -> FF + Send + Sync + 'static,
+        FF: Future<Output = ()> + Send + 'static,
+        S: Fn(LinkState) -> SF + Send + Sync + 'static,
+        SF: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            on_frame: Arc::new(move |channel, data| Box::pin(on_frame(channel, data))),
+            on_state: Arc::new(move |state| Box::pin(on_state(state))),
+        }
+    }
+
+    pub async fn frame(&self, channel: Channel, data: Bytes) {
+        (self.on_frame)(channel, data).await
+    }
+
+    pub async fn state(&self, state: LinkState) {
+        (self.on_state)(state).await
+    }
+}
+
+/// One link to one peer. The peer manager frames, queues and paces
+/// traffic the same way whatever carries it; a transport only moves whole
+/// frames on the three channels and says how the link is doing.
+pub trait PeerTransport: Send + Sync {
+    /// Short name for listings and metrics: `webrtc`, `quic`, `loopback`.
+    fn kind(&self) -> &'static str;
+
+    /// Starts handing received frames and state changes to `events`.
+    /// Called once, as the manager takes the link on, before anything is
+    /// sent on it.
+    fn start(&self, events: LinkEvents);
+
+    /// Sends one frame and returns the bytes handed on. May wait for room,
+    /// but a channel that is not open fails rather than waits.
+    fn send<'a>(&'a self, channel: Channel, frame: &'a Bytes) -> BoxFuture<'a, Result<usize>>;
+
+    fn channel_state(&self, channel: Channel) -> ChannelState;
+
+    /// Bytes sent on `channel` but still buffered below us.
+    fn buffered(&self, channel: Channel) -> BoxFuture<'_, usize> {
+        let _ = channel;
+        Box::pin(async { 0 })
+    }
+
+    /// Resolves once what is buffered on `channel` has gone down, for
+    /// transports that buffer at all.
+    fn drained(&self, channel: Channel) -> BoxFuture<'_, ()> {
+        let _ = channel;
+        Box::pin(std::future::pending())
+    }
+
+    fn state(&self) -> LinkState;
+
+    fn stats(&self) -> BoxFuture<'_, TransportStats> {
+        Box::pin(async { TransportStats::default() })
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+impl fmt::Debug for dyn PeerTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} link ({})", self.kind(), self.state())
+    }
+}
+
+#[cfg(test)]
+impl LinkEvents {
+    /// Events that only record what they are handed, for tests.
+    pub(crate) fn recorder() -> (
+        Self,
+        tokio::sync::mpsc::UnboundedReceiver<(Channel, Bytes)>,
+        tokio::sync::mpsc::UnboundedReceiver<LinkState>,
+    ) {
+        let (frame_tx, frames) = tokio::sync::mpsc::unbounded_channel();
+        let (state_tx, states) = tokio::sync::mpsc::unbounded_channel();
+        let events = Self::new(
+            move |channel, data| {
+                let _ = frame_tx.send((channel, data));
+                async {}
+            },
+            move |state| {
+                let _ = state_tx.send(state);
+                async {}
+            },
+        );
+        (events, frames, states)
+    }
+}
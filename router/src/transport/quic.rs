@@ -0,0 +1,465 @@
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{
+    Connection, ConnectionError, Endpoint, IdleTimeout, Incoming, ReadExactError, RecvStream, SendStream,
+    TransportConfig, VarInt,
+};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{Channel, ChannelState, LinkEvents, LinkState, PeerTransport, TransportStats};
+use crate::identity::{self, Identity};
+
+// Name the client asks for. Nothing checks it; see `PeerCertificate`.
+const SERVER_NAME: &str = "lan-racer";
+const ALPN: &[u8] = b"lan-racer/1";
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+// In milliseconds: a peer silent this long, keep-alives included, is gone.
+const IDLE_TIMEOUT_MS: u32 = 30_000;
+// Longest peer id taken in the handshake.
+const MAX_ID: usize = 256;
+// Longest frame taken on a stream; file chunks are far smaller.
+const MAX_FRAME: usize = 1024 * 1024;
+
+/// A UDP socket for direct QUIC links to peers that can be reached without
+/// NAT traversal, such as on the same LAN: no signaling server, no STUN.
+/// Either side connects to the other's address; the one connecting goes
+/// first in a handshake that swaps peer ids.
+///
+/// Both sides show the certificate of their `Identity` and prove they hold
+/// its key. Any certificate is taken; whether its fingerprint goes with the
+/// peer id is for the trust list to say.
+#[derive(Clone)]
+pub struct DirectEndpoint {
+    endpoint: Endpoint,
+}
+
+impl DirectEndpoint {
+    /// Listens on `addr` as `identity`; port 0 for one that only connects
+    /// out.
+    pub fn bind(addr: SocketAddr, identity: &Identity) -> Result<Self> {
+        let mut endpoint = Endpoint::server(server_config(identity)?, addr)?;
+        endpoint.set_default_client_config(client_config(identity)?);
+        Ok(Self { endpoint })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Opens a link to the peer at `addr`, introducing ourselves as
+    /// `local_id`, and returns the peer's id with the link.
+    pub async fn connect(&self, addr: SocketAddr, local_id: &str) -> Result<(String, QuicTransport)> {
+        let handshake = async {
+            let conn = self.endpoint.connect(addr, SERVER_NAME)?.await?;
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(local_id.as_bytes()).await?;
+            send.finish()?;
+            let peer_id = read_id(&mut recv).await?;
+            Ok((peer_id, QuicTransport::new(conn).await?))
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| anyhow!("Handshake with {} timed out", addr))?
+    }
+
+    /// Next peer trying to connect, `None` once the endpoint is closed.
+    pub async fn accept(&self) -> Option<Incoming> {
+        self.endpoint.accept().await
+    }
+
+    /// Answers a peer's handshake as `local_id` and returns the peer's id
+    /// with the link.
+    pub async fn handshake(incoming: Incoming, local_id: &str) -> Result<(String, QuicTransport)> {
+        let remote = incoming.remote_address();
+        let handshake = async {
+            let conn = incoming.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let peer_id = read_id(&mut recv).await?;
+            send.write_all(local_id.as_bytes()).await?;
+            send.finish()?;
+            Ok((peer_id, QuicTransport::new(conn).await?))
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| anyhow!("Handshake with {} timed out", remote))?
+    }
+}
+
+async fn read_id(recv: &mut RecvStream) -> Result<String> {
+    let id = String::from_utf8(recv.read_to_end(MAX_ID).await?)?;
+    if id.is_empty() {
+        bail!("empty peer id");
+    }
+    Ok(id)
+}
+
+/// A QUIC connection to one peer. Packet frames go as datagrams, unless
+/// they are too big for one; each channel has a unidirectional stream for
+/// what needs to be reliable, its frames prefixed with their length.
+pub struct QuicTransport {
+    conn: Connection,
+    streams: HashMap<Channel, Mutex<SendStream>>,
+    fingerprint: String,
+}
+
+impl QuicTransport {
+    async fn new(conn: Connection) -> Result<Self> {
+        let fingerprint = conn
+            .peer_identity()
+            .and_then(|certs| certs.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| certs.first().map(|cert| identity::fingerprint(cert)))
+            .ok_or(anyhow!("no peer certificate"))?;
+        let mut streams = HashMap::new();
+        for channel in Channel::ALL {
+            let mut stream = conn.open_uni().await?;
+            // Chat and other control frames go ahead of file chunks.
+            stream.set_priority(if channel == Channel::File { 0 } else { 1 })?;
+            stream.write_all(&[tag(channel)]).await?;
+            streams.insert(channel, Mutex::new(stream));
+        }
+        Ok(Self { conn, streams, fingerprint })
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Fingerprint of the certificate the peer proved it holds the key of.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+fn tag(channel: Channel) -> u8 {
+    match channel {
+        Channel::Packet => 0,
+        Channel::Control => 1,
+        Channel::File => 2,
+    }
+}
+
+fn from_tag(tag: u8) -> Option<Channel> {
+    Channel::ALL.into_iter().find(|&c| self::tag(c) == tag)
+}
+
+// Hands every frame on `stream` to `events` until the stream ends.
+async fn read_frames(mut stream: RecvStream, events: LinkEvents) -> Result<()> {
+    let mut tag = [0u8];
+    stream.read_exact(&mut tag).await?;
+    let channel = from_tag(tag[0]).ok_or(anyhow!("unknown stream tag {}", tag[0]))?;
+
+    loop {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(0)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            bail!("{} byte frame on the {} stream", len, channel.label());
+        }
+        let mut frame = vec![0u8; len];
+        stream.read_exact(&mut frame).await?;
+        events.frame(channel, Bytes::from(frame)).await;
+    }
+}
+
+impl PeerTransport for QuicTransport {
+    fn kind(&self) -> &'static str {
+        "quic"
+    }
+
+    fn start(&self, events: LinkEvents) {
+        let conn = self.conn.clone();
+        let datagrams = events.clone();
+        tokio::spawn(async move {
+            while let Ok(datagram) = conn.read_datagram().await {
+                datagrams.frame(Channel::Packet, datagram).await;
+            }
+        });
+
+        let conn = self.conn.clone();
+        let streams = events.clone();
+        tokio::spawn(async move {
+            while let Ok(stream) = conn.accept_uni().await {
+                let events = streams.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = read_frames(stream, events).await {
+                        debug!("Direct link stream ended: {}", e);
+                    }
+                });
+            }
+        });
+
+        let conn = self.conn.clone();
+        tokio::spawn(async move {
+            events.state(LinkState::Connected).await;
+            let reason = conn.closed().await;
+            events.state(closed_state(&reason)).await;
+        });
+    }
+
+    fn send<'a>(&'a self, channel: Channel, frame: &'a Bytes) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            if channel == Channel::Packet && self.conn.max_datagram_size().is_some_and(|max| frame.len() <= max) {
+                self.conn.send_datagram(frame.clone())?;
+                return Ok(frame.len());
+            }
+
+            let mut stream = self.streams[&channel].lock().await;
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+            stream.write_all(frame).await?;
+            Ok(frame.len())
+        })
+    }
+
+    fn channel_state(&self, _channel: Channel) -> ChannelState {
+        match self.conn.close_reason() {
+            None => ChannelState::Open,
+            Some(_) => ChannelState::Closed,
+        }
+    }
+
+    fn state(&self) -> LinkState {
+        match self.conn.close_reason() {
+            None => LinkState::Connected,
+            Some(reason) => closed_state(&reason),
+        }
+    }
+
+    fn stats(&self) -> BoxFuture<'_, TransportStats> {
+        let rtt = self.conn.rtt().as_secs_f64();
+        Box::pin(async move {
+            TransportStats {
+                candidates: None,
+                rtt_seconds: Some(rtt),
+            }
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        self.conn.close(VarInt::from_u32(0), b"closed");
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Either side closing on purpose is a close; anything else a failure.
+fn closed_state(reason: &ConnectionError) -> LinkState {
+    match reason {
+        ConnectionError::LocallyClosed | ConnectionError::ApplicationClosed(_) => LinkState::Closed,
+        _ => LinkState::Failed,
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(KEEP_ALIVE));
+    config.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(IDLE_TIMEOUT_MS))));
+    Arc::new(config)
+}
+
+fn server_config(identity: &Identity) -> Result<quinn::ServerConfig> {
+    let provider = provider();
+    let (cert, key) = identity.tls_parts();
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(PeerCertificate(provider)))
+        .with_single_cert(vec![cert], key.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn client_config(identity: &Identity) -> Result<quinn::ClientConfig> {
+    let provider = provider();
+    let (cert, key) = identity.tls_parts();
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PeerCertificate(provider)))
+        .with_client_auth_cert(vec![cert], key.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// Takes whatever certificate the other side shows, as long as it holds
+// the key. Peers have no names a CA could vouch for; they are known by
+// fingerprint, which `QuicTransport::fingerprint` hands to the trust list.
+#[derive(Debug)]
+struct PeerCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for PeerCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PeerCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerManager;
+    use crate::trust::TrustMode;
+    use tokio::sync::mpsc;
+
+    async fn next<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()
+    }
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    // A free UDP port on localhost, for a listener that binds by itself.
+    fn free_port() -> SocketAddr {
+        std::net::UdpSocket::bind(localhost()).unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn carries_every_channel_both_ways() {
+        let (alice_identity, bob_identity) = (Identity::ephemeral().unwrap(), Identity::ephemeral().unwrap());
+        let alice = DirectEndpoint::bind(localhost(), &alice_identity).unwrap();
+        let bob = DirectEndpoint::bind(localhost(), &bob_identity).unwrap();
+        let bob_addr = bob.local_addr().unwrap();
+
+        let accepted = tokio::spawn(async move {
+            let incoming = bob.accept().await.unwrap();
+            DirectEndpoint::handshake(incoming, "bob").await.unwrap()
+        });
+        let (bob_id, at_alice) = alice.connect(bob_addr, "alice").await.unwrap();
+        let (alice_id, at_bob) = accepted.await.unwrap();
+        assert_eq!((alice_id.as_str(), bob_id.as_str()), ("alice", "bob"));
+        assert_eq!(at_alice.fingerprint(), bob_identity.fingerprint());
+        assert_eq!(at_bob.fingerprint(), alice_identity.fingerprint());
+
+        let (events, mut to_alice, mut alice_states) = LinkEvents::recorder();
+        at_alice.start(events);
+        let (events, mut to_bob, _) = LinkEvents::recorder();
+        at_bob.start(events);
+        assert_eq!(next(&mut alice_states).await, LinkState::Connected);
+
+        // Too big for a datagram, so it goes on the packet stream instead.
+        let big = (Channel::Packet, Bytes::from(vec![7u8; 4000]));
+        let frames = Channel::ALL.map(|c| (c, Bytes::from(c.label())));
+        for (channel, data) in frames.into_iter().chain([big]) {
+            at_alice.send(channel, &data).await.unwrap();
+            assert_eq!(next(&mut to_bob).await, (channel, data.clone()));
+            at_bob.send(channel, &data).await.unwrap();
+            assert_eq!(next(&mut to_alice).await, (channel, data));
+        }
+
+        at_bob.close().await.unwrap();
+        assert_eq!(next(&mut alice_states).await, LinkState::Closed);
+        assert_eq!(at_alice.channel_state(Channel::Control), ChannelState::Closed);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_peer_with_another_fingerprint() {
+        let bob = PeerManager::in_memory("bob", TrustMode::Tofu).await;
+        let addr = free_port();
+        let listening = bob.clone();
+        tokio::spawn(async move { listening.serve_direct(addr).await });
+        // It binds before it first waits.
+        tokio::task::yield_now().await;
+
+        let alice = PeerManager::in_memory("alice", TrustMode::Tofu).await;
+        let other = Identity::ephemeral().unwrap();
+        alice.trust("bob", Some(other.fingerprint().to_owned())).await.unwrap();
+        let err = alice.connect_direct(addr).await.unwrap_err();
+        assert_eq!(err.to_string(), "Peer bob is trusted with another fingerprint");
+        assert!(!alice.has_peer("bob").await);
+
+        alice.trust("bob", Some(bob.fingerprint().to_owned())).await.unwrap();
+        assert_eq!(alice.connect_direct(addr).await.unwrap(), "bob");
+        assert!(alice.has_peer("bob").await);
+    }
+}
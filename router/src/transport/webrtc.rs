@@ -0,0 +1,281 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use tracing::{Span, warn};
+use webrtc::api::API;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::ice::candidate::CandidatePairState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::certificate::RTCCertificate;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::stats::StatsReportType;
+
+use super::{Channel, ChannelState, LinkEvents, LinkState, PeerTransport, TransportStats};
+use crate::peer;
+
+// Label used by older builds for their single combined channel. Those
+// builds only understand packet and chat frames and never send a hello.
+const LEGACY_CHANNEL: &str = "chat";
+
+// The buffered amount low callback fires once a channel drains below this.
+const LOW_WATERMARK: usize = 256 * 1024;
+
+/// A WebRTC peer connection with one data channel per [`Channel`]. The
+/// offer and answer travel through the signaling server or by hand.
+pub struct WebRtcTransport {
+    pc: Arc<RTCPeerConnection>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    channels: Mutex<HashMap<Channel, Arc<RTCDataChannel>>>,
+    drained: HashMap<Channel, Notify>,
+    events: OnceLock<LinkEvents>,
+    span: Span,
+}
+
+impl WebRtcTransport {
+    pub async fn new(api: &API, peer_id: &str, certificate: &RTCCertificate) -> Result<Self> {
+        let config = RTCConfiguration {
+            certificates: vec![certificate.clone()],
+            ice_servers: vec![webrtc::ice_transport::ice_server::RTCIceServer {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let pc = Arc::new(api.new_peer_connection(config).await?);
+        let shared = Arc::new(Shared {
+            channels: Mutex::new(HashMap::new()),
+            drained: Channel::ALL.into_iter().map(|c| (c, Notify::new())).collect(),
+            events: OnceLock::new(),
+            span: peer::span(peer_id),
+        });
+
+        // The connection keeps its callbacks alive, so they only hold on
+        // to the shared state weakly.
+        let weak = Arc::downgrade(&shared);
+        pc.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+            let shared = weak.upgrade();
+            Box::pin(async move {
+                if let Some(shared) = shared
+                    && let Some(events) = shared.events.get()
+                {
+                    events.state(link_state(s)).await;
+                }
+            })
+        }));
+
+        let weak = Arc::downgrade(&shared);
+        pc.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
+            let shared = weak.upgrade();
+            Box::pin(async move {
+                if let Some(shared) = shared {
+                    shared.add_channel(dc).await;
+                }
+            })
+        }));
+
+        Ok(Self { pc, shared })
+    }
+
+    /// Opens the data channels and returns the offer, every candidate
+    /// gathered, as JSON.
+    pub async fn create_offer(&self) -> Result<String> {
+        let control = self.pc.create_data_channel(Channel::Control.label(), None).await?;
+        self.shared.add_channel(control).await;
+
+        let packet_init = RTCDataChannelInit {
+            ordered: Some(false),
+            max_retransmits: Some(0),
+            ..Default::default()
+        };
+        let packet = self
+            .pc
+            .create_data_channel(Channel::Packet.label(), Some(packet_init))
+            .await?;
+        self.shared.add_channel(packet).await;
+
+        let file = self.pc.create_data_channel(Channel::File.label(), None).await?;
+        self.shared.add_channel(file).await;
+
+        let offer = self.pc.create_offer(None).await?;
+        self.describe(offer).await
+    }
+
+    /// Applies the peer's offer and returns our answer as JSON.
+    pub async fn accept_offer(&self, offer: RTCSessionDescription) -> Result<String> {
+        self.pc.set_remote_description(offer).await?;
+        let answer = self.pc.create_answer(None).await?;
+        self.describe(answer).await
+    }
+
+    /// Applies the peer's answer to our offer.
+    pub async fn set_answer(&self, answer: RTCSessionDescription) -> Result<()> {
+        self.pc.set_remote_description(answer).await?;
+        Ok(())
+    }
+
+    // Sets `desc` as the local description and returns it once candidate
+    // gathering is complete, so it can be passed on in one piece.
+    async fn describe(&self, desc: RTCSessionDescription) -> Result<String> {
+        let mut gather_complete = self.pc.gathering_complete_promise().await;
+        self.pc.set_local_description(desc).await?;
+        let _ = gather_complete.recv().await;
+
+        let local_desc = self.pc.local_description().await.ok_or(anyhow!("No SDP"))?;
+        Ok(serde_json::to_string(&local_desc)?)
+    }
+
+    // The data channel carrying `channel`. Older builds never open a
+    // packet channel; their packets share the control one.
+    fn channel(&self, channel: Channel) -> Option<Arc<RTCDataChannel>> {
+        let channels = self.shared.channels.lock().unwrap();
+        let dc = match channel {
+            Channel::Packet => channels.get(&channel).or(channels.get(&Channel::Control)),
+            _ => channels.get(&channel),
+        };
+        dc.cloned()
+    }
+}
+
+impl Shared {
+    async fn add_channel(self: &Arc<Self>, dc: Arc<RTCDataChannel>) {
+        let channel = match dc.label() {
+            label if label == Channel::Packet.label() => Channel::Packet,
+            label if label == Channel::Control.label() || label == LEGACY_CHANNEL => Channel::Control,
+            label if label == Channel::File.label() => Channel::File,
+            other => {
+                warn!(parent: &self.span, "Ignoring unknown data channel: {}", other);
+                return;
+            }
+        };
+
+        let weak = Arc::downgrade(self);
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let shared = weak.upgrade();
+            Box::pin(async move {
+                if let Some(shared) = shared
+                    && let Some(events) = shared.events.get()
+                {
+                    events.frame(channel, msg.data).await;
+                }
+            })
+        }));
+
+        dc.set_buffered_amount_low_threshold(LOW_WATERMARK).await;
+        let weak = Arc::downgrade(self);
+        dc.on_buffered_amount_low(Box::new(move || {
+            let shared = weak.upgrade();
+            Box::pin(async move {
+                if let Some(shared) = shared {
+                    shared.drained[&channel].notify_one();
+                }
+            })
+        }))
+        .await;
+
+        self.channels.lock().unwrap().insert(channel, dc);
+    }
+}
+
+impl PeerTransport for WebRtcTransport {
+    fn kind(&self) -> &'static str {
+        "webrtc"
+    }
+
+    fn start(&self, events: LinkEvents) {
+        let _ = self.shared.events.set(events);
+    }
+
+    fn send<'a>(&'a self, channel: Channel, frame: &'a Bytes) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let dc = self.channel(channel).ok_or(anyhow!("No {} channel", channel.label()))?;
+            Ok(dc.send(frame).await?)
+        })
+    }
+
+    fn channel_state(&self, channel: Channel) -> ChannelState {
+        let Some(dc) = self.channel(channel) else {
+            return if self.state().is_down() { ChannelState::Closed } else { ChannelState::Connecting };
+        };
+        match dc.ready_state() {
+            RTCDataChannelState::Open => ChannelState::Open,
+            RTCDataChannelState::Closing | RTCDataChannelState::Closed => ChannelState::Closed,
+            _ => ChannelState::Connecting,
+        }
+    }
+
+    fn buffered(&self, channel: Channel) -> BoxFuture<'_, usize> {
+        Box::pin(async move {
+            match self.channel(channel) {
+                Some(dc) => dc.buffered_amount().await,
+                None => 0,
+            }
+        })
+    }
+
+    fn drained(&self, channel: Channel) -> BoxFuture<'_, ()> {
+        Box::pin(self.shared.drained[&channel].notified())
+    }
+
+    fn state(&self) -> LinkState {
+        link_state(self.pc.connection_state())
+    }
+
+    fn stats(&self) -> BoxFuture<'_, TransportStats> {
+        Box::pin(selected_pair(&self.pc))
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.pc.close().await?;
+            Ok(())
+        })
+    }
+}
+
+fn link_state(state: RTCPeerConnectionState) -> LinkState {
+    match state {
+        RTCPeerConnectionState::Connecting => LinkState::Connecting,
+        RTCPeerConnectionState::Connected => LinkState::Connected,
+        RTCPeerConnectionState::Disconnected => LinkState::Disconnected,
+        RTCPeerConnectionState::Failed => LinkState::Failed,
+        RTCPeerConnectionState::Closed => LinkState::Closed,
+        RTCPeerConnectionState::New | RTCPeerConnectionState::Unspecified => LinkState::New,
+    }
+}
+
+// The candidate types and RTT of the nominated ICE pair. The pair itself
+// keeps its candidates private, so this goes through the stats.
+async fn selected_pair(pc: &RTCPeerConnection) -> TransportStats {
+    let report = pc.get_stats().await;
+    let pair = report.reports.values().find_map(|r| match r {
+        StatsReportType::CandidatePair(p) if p.nominated && p.state == CandidatePairState::Succeeded => Some(p),
+        _ => None,
+    });
+    let Some(pair) = pair else {
+        return TransportStats::default();
+    };
+
+    let candidate_type = |id: &str| {
+        report.reports.values().find_map(|r| match r {
+            StatsReportType::LocalCandidate(c) | StatsReportType::RemoteCandidate(c) if c.id == id => {
+                Some(c.candidate_type.to_string())
+            }
+            _ => None,
+        })
+    };
+    TransportStats {
+        candidates: candidate_type(&pair.local_candidate_id).zip(candidate_type(&pair.remote_candidate_id)),
+        rtt_seconds: Some(pair.current_round_trip_time),
+    }
+}
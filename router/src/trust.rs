@@ -0,0 +1,263 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ban;
+use crate::chat::{self, now_millis};
+use crate::config;
+
+/// What to do with a peer whose fingerprint is not the one it is trusted
+/// with, or that is not trusted at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustMode {
+    /// Trust a peer with the fingerprint it first connects with, and
+    /// refuse it under any other.
+    #[default]
+    Tofu,
+    /// Only link with peers trusted by hand.
+    Explicit,
+    /// Like `tofu`, but only warn about a changed fingerprint.
+    Warn,
+}
+
+/// How a peer came to be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustSource {
+    /// Pinned the first time the peer connected.
+    FirstUse,
+    /// Pinned with `trust`.
+    Explicit,
+}
+
+/// A peer id and the certificate fingerprint it has to connect with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub peer_id: String,
+    pub fingerprint: String,
+    pub source: TrustSource,
+    /// Milliseconds since the Unix epoch.
+    pub since: u64,
+}
+
+/// What the trust list says about a peer id connecting with a fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Trusted,
+    /// The id is not pinned yet.
+    Unknown,
+    /// The id is pinned to another fingerprint.
+    Mismatch { expected: String },
+}
+
+/// Trusted peers, kept as a JSON file that is rewritten on every change.
+#[derive(Debug, Default)]
+pub struct TrustList {
+    path: Option<PathBuf>,
+    mode: TrustMode,
+    peers: Vec<TrustedPeer>,
+}
+
+impl TrustList {
+    /// Trust list that is never written to disk.
+    pub fn in_memory(mode: TrustMode) -> Self {
+        Self { mode, ..Default::default() }
+    }
+
+    pub fn open(path: &Path, mode: TrustMode) -> Result<Self> {
+        let peers = if path.exists() {
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            mode,
+            peers,
+        })
+    }
+
+    pub fn mode(&self) -> TrustMode {
+        self.mode
+    }
+
+    pub fn check(&self, peer_id: &str, fingerprint: Option<&str>) -> Verdict {
+        match self.peers.iter().find(|p| p.peer_id == peer_id) {
+            None => Verdict::Unknown,
+            Some(p) if fingerprint.is_some_and(|fp| ban::normalize(fp) == p.fingerprint) => Verdict::Trusted,
+            Some(p) => Verdict::Mismatch { expected: p.fingerprint.clone() },
+        }
+    }
+
+    /// Whether the mode lets a link through with `verdict`.
+    pub fn allows(&self, verdict: &Verdict) -> bool {
+        match verdict {
+            Verdict::Trusted => true,
+            Verdict::Unknown => self.mode != TrustMode::Explicit,
+            Verdict::Mismatch { .. } => self.mode == TrustMode::Warn,
+        }
+    }
+
+    /// Trusts `peer_id` with `fingerprint` from now on, replacing what it
+    /// was trusted with.
+    pub fn pin(&mut self, peer_id: &str, fingerprint: &str, source: TrustSource) -> Result<()> {
+        self.peers.retain(|p| p.peer_id != peer_id);
+        self.peers.push(TrustedPeer {
+            peer_id: peer_id.to_owned(),
+            fingerprint: ban::normalize(fingerprint),
+            source,
+            since: now_millis(),
+        });
+        self.save()
+    }
+
+    /// Forgets the fingerprint `peer_id` is trusted with. Returns whether
+    /// there was one.
+    pub fn unpin(&mut self, peer_id: &str) -> Result<bool> {
+        let before = self.peers.len();
+        self.peers.retain(|p| p.peer_id != peer_id);
+        if self.peers.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<TrustedPeer> {
+        self.peers.clone()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(&self.peers)?)?;
+        Ok(())
+    }
+}
+
+/// Default trust list file for the router that goes by `peer_id`.
+pub fn default_path(peer_id: &str) -> PathBuf {
+    config::data_dir().join("trusted").join(format!("{}.json", chat::sanitize(peer_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerManager;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    const FINGERPRINT: &str =
+        "sha-256 3C:4F:A1:0B:92:7E:11:D8:05:6A:BE:CC:29:F0:73:84:5D:E2:19:60:AF:0D:B7:48:92:35:C1:7A:EE:06:5B:F3";
+    const OTHER: &str =
+        "sha-256 00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF";
+
+    // A manager taking direct links on a free port of its own.
+    async fn listening(peer_id: &str) -> (PeerManager, SocketAddr) {
+        let manager = PeerManager::in_memory(peer_id, TrustMode::Tofu).await;
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let serving = manager.clone();
+        tokio::spawn(async move { serving.serve_direct(addr).await });
+        // It binds before it first waits.
+        tokio::task::yield_now().await;
+        (manager, addr)
+    }
+
+    #[test]
+    fn checks_fingerprints() {
+        let mut trust = TrustList::in_memory(TrustMode::Tofu);
+        assert_eq!(trust.check("bob", Some(FINGERPRINT)), Verdict::Unknown);
+        trust.pin("bob", &FINGERPRINT.to_lowercase(), TrustSource::Explicit).unwrap();
+
+        assert_eq!(trust.check("bob", Some(FINGERPRINT)), Verdict::Trusted);
+        let mismatch = Verdict::Mismatch { expected: FINGERPRINT.into() };
+        assert_eq!(trust.check("bob", Some(OTHER)), mismatch);
+        assert_eq!(trust.check("bob", None), mismatch);
+        assert_eq!(trust.check("carol", Some(FINGERPRINT)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn allows_by_mode() {
+        let mismatch = Verdict::Mismatch { expected: FINGERPRINT.into() };
+        for (mode, unknown, changed) in
+            [(TrustMode::Tofu, true, false), (TrustMode::Explicit, false, false), (TrustMode::Warn, true, true)]
+        {
+            let trust = TrustList::in_memory(mode);
+            assert!(trust.allows(&Verdict::Trusted));
+            assert_eq!(trust.allows(&Verdict::Unknown), unknown, "{:?}", mode);
+            assert_eq!(trust.allows(&mismatch), changed, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn persists_pins() {
+        let dir = std::env::temp_dir().join(format!("lan-racer-trust-test-{}", std::process::id()));
+        let path = dir.join("trusted.json");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut trust = TrustList::open(&path, TrustMode::Tofu).unwrap();
+        trust.pin("bob", FINGERPRINT, TrustSource::FirstUse).unwrap();
+        trust.pin("carol", FINGERPRINT, TrustSource::FirstUse).unwrap();
+        // Pinning again replaces the old pin.
+        trust.pin("bob", OTHER, TrustSource::Explicit).unwrap();
+        assert!(trust.unpin("carol").unwrap());
+        assert!(!trust.unpin("carol").unwrap());
+
+        let reopened = TrustList::open(&path, TrustMode::Explicit).unwrap();
+        assert_eq!(reopened.list(), trust.list());
+        assert_eq!(reopened.list().len(), 1);
+        assert_eq!(reopened.list()[0].source, TrustSource::Explicit);
+        assert_eq!(reopened.check("bob", Some(OTHER)), Verdict::Trusted);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn pins_on_first_use_and_refuses_a_changed_fingerprint() {
+        let (bob, addr) = listening("bob").await;
+        let alice = PeerManager::in_memory("alice", TrustMode::Tofu).await;
+        alice.connect_direct(addr).await.unwrap();
+
+        // Pinned once the link is up.
+        let pinned = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let [pin] = &alice.trusted()[..] {
+                    return pin.clone();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!((pinned.peer_id.as_str(), pinned.fingerprint.as_str()), ("bob", bob.fingerprint()));
+        assert_eq!(pinned.source, TrustSource::FirstUse);
+
+        // Someone else calling itself bob.
+        let (_impostor, addr) = listening("bob").await;
+        let err = alice.connect_direct(addr).await.unwrap_err();
+        assert_eq!(err.to_string(), "Peer bob is trusted with another fingerprint");
+        assert_eq!(alice.trusted(), [pinned]);
+    }
+
+    #[tokio::test]
+    async fn explicit_mode_refuses_unknown_peers() {
+        let (bob, addr) = listening("bob").await;
+        let alice = PeerManager::in_memory("alice", TrustMode::Explicit).await;
+        let err = alice.connect_direct(addr).await.unwrap_err();
+        assert_eq!(err.to_string(), "Peer bob is not trusted");
+        assert!(!alice.has_peer("bob").await);
+
+        alice.trust("bob", Some(bob.fingerprint().to_owned())).await.unwrap();
+        assert_eq!(alice.connect_direct(addr).await.unwrap(), "bob");
+        // Nothing is pinned on first use in explicit mode.
+        let trusted = alice.trusted();
+        assert_eq!(trusted.len(), 1);
+        assert_eq!(trusted[0].source, TrustSource::Explicit);
+    }
+}